@@ -1,5 +1,6 @@
 use nat_traversal::client::Client;
-use nat_traversal::logger::NatLoggable;
+use nat_traversal::event::ClientEvent;
+use nat_traversal::logger;
 use std::env;
 use std::io::{self, Write};
 use std::net::SocketAddr;
@@ -28,9 +29,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Client ID: {}", client_id);
     println!("Server: {}", server_addr);
 
+    // the events below are all the REPL shows, unless `NT_VERBOSE=1` adds
+    // every step the library takes
+    logger::set_verbose(env::var("NT_VERBOSE").is_ok_and(|v| v == "1"));
+
     // Create and register client
     let mut client = Client::new(client_id.clone(), server_addr)?;
 
+    // same file format as the server's; only our own line is used
+    if let Ok(path) = env::var("NT_CREDENTIALS") {
+        client.load_credentials(&path)?;
+        println!("🔐 Registration will be authenticated");
+    }
+
+    // accepted files land here
+    let download_dir = env::var("NT_DOWNLOADS").unwrap_or_else(|_| ".".to_string());
+
+    // what happens in the background shows up between commands
+    let prompt_id = client_id.clone();
+    client.on_event(move |event| {
+        if show_event(event) {
+            print!("\n{} > ", prompt_id);
+            io::stdout().flush().unwrap();
+        }
+    });
+
     println!("\n📡 Registering with signaling server...");
     client.register()?;
 
@@ -38,8 +61,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!();
     print_commands();
 
-    let mut connected_peer: Option<SocketAddr> = None;
-
     // Interactive command loop
     loop {
         print!("\n{} > ", client_id);
@@ -68,8 +89,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
 
                 match client.connect_to_peer(peer_id) {
-                    Ok(peer_addr) => {
-                        connected_peer = Some(peer_addr);
+                    Ok(_) => {
                         println!("\n🎉 Connection process completed!");
                         println!("✅ You can now send messages to '{}'", peer_id);
 
@@ -84,21 +104,103 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
 
             "send" => {
-                if parts.len() < 2 {
-                    println!("❌ Usage: send <message>");
+                if parts.len() < 3 {
+                    println!("❌ Usage: send <peer_id> <message>");
                     continue;
                 }
 
-                if let Some(peer_addr) = connected_peer {
-                    let message = parts[1..].join(" ");
-                    match client.send_message(peer_addr, &message) {
+                let peer_id = parts[1];
+                if client.peer_addr(peer_id).is_some() {
+                    let message = parts[2..].join(" ");
+                    match client.send_message(peer_id, &message) {
                         Ok(()) => {
                             println!("✅ Message sent successfully!");
                         }
                         Err(e) => println!("❌ Send failed: {}", e),
                     }
                 } else {
-                    println!("❌ Not connected to any peer. Use 'connect <peer_id>' first.");
+                    println!(
+                        "❌ Not connected to '{}'. Use 'connect {}' first.",
+                        peer_id, peer_id
+                    );
+                }
+            }
+
+            "sendfile" => {
+                if parts.len() < 3 {
+                    println!("❌ Usage: sendfile <peer_id> <path>");
+                    continue;
+                }
+
+                let peer_id = parts[1];
+                let path = parts[2..].join(" ");
+                match client.send_file(peer_id, &path) {
+                    Ok(()) => println!("✅ '{}' delivered to {}", path, peer_id),
+                    Err(e) => println!("❌ Transfer failed: {}", e),
+                }
+            }
+
+            "accept" => {
+                match client.accept_file(parts.get(1).copied(), &download_dir) {
+                    Ok(path) => println!("✅ Saved {}", path.display()),
+                    Err(e) => println!("❌ Transfer failed: {}", e),
+                }
+            }
+
+            "reject" => {
+                match client.reject_file(parts.get(1).copied(), "rejected by user") {
+                    Ok(offer) => println!("🚫 Rejected '{}' from {}", offer.name, offer.peer_id),
+                    Err(e) => println!("❌ {}", e),
+                }
+            }
+
+            "offers" => {
+                let offers = client.pending_offers();
+                if offers.is_empty() {
+                    println!("No pending file offers.");
+                }
+                for offer in offers {
+                    println!("  {:<14} {} ({} bytes)", offer.peer_id, offer.name, offer.size);
+                }
+            }
+
+            "peers" => {
+                let peers = client.get_connected_peers();
+                if peers.is_empty() {
+                    println!("No peers yet. Use 'connect <peer_id>' first.");
+                }
+                for (peer_id, peer) in peers {
+                    println!(
+                        "  {:<14} {:<22} {:<6} seen {:.1}s ago",
+                        peer_id,
+                        peer.addr,
+                        peer.state,
+                        peer.last_seen.elapsed().as_secs_f32()
+                    );
+                }
+            }
+
+            "stun" => {
+                // defaults to the signaling server, which also speaks STUN
+                let target = match parts.get(1).map(|s| s.parse::<SocketAddr>()) {
+                    Some(Ok(addr)) => addr,
+                    Some(Err(_)) => {
+                        println!("❌ Usage: stun [host:port]");
+                        continue;
+                    }
+                    None => server_addr,
+                };
+                match client.stun_binding(target) {
+                    Ok(mapped) => println!("🧭 Reflexive address via {}: {}", target, mapped),
+                    Err(e) => println!("❌ STUN binding failed: {}", e),
+                }
+            }
+
+            "nat" => {
+                println!("\n🧪 Running NAT behavior tests against {}...", server_addr);
+                match client.detect_nat_type() {
+                    Ok(nat_type) => println!("🏷️ NAT type: {}", nat_type),
+                    Err(e) => println!("❌ NAT detection failed: {}", e),
                 }
             }
 
@@ -141,6 +243,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
 
             "quit" | "exit" => {
+                for (peer_id, _) in client.get_connected_peers() {
+                    let _ = client.close(&peer_id);
+                }
                 println!("\n📋 Final Report");
                 println!("━━━━━━━━━━━━━━━");
                 client.print_detailed_report();
@@ -160,11 +265,47 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// Print what the user should see of `event`; false if nothing.
+fn show_event(event: &ClientEvent) -> bool {
+    match event {
+        ClientEvent::MessageReceived { peer_id, message } => {
+            println!("\n📥 {}: {}", peer_id, message);
+        }
+        ClientEvent::FileOffered(offer) => {
+            println!(
+                "\n📁 {} offers '{}' ({} bytes). Type 'accept {}' or 'reject {}'",
+                offer.peer_id, offer.name, offer.size, offer.peer_id, offer.peer_id
+            );
+        }
+        ClientEvent::Connected { peer_id, addr } => {
+            println!("\n🔗 {} reachable at {}", peer_id, addr);
+        }
+        ClientEvent::Disconnected { peer_id, reason } => {
+            println!("\n👋 {} disconnected: {}", peer_id, reason);
+        }
+        ClientEvent::Error { peer_id: Some(peer_id), message } => {
+            println!("\n❌ {}: {}", peer_id, message);
+        }
+        ClientEvent::Error { peer_id: None, message } => {
+            println!("\n❌ {}", message);
+        }
+        ClientEvent::PeerDiscovered { .. } | ClientEvent::PunchStarted { .. } => return false,
+    }
+    true
+}
+
 fn print_commands() {
     println!("📚 Available Commands:");
     println!("━━━━━━━━━━━━━━━━━━━━━");
     println!("  connect <peer_id>  - Initiate hole punching with peer");
-    println!("  send <message>     - Send direct P2P message");
+    println!("  send <peer_id> <message> - Send direct P2P message");
+    println!("  sendfile <peer_id> <path> - Send a file (resumes if interrupted)");
+    println!("  accept [peer_id]  - Accept a file offer into $NT_DOWNLOADS or .");
+    println!("  reject [peer_id]  - Reject a file offer");
+    println!("  offers            - List pending file offers");
+    println!("  peers             - List peer connections");
+    println!("  stun [host:port]   - Discover reflexive address via STUN");
+    println!("  nat               - Detect NAT type (server needs an alternate address)");
     println!("  status            - Show current connection status");
     println!("  report            - Display detailed NAT traversal report");
     println!("  test <peer_id>     - Run automated connection test");
@@ -178,7 +319,7 @@ fn run_connection_test(client: &mut Client, peer_id: &str) {
     println!("───────────────────────────────────");
 
     match client.connect_to_peer(peer_id) {
-        Ok(peer_addr) => {
+        Ok(_) => {
             println!("✅ Phase 1 Complete: Connection established");
 
             println!("\n🔬 Test Phase 2: Message Exchange");
@@ -187,7 +328,7 @@ fn run_connection_test(client: &mut Client, peer_id: &str) {
             // Send test messages
             for i in 1..=3 {
                 let test_msg = format!("Test message #{} from automated test", i);
-                match client.send_message(peer_addr, &test_msg) {
+                match client.send_message(peer_id, &test_msg) {
                     Ok(()) => println!("✅ Test message {} sent", i),
                     Err(e) => println!("❌ Test message {} failed: {}", i, e),
                 }
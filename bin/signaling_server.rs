@@ -1,5 +1,7 @@
-use nat_traversal::server::Server;
+use nat_traversal::logger;
+use nat_traversal::server::{RelayConfig, Server};
 use std::env;
+use std::time::Duration;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Simple NAT Traversal - Signaling Server");
@@ -11,9 +13,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap_or_else(|| "0.0.0.0:9090".to_string());
 
     println!("Starting signaling server on {}", bind_addr);
+    // the server's log is what it prints
+    logger::set_verbose(true);
 
     let mut server = Server::new(&bind_addr)?;
 
+    // optional registration TTL in seconds
+    if let Some(ttl) = env::args().nth(2) {
+        server.set_registration_ttl(Duration::from_secs(ttl.parse()?));
+    }
+
+    // optional alternate STUN addresses for NAT type detection
+    for alternate in env::args().skip(3) {
+        server.add_alternate(&alternate)?;
+    }
+
+    // optional pre-shared keys, one `id hexkey` per line
+    if let Ok(path) = env::var("NT_CREDENTIALS") {
+        server.load_credentials(&path)?;
+    }
+
+    // optional relay fallback, `NT_RELAY=1` or `NT_RELAY=<quota bytes>:<lifetime secs>`
+    if let Ok(relay) = env::var("NT_RELAY") {
+        let mut config = RelayConfig::default();
+        if let Some((quota, lifetime)) = relay.split_once(':') {
+            config.quota_bytes = quota.parse()?;
+            config.lifetime = Duration::from_secs(lifetime.parse()?);
+        }
+        server.enable_relay(config);
+    }
+
     println!("✅ Signaling server ready!");
     println!("   Clients can register and discover peers");
     println!("   Press Ctrl+C to stop");
@@ -1,8 +1,13 @@
 // src/bin/test_signaling.rs
 // Simple test to verify signaling server works
 
-use nat_traversal::client::Client;
-use std::net::SocketAddr;
+use nat_traversal::client::{Client, ClientConfig};
+use nat_traversal::event::ClientEvent;
+use nat_traversal::logger::{self, ConnectionState};
+use nat_traversal::protocol::{Message, PeerMessage};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::Ordering;
 use std::thread;
 use std::time::Duration;
 
@@ -11,6 +16,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("=========================================");
 
     let server_addr: SocketAddr = "127.0.0.1:9090".parse()?;
+    logger::set_verbose(true);
 
     println!("Starting test sequence...");
     println!("(Make sure signaling server is running on {})", server_addr);
@@ -21,6 +27,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("1️⃣  Creating and registering Alice...");
     let mut alice = Client::new("alice".to_string(), server_addr)?;
+    alice.set_keepalive_interval(Duration::from_secs(1));
     alice.register()?;
     println!("   ✅ Alice registered successfully");
 
@@ -28,6 +35,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("2️⃣  Creating and registering Bob...");
     let mut bob = Client::new("bob".to_string(), server_addr)?;
+    bob.set_keepalive_interval(Duration::from_secs(1));
     bob.register()?;
     println!("   ✅ Bob registered successfully");
 
@@ -59,7 +67,144 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     thread::sleep(Duration::from_millis(500));
 
-    println!("5️⃣  Testing peer not found...");
+    println!("5️⃣  Alice sending Bob an encrypted message...");
+    let bob_events = bob.events();
+    alice.send_message("bob", "hello over Noise")?;
+    println!("   ✅ Sealed message sent");
+
+    let delivered = std::iter::from_fn(|| bob_events.recv_timeout(Duration::from_secs(2)).ok()).any(|event| {
+        matches!(event, ClientEvent::MessageReceived { peer_id, message } if peer_id == "alice" && message == "hello over Noise")
+    });
+    if !delivered {
+        println!("   ❌ Bob never saw the message");
+        return Err("message not delivered".into());
+    }
+    println!("   ✅ Bob got it as an event");
+
+    println!("6️⃣  Forging a plaintext message to Bob...");
+    let bob_addr = alice.peer_addr("bob").ok_or("alice lost bob")?;
+    let forged = PeerMessage::Data {
+        payload: "send money".to_string(),
+    };
+    UdpSocket::bind("0.0.0.0:0")?.send_to(&forged.encode(), bob_addr)?;
+    thread::sleep(Duration::from_millis(500));
+    if bob.console_logger.lock().unwrap().auth_failures() == 0 {
+        println!("   ❌ Bob accepted an unauthenticated message!");
+        return Err("forged message accepted".into());
+    }
+    println!("   ✅ Bob dropped the forged message");
+
+    let impostor = UdpSocket::bind("0.0.0.0:0")?;
+    let forged = Message::StartPunchWithPeer {
+        timestamp: 0,
+        delay_ms: 0,
+        peer_id: "mallory".to_string(),
+        peer_addr: impostor.local_addr()?,
+        peer_key: vec![0; 32],
+        peer_candidates: Vec::new(),
+        peer_next_port: 0,
+        peer_port_step: 0,
+    };
+    impostor.send_to(&forged.encode(), bob_addr)?;
+    thread::sleep(Duration::from_millis(500));
+    let injected = bob_events.try_iter().any(|event| {
+        matches!(event, ClientEvent::PeerDiscovered { peer_id, .. } if peer_id == "mallory")
+    });
+    if injected {
+        println!("   ❌ Bob took a punch command from {}", impostor.local_addr()?);
+        return Err("forged server command accepted".into());
+    }
+    println!("   ✅ Bob ignored a punch command from a fake server");
+
+    thread::sleep(Duration::from_millis(500));
+
+    println!("7️⃣  Alice streaming 200KB to Bob...");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut outgoing = alice.open_stream("bob")?;
+    outgoing.write_all(&data)?;
+    outgoing.shutdown();
+    let mut incoming = bob
+        .accept_stream(Duration::from_secs(5))
+        .ok_or("bob never saw the stream")?;
+    incoming.set_read_timeout(Some(Duration::from_secs(10)));
+    let mut received = Vec::new();
+    incoming.read_to_end(&mut received)?;
+    outgoing.flush()?;
+    if received != data {
+        println!("   ❌ Stream delivered {} bytes, corrupted", received.len());
+        return Err("stream corrupted".into());
+    }
+    println!("   ✅ Bob received all {} bytes in order", received.len());
+
+    thread::sleep(Duration::from_millis(500));
+
+    println!("8️⃣  Alice sending Bob a file, half of which he already has...");
+    let dir = std::env::temp_dir().join(format!("nt-test-{}", std::process::id()));
+    let (outbox, inbox) = (dir.join("alice"), dir.join("bob"));
+    std::fs::create_dir_all(&outbox)?;
+    std::fs::create_dir_all(&inbox)?;
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 253) as u8).collect();
+    std::fs::write(outbox.join("payload.bin"), &contents)?;
+    // as if an earlier attempt got cut off
+    std::fs::write(inbox.join("payload.bin.part"), &contents[..120_000])?;
+
+    let saved = thread::scope(|s| {
+        let sender = s.spawn(|| alice.send_file("bob", outbox.join("payload.bin")));
+        while bob.pending_offers().is_empty() {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let saved = bob.accept_file(Some("alice"), &inbox);
+        sender.join().unwrap().and(saved)
+    })?;
+    if std::fs::read(&saved)? != contents {
+        println!("   ❌ {} does not match the original", saved.display());
+        return Err("file corrupted".into());
+    }
+    std::fs::remove_dir_all(&dir)?;
+    println!("   ✅ Bob resumed and verified the file");
+
+    thread::sleep(Duration::from_millis(500));
+
+    println!("9️⃣  Carol giving up on punching and relaying to Alice...");
+    let impatient = ClientConfig {
+        punch_timeout: Duration::ZERO,
+        ..ClientConfig::default()
+    };
+    let mut carol = Client::with_config("carol".to_string(), server_addr, impatient)?;
+    carol.register()?;
+    match carol.connect_to_peer("alice") {
+        Ok(addr) if addr.port() != server_addr.port() && Some(addr) != alice.external_addr => {
+            carol.send_message("alice", "hello through the relay")?;
+            println!("   ✅ Carol reached Alice through the relay at {}", addr);
+        }
+        Ok(addr) => {
+            println!("   ❌ Carol connected at {}, expected a relay", addr);
+            return Err("relay fallback not used".into());
+        }
+        Err(e) => {
+            println!("   ❌ Relay fallback failed: {} (start the server with NT_RELAY=1)", e);
+            return Err(e.into());
+        }
+    }
+    carol.should_listen.store(false, Ordering::Relaxed);
+
+    thread::sleep(Duration::from_millis(500));
+
+    println!("🔟 Bob going silent...");
+    bob.should_listen.store(false, Ordering::Relaxed);
+    thread::sleep(Duration::from_secs(5));
+    match alice.get_connected_peers().iter().find(|(id, _)| id == "bob") {
+        // by now she may already be trying to punch through again
+        Some((_, peer)) if matches!(peer.state, ConnectionState::Disconnected | ConnectionState::HolePunching) => {
+            println!("   ✅ Alice marked Bob disconnected ({})", peer.state);
+        }
+        other => {
+            println!("   ❌ Bob still looks alive: {:?}", other);
+            return Err("missed keepalives not detected".into());
+        }
+    }
+
+    println!("1️⃣1️⃣ Testing peer not found...");
     match alice.connect_to_peer("charlie") {
         Ok(_) => {
             println!("   ❌ Should not have found charlie!");
@@ -73,6 +218,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("🎉 All signaling tests passed!");
     println!("✅ Basic peer discovery works");
     println!("✅ Hole punch coordination triggers");
+    println!("✅ Peer traffic is encrypted and authenticated");
+    println!("✅ Reliable streams deliver in order");
+    println!("✅ File transfers resume and verify");
+    println!("✅ The relay carries traffic when punching fails");
+    println!("✅ Keepalives notice dead paths");
     println!("✅ Error handling works");
     println!();
     println!("Next steps:");
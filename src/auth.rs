@@ -0,0 +1,265 @@
+use crate::protocol::{from_hex, to_hex, Message, WireFormat};
+use crate::secure::ReplayWindow;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::Path;
+use std::{fmt, fs, io};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Pre-shared keys by client id.
+///
+/// The file format is one `id hexkey` pair per line; blank lines and lines
+/// starting with `#` are ignored. The server loads every entry, a client
+/// only needs the line for its own id.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl Credentials {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut creds = Self::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("credentials line {}: expected `id hexkey`", n + 1),
+                )
+            };
+            let mut parts = line.split_whitespace();
+            let (Some(id), Some(key), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(bad());
+            };
+            creds.insert(id, from_hex(key).ok_or_else(bad)?);
+        }
+        Ok(creds)
+    }
+
+    pub fn insert(&mut self, id: &str, key: Vec<u8>) {
+        self.keys.insert(id.to_string(), key);
+    }
+
+    pub fn key(&self, id: &str) -> Option<&[u8]> {
+        self.keys.get(id).map(|k| k.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// never print the keys themselves
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.keys.keys()).finish()
+    }
+}
+
+/// The signed fields of an `AuthRegister`.
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterFields<'a> {
+    pub id: &'a str,
+    pub port: u16,
+    pub public_key: &'a [u8],
+    pub nonce: u64,
+}
+
+/// HMAC-SHA256 over the fields of an `AuthRegister`, hex encoded.
+pub fn register_mac(key: &[u8], fields: RegisterFields) -> String {
+    to_hex(&register_hmac(key, fields).finalize().into_bytes())
+}
+
+/// Check an `AuthRegister` MAC in constant time.
+pub fn verify_register(key: &[u8], fields: RegisterFields, mac: &str) -> bool {
+    match from_hex(mac) {
+        Some(tag) => register_hmac(key, fields).verify_slice(&tag).is_ok(),
+        None => false,
+    }
+}
+
+fn register_hmac(key: &[u8], f: RegisterFields) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    // length-prefix the variable fields so they can't bleed into each other
+    mac.update(&(f.id.len() as u16).to_be_bytes());
+    mac.update(f.id.as_bytes());
+    mac.update(&f.port.to_be_bytes());
+    mac.update(&(f.public_key.len() as u16).to_be_bytes());
+    mac.update(f.public_key);
+    mac.update(&f.nonce.to_be_bytes());
+    mac
+}
+
+/// The server key of a client with a pre-shared key. Derived rather than
+/// sent in the registration, so it never crosses the wire.
+pub fn server_key(psk: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(psk).expect("HMAC takes keys of any length");
+    mac.update(b"nat_traversal server key");
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Wrap `msg` for the client whose server key is `key`. `seq` must not
+/// repeat one signed for that client before.
+pub fn sign(key: &[u8], seq: u64, msg: &Message, format: WireFormat) -> Message {
+    let payload = msg.encode_as(format);
+    let mac = signed_hmac(key, seq, &payload).finalize().into_bytes().to_vec();
+    Message::Signed { seq, payload, mac }
+}
+
+fn signed_hmac(key: &[u8], seq: u64, payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&seq.to_be_bytes());
+    mac.update(payload);
+    mac
+}
+
+/// A client's side of `sign`: checks `Signed` packets from its server and
+/// refuses replays. The server's relays sign from the same sequence and
+/// UDP may reorder, so a window of recent `seq`s is accepted, as for peer
+/// traffic. The server seeds its sequence from the clock, so it keeps
+/// growing across restarts and the window never has to start over.
+pub struct ServerAuth {
+    key: Vec<u8>,
+    replay: ReplayWindow,
+}
+
+impl ServerAuth {
+    pub fn new(key: Vec<u8>) -> Self {
+        Self {
+            key,
+            replay: ReplayWindow::default(),
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The message inside `signed`, if its MAC is good and its `seq` was
+    /// not seen before.
+    pub fn open(&mut self, signed: &Message) -> Result<Message, String> {
+        let Message::Signed { seq, payload, mac } = signed else {
+            return Err(format!("unsigned {}", signed));
+        };
+        if signed_hmac(&self.key, *seq, payload).verify_slice(mac).is_err() {
+            return Err("bad server MAC".to_string());
+        }
+        if !self.replay.is_fresh(*seq) {
+            return Err(format!("replayed server packet {}", seq));
+        }
+        let inner = Message::decode(payload).map_err(|e| e.to_string())?;
+        if let Message::Signed { .. } = inner {
+            return Err("nested signature".to_string());
+        }
+        self.replay.mark(*seq);
+        Ok(inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_mac() {
+        let key = b"alice-secret";
+        let fields = RegisterFields {
+            id: "alice",
+            port: 5000,
+            public_key: &[1, 2, 3],
+            nonce: 42,
+        };
+        let mac = register_mac(key, fields);
+        assert_eq!(mac.len(), 64);
+        assert!(verify_register(key, fields, &mac));
+        assert!(!verify_register(key, RegisterFields { nonce: 43, ..fields }, &mac));
+        assert!(!verify_register(key, RegisterFields { port: 5001, ..fields }, &mac));
+        assert!(!verify_register(key, RegisterFields { id: "mallory", ..fields }, &mac));
+        assert!(!verify_register(key, RegisterFields { public_key: &[9], ..fields }, &mac));
+        assert!(!verify_register(b"guess", fields, &mac));
+        assert!(!verify_register(key, fields, "not hex"));
+    }
+
+    #[test]
+    fn test_server_signatures() {
+        let key = server_key(b"alice-secret");
+        assert_eq!(key.len(), 32);
+        assert_ne!(key, server_key(b"bob-secret"));
+
+        let msg = Message::HeartbeatAck { ttl_secs: 60 };
+        let mut auth = ServerAuth::new(key.clone());
+        for format in [WireFormat::Binary, WireFormat::Text] {
+            let signed = sign(&key, 10, &msg, format);
+            let wire = Message::decode(&signed.encode_as(format)).unwrap();
+            assert!(matches!(
+                ServerAuth::new(key.clone()).open(&wire),
+                Ok(Message::HeartbeatAck { ttl_secs: 60 })
+            ));
+        }
+
+        assert!(auth.open(&sign(&key, 5, &msg, WireFormat::Binary)).is_ok());
+        let replay = sign(&key, 5, &msg, WireFormat::Binary);
+        assert!(auth.open(&replay).unwrap_err().contains("replayed"));
+        // a relay's packet may overtake one signed just before it
+        assert!(auth.open(&sign(&key, 7, &msg, WireFormat::Binary)).is_ok());
+        assert!(auth.open(&sign(&key, 6, &msg, WireFormat::Binary)).is_ok());
+        assert!(auth.open(&sign(&key, 6, &msg, WireFormat::Binary)).is_err());
+
+        assert!(auth.open(&sign(b"guess", 7, &msg, WireFormat::Binary)).is_err());
+        let Message::Signed { payload, mac, .. } = sign(&key, 7, &msg, WireFormat::Binary) else {
+            unreachable!()
+        };
+        let moved = Message::Signed { seq: 8, payload, mac };
+        assert!(auth.open(&moved).is_err());
+        assert!(auth.open(&msg).unwrap_err().starts_with("unsigned"));
+        let nested = sign(&key, 9, &sign(&key, 9, &msg, WireFormat::Binary), WireFormat::Binary);
+        assert!(auth.open(&nested).is_err());
+    }
+
+    #[test]
+    fn test_old_register_ok_is_refused() {
+        let key = server_key(b"alice-secret");
+        let mut auth = ServerAuth::new(key.clone());
+        let ack = Message::HeartbeatAck { ttl_secs: 60 };
+        let ok = Message::RegisterOk {
+            external_addr: "203.0.113.7:5000".parse().unwrap(),
+            claimed_addr: "203.0.113.7:5000".parse().unwrap(),
+        };
+        let recorded = sign(&key, 10, &ok, WireFormat::Binary);
+        assert!(auth.open(&recorded).is_ok());
+        assert!(auth.open(&sign(&key, 1000, &ack, WireFormat::Binary)).is_ok());
+
+        // replaying it is no way back into the window, even while we
+        // re-register
+        assert!(auth.open(&recorded).unwrap_err().contains("replayed"));
+        assert!(auth.open(&sign(&key, 11, &ack, WireFormat::Binary)).is_err());
+        assert!(auth.open(&sign(&key, 1001, &ok, WireFormat::Binary)).is_ok());
+    }
+
+    #[test]
+    fn test_credentials_file() {
+        let creds = Credentials::parse("# comment\n\nalice 00ff10\nbob  616263\n").unwrap();
+        assert_eq!(creds.len(), 2);
+        assert_eq!(creds.key("alice"), Some(&[0x00, 0xff, 0x10][..]));
+        assert_eq!(creds.key("bob"), Some(&b"abc"[..]));
+        assert_eq!(creds.key("carol"), None);
+        assert_eq!(format!("{:?}", Credentials::parse("bob 61").unwrap()), "{\"bob\"}");
+
+        let err = Credentials::parse("alice\n").unwrap_err();
+        assert!(err.to_string().contains("line 1"));
+        assert!(Credentials::parse("alice xyz").is_err());
+    }
+}
@@ -1,126 +1,2213 @@
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{io, thread};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io::{Read, Write};
+use std::{fmt, io, thread};
+
+use crate::auth::{self, Credentials, ServerAuth};
+use crate::clock::{Clock, ClockOffset, OffsetEstimator, SystemClock};
+use crate::event::{ClientEvent, Events};
+use crate::ice::{self, Candidate, CandidateKind, CheckList, NOMINATION_SEQ};
+use crate::logger::{verbose, ConnectionState, NatConsoleLogger};
+use crate::protocol::{Message, PeerMessage, WireFormat, MAX_CANDIDATES, MAX_ID_LEN};
+use crate::nat::{self, BehaviorTests, NatType};
+use crate::secure::Channels;
+use crate::spray::{self, Allocation, Spray, SprayConfig, Strategy};
+use crate::stream::{Stream, Streams};
+use crate::transfer::{self, FileOffer, Offers};
+use crate::stun::{self, BindingRequest, BindingResponse, StunReply};
+use crate::transport::{DatagramTransport, SocketGroup};
+
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncClient;
+
+// how often the listener runs stream retransmission timers
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(20);
+// how long `register` waits for the server's answer
+const REGISTER_TIMEOUT: Duration = Duration::from_millis(100);
+// a punch sequence is this many rounds to every candidate, this far apart
+const PUNCH_ROUNDS: u32 = 10;
+const PUNCH_SPACING: Duration = Duration::from_millis(50);
+// `TimeRequest`s sent on registering, so one lost packet doesn't leave us
+// without a clock offset; each heartbeat adds another
+const CLOCK_SYNC_REQUESTS: usize = 3;
+// longest `send_message` text; it has to fit one sealed datagram
+const MAX_MESSAGE_LEN: usize = 60 * 1024;
+// how often the keepalive scheduler checks what is due
+pub(crate) const KEEPALIVE_TICK: Duration = Duration::from_millis(100);
+
+/// How often a registered client refreshes its registration with the server.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+/// How often connected peers get a keepalive. Below the 30s many NATs
+/// allow an idle UDP mapping.
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Tunables for a [`Client`]. The defaults suit the docker setup.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub wire_format: WireFormat,
+    /// Keep well below the server's registration TTL.
+    pub heartbeat_interval: Duration,
+    /// How often each peer, and at least this often the server, hears from
+    /// us so the NAT mappings stay open.
+    pub keepalive_interval: Duration,
+    /// A peer that stays silent for this many keepalive intervals is marked
+    /// `Disconnected`; the server likewise counts as unreachable.
+    pub keepalive_misses: u32,
+    /// Re-register and ask the server for a fresh hole punch whenever a
+    /// peer is `Disconnected`, keeping the secure session and its streams.
+    /// Attempts back off and stop after a few.
+    pub auto_repunch: bool,
+    /// How long `connect_to_peer` waits for the answer to `Discover`.
+    pub discover_timeout: Duration,
+    /// How long `connect_to_peer` waits for the peer to acknowledge a punch,
+    /// counted from the coordination request (the server adds a 2s lead).
+    pub punch_timeout: Duration,
+    /// How long `connect_to_peer` retries the Noise handshake once punched.
+    pub handshake_timeout: Duration,
+    /// When no punch is acknowledged in time, ask the server to relay
+    /// traffic instead. Only works if the server has relaying enabled.
+    pub relay_fallback: bool,
+    /// How long `send_file` waits for the peer to accept or reject, and for
+    /// its verdict on the hash once the file is through.
+    pub offer_timeout: Duration,
+    /// Also spray symmetric NATs with predicted or random ports when
+    /// punching (see `spray`). Costs many sockets and packets per punch.
+    pub spray: Option<SprayConfig>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            wire_format: WireFormat::default(),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            keepalive_misses: 3,
+            auto_repunch: true,
+            discover_timeout: Duration::from_secs(3),
+            punch_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(3),
+            relay_fallback: true,
+            offer_timeout: Duration::from_secs(60),
+            spray: None,
+        }
+    }
+}
+
+/// Why `connect_to_peer` gave up.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// `register` has not succeeded yet, so nobody listens for replies.
+    NotRegistered,
+    /// The server answered that no such peer is registered.
+    PeerNotFound(String),
+    /// The server never answered the discovery request.
+    ServerUnreachable(SocketAddr),
+    /// Coordination was requested but the peer never acknowledged a punch.
+    PunchTimeout(String),
+    /// The path works but the peer never completed the secure handshake.
+    HandshakeFailed(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::NotRegistered => write!(f, "client is not registered"),
+            ConnectError::PeerNotFound(id) => write!(f, "peer '{}' is not registered", id),
+            ConnectError::ServerUnreachable(addr) => {
+                write!(f, "signaling server {} did not answer", addr)
+            }
+            ConnectError::PunchTimeout(id) => {
+                write!(f, "no punch acknowledgement from '{}'", id)
+            }
+            ConnectError::HandshakeFailed(id) => {
+                write!(f, "secure handshake with '{}' failed", id)
+            }
+            ConnectError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+impl From<io::Error> for ConnectError {
+    fn from(e: io::Error) -> Self {
+        ConnectError::Io(e)
+    }
+}
+
+/// A peer in `Client::connected_peers`, keyed by its id.
+#[derive(Debug, Clone)]
+pub struct PeerConnection {
+    pub addr: SocketAddr,
+    pub state: ConnectionState,
+    /// When we last heard anything from the peer.
+    pub last_seen: Instant,
+}
+
+type PeerTable = Mutex<HashMap<String, PeerConnection>>;
+
+// Relays the server allocated us, by peer id: where, and the token to bind with.
+type RelayTable = Mutex<HashMap<String, (SocketAddr, u64)>>;
+
+// Insert or update `peer_id`, counting this as hearing from it.
+fn update_peer(peers: &PeerTable, peer_id: &str, addr: SocketAddr, state: ConnectionState) {
+    let mut peers = peers.lock().unwrap();
+    peers.insert(
+        peer_id.to_string(),
+        PeerConnection {
+            addr,
+            state,
+            last_seen: Instant::now(),
+        },
+    );
+}
+
+// Authenticated traffic from `peer_id` arrived.
+fn peer_seen(peers: &PeerTable, peer_id: &str) {
+    if let Some(peer) = peers.lock().unwrap().get_mut(peer_id) {
+        peer.last_seen = Instant::now();
+    }
+}
+
+// Mark peers `Disconnected` once silent for longer than `silence`, and
+// `Connected` again when they speak up. A punch that never got an answer
+// counts as silence too, so a failed re-punch gets retried. Returns the
+// peers that changed.
+fn check_liveness(peers: &PeerTable, silence: Duration) -> Vec<(String, ConnectionState, Duration)> {
+    let mut changed = Vec::new();
+    for (peer_id, peer) in peers.lock().unwrap().iter_mut() {
+        let quiet_for = peer.last_seen.elapsed();
+        let state = match peer.state {
+            ConnectionState::Connected | ConnectionState::HolePunching if quiet_for > silence => {
+                ConnectionState::Disconnected
+            }
+            ConnectionState::Disconnected if quiet_for <= silence => ConnectionState::Connected,
+            _ => continue,
+        };
+        peer.state = state;
+        changed.push((peer_id.clone(), state, quiet_for));
+    }
+    changed
+}
+
+// A punch from `peer_id` at `addr` got through. If we already share a
+// secure session and the path had been lost or moved, this is a re-punch:
+// the session and its streams carry on at the (maybe new) address.
+fn is_resumption(peers: &PeerTable, secure: &Mutex<Channels>, peer_id: &str, addr: SocketAddr) -> bool {
+    let lost = peers
+        .lock()
+        .unwrap()
+        .get(peer_id)
+        .is_some_and(|p| p.state != ConnectionState::Connected || p.addr != addr);
+    lost && secure.lock().unwrap().has_session(peer_id)
+}
+
+// `peer_id` answered at `addr`. If the session was lost, relayed or at
+// another address, it carries on here with its streams.
+fn mark_connected(
+    peers: &PeerTable,
+    secure: &Mutex<Channels>,
+    streams: &Streams,
+    logger: &Mutex<NatConsoleLogger>,
+    events: &Events,
+    peer_id: &str,
+    addr: SocketAddr,
+) {
+    let resumed = is_resumption(peers, secure, peer_id, addr);
+    let moved = peers
+        .lock()
+        .unwrap()
+        .get(peer_id)
+        .is_none_or(|p| p.state != ConnectionState::Connected || p.addr != addr);
+    update_peer(peers, peer_id, addr, ConnectionState::Connected);
+    if moved {
+        events.emit(ClientEvent::Connected {
+            peer_id: peer_id.to_string(),
+            addr,
+        });
+    }
+    if resumed {
+        say!("♻️ Session with {} resumed at {}", peer_id, addr);
+        logger.lock().unwrap().log_peer_reconnected(peer_id);
+        streams.resume(peer_id);
+    }
+}
+
+// `mark_connected` for a plaintext check or nomination. Anyone can send
+// those, so they may connect a peer but not move one that is connected or
+// relayed elsewhere; `follow_peer` does that once sealed traffic arrives
+// from the new address. Returns whether the peer is now at `addr`.
+fn mark_punched(
+    peers: &PeerTable,
+    secure: &Mutex<Channels>,
+    streams: &Streams,
+    logger: &Mutex<NatConsoleLogger>,
+    events: &Events,
+    peer_id: &str,
+    addr: SocketAddr,
+) -> bool {
+    let elsewhere = peers.lock().unwrap().get(peer_id).is_some_and(|p| {
+        matches!(p.state, ConnectionState::Connected | ConnectionState::Relayed) && p.addr != addr
+    });
+    if elsewhere {
+        say!("⏳ {} answered at {}, moving there once it speaks sealed", peer_id, addr);
+    } else {
+        mark_connected(peers, secure, streams, logger, events, peer_id, addr);
+    }
+    !elsewhere
+}
+
+// Authenticated traffic from `peer_id` came from `sender`, so a peer we
+// know at another address moved there: to a new NAT mapping, or a path a
+// punch opened. Callers skip traffic through our relay, which must not
+// pull a direct peer back.
+fn follow_peer(
+    peers: &PeerTable,
+    secure: &Mutex<Channels>,
+    streams: &Streams,
+    logger: &Mutex<NatConsoleLogger>,
+    events: &Events,
+    peer_id: &str,
+    sender: SocketAddr,
+) {
+    let moved = peers.lock().unwrap().get(peer_id).is_some_and(|p| p.addr != sender);
+    if moved {
+        mark_connected(peers, secure, streams, logger, events, peer_id, sender);
+    }
+}
+
+fn is_relay_of(relays: &RelayTable, peer_id: &str, addr: SocketAddr) -> bool {
+    relays.lock().unwrap().get(peer_id).is_some_and(|(relay, _)| *relay == addr)
+}
+
+// Our host candidates plus, once registered, the mapping the server saw.
+fn local_candidates(local_addr: SocketAddr, external_addr: Option<SocketAddr>) -> Vec<Candidate> {
+    let mut candidates = if local_addr.ip().is_unspecified() {
+        ice::gather_host(local_addr.port())
+    } else {
+        vec![Candidate::new(CandidateKind::Host, local_addr, u16::MAX)]
+    };
+    // the server keeps no more than that, and the mapping matters most
+    candidates.truncate(MAX_CANDIDATES - 1);
+    if let Some(addr) = external_addr.filter(|a| candidates.iter().all(|c| c.addr != *a)) {
+        candidates.push(Candidate::new(CandidateKind::ServerReflexive, addr, u16::MAX));
+    }
+    candidates
+}
+
+// Traffic from `sender`, claiming to be `peer_id`, failed authentication.
+fn auth_failure(logger: &Mutex<NatConsoleLogger>, events: &Events, peer_id: &str, sender: SocketAddr, reason: &str) {
+    logger.lock().unwrap().log_auth_failure(peer_id, sender, reason);
+    events.emit(ClientEvent::Error {
+        peer_id: Some(peer_id.to_string()),
+        message: format!("{} from {}", reason, sender),
+    });
+}
+
+// Which peer we know at `addr`, if any.
+fn peer_at(peers: &PeerTable, addr: SocketAddr) -> Option<String> {
+    let peers = peers.lock().unwrap();
+    peers
+        .iter()
+        .find(|(_, p)| p.addr == addr)
+        .map(|(id, _)| id.clone())
+}
+
+// `Register`, or `AuthRegister` when we have a pre-shared key, which the
+// server derives our server key from.
+fn registration(
+    id: &str,
+    port: u16,
+    public_key: &[u8],
+    server_key: &[u8],
+    psk: Option<&[u8]>,
+) -> Message {
+    let Some(key) = psk else {
+        return Message::Register {
+            id: id.to_string(),
+            port,
+            public_key: public_key.to_vec(),
+            server_key: server_key.to_vec(),
+        };
+    };
+    // wall-clock millis keep nonces increasing across restarts
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let fields = auth::RegisterFields {
+        id,
+        port,
+        public_key,
+        nonce,
+    };
+    Message::AuthRegister {
+        id: id.to_string(),
+        port,
+        public_key: public_key.to_vec(),
+        nonce,
+        mac: auth::register_mac(key, fields),
+    }
+}
+
+// Which addresses are the signaling server, and the key its packets to us
+// are signed with, so other hosts can't speak for it.
+pub(crate) struct ServerLink {
+    // the configured address, then alternates learned from STUN
+    addrs: Vec<SocketAddr>,
+    auth: ServerAuth,
+}
+
+impl ServerLink {
+    // With a fresh random key, for a client without a pre-shared key.
+    fn new(addr: SocketAddr) -> Self {
+        let mut key = vec![0; 32];
+        getrandom::fill(&mut key).expect("no system randomness");
+        Self {
+            addrs: vec![addr],
+            auth: ServerAuth::new(key),
+        }
+    }
+
+    fn is_server(&self, addr: SocketAddr) -> bool {
+        self.addrs.contains(&addr)
+    }
+
+    fn key(&self) -> Vec<u8> {
+        self.auth.key().to_vec()
+    }
+
+    // What the server said in `msg`. Only answers to hosts the server has
+    // no key for may come unsigned.
+    fn open(&mut self, msg: Message, own_id: &str) -> Result<Message, String> {
+        match msg {
+            Message::RegisterDenied { .. } => Ok(msg),
+            Message::NotRegistered { ref id } if id == own_id => Ok(msg),
+            msg => self.auth.open(&msg),
+        }
+    }
+}
+
+// Replies the background listener hands over to a foreground call that is
+// blocked waiting for them. Bounded so unclaimed replies can't pile up.
+const INBOX_CAPACITY: usize = 64;
+
+enum Inbound {
+    Stun(StunReply),
+    Signal(Message),
+    /// Connectivity checks with `from` settled on `addr`.
+    Nominated { from: String, addr: SocketAddr },
+    Secured { from: String },
+    /// The offset to send from and the hash of what the peer has below
+    /// it, or why the peer declined.
+    FileAnswer {
+        from: String,
+        stream_id: u32,
+        answer: Result<(u64, Vec<u8>), String>,
+    },
+}
+
+// offers are datagrams; repeat them until the peer answers
+const OFFER_RESEND: Duration = Duration::from_secs(1);
+// between re-registering and asking for a re-punch
+const REPUNCH_DELAY: Duration = Duration::from_millis(500);
+// re-punch attempts per lost peer; the pause after each one doubles, from
+// one keepalive interval up to `MAX_REPUNCH_PAUSE`
+const MAX_REPUNCHES: u32 = 8;
+const MAX_REPUNCH_PAUSE: Duration = Duration::from_secs(300);
+// an accepted transfer fails if nothing arrives for this long
+const TRANSFER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Inbox {
+    queue: Mutex<VecDeque<Inbound>>,
+    ready: Condvar,
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify,
+}
+
+impl Inbox {
+    fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            ready: Condvar::new(),
+            #[cfg(feature = "async")]
+            notify: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn push(&self, item: Inbound) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() == INBOX_CAPACITY {
+            queue.pop_front();
+        }
+        queue.push_back(item);
+        self.ready.notify_all();
+        #[cfg(feature = "async")]
+        self.notify.notify_waiters();
+    }
+
+    fn discard(&self, mut matches: impl FnMut(&Inbound) -> bool) {
+        self.queue.lock().unwrap().retain(|item| !matches(item));
+    }
+
+    /// Block until `pick` accepts a queued item or `timeout` passes.
+    /// Accepted items are removed, everything else stays queued.
+    fn wait_for<T>(
+        &self,
+        timeout: Duration,
+        mut pick: impl FnMut(&Inbound) -> Option<T>,
+    ) -> Option<T> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(i) = queue.iter().position(|item| pick(item).is_some()) {
+                let item = queue.remove(i).unwrap();
+                return pick(&item);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            queue = self.ready.wait_timeout(queue, deadline - now).unwrap().0;
+        }
+    }
+
+    /// `wait_for` without blocking the thread.
+    #[cfg(feature = "async")]
+    async fn wait_for_async<T>(
+        &self,
+        timeout: Duration,
+        mut pick: impl FnMut(&Inbound) -> Option<T>,
+    ) -> Option<T> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // registered before looking, so a push in between isn't missed
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(i) = queue.iter().position(|item| pick(item).is_some()) {
+                    let item = queue.remove(i).unwrap();
+                    return pick(&item);
+                }
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+// What the steps of `connect_to_peer` wait for in the inbox.
+
+fn discovered(item: &Inbound, peer_id: &str) -> Option<Option<SocketAddr>> {
+    match item {
+        Inbound::Signal(Message::PeerFound { id, addr, .. }) if id == peer_id => Some(Some(*addr)),
+        Inbound::Signal(Message::PeerNotFound { id }) if id == peer_id => Some(None),
+        _ => None,
+    }
+}
+
+fn nominated(item: &Inbound, peer_id: &str) -> Option<SocketAddr> {
+    match item {
+        Inbound::Nominated { from, addr } if from == peer_id => Some(*addr),
+        _ => None,
+    }
+}
 
-use crate::logger::{ConnectionState, NatConsoleLogger, NatLoggable};
-use crate::protocol::Message;
+fn relay_outcome(item: &Inbound, peer_id: &str, server_ip: IpAddr) -> Option<Result<SocketAddr, String>> {
+    match item {
+        Inbound::Signal(Message::RelayAllocated { peer_id: id, relay_port, .. }) if id == peer_id => {
+            Some(Ok(SocketAddr::new(server_ip, *relay_port)))
+        }
+        Inbound::Signal(Message::RelayDenied { peer_id: id, reason }) if id == peer_id => {
+            Some(Err(format!("relay denied: {}", reason)))
+        }
+        _ => None,
+    }
+}
+
+fn secured(item: &Inbound, peer_id: &str) -> Option<()> {
+    match item {
+        Inbound::Secured { from } if from == peer_id => Some(()),
+        _ => None,
+    }
+}
 
 pub struct Client {
     id: String,
-    socket: Arc<UdpSocket>, // share with background thread
+    socket: Arc<dyn DatagramTransport>, // share with background thread
     server_addr: SocketAddr,
+    config: ClientConfig,
     pub external_addr: Option<SocketAddr>,
     listening: bool,
+    keeping_alive: bool,
     pub should_listen: Arc<AtomicBool>,
-    pub connected_peers: Arc<std::sync::Mutex<std::collections::HashMap<String, SocketAddr>>>, // prolly shit but will do, refactor
+    pub connected_peers: Arc<PeerTable>,
     pub console_logger: Arc<Mutex<NatConsoleLogger>>,
+    inbox: Arc<Inbox>,
+    psk: Option<Vec<u8>>,
+    server: Arc<Mutex<ServerLink>>,
+    secure: Arc<Mutex<Channels>>,
+    streams: Arc<Streams>,
+    offers: Arc<Mutex<Offers>>,
+    // when the signaling server last answered
+    server_seen: Arc<Mutex<Instant>>,
+    relays: Arc<RelayTable>,
+    // what we last advertised with `Candidates`
+    candidates: Arc<Mutex<Vec<Candidate>>>,
+    events: Arc<Events>,
+    clock: Arc<dyn Clock>,
+    // how far the server's clock is off ours, from `TimeRequest`s
+    clock_offset: Arc<Mutex<OffsetEstimator>>,
+    // `socket`, when spraying adds sockets to it
+    group: Option<Arc<SocketGroup>>,
+    // what we last advertised with `PortAllocation`
+    allocation: Arc<Mutex<Option<Allocation>>>,
+}
+
+// Everything sealing and sending to a peer needs, so the stream layer can
+// do it without borrowing the client.
+#[derive(Clone)]
+struct PeerSender {
+    id: String,
+    socket: Arc<dyn DatagramTransport>,
+    secure: Arc<Mutex<Channels>>,
+    peers: Arc<PeerTable>,
+    wire_format: WireFormat,
+}
+
+impl PeerSender {
+    // Seal `msg` under the session with `peer_id` and send it there.
+    fn send(&self, peer_id: &str, msg: &PeerMessage) -> io::Result<SocketAddr> {
+        let peer_addr = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .map(|p| p.addr)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("not connected to peer '{}'", peer_id),
+                )
+            })?;
+        let (nonce, payload) = self
+            .secure
+            .lock()
+            .unwrap()
+            .seal(peer_id, &msg.encode())
+            .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+        let sealed = PeerMessage::Sealed {
+            from: self.id.clone(),
+            nonce,
+            payload,
+        };
+        self.socket
+            .send_to(&sealed.encode_as(self.wire_format), peer_addr)?;
+        Ok(peer_addr)
+    }
+}
+
+/// Heartbeats to the server and sealed keepalives to each peer, so every
+/// NAT mapping we rely on stays open. Peers that stop answering are marked
+/// `Disconnected`, and `Connected` again if they come back.
+///
+/// With `auto_repunch`, a `Disconnected` peer also makes us re-register, in
+/// case our own mapping moved, and then ask the server to coordinate a
+/// fresh punch to it. The attempts back off and stop after `MAX_REPUNCHES`.
+///
+/// `tick` does whatever is due; the client calls it every `KEEPALIVE_TICK`
+/// from a thread, the async client from a task.
+pub(crate) struct Keepalive {
+    socket: Arc<dyn DatagramTransport>,
+    server_addr: SocketAddr,
+    peers: Arc<PeerTable>,
+    sender: PeerSender,
+    logger: Arc<Mutex<NatConsoleLogger>>,
+    events: Arc<Events>,
+    server_seen: Arc<Mutex<Instant>>,
+    relays: Arc<RelayTable>,
+    heartbeat_every: Duration,
+    keepalive_every: Duration,
+    peer_silence: Duration,
+    server_silence: Duration,
+    auto_repunch: bool,
+    client_id: String,
+    wire_format: WireFormat,
+    psk: Option<Vec<u8>>,
+    server: Arc<Mutex<ServerLink>>,
+    public_key: Vec<u8>,
+    clock: Arc<dyn Clock>,
+    heartbeat: Vec<u8>,
+    last_heartbeat: Instant,
+    last_keepalive: Instant,
+    seq: u32,
+    server_lost: bool,
+    // peers to punch once the re-registration had time to land
+    repunch: Option<(Instant, Vec<String>)>,
+    // per lost peer: attempts so far and when the next one is due
+    repunch_tries: HashMap<String, (u32, Instant)>,
+}
+
+impl Keepalive {
+    pub(crate) fn tick(&mut self) {
+        let server_addr = self.server_addr;
+        if self.last_heartbeat.elapsed() >= self.heartbeat_every {
+            self.last_heartbeat = Instant::now();
+            if let Err(e) = self.socket.send_to(&self.heartbeat, server_addr) {
+                say!("❌ Heartbeat to {} failed: {}", server_addr, e);
+            }
+            let sync = Message::TimeRequest {
+                sent: self.clock.unix_millis(),
+            };
+            if let Err(e) = self.socket.send_to(&sync.encode_as(self.wire_format), server_addr) {
+                say!("❌ Clock sync with {} failed: {}", server_addr, e);
+            }
+        }
+        let silent = self.server_seen.lock().unwrap().elapsed() > self.server_silence;
+        if silent != self.server_lost {
+            self.server_lost = silent;
+            if silent {
+                say!("📵 Signaling server {} stopped answering", server_addr);
+                self.events.emit(ClientEvent::Error {
+                    peer_id: None,
+                    message: format!("signaling server {} stopped answering", server_addr),
+                });
+            } else {
+                say!("📶 Signaling server {} is answering again", server_addr);
+            }
+        }
+
+        if let Some((_, lost)) = self.repunch.take_if(|(at, _)| at.elapsed() >= REPUNCH_DELAY) {
+            for peer_id in lost {
+                let punch = Message::HolePunch {
+                    from: self.client_id.clone(),
+                    to: peer_id,
+                };
+                if let Err(e) = self.socket.send_to(&punch.encode_as(self.wire_format), server_addr) {
+                    say!("❌ Re-punch request failed: {}", e);
+                }
+            }
+        }
+
+        if self.last_keepalive.elapsed() < self.keepalive_every {
+            return;
+        }
+        self.last_keepalive = Instant::now();
+
+        for (peer_id, state, quiet_for) in check_liveness(&self.peers, self.peer_silence) {
+            let event = match state {
+                ConnectionState::Disconnected => {
+                    self.logger.lock().unwrap().log_peer_disconnected(&peer_id, quiet_for);
+                    ClientEvent::Disconnected {
+                        peer_id,
+                        reason: format!("silent for {}s", quiet_for.as_secs()),
+                    }
+                }
+                _ => {
+                    self.logger.lock().unwrap().log_peer_reconnected(&peer_id);
+                    let addr = self.peers.lock().unwrap().get(&peer_id).map(|p| p.addr);
+                    let Some(addr) = addr else { continue };
+                    ClientEvent::Connected { peer_id, addr }
+                }
+            };
+            self.events.emit(event);
+        }
+
+        let lost: Vec<String> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, p)| p.state == ConnectionState::Disconnected)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let lost = self.due_for_repunch(lost);
+        if self.auto_repunch && !lost.is_empty() {
+            say!("♻️ Path lost to {}, re-registering and re-punching", lost.join(", "));
+            let port = self.socket.local_addr().map(|a| a.port()).unwrap_or(0);
+            let server_key = self.server.lock().unwrap().key();
+            let msg = registration(&self.client_id, port, &self.public_key, &server_key, self.psk.as_deref());
+            if let Err(e) = self.socket.send_to(&msg.encode_as(self.wire_format), server_addr) {
+                say!("❌ Re-registration failed: {}", e);
+            }
+            self.repunch = Some((Instant::now(), lost));
+        }
+
+        // keeps our mapping towards each relay open as well
+        for (relay_addr, token) in self.relays.lock().unwrap().values() {
+            let bind = Message::RelayBind {
+                id: self.client_id.clone(),
+                token: *token,
+            };
+            if let Err(e) = self.socket.send_to(&bind.encode_as(self.wire_format), *relay_addr) {
+                say!("❌ Relay bind to {} failed: {}", relay_addr, e);
+            }
+        }
+
+        // disconnected peers too, so we notice when the path returns
+        self.seq += 1;
+        let ids: Vec<String> = self.peers.lock().unwrap().keys().cloned().collect();
+        for peer_id in ids {
+            match self.sender.send(&peer_id, &PeerMessage::Keepalive { seq: self.seq }) {
+                // punched but not secured yet
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => {}
+                Err(e) => say!("❌ Keepalive to {} failed: {}", peer_id, e),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    // The lost peers whose next re-punch is due, counting the attempt.
+    // Peers that came back start over; those out of attempts are left
+    // for the application to reconnect.
+    fn due_for_repunch(&mut self, lost: Vec<String>) -> Vec<String> {
+        let now = Instant::now();
+        self.repunch_tries.retain(|peer_id, _| lost.contains(peer_id));
+        let mut due = Vec::new();
+        for peer_id in lost {
+            let (tries, next) = self.repunch_tries.entry(peer_id.clone()).or_insert((0, now));
+            if *tries >= MAX_REPUNCHES || now < *next {
+                continue;
+            }
+            *tries += 1;
+            *next = now + (self.keepalive_every * 2u32.pow(*tries - 1)).min(MAX_REPUNCH_PAUSE);
+            if *tries == MAX_REPUNCHES {
+                say!("🛑 Last re-punch attempt for {}", peer_id);
+                self.events.emit(ClientEvent::Error {
+                    peer_id: Some(peer_id.clone()),
+                    message: format!("giving up re-punching after {} attempts", MAX_REPUNCHES),
+                });
+            }
+            due.push(peer_id);
+        }
+        due
+    }
+}
+
+// A punch sequence waiting for its rounds to come due, see `Listener::tick`.
+struct ScheduledPunch {
+    peer_id: String,
+    start: Instant,
+    rounds: u32,
+}
+
+/// The background side of a client: what happens when a packet arrives
+/// and when a timer fires. Neither `handle` nor `tick` blocks, so a thread
+/// or an async task can drive them.
+pub(crate) struct Listener {
+    socket: Arc<dyn DatagramTransport>,
+    client_id: String,
+    wire_format: WireFormat,
+    server_addr: SocketAddr,
+    connected_peers: Arc<PeerTable>,
+    inbox: Arc<Inbox>,
+    psk: Option<Vec<u8>>,
+    server: Arc<Mutex<ServerLink>>,
+    public_key: Vec<u8>,
+    secure: Arc<Mutex<Channels>>,
+    streams: Arc<Streams>,
+    offers: Arc<Mutex<Offers>>,
+    peer_sender: PeerSender,
+    server_seen: Arc<Mutex<Instant>>,
+    relays: Arc<RelayTable>,
+    candidates: Arc<Mutex<Vec<Candidate>>>,
+    bg_logger: Arc<Mutex<NatConsoleLogger>>,
+    events: Arc<Events>,
+    // connectivity checks, one list per peer we last punched
+    checks: HashMap<String, CheckList>,
+    // punch rounds, checks and sprays run on this clock's `now`
+    clock: Arc<dyn Clock>,
+    clock_offset: Arc<Mutex<OffsetEstimator>>,
+    punches: Vec<ScheduledPunch>,
+    // with `ClientConfig::spray` on
+    spraying: Option<(SprayConfig, Arc<SocketGroup>)>,
+    sprays: Vec<Spray>,
+    allocation: Arc<Mutex<Option<Allocation>>>,
+    last_poll: Instant,
+}
+
+impl Listener {
+    /// Run the timers that are due on the client's clock: stream
+    /// retransmissions, punch rounds and nominations. Call at least every
+    /// `STREAM_POLL_INTERVAL`.
+    pub(crate) fn tick(&mut self) {
+        let now = self.clock.now();
+        if now < self.last_poll + STREAM_POLL_INTERVAL {
+            return;
+        }
+        self.last_poll = now;
+        self.streams.poll();
+
+        let client_id = &self.client_id;
+        for punch in &mut self.punches {
+            if now < punch.start + PUNCH_SPACING * punch.rounds {
+                continue;
+            }
+            let Some(list) = self.checks.get_mut(&punch.peer_id) else {
+                punch.rounds = PUNCH_ROUNDS;
+                continue;
+            };
+            let targets = list.targets();
+            if punch.rounds == 0 {
+                say!(
+                    "🕳️ [{}] STARTING HOLE PUNCH SEQUENCE TO {}",
+                    client_id, punch.peer_id
+                );
+                self.events.emit(ClientEvent::PunchStarted {
+                    peer_id: punch.peer_id.clone(),
+                    candidates: targets.len(),
+                });
+            }
+            self.bg_logger.lock().unwrap().log_hole_punch_attempt(&punch.peer_id);
+
+            let punch_msg = PeerMessage::Punch {
+                from: client_id.clone(),
+                seq: punch.rounds,
+            };
+            for target in targets {
+                match self.socket.send_to(&punch_msg.encode_as(self.wire_format), target) {
+                    Ok(_) => {
+                        say!(
+                            "🕳️ [{}] Sent hole punch {} to {}",
+                            client_id, punch.rounds, target
+                        );
+                    }
+                    Err(e) => {
+                        self.bg_logger.lock().unwrap().log_hole_punch_failure(&punch.peer_id);
+                        say!(
+                            "❌ [{}] Hole punch {} to {} failed: {}",
+                            client_id, punch.rounds, target, e
+                        );
+                    }
+                }
+            }
+
+            punch.rounds += 1;
+            if punch.rounds == PUNCH_ROUNDS {
+                say!(
+                    "✅ [{}] Hole punch sequence completed to {}",
+                    client_id, punch.peer_id
+                );
+            }
+        }
+        self.punches.retain(|p| p.rounds < PUNCH_ROUNDS);
+        self.tick_sprays(now);
+
+        let client_id = &self.client_id;
+        for (peer_id, list) in self.checks.iter_mut() {
+            list.expire(now);
+            if let Some(addr) = list.due_nomination(now) {
+                let nominate = PeerMessage::Nominate {
+                    from: client_id.clone(),
+                    seq: NOMINATION_SEQ,
+                };
+                if let Err(e) = self.socket.send_to(&nominate.encode_as(self.wire_format), addr) {
+                    say!("❌ [{}] Nominating {} at {} failed: {}", client_id, peer_id, addr, e);
+                }
+            }
+        }
+    }
+
+    // Send what is due of each spray. Once one is over, log which strategy
+    // got through and close the sockets nothing came back on.
+    fn tick_sprays(&mut self, now: Instant) {
+        let Some((_, group)) = &self.spraying else {
+            return;
+        };
+        let (client_id, wire_format) = (&self.client_id, self.wire_format);
+        for spray in &mut self.sprays {
+            if self.checks.get(&spray.peer_id).is_some_and(|list| list.is_nominated()) {
+                spray.stop(now);
+            }
+            spray.send_due(now, &*self.socket, |seq| {
+                PeerMessage::Punch { from: client_id.clone(), seq }.encode_as(wire_format)
+            });
+        }
+
+        let (done, running) = std::mem::take(&mut self.sprays).into_iter().partition(|s| s.is_done(now));
+        self.sprays = running;
+        for spray in done {
+            let addr = self
+                .connected_peers
+                .lock()
+                .unwrap()
+                .get(&spray.peer_id)
+                .filter(|p| p.state == ConnectionState::Connected)
+                .map(|p| p.addr);
+            let sprayed = addr.is_some_and(|a| group.is_routed(a) || spray.aimed_at(a));
+            let mut logger = self.bg_logger.lock().unwrap();
+            logger.log_punch_strategy(&spray.peer_id, Strategy::Candidates, addr.is_some() && !sprayed);
+            logger.log_punch_strategy(&spray.peer_id, spray.strategy, sprayed);
+            drop(logger);
+            let kept = group.release_unrouted(&spray.sockets());
+            say!(
+                "🎲 [{}] Spray at {} over, keeping {} of its sockets",
+                client_id,
+                spray.peer_id,
+                kept
+            );
+        }
+    }
+
+    /// Handle one datagram that arrived from `sender`.
+    pub(crate) fn handle(&mut self, buf: &[u8], sender: SocketAddr) {
+        let Listener {
+            socket,
+            client_id,
+            wire_format,
+            server_addr,
+            connected_peers,
+            inbox,
+            psk,
+            server,
+            public_key,
+            secure,
+            streams,
+            offers,
+            peer_sender,
+            server_seen,
+            relays,
+            candidates,
+            bg_logger,
+            events,
+            checks,
+            clock,
+            clock_offset,
+            punches,
+            spraying,
+            sprays,
+            allocation,
+            last_poll: _,
+        } = self;
+        let (client_id, wire_format, server_addr) = (&*client_id, *wire_format, *server_addr);
+        let len = buf.len();
+
+        if stun::is_stun(buf) {
+            match StunReply::decode(buf) {
+                Ok(reply) => inbox.push(Inbound::Stun(reply)),
+                Err(e) => say!(
+                    "❌ [{}] Bad STUN packet from {}: {}",
+                    client_id, sender, e
+                ),
+            }
+            return;
+        }
+
+        // Check if this is from the signaling server
+        if server.lock().unwrap().is_server(sender) {
+            let opened = Message::decode(&buf[..len])
+                .map(|msg| server.lock().unwrap().open(msg, client_id));
+            match opened {
+                Ok(Err(reason)) => {
+                    say!("🚫 [{}] Dropped server packet from {}: {}", client_id, sender, reason);
+                    events.emit(ClientEvent::Error {
+                        peer_id: None,
+                        message: format!("server packet from {} rejected: {}", sender, reason),
+                    });
+                }
+                Ok(Ok(msg)) => {
+                    *server_seen.lock().unwrap() = Instant::now();
+                    say!(
+                        "✅ [{}] Successfully parsed message: {:?}",
+                        client_id, msg
+                    );
+                    match msg {
+                        Message::StartPunchWithPeer {
+                            timestamp,
+                            delay_ms,
+                            peer_id,
+                            peer_addr,
+                            peer_key,
+                            peer_candidates,
+                            peer_next_port,
+                            peer_port_step,
+                        } => {
+                            say!(
+                                "\n🚀 [{}] HOLE PUNCH COORDINATION RECEIVED!",
+                                client_id
+                            );
+                            say!(
+                                "🎯 [{}] Target: {} at {}, Timestamp: {}",
+                                client_id, peer_id, peer_addr, timestamp
+                            );
+
+                            bg_logger.lock().unwrap().log_peer_discovery(
+                                peer_id.clone(),
+                                Some(peer_addr),
+                            );
+                            events.emit(ClientEvent::PeerDiscovered {
+                                peer_id: peer_id.clone(),
+                                addr: peer_addr,
+                            });
+                            secure.lock().unwrap().set_peer_key(&peer_id, peer_key);
+                            // a re-punch must not demote a working connection
+                            let connected = connected_peers.lock().unwrap().get(&peer_id).is_some_and(|p| {
+                                (p.addr == peer_addr && p.state == ConnectionState::Connected)
+                                    || p.state == ConnectionState::Relayed
+                            });
+                            if !connected {
+                                update_peer(connected_peers, &peer_id, peer_addr, ConnectionState::HolePunching);
+                            }
+
+                            // check every address the peer might be at, plus
+                            // the relay between us if there is one
+                            let mut remote = peer_candidates;
+                            if remote.iter().all(|c| c.addr != peer_addr) {
+                                remote.push(Candidate::new(CandidateKind::ServerReflexive, peer_addr, 0));
+                            }
+                            if let Some((relay_addr, _)) = relays.lock().unwrap().get(&peer_id) {
+                                remote.push(Candidate::new(CandidateKind::Relayed, *relay_addr, 0));
+                            }
+                            let controlling = *client_id < peer_id;
+                            let local = candidates.lock().unwrap().clone();
+                            checks.insert(peer_id.clone(), CheckList::new(&local, &remote, controlling, clock.now()));
+
+                            // the start time on our clock if we know how far
+                            // off the server's is, else the relative delay,
+                            // which is late by however long this took to arrive
+                            let now = clock.unix_millis();
+                            let offset = clock_offset.lock().unwrap().estimate();
+                            let delay = match offset {
+                                Some(offset) => offset.to_local(timestamp).saturating_sub(now),
+                                None => delay_ms as u64,
+                            };
+                            say!(
+                                "⏰ [{}] Now: {}, Start: {} (server clock), offset: {}",
+                                client_id,
+                                now,
+                                timestamp,
+                                offset.map_or("unknown".to_string(), |o| format!("{:+} ms", o.offset_ms))
+                            );
+                            say!(
+                                "⏳ [{}] Punching {} at {} candidates as {} in {} ms...",
+                                client_id,
+                                peer_id,
+                                remote.len(),
+                                if controlling { "controlling" } else { "controlled" },
+                                delay
+                            );
+                            let start = clock.now() + Duration::from_millis(delay);
+                            if let Some((config, group)) = spraying {
+                                let peer_allocation = Allocation::from_wire(peer_next_port, peer_port_step);
+                                if let Some(i) = sprays.iter().position(|s| s.peer_id == peer_id) {
+                                    group.release_unrouted(&sprays.remove(i).sockets());
+                                }
+                                match Spray::new(config, group, peer_id.clone(), peer_addr, peer_allocation, controlling, start) {
+                                    Ok(spray) => {
+                                        say!(
+                                            "🎲 [{}] Spraying {} by {} as well: {} packets ({})",
+                                            client_id,
+                                            peer_id,
+                                            spray.strategy,
+                                            spray.packets(),
+                                            peer_allocation.map_or("allocation unknown".to_string(), |a| a.to_string())
+                                        );
+                                        sprays.push(spray);
+                                    }
+                                    Err(e) => say!("❌ [{}] Could not set up a spray at {}: {}", client_id, peer_id, e),
+                                }
+                            }
+                            punches.retain(|p| p.peer_id != peer_id);
+                            punches.push(ScheduledPunch {
+                                peer_id,
+                                start,
+                                rounds: 0,
+                            });
+                        }
+
+                        Message::StartPunch { .. } => {
+                            say!("🚀 [{}] Received OLD FORMAT hole punch (no peer address)", client_id);
+                        }
+
+                        Message::PeerFound { id, addr, public_key } => {
+                            bg_logger.lock().unwrap().log_peer_discovery(id.clone(), Some(addr));
+                            events.emit(ClientEvent::PeerDiscovered { peer_id: id.clone(), addr });
+                            secure.lock().unwrap().set_peer_key(&id, public_key.clone());
+
+                            say!(
+                                "🔍 [{}] Peer discovery result: {} at {}",
+                                client_id, id, addr
+                            );
+                            inbox.push(Inbound::Signal(Message::PeerFound { id, addr, public_key }));
+                        }
+
+                        Message::TimeResponse {
+                            sent,
+                            received,
+                            replied,
+                        } => {
+                            let sample = ClockOffset::from_exchange(sent, received, replied, clock.unix_millis());
+                            let mut estimator = clock_offset.lock().unwrap();
+                            let first = estimator.estimate().is_none();
+                            estimator.add(sample);
+                            let estimate = estimator.estimate();
+                            drop(estimator);
+                            say!(
+                                "🕰️ [{}] Server clock {:+} ms off ours, rtt {} ms",
+                                client_id,
+                                sample.offset_ms,
+                                sample.rtt.as_millis()
+                            );
+                            let mut logger = bg_logger.lock().unwrap();
+                            logger.set_clock_offset(estimate);
+                            if first && verbose() {
+                                logger.print_address_table();
+                            }
+                        }
+
+                        Message::HeartbeatAck { ttl_secs } => {
+                            say!(
+                                "💓 [{}] Registration refreshed for {}s",
+                                client_id, ttl_secs
+                            );
+                        }
+
+                        // the server forgot us: register again
+                        Message::NotRegistered { id } if id == *client_id => {
+                            say!(
+                                "♻️ [{}] Registration expired, re-registering",
+                                client_id
+                            );
+                            let port = socket.local_addr().map(|a| a.port()).unwrap_or(0);
+                            let server_key = server.lock().unwrap().key();
+                            let msg = registration(client_id, port, public_key, &server_key, psk.as_deref());
+                            if let Err(e) = socket.send_to(&msg.encode_as(wire_format), server_addr) {
+                                say!("❌ [{}] Re-registration failed: {}", client_id, e);
+                            }
+                        }
+
+                        Message::RegisterOk { external_addr, .. } => {
+                            bg_logger.lock().unwrap().set_external_addr(external_addr);
+                            say!(
+                                "✅ [{}] Re-registered, external address: {}",
+                                client_id, external_addr
+                            );
+                            // a new registration starts without candidates
+                            let local_addr = socket.local_addr().unwrap_or(SocketAddr::from(([0; 4], 0)));
+                            let local = local_candidates(local_addr, Some(external_addr));
+                            *candidates.lock().unwrap() = local.clone();
+                            let msg = Message::Candidates { id: client_id.clone(), candidates: local };
+                            if let Err(e) = socket.send_to(&msg.encode_as(wire_format), server_addr) {
+                                say!("❌ [{}] Advertising candidates failed: {}", client_id, e);
+                            }
+                            // likewise the port allocation, stale as it may be by now
+                            if let Some(allocation) = *allocation.lock().unwrap() {
+                                let (next_port, step) = allocation.to_wire();
+                                let msg = Message::PortAllocation { id: client_id.clone(), next_port, step };
+                                if let Err(e) = socket.send_to(&msg.encode_as(wire_format), server_addr) {
+                                    say!("❌ [{}] Advertising port allocation failed: {}", client_id, e);
+                                }
+                            }
+                        }
+
+                        Message::RegisterDenied { reason, .. } => {
+                            say!(
+                                "🚫 [{}] Re-registration refused: {}",
+                                client_id, reason
+                            );
+                            events.emit(ClientEvent::Error {
+                                peer_id: None,
+                                message: format!("re-registration refused: {}", reason),
+                            });
+                        }
+
+                        Message::PeerNotFound { id } => {
+                            say!("❓ [{}] Peer '{}' is not registered", client_id, id);
+                            inbox.push(Inbound::Signal(Message::PeerNotFound { id }));
+                        }
+
+                        Message::RelayAllocated { peer_id, relay_port, token, lifetime_secs, quota_bytes } => {
+                            let relay_addr = SocketAddr::new(server_addr.ip(), relay_port);
+                            say!(
+                                "🔁 [{}] Relay to {} at {} ({} bytes, {}s)",
+                                client_id, peer_id, relay_addr, quota_bytes, lifetime_secs
+                            );
+                            let bind = Message::RelayBind { id: client_id.clone(), token };
+                            if let Err(e) = socket.send_to(&bind.encode_as(wire_format), relay_addr) {
+                                say!("❌ [{}] Relay bind failed: {}", client_id, e);
+                            }
+                            relays.lock().unwrap().insert(peer_id.clone(), (relay_addr, token));
+                            // a direct path that works beats the relay
+                            let direct = connected_peers.lock().unwrap().get(&peer_id)
+                                .is_some_and(|p| p.state == ConnectionState::Connected);
+                            if !direct {
+                                update_peer(connected_peers, &peer_id, relay_addr, ConnectionState::Relayed);
+                                bg_logger.lock().unwrap().log_relayed(&peer_id, relay_addr);
+                                events.emit(ClientEvent::Connected { peer_id: peer_id.clone(), addr: relay_addr });
+                            }
+                            inbox.push(Inbound::Signal(Message::RelayAllocated {
+                                peer_id, relay_port, token, lifetime_secs, quota_bytes,
+                            }));
+                        }
+
+                        Message::RelayDenied { peer_id, reason } => {
+                            say!("🚫 [{}] No relay to {}: {}", client_id, peer_id, reason);
+                            inbox.push(Inbound::Signal(Message::RelayDenied { peer_id, reason }));
+                        }
+
+                        Message::RelayClosed { peer_id, reason } => {
+                            say!("🔁 [{}] Relay to {} closed: {}", client_id, peer_id, reason);
+                            let relay = relays.lock().unwrap().remove(&peer_id);
+                            let mut peers = connected_peers.lock().unwrap();
+                            if let Some(peer) = peers.get_mut(&peer_id).filter(|p| {
+                                p.state == ConnectionState::Relayed && relay.is_some_and(|(addr, _)| addr == p.addr)
+                            }) {
+                                // the keepalive loop takes it from here
+                                peer.state = ConnectionState::Disconnected;
+                                let quiet_for = peer.last_seen.elapsed();
+                                drop(peers);
+                                bg_logger.lock().unwrap().log_peer_disconnected(&peer_id, quiet_for);
+                                events.emit(ClientEvent::Disconnected {
+                                    peer_id,
+                                    reason: format!("relay closed: {}", reason),
+                                });
+                            }
+                        }
+
+                        _ => {
+                            say!(
+                                "🔍 [{}] Other server message: {:?}",
+                                client_id, msg
+                            );
+                        }
+                    }
+                }
+                Err(parse_error) => {
+                    say!(
+                        "❌ [{}] Failed to parse server message: {}",
+                        client_id, parse_error
+                    );
+                    say!("🔍 [{}] Raw bytes: {:?}", client_id, &buf[..len]);
+                }
+            }
+        } else {
+            // handle P2P messages (not from signaling server)
+            let format = WireFormat::detect(&buf[..len]);
+
+            // unwrap sealed packets; the id that comes with
+            // the opened message is the authenticated sender
+            let packet = match PeerMessage::decode(&buf[..len]) {
+                Ok(PeerMessage::Sealed { from, nonce, payload }) => {
+                    let opened = secure
+                        .lock()
+                        .unwrap()
+                        .open(&from, nonce, &payload)
+                        .map_err(|e| e.to_string())
+                        .and_then(|plain| PeerMessage::decode(&plain).map_err(|e| e.to_string()));
+                    match opened {
+                        Ok(inner) => {
+                            if !is_relay_of(relays, &from, sender) {
+                                follow_peer(connected_peers, secure, streams, bg_logger, events, &from, sender);
+                            }
+                            Some((inner, Some(from)))
+                        }
+                        Err(reason) => {
+                            auth_failure(bg_logger, events, &from, sender, &reason);
+                            None
+                        }
+                    }
+                }
+                Ok(msg) => Some((msg, None)),
+                Err(e) => {
+                    say!(
+                        "\n🔍 [{}] Unknown P2P message from {}: {}",
+                        client_id, sender, e
+                    );
+                    None
+                }
+            };
+
+            match packet {
+                Some((msg @ (PeerMessage::StreamData { .. } | PeerMessage::StreamAck { .. }), Some(peer_id))) => {
+                    peer_seen(connected_peers, &peer_id);
+                    streams.handle(&peer_id, &msg);
+                }
+                Some((PeerMessage::FileOffer { stream_id, name, size, sha256 }, Some(peer_id))) => {
+                    peer_seen(connected_peers, &peer_id);
+                    let answer = offers.lock().unwrap().answer_for(&peer_id, stream_id).cloned();
+                    let answer = answer.or_else(|| {
+                        (!transfer::is_safe_name(&name)).then(|| PeerMessage::FileReject {
+                            stream_id,
+                            reason: format!("refusing file name '{}'", name),
+                        })
+                    });
+                    if let Some(answer) = answer {
+                        // a repeat of one we answered, or one we never will
+                        if let Err(e) = peer_sender.send(&peer_id, &answer) {
+                            say!("❌ [{}] Failed to answer file offer: {}", client_id, e);
+                        }
+                    } else {
+                        let offer = FileOffer {
+                            peer_id: peer_id.clone(),
+                            stream_id,
+                            name: name.clone(),
+                            size,
+                            sha256,
+                        };
+                        if offers.lock().unwrap().offer(offer.clone()) {
+                            say!("📁 [{}] {} offers '{}' ({} bytes)", client_id, peer_id, name, size);
+                            events.emit(ClientEvent::FileOffered(offer));
+                        }
+                    }
+                }
+                Some((PeerMessage::FileAccept { stream_id, offset, prefix_sha256 }, Some(peer_id))) => {
+                    peer_seen(connected_peers, &peer_id);
+                    let answer = Ok((offset, prefix_sha256));
+                    inbox.push(Inbound::FileAnswer { from: peer_id, stream_id, answer });
+                }
+                Some((PeerMessage::FileReject { stream_id, reason }, Some(peer_id))) => {
+                    peer_seen(connected_peers, &peer_id);
+                    inbox.push(Inbound::FileAnswer { from: peer_id, stream_id, answer: Err(reason) });
+                }
+                Some((PeerMessage::Data { payload }, Some(peer_id))) => {
+                    peer_seen(connected_peers, &peer_id);
+                    bg_logger.lock().unwrap().log_direct_message_received(&peer_id, &payload, sender);
+                    bg_logger.lock().unwrap().print_live_update(&peer_id);
+                    events.emit(ClientEvent::MessageReceived { peer_id, message: payload });
+                }
+                Some((PeerMessage::Keepalive { seq }, Some(peer_id))) => {
+                    peer_seen(connected_peers, &peer_id);
+                    if let Err(e) = peer_sender.send(&peer_id, &PeerMessage::KeepaliveAck { seq }) {
+                        say!("❌ [{}] Keepalive ack to {} failed: {}", client_id, peer_id, e);
+                    }
+                }
+                Some((PeerMessage::KeepaliveAck { .. }, Some(peer_id))) => {
+                    peer_seen(connected_peers, &peer_id);
+                }
+                Some((PeerMessage::Close { .. }, Some(peer_id))) => {
+                    say!("👋 [{}] {} ({}) closed the connection", client_id, peer_id, sender);
+                    let gone = connected_peers.lock().unwrap().remove(&peer_id);
+                    if let (Some(peer), Some((_, group))) = (gone, &spraying) {
+                        group.forget(peer.addr);
+                    }
+                    secure.lock().unwrap().forget(&peer_id);
+                    streams.drop_peer(&peer_id);
+                    checks.remove(&peer_id);
+                    events.emit(ClientEvent::Disconnected { peer_id, reason: "closed by peer".to_string() });
+                }
+                Some((PeerMessage::Punch { from, seq }, None)) => {
+                    say!(
+                        "\n🕳️ [{}] Received hole punch #{} from {} ({})",
+                        client_id, seq, from, sender
+                    );
+                    let Some(list) = checks.get_mut(&from) else {
+                        auth_failure(bg_logger, events, &from, sender, "punch nobody coordinated");
+                        return;
+                    };
+                    let response = PeerMessage::PunchAck {
+                        from: client_id.clone(),
+                        seq,
+                    };
+                    match socket.send_to(&response.encode_as(format), sender) {
+                        Ok(_) => {
+                            say!("🤝 [{}] Sent punch ACK to {}", client_id, sender);
+
+                            bg_logger.lock().unwrap().log_punch_traffic(&from, 50, "PUNCH");
+                        }
+                        Err(e) => say!(
+                            "❌ [{}] Failed to send punch ACK: {}",
+                            client_id, e
+                        ),
+                    }
+
+                    // anyone can send a punch; the path only counts once
+                    // the peer answers a check of ours along it
+                    if list.on_request(sender) {
+                        let check = PeerMessage::Punch { from: client_id.clone(), seq };
+                        match socket.send_to(&check.encode_as(format), sender) {
+                            Ok(_) => say!("🕳️ [{}] Sent triggered check to {}", client_id, sender),
+                            Err(e) => say!("❌ [{}] Triggered check to {} failed: {}", client_id, sender, e),
+                        }
+                    }
+                }
+                Some((PeerMessage::PunchAck { from, seq }, None)) => {
+                    say!(
+                        "\n🤝 [{}] Received punch ACK #{} from {} ({})",
+                        client_id, seq, from, sender
+                    );
+                    // only answers to our own checks count
+                    let Some(list) = checks.get_mut(&from).filter(|list| list.is_checking(sender)) else {
+                        auth_failure(bg_logger, events, &from, sender, "punch ack for no check of ours");
+                        return;
+                    };
+                    bg_logger.lock().unwrap().log_punch_traffic(&from, 100, "PUNCH_ACK");
+
+                    let was_nominated = list.is_nominated();
+                    if seq == NOMINATION_SEQ {
+                        list.on_nomination_ack(sender);
+                    } else {
+                        list.on_response(sender, clock.now());
+                    }
+                    let nominated = !was_nominated && list.is_nominated();
+                    let addr = list.selected().unwrap_or(sender);
+                    if mark_punched(connected_peers, secure, streams, bg_logger, events, &from, addr) {
+                        say!(
+                            "🔗 [{}] ACK-CONNECTED: {} at {}",
+                            client_id, from, addr
+                        );
+                    }
+
+                    if nominated {
+                        let kind = checks.get(&from).and_then(|l| l.kind_of(addr));
+                        let kind = kind.map_or("unlisted".to_string(), |k| k.to_string());
+                        say!("🧭 [{}] Nominated {} at {} ({})", client_id, from, addr, kind);
+                        say!("🎉 DIRECT P2P CONNECTION ESTABLISHED!");
+                        bg_logger.lock().unwrap().print_live_update(&from);
+                        inbox.push(Inbound::Nominated { from, addr });
+                    }
+                }
+                Some((PeerMessage::Nominate { from, seq }, None)) => {
+                    let accepted = checks.get_mut(&from).is_some_and(|list| list.on_nominate(sender));
+                    // a repeat of a nomination we already took gets acked again
+                    let current = connected_peers.lock().unwrap().get(&from).is_some_and(|p| p.addr == sender);
+                    if accepted || current {
+                        let ack = PeerMessage::PunchAck { from: client_id.clone(), seq };
+                        if let Err(e) = socket.send_to(&ack.encode_as(format), sender) {
+                            say!("❌ [{}] Failed to acknowledge nomination: {}", client_id, e);
+                        }
+                    }
+                    if accepted {
+                        mark_punched(connected_peers, secure, streams, bg_logger, events, &from, sender);
+                        let kind = checks.get(&from).and_then(|l| l.kind_of(sender));
+                        let kind = kind.map_or("unlisted".to_string(), |k| k.to_string());
+                        say!("🧭 [{}] {} nominated {} ({})", client_id, from, sender, kind);
+                        say!("🎉 DIRECT P2P CONNECTION ESTABLISHED!");
+                        bg_logger.lock().unwrap().print_live_update(&from);
+                        inbox.push(Inbound::Nominated { from, addr: sender });
+                    } else if !current {
+                        auth_failure(bg_logger, events, &from, sender, "nomination of an unchecked path");
+                    }
+                }
+                Some((PeerMessage::HandshakeInit { from, payload }, None)) => {
+                    let mut channels = secure.lock().unwrap();
+                    let was_initiating = channels.is_initiating(&from);
+                    // both sides connecting at once: the smaller id stays initiator
+                    if was_initiating && *client_id < from {
+                        say!("🔀 [{}] Crossed handshake with {}, keeping ours", client_id, from);
+                    } else {
+                        match channels.respond(&from, &payload) {
+                            Ok(reply) => {
+                                let reply = PeerMessage::HandshakeReply {
+                                    from: client_id.clone(),
+                                    payload: reply,
+                                };
+                                if let Err(e) = socket.send_to(&reply.encode_as(format), sender) {
+                                    say!("❌ [{}] Failed to send handshake reply: {}", client_id, e);
+                                }
+                                say!("🔒 [{}] Secure session with {} established", client_id, from);
+                                if was_initiating {
+                                    inbox.push(Inbound::Secured { from });
+                                }
+                            }
+                            Err(e) => auth_failure(bg_logger, events, &from, sender, &e.to_string()),
+                        }
+                    }
+                }
+                Some((PeerMessage::HandshakeReply { from, payload }, None)) => {
+                    let completed = secure.lock().unwrap().complete(&from, &payload);
+                    match completed {
+                        Ok(()) => {
+                            say!("🔒 [{}] Secure session with {} established", client_id, from);
+                            // the reply answers our fresh handshake, so it is
+                            // as good as sealed traffic from `sender`
+                            if !is_relay_of(relays, &from, sender) {
+                                follow_peer(connected_peers, secure, streams, bg_logger, events, &from, sender);
+                            }
+                            inbox.push(Inbound::Secured { from });
+                        }
+                        Err(e) => auth_failure(bg_logger, events, &from, sender, &e.to_string()),
+                    }
+                }
+                Some((msg, None)) => {
+                    // application traffic must come sealed
+                    let peer_id = peer_at(connected_peers, sender)
+                        .unwrap_or_else(|| sender.to_string());
+                    let reason = format!("unencrypted {}", msg);
+                    auth_failure(bg_logger, events, &peer_id, sender, &reason);
+                }
+                Some((msg, Some(peer_id))) => {
+                    say!("🔍 [{}] Ignoring sealed {} from {}", client_id, msg, peer_id);
+                }
+                None => {}
+            }
+        }
+    }
 }
 
-impl Client {
-    pub fn new(id: String, server_addr: SocketAddr) -> io::Result<Self> {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
-        println!(
-            "🔌 Client '{}' created, local: {}",
-            id,
-            socket.local_addr()?
-        );
+impl Client {
+    pub fn new(id: String, server_addr: SocketAddr) -> io::Result<Self> {
+        Self::with_config(id, server_addr, ClientConfig::default())
+    }
+
+    pub fn with_config(
+        id: String,
+        server_addr: SocketAddr,
+        config: ClientConfig,
+    ) -> io::Result<Self> {
+        Self::with_transport(id, server_addr, config, Arc::new(UdpSocket::bind("0.0.0.0:0")?))
+    }
+
+    /// A client on `socket` instead of a UDP socket of its own, e.g. one on
+    /// a simulated network (see `sim`).
+    pub fn with_transport(
+        id: String,
+        server_addr: SocketAddr,
+        config: ClientConfig,
+        socket: Arc<dyn DatagramTransport>,
+    ) -> io::Result<Self> {
+        if id.len() > MAX_ID_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("client id longer than {} bytes", MAX_ID_LEN),
+            ));
+        }
+        // sprayed sockets that get through must carry the connection
+        let group = match config.spray {
+            Some(_) => Some(Arc::new(SocketGroup::new(socket.clone())?)),
+            None => None,
+        };
+        let socket = match &group {
+            Some(group) => group.clone() as Arc<dyn DatagramTransport>,
+            None => socket,
+        };
+        socket.set_read_timeout(Some(REGISTER_TIMEOUT))?;
+        say!(
+            "🔌 Client '{}' created, local: {}",
+            id,
+            socket.local_addr()?
+        );
+
+        let local_addr = socket.local_addr()?;
+        let secure = Channels::new().map_err(io::Error::other)?;
+        let console_logger = Arc::new(Mutex::new(NatConsoleLogger::new(local_addr)));
+
+        if verbose() {
+            console_logger.lock().unwrap().print_address_table();
+        }
+
+        let connected_peers = Arc::new(Mutex::new(HashMap::new()));
+        let secure = Arc::new(Mutex::new(secure));
+        let sender = PeerSender {
+            id: id.clone(),
+            socket: socket.clone(),
+            secure: secure.clone(),
+            peers: Arc::clone(&connected_peers),
+            wire_format: config.wire_format,
+        };
+        let streams = Streams::new(
+            id.clone(),
+            Arc::new(move |peer_id: &str, msg: &PeerMessage| sender.send(peer_id, msg).map(|_| ())),
+        );
+
+        let client = Self {
+            id: id.clone(),
+            socket: socket.clone(),
+            server_addr,
+            config,
+            external_addr: None,
+            listening: false,
+            keeping_alive: false,
+            should_listen: Arc::new(AtomicBool::new(true)),
+            connected_peers,
+            console_logger,
+            inbox: Arc::new(Inbox::new()),
+            psk: None,
+            server: Arc::new(Mutex::new(ServerLink::new(server_addr))),
+            secure,
+            streams: Arc::new(streams),
+            offers: Arc::new(Mutex::new(Offers::default())),
+            server_seen: Arc::new(Mutex::new(Instant::now())),
+            relays: Arc::new(Mutex::new(HashMap::new())),
+            candidates: Arc::new(Mutex::new(Vec::new())),
+            events: Arc::new(Events::default()),
+            clock: Arc::new(SystemClock),
+            clock_offset: Arc::new(Mutex::new(OffsetEstimator::default())),
+            group,
+            allocation: Arc::new(Mutex::new(None)),
+        };
+
+        Ok(client)
+    }
+
+    /// Switch between the binary and the text wire format for signaling.
+    /// The server answers in whichever format it last received from us.
+    pub fn set_wire_format(&mut self, format: WireFormat) {
+        self.config.wire_format = format;
+    }
+
+    /// Set how often heartbeats are sent once registered. Keep it well below
+    /// the server's registration TTL.
+    pub fn set_heartbeat_interval(&mut self, interval: Duration) {
+        self.config.heartbeat_interval = interval;
+    }
+
+    /// Set how often peers get keepalives. Call before `register`.
+    pub fn set_keepalive_interval(&mut self, interval: Duration) {
+        self.config.keepalive_interval = interval;
+    }
+
+    /// Whether the signaling server answered within the last
+    /// `keepalive_misses` heartbeats.
+    pub fn server_reachable(&self) -> bool {
+        self.server_seen.lock().unwrap().elapsed() <= self.server_silence()
+    }
+
+    fn server_silence(&self) -> Duration {
+        let every = self.config.heartbeat_interval.min(self.config.keepalive_interval);
+        every * self.config.keepalive_misses.max(1)
+    }
+
+    /// Authenticate registrations with the pre-shared key for our id from a
+    /// credentials file (see `Credentials`). Call before `register`.
+    pub fn load_credentials(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let credentials = Credentials::load(path)?;
+        let key = credentials.key(&self.id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no credentials for '{}'", self.id),
+            )
+        })?;
+        self.set_psk(key.to_vec());
+        Ok(())
+    }
+
+    /// Authenticate registrations with `key`. Call before `register`.
+    pub fn set_psk(&mut self, key: Vec<u8>) {
+        self.server.lock().unwrap().auth = ServerAuth::new(auth::server_key(&key));
+        self.psk = Some(key);
+    }
+
+    /// Schedule punches, and run the connectivity checks and sprays that
+    /// follow them, on `clock` instead of the system clock. Call before
+    /// `register`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// How far the server's wall clock is ahead of ours, and the round trip
+    /// it was measured over. None until the server answered a clock sync,
+    /// which starts with `register`.
+    pub fn clock_offset(&self) -> Option<ClockOffset> {
+        self.clock_offset.lock().unwrap().estimate()
+    }
 
-        let socket = Arc::new(socket);
-        let local_addr = socket.local_addr()?;
-        let mut console_logger = Arc::new(Mutex::new(NatConsoleLogger::new(local_addr)));
+    /// Call `handler` with every `ClientEvent` from now on. It runs on the
+    /// listener's thread (or task), so it should return quickly.
+    pub fn on_event(&self, handler: impl Fn(&ClientEvent) + Send + Sync + 'static) {
+        self.events.add(handler);
+    }
 
-        console_logger.lock().unwrap().print_address_table();
+    /// Every `ClientEvent` from now on, as a channel.
+    pub fn events(&self) -> mpsc::Receiver<ClientEvent> {
+        self.events.channel()
+    }
 
-        let client = Self {
-            id: id.clone(),
-            socket: socket.clone(),
-            server_addr,
-            external_addr: None,
-            listening: false,
-            should_listen: Arc::new(AtomicBool::new(true)),
-            connected_peers: Arc::new(std::sync::Mutex::new(std::collections::HashMap::new())),
-            console_logger,
+    /// Our static Noise key, which the server hands to peers.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.secure.lock().unwrap().public_key().to_vec()
+    }
+
+    pub fn register(&mut self) -> io::Result<()> {
+        self.send_to_server(&self.registration()?)?;
+        say!("✅ Registration packet sent successfully");
+
+        let deadline = Instant::now() + REGISTER_TIMEOUT;
+        let mut buf = [0; 1024];
+        let response = loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.socket.set_read_timeout(Some(wait))?;
+            let (len, sender) = self.socket.recv_from(&mut buf)?;
+            if let Some(response) = self.server_reply(&buf[..len], sender)? {
+                break response;
+            }
         };
+        self.socket.set_read_timeout(Some(REGISTER_TIMEOUT))?;
+        self.registered(response)?;
 
-        Ok(client)
+        // start background listening after successful registration
+        self.start_background_listening()?;
+        self.start_keepalive();
+        Ok(())
     }
 
-    pub fn register(&mut self) -> io::Result<()> {
+    fn registration(&self) -> io::Result<Message> {
         let local_port = self.socket.local_addr()?.port();
-        let msg = Message::Register {
-            id: self.id.clone(),
-            port: local_port,
-        };
-        self.send_to_server(&msg)?;
-        println!("✅ Registration packet sent successfully");
+        let server_key = self.server.lock().unwrap().key();
+        Ok(registration(&self.id, local_port, &self.public_key(), &server_key, self.psk.as_deref()))
+    }
 
-        let mut buf = [0; 1024];
-        let (len, _) = self.socket.recv_from(&mut buf)?;
-        let response = Message::decode(&String::from_utf8_lossy(&buf[..len]))
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    // The message in a packet that arrived before the listener runs, or
+    // None if it isn't from the server.
+    fn server_reply(&self, buf: &[u8], sender: SocketAddr) -> io::Result<Option<Message>> {
+        let mut server = self.server.lock().unwrap();
+        if !server.is_server(sender) {
+            return Ok(None);
+        }
+        let msg = Message::decode(buf)?;
+        server
+            .open(msg, &self.id)
+            .map(Some)
+            .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))
+    }
 
-        if let Message::RegisterOk { external_addr } = response {
+    // Take in the server's answer to our registration. On success the
+    // caller starts the listener and keepalives.
+    fn registered(&mut self, response: Message) -> io::Result<()> {
+        if let Message::RegisterOk {
+            external_addr,
+            claimed_addr,
+        } = response
+        {
             self.external_addr = Some(external_addr);
             self.console_logger.lock().unwrap().set_external_addr(external_addr);
-            println!("✅ Registered! External address: {}", external_addr);
-
-            self.console_logger.lock().unwrap().print_address_table();
-            // start background listening after successful registration
-            self.start_background_listening()?;
+            say!("✅ Registered! External address: {}", external_addr);
+            if external_addr.port() != claimed_addr.port() {
+                say!(
+                    "🔀 NAT translated local port {} to {}",
+                    claimed_addr.port(),
+                    external_addr.port()
+                );
+            }
 
+            if verbose() {
+                self.console_logger.lock().unwrap().print_address_table();
+            }
+            self.advertise_candidates(self.socket.local_addr()?, external_addr)?;
+            self.advertise_allocation();
+            for _ in 0..CLOCK_SYNC_REQUESTS {
+                self.send_to_server(&Message::TimeRequest {
+                    sent: self.clock.unix_millis(),
+                })?;
+            }
+            *self.server_seen.lock().unwrap() = Instant::now();
             Ok(())
+        } else if let Message::RegisterDenied { reason, .. } = response {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("registration refused: {}", reason),
+            ))
         } else {
-            Err(io::Error::new(io::ErrorKind::Other, "Registration failed"))
+            Err(io::Error::other("Registration failed"))
         }
     }
 
-    pub fn connect_to_peer(&mut self, peer_id: &str) -> io::Result<SocketAddr> {
-        println!("🔍 Step 1: Discovering peer '{}'...", peer_id);
+    /// Gather our candidates and tell the server, which passes them on to
+    /// peers when it coordinates a punch.
+    fn advertise_candidates(&self, local_addr: SocketAddr, external_addr: SocketAddr) -> io::Result<()> {
+        let candidates = local_candidates(local_addr, Some(external_addr));
+        for candidate in &candidates {
+            say!("🧭 Candidate {}", candidate);
+        }
+        *self.candidates.lock().unwrap() = candidates.clone();
+        self.send_to_server(&Message::Candidates {
+            id: self.id.clone(),
+            candidates,
+        })
+    }
 
-        // send discovery request
-        let discover_msg = Message::Discover {
-            target: peer_id.to_string(),
+    /// With `spray` on, measure how our NAT allocates ports and tell the
+    /// server, which passes it on to peers we punch. Punching goes ahead
+    /// without it if that fails.
+    fn advertise_allocation(&self) {
+        let Some(config) = self.config.spray else {
+            return;
         };
-        self.send_to_server(&discover_msg)?;
+        let measured = spray::measure(&*self.socket, self.server_addr, config.probes).and_then(|ports| {
+            let Some(allocation) = Allocation::from_ports(&ports) else {
+                return Ok(());
+            };
+            say!("🎲 Probe sockets were mapped to {:?}: {}", ports, allocation);
+            *self.allocation.lock().unwrap() = Some(allocation);
+            let (next_port, step) = allocation.to_wire();
+            self.send_to_server(&Message::PortAllocation {
+                id: self.id.clone(),
+                next_port,
+                step,
+            })
+        });
+        if let Err(e) = measured {
+            say!("❌ Measuring port allocation failed: {}", e);
+        }
+    }
+
+    /// Discover `peer_id`, ask the server to coordinate a hole punch, and
+    /// wait until connectivity checks against all of the peer's candidates
+    /// nominate a path. Then run the Noise handshake over that path, and
+    /// return its address.
+    ///
+    /// With `relay_fallback`, a punch that times out is followed by a relay
+    /// request; the handshake then runs through the relay and its address
+    /// is returned instead. A punch that gets through later still upgrades
+    /// the connection to the direct path.
+    pub fn connect_to_peer(&mut self, peer_id: &str) -> Result<SocketAddr, ConnectError> {
+        if !self.listening {
+            return Err(ConnectError::NotRegistered);
+        }
+        // nobody can register with it
+        if peer_id.len() > MAX_ID_LEN {
+            return Err(ConnectError::PeerNotFound(peer_id.to_string()));
+        }
+
+        let discover_msg = self.start_discovery(peer_id);
+
+        // resend once a second in case the request or the answer got lost
+        let deadline = Instant::now() + self.config.discover_timeout;
+        let mut found = None;
+        while found.is_none() && Instant::now() < deadline {
+            self.send_to_server(&discover_msg)?;
+            let wait = (deadline - Instant::now()).min(Duration::from_secs(1));
+            found = self.inbox.wait_for(wait, |item| discovered(item, peer_id));
+        }
+        let peer_addr = self.found(peer_id, found)?;
+        say!("✅ Step 1: '{}' is registered at {}", peer_id, peer_addr);
+
+        self.request_punch(peer_id)?;
+        say!("🔍 Step 3: Waiting for checks with '{}' to nominate a path...", peer_id);
+        let verified = self
+            .inbox
+            .wait_for(self.config.punch_timeout, |item| nominated(item, peer_id));
+
+        match verified {
+            Some(addr) => {
+                say!("✅ Hole punch successful! Connection established to {}", addr);
+                self.secure_handshake(peer_id, addr)?;
+                Ok(addr)
+            }
+            None if self.config.relay_fallback => match self.request_relay(peer_id)? {
+                Ok(relay_addr) => {
+                    self.secure_handshake(peer_id, relay_addr)?;
+                    Ok(relay_addr)
+                }
+                Err(reason) => Err(self.punch_failed(peer_id, &format!("no punch acknowledgement, {}", reason))),
+            },
+            None => Err(self.punch_failed(peer_id, "no punch acknowledgement")),
+        }
+    }
+
+    // The `Discover` of step 1 of `connect_to_peer`.
+    fn start_discovery(&self, peer_id: &str) -> Message {
+        say!("🔍 Step 1: Discovering peer '{}'...", peer_id);
+        // answers to an earlier lookup would pass for this one's
+        self.inbox.discard(|item| discovered(item, peer_id).is_some());
+        Message::Discover {
+            target: peer_id.to_string(),
+        }
+    }
 
-        // wait for discovery response (background listener will show it)
-        thread::sleep(Duration::from_millis(1000));
+    // What step 1 of `connect_to_peer` came to.
+    fn found(&self, peer_id: &str, found: Option<Option<SocketAddr>>) -> Result<SocketAddr, ConnectError> {
+        match found {
+            Some(Some(addr)) => Ok(addr),
+            Some(None) => Err(ConnectError::PeerNotFound(peer_id.to_string())),
+            None => Err(ConnectError::ServerUnreachable(self.server_addr)),
+        }
+    }
 
-        println!("🔍 Step 2: Requesting hole punch coordination...");
+    fn request_punch(&self, peer_id: &str) -> io::Result<()> {
+        say!("🔍 Step 2: Requesting hole punch coordination...");
+        // nominations left over from an earlier attempt prove nothing about this one
+        self.inbox
+            .discard(|item| matches!(item, Inbound::Nominated { from, .. } if from == peer_id));
+        // ports moved on since registering
+        self.advertise_allocation();
         let punch_msg = Message::HolePunch {
             from: self.id.clone(),
             to: peer_id.to_string(),
         };
-        self.send_to_server(&punch_msg)?;
-        println!("✅ Step 2: Hole punch request sent");
+        self.send_to_server(&punch_msg)
+    }
+
+    fn punch_failed(&self, peer_id: &str, reason: &str) -> ConnectError {
+        self.console_logger
+            .lock()
+            .unwrap()
+            .log_connection_failed(peer_id, reason);
+        ConnectError::PunchTimeout(peer_id.to_string())
+    }
+
+    /// Ask the server for a relay to `peer_id`. The listener binds it when
+    /// the allocation arrives; we just wait for the outcome.
+    fn request_relay(&mut self, peer_id: &str) -> io::Result<Result<SocketAddr, String>> {
+        self.send_relay_request(peer_id)?;
+        let server_ip = self.server_addr.ip();
+        let outcome = self.inbox.wait_for(self.config.discover_timeout, |item| {
+            relay_outcome(item, peer_id, server_ip)
+        });
+        Ok(outcome.unwrap_or_else(|| Err("no answer to the relay request".to_string())))
+    }
+
+    fn send_relay_request(&self, peer_id: &str) -> io::Result<()> {
+        say!("🔁 Step 3b: No direct path to '{}', asking for a relay...", peer_id);
+        self.inbox.discard(|item| {
+            matches!(item, Inbound::Signal(Message::RelayAllocated { peer_id: id, .. } | Message::RelayDenied { peer_id: id, .. }) if id == peer_id)
+        });
+        let request = Message::RelayRequest {
+            from: self.id.clone(),
+            to: peer_id.to_string(),
+        };
+        self.send_to_server(&request)
+    }
+
+    /// Run the Noise handshake with a peer we just punched through to,
+    /// resending our first message until the reply arrives.
+    fn secure_handshake(&mut self, peer_id: &str, peer_addr: SocketAddr) -> Result<(), ConnectError> {
+        let packet = self.handshake_init(peer_id)?;
+        let deadline = Instant::now() + self.config.handshake_timeout;
+        while Instant::now() < deadline {
+            self.socket.send_to(&packet, peer_addr)?;
+            let wait = (deadline - Instant::now()).min(Duration::from_millis(500));
+            if self.inbox.wait_for(wait, |item| secured(item, peer_id)).is_some() {
+                say!("🔒 Step 4: Channel to '{}' is end-to-end encrypted", peer_id);
+                self.announce_path(peer_id);
+                return Ok(());
+            }
+        }
+        self.secure.lock().unwrap().forget(peer_id);
+        Err(self.handshake_failed(peer_id, "no handshake reply"))
+    }
 
-        println!("🔍 Step 3: Waiting for hole punch coordination...");
-        println!("   (Background listener will handle START_PEER message)");
+    // The peer moves to the path we just secured only once sealed traffic
+    // arrives on it (see `follow_peer`), so send some now rather than with
+    // the next keepalive.
+    fn announce_path(&self, peer_id: &str) {
+        if let Err(e) = self.send_sealed(peer_id, &PeerMessage::Keepalive { seq: 0 }) {
+            say!("❌ Keepalive to {} failed: {}", peer_id, e);
+        }
+    }
 
-        // wait longer for hole punch to complete
-        thread::sleep(Duration::from_millis(8000));
+    // The first handshake message to `peer_id`, ready to send.
+    fn handshake_init(&self, peer_id: &str) -> Result<Vec<u8>, ConnectError> {
+        say!("🔍 Step 4: Securing the channel to '{}'...", peer_id);
+        self.inbox
+            .discard(|item| matches!(item, Inbound::Secured { from } if from == peer_id));
+        let init = match self.secure.lock().unwrap().initiate(peer_id) {
+            Ok(init) => init,
+            Err(e) => return Err(self.handshake_failed(peer_id, e)),
+        };
+        Ok(PeerMessage::HandshakeInit {
+            from: self.id.clone(),
+            payload: init,
+        }
+        .encode_as(self.config.wire_format))
+    }
 
-        self.print_status_report();
+    fn handshake_failed(&self, peer_id: &str, reason: impl fmt::Display) -> ConnectError {
+        self.console_logger
+            .lock()
+            .unwrap()
+            .log_connection_failed(peer_id, &format!("handshake: {}", reason));
+        ConnectError::HandshakeFailed(peer_id.to_string())
+    }
 
-        // check if connection was established by looking at connected peers
-        if let Ok(peers) = self.connected_peers.lock() {
-            if let Some(&peer_addr) = peers.get("peer") {
-                println!(
-                    "✅ Hole punch successful! Connection established to {}",
-                    peer_addr
-                );
-                return Ok(peer_addr);
+    /// Until the client stops listening, refresh our registration and keep
+    /// every NAT mapping we rely on open, see `Keepalive`.
+    fn start_keepalive(&mut self) {
+        if self.keeping_alive {
+            return;
+        }
+        self.keeping_alive = true;
+        let mut keepalive = self.keepalive();
+        let should_listen = self.should_listen.clone();
+        thread::spawn(move || {
+            while should_listen.load(Ordering::Relaxed) {
+                // short naps so we notice shutdown promptly
+                thread::sleep(KEEPALIVE_TICK);
+                keepalive.tick();
+            }
+        });
+    }
+
+    pub(crate) fn keepalive(&self) -> Keepalive {
+        let keepalive_every = self.config.keepalive_interval;
+        let now = Instant::now();
+        Keepalive {
+            socket: self.socket.clone(),
+            server_addr: self.server_addr,
+            peers: self.connected_peers.clone(),
+            sender: self.sender(),
+            logger: self.console_logger.clone(),
+            events: self.events.clone(),
+            server_seen: self.server_seen.clone(),
+            relays: self.relays.clone(),
+            heartbeat_every: self.config.heartbeat_interval.min(keepalive_every),
+            keepalive_every,
+            peer_silence: keepalive_every * self.config.keepalive_misses.max(1),
+            server_silence: self.server_silence(),
+            auto_repunch: self.config.auto_repunch,
+            client_id: self.id.clone(),
+            wire_format: self.config.wire_format,
+            psk: self.psk.clone(),
+            server: self.server.clone(),
+            public_key: self.public_key(),
+            clock: self.clock.clone(),
+            heartbeat: Message::Heartbeat {
+                id: self.id.clone(),
+            }
+            .encode_as(self.config.wire_format),
+            last_heartbeat: now,
+            last_keepalive: now,
+            seq: 0,
+            server_lost: false,
+            repunch: None,
+            repunch_tries: HashMap::new(),
+        }
+    }
+
+    /// Send a STUN Binding Request to `stun_server` and return the reflexive
+    /// address it reports for our socket. Works against our own signaling
+    /// server as well as any RFC 5389 server.
+    pub fn stun_binding(&self, stun_server: SocketAddr) -> io::Result<SocketAddr> {
+        let response = self.binding(stun_server)?;
+        say!(
+            "🧭 STUN {} reports our address as {}",
+            stun_server, response.mapped_addr
+        );
+        Ok(response.mapped_addr)
+    }
+
+    /// Classify our NAT with the RFC 5780 behavior tests. The signaling
+    /// server must have an alternate address (`Server::add_alternate`).
+    pub fn detect_nat_type(&mut self) -> io::Result<NatType> {
+        let primary = self.server_addr;
+        let first = self.binding(primary)?;
+        let alternate = first.other_addr.ok_or_else(|| {
+            io::Error::other("signaling server has no alternate address for NAT tests")
+        })?;
+        let mut server = self.server.lock().unwrap();
+        if !server.is_server(alternate) {
+            server.addrs.push(alternate);
+        }
+        drop(server);
+
+        // the address we'd be seen as without a NAT: our port on the
+        // interface that routes to the server
+        let mut local_addr = self.socket.local_addr()?;
+        if local_addr.ip().is_unspecified() {
+            let probe = UdpSocket::bind("0.0.0.0:0")?;
+            probe.connect(primary)?;
+            local_addr.set_ip(probe.local_addr()?.ip());
+        }
+
+        // filtering first: the mapping test sends to the alternate, which
+        // would open our filter for it
+        say!("🧪 Filtering test: answer from other IP and port");
+        let reached_from_other_ip = self.filtering_test(primary, true)?;
+        say!("🧪 Filtering test: answer from other port");
+        let reached_from_other_port = self.filtering_test(primary, false)?;
+        say!("🧪 Mapping test: binding to alternate {}", alternate);
+        let second = self.binding(alternate)?;
+
+        let nat_type = nat::classify(&BehaviorTests {
+            local_addr,
+            mapped_primary: first.mapped_addr,
+            mapped_alternate: second.mapped_addr,
+            reached_from_other_ip,
+            reached_from_other_port,
+        });
+
+        say!("🏷️ NAT type: {}", nat_type);
+        let mut logger = self.console_logger.lock().unwrap();
+        logger.set_nat_type(nat_type);
+        if verbose() {
+            logger.print_address_table();
+        }
+        Ok(nat_type)
+    }
+
+    // Some(reached) or None when the server can't send from such an address.
+    fn filtering_test(&self, server: SocketAddr, change_ip: bool) -> io::Result<Option<bool>> {
+        match self.stun_transaction(server, BindingRequest::with_change(change_ip, true))? {
+            Some(StunReply::Success(_)) => Ok(Some(true)),
+            Some(StunReply::Error(e)) => {
+                say!("   server can't run this test: {} {}", e.code, e.reason);
+                Ok(None)
             }
+            None => Ok(Some(false)),
+        }
+    }
+
+    fn binding(&self, stun_server: SocketAddr) -> io::Result<BindingResponse> {
+        match self.stun_transaction(stun_server, BindingRequest::new())? {
+            Some(StunReply::Success(response)) => Ok(response),
+            Some(StunReply::Error(e)) => Err(io::Error::other(format!(
+                "STUN error {} from {}: {}",
+                e.code, stun_server, e.reason
+            ))),
+            None => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no STUN response from {}", stun_server),
+            )),
         }
+    }
 
-        // fallback: return the discovered address even if hole punch uncertain
-        println!("⚠️ Hole punch status uncertain, but proceeding...");
+    /// Send `request` with RFC 5389 style retransmits (500ms, 1s, 2s) and
+    /// wait for the reply with the same transaction id. A reply may come
+    /// from a different address than `stun_server` (CHANGE-REQUEST).
+    fn stun_transaction(
+        &self,
+        stun_server: SocketAddr,
+        request: BindingRequest,
+    ) -> io::Result<Option<StunReply>> {
+        let data = request.encode();
+        let mut rto = Duration::from_millis(500);
+        for _ in 0..3 {
+            self.socket.send_to(&data, stun_server)?;
+            let reply = if self.listening {
+                self.inbox.wait_for(rto, |item| match item {
+                    Inbound::Stun(r) if r.transaction_id() == request.transaction_id => {
+                        Some(r.clone())
+                    }
+                    _ => None,
+                })
+            } else {
+                self.recv_stun_reply(&request, rto)?
+            };
+            if reply.is_some() {
+                return Ok(reply);
+            }
+            rto *= 2;
+        }
+        Ok(None)
+    }
 
-        // just return a dummy address - the actual connection is tracked in connected_peers
-        Ok("127.0.0.1:1234".parse().unwrap())
+    // used before the background listener owns the socket
+    fn recv_stun_reply(
+        &self,
+        request: &BindingRequest,
+        timeout: Duration,
+    ) -> io::Result<Option<StunReply>> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0; 1024];
+        while Instant::now() < deadline {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, _)) => {
+                    if let Ok(reply) = StunReply::decode(&buf[..len]) {
+                        if reply.transaction_id() == request.transaction_id {
+                            return Ok(Some(reply));
+                        }
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
     }
 
     // refator: auto-triggers connection for the receiving peer
@@ -130,265 +2217,385 @@ impl Client {
         }
 
         self.listening = true;
+        // the listener's timers run between packets
+        self.socket.set_read_timeout(Some(STREAM_POLL_INTERVAL))?;
         let socket = self.socket.clone();
         let client_id = self.id.clone();
         let should_listen = self.should_listen.clone();
-        let connected_peers = self.connected_peers.clone();
-
-        let bg_logger = self.console_logger.clone();
-        // if let Some(ext_addr) = self.external_addr {
-        //     bg_logger.set_external_addr(ext_addr);
-        // }
+        let events = self.events.clone();
+        let mut listener = self.listener();
 
         thread::spawn(move || {
-            println!("🔊 Background listener started for {}", client_id);
-            let mut buf = [0; 1024];
+            say!("🔊 Background listener started for {}", client_id);
+            // sealed stream segments run well past 1KiB
+            let mut buf = vec![0; 65536];
 
             while should_listen.load(Ordering::Relaxed) {
+                listener.tick();
                 match socket.recv_from(&mut buf) {
-                    Ok((len, sender)) => {
-                        let data = String::from_utf8_lossy(&buf[..len]);
-                        println!(
-                            "\n🔍 [{}] DEBUG: Received {} bytes from {}: '{}'",
-                            client_id, len, sender, data
-                        );
-
-                        // Check if this is from the signaling server
-                        if sender.port() == 9090 {
-                            println!("📡 [{}] This is from signaling server", client_id);
-
-                            match Message::decode(&data) {
-                                Ok(msg) => {
-                                    println!(
-                                        "✅ [{}] Successfully parsed message: {:?}",
-                                        client_id, msg
-                                    );
-                                    match msg {
-                                        Message::StartPunchWithPeer {
-                                            timestamp,
-                                            peer_addr,
-                                        } => {
-                                            println!(
-                                                "\n🚀 [{}] HOLE PUNCH COORDINATION RECEIVED!",
-                                                client_id
-                                            );
-                                            println!(
-                                                "🎯 [{}] Target: {}, Timestamp: {}",
-                                                client_id, peer_addr, timestamp
-                                            );
-
-                                            bg_logger.lock().unwrap().log_peer_discovery(
-                                                "peer".to_string(),
-                                                Some(peer_addr),
-                                            );
-
-                                            // calculate delay
-                                            let now = SystemTime::now()
-                                                .duration_since(UNIX_EPOCH)
-                                                .unwrap()
-                                                .as_millis()
-                                                as u64;
-                                            println!(
-                                                "⏰ [{}] Now: {}, Start: {}",
-                                                client_id, now, timestamp
-                                            );
-
-                                            if timestamp > now {
-                                                let delay = timestamp - now;
-                                                println!(
-                                                    "⏳ [{}] Waiting {} ms before starting...",
-                                                    client_id, delay
-                                                );
-                                                thread::sleep(Duration::from_millis(delay));
-                                            }
-
-                                            println!(
-                                                "🕳️ [{}] STARTING HOLE PUNCH SEQUENCE TO {}",
-                                                client_id, peer_addr
-                                            );
-
-                                            for i in 0..10 {
-                                                bg_logger.lock().unwrap().log_hole_punch_attempt("peer");
-
-                                                let punch_msg = format!("PUNCH:{}", i);
-                                                match socket
-                                                    .send_to(punch_msg.as_bytes(), peer_addr)
-                                                {
-                                                    Ok(_) => {
-                                                        println!(
-                                                            "🕳️ [{}] Sent hole punch {} to {}",
-                                                            client_id, i, peer_addr
-                                                        );
-                                                    }
-                                                    Err(e) => {
-                                                        bg_logger.lock().unwrap().log_hole_punch_failure("peer");
-                                                        println!(
-                                                            "❌ [{}] Hole punch {} failed: {}",
-                                                            client_id, i, e
-                                                        );
-                                                    }
-                                                }
-                                                thread::sleep(Duration::from_millis(50));
-                                            }
-
-                                            if let Ok(mut peers) = connected_peers.lock() {
-                                                peers.insert("peer".to_string(), peer_addr);
-                                                // println!("🔗 [{}] AUTO-CONNECTED: Stored connection to {}", client_id, peer_addr);
-                                            }
-
-                                            println!(
-                                                "✅ [{}] Hole punch sequence completed to {}",
-                                                client_id, peer_addr
-                                            );
-                                            println!(
-                                                "🎉 [{}] Ready to send/receive messages!",
-                                                client_id
-                                            );
-                                        }
-
-                                        Message::StartPunch { timestamp } => {
-                                            println!("🚀 [{}] Received OLD FORMAT hole punch (no peer address)", client_id);
-                                        }
-
-                                        Message::PeerFound { id, addr } => {
-                                            bg_logger.lock().unwrap().log_peer_discovery(id.clone(), Some(addr));
-
-                                            println!(
-                                                "🔍 [{}] Peer discovery result: {} at {}",
-                                                client_id, id, addr
-                                            );
-                                        }
-
-                                        _ => {
-                                            println!(
-                                                "🔍 [{}] Other server message: {:?}",
-                                                client_id, msg
-                                            );
-                                        }
-                                    }
-                                }
-                                Err(parse_error) => {
-                                    println!(
-                                        "❌ [{}] Failed to parse server message '{}': {}",
-                                        client_id, data, parse_error
-                                    );
-                                    println!("🔍 [{}] Raw bytes: {:?}", client_id, &buf[..len]);
-                                }
-                            }
-                        } else {
-                            // handle P2P messages (not from signaling server)
-                            println!("🤝 [{}] This is P2P traffic from {}", client_id, sender);
-
-                            if data.starts_with("MSG:") {
-                                let message = &data[4..];
-                                println!(
-                                    "\n📥 [{}] Received message from {}: {}",
-                                    client_id, sender, message
-                                );
-                                bg_logger.lock().unwrap().log_direct_message_received("peer", message, sender);
-                                bg_logger.lock().unwrap().print_live_update("peer");
-                            } else if data.starts_with("PUNCH:") {
-                                println!(
-                                    "\n🕳️ [{}] Received hole punch from {}: {}",
-                                    client_id, sender, data
-                                );
-                                let response = format!("PUNCH_ACK:{}", client_id);
-                                match socket.send_to(response.as_bytes(), sender) {
-                                    Ok(_) => {
-                                        println!("🤝 [{}] Sent punch ACK to {}", client_id, sender);
-
-                                        bg_logger.lock().unwrap().log_punch_traffic("peer", 50, "PUNCH");
-                                    }
-                                    Err(e) => println!(
-                                        "❌ [{}] Failed to send punch ACK: {}",
-                                        client_id, e
-                                    ),
-                                }
-
-                                // store the peer connection when receiving hole punch
-                                if let Ok(mut peers) = connected_peers.lock() {
-                                    peers.insert("peer".to_string(), sender);
-                                    println!(
-                                        "🔗 [{}] PUNCH-CONNECTED: Stored connection to {}",
-                                        client_id, sender
-                                    );
-                                }
-                            } else if data.starts_with("PUNCH_ACK:") {
-                                println!(
-                                    "\n🤝 [{}] Received punch ACK from {}: {}",
-                                    client_id, sender, data
-                                );
-
-                                bg_logger.lock().unwrap().log_punch_traffic("peer", 100, "PUNCH_ACK");
-
-                                // store the peer connection when receiving punch ACK
-                                if let Ok(mut peers) = connected_peers.lock() {
-                                    peers.insert("peer".to_string(), sender);
-                                    println!(
-                                        "🔗 [{}] ACK-CONNECTED: Stored connection to {}",
-                                        client_id, sender
-                                    );
-                                }
-
-                                println!("🎉 DIRECT P2P CONNECTION ESTABLISHED!");
-                                bg_logger.lock().unwrap().print_live_update("peer");
-                            } else {
-                                println!(
-                                    "\n🔍 [{}] Unknown P2P message from {}: {}",
-                                    client_id, sender, data
-                                );
-                            }
-                        }
-
-                        print!("\n{} > ", client_id);
-                        std::io::Write::flush(&mut std::io::stdout()).unwrap();
-                    }
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        thread::sleep(Duration::from_millis(10));
-                    }
+                    Ok((len, sender)) => listener.handle(&buf[..len], sender),
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut => {}
                     Err(e) => {
-                        println!("❌ [{}] Background listener error: {}", client_id, e);
+                        say!("❌ [{}] Background listener error: {}", client_id, e);
+                        events.emit(ClientEvent::Error {
+                            peer_id: None,
+                            message: format!("listener stopped: {}", e),
+                        });
                         break;
                     }
                 }
             }
 
-            println!("🔇 Background listener stopped for {}", client_id);
+            say!("🔇 Background listener stopped for {}", client_id);
         });
 
         Ok(())
     }
 
-    pub fn send_message(&mut self, peer_addr: SocketAddr, message: &str) -> io::Result<()> {
-        let data = format!("MSG:{}", message);
-        self.socket.send_to(data.as_bytes(), peer_addr)?;
+    /// The packet and timer handling behind `start_background_listening`.
+    pub(crate) fn listener(&self) -> Listener {
+        Listener {
+            socket: self.socket.clone(),
+            client_id: self.id.clone(),
+            wire_format: self.config.wire_format,
+            server_addr: self.server_addr,
+            connected_peers: self.connected_peers.clone(),
+            inbox: self.inbox.clone(),
+            psk: self.psk.clone(),
+            server: self.server.clone(),
+            public_key: self.public_key(),
+            secure: self.secure.clone(),
+            streams: self.streams.clone(),
+            offers: self.offers.clone(),
+            peer_sender: self.sender(),
+            server_seen: self.server_seen.clone(),
+            relays: self.relays.clone(),
+            candidates: self.candidates.clone(),
+            bg_logger: self.console_logger.clone(),
+            events: self.events.clone(),
+            checks: HashMap::new(),
+            clock: self.clock.clone(),
+            clock_offset: self.clock_offset.clone(),
+            punches: Vec::new(),
+            spraying: self.config.spray.zip(self.group.clone()),
+            sprays: Vec::new(),
+            allocation: self.allocation.clone(),
+            last_poll: self.clock.now(),
+        }
+    }
+
+    /// Send a chat message to a peer we have a secure session with.
+    pub fn send_message(&mut self, peer_id: &str, message: &str) -> io::Result<()> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("message longer than {} bytes, send it as a file", MAX_MESSAGE_LEN),
+            ));
+        }
+        let data = PeerMessage::Data {
+            payload: message.to_string(),
+        };
+        let peer_addr = self.send_sealed(peer_id, &data)?;
+
+        self.log_message_sent(peer_id, message);
+        self.console_logger.lock().unwrap().print_live_update(peer_id);
+
+        say!("📤 Sent message to {} ({}): {}", peer_id, peer_addr, message);
+        Ok(())
+    }
+
+    /// Tell a peer we are done with the direct path.
+    pub fn close(&mut self, peer_id: &str) -> io::Result<()> {
+        let msg = PeerMessage::Close {
+            from: self.id.clone(),
+        };
+        let sent = self.send_sealed(peer_id, &msg);
+        let gone = self.connected_peers.lock().unwrap().remove(peer_id);
+        if let (Some(peer), Some(group)) = (gone, &self.group) {
+            group.forget(peer.addr);
+        }
+        self.secure.lock().unwrap().forget(peer_id);
+        self.streams.drop_peer(peer_id);
+        sent.map(|_| ())
+    }
+
+    /// Open a reliable, ordered stream to a peer `connect_to_peer` succeeded
+    /// with. The peer picks it up with `accept_stream`.
+    pub fn open_stream(&self, peer_id: &str) -> io::Result<Stream> {
+        self.require_session(peer_id)?;
+        Ok(self.streams.open(peer_id))
+    }
+
+    /// Wait up to `timeout` for a peer to open a stream to us.
+    pub fn accept_stream(&self, timeout: Duration) -> Option<Stream> {
+        self.streams.accept(timeout)
+    }
+
+    /// Send a file to a connected peer and block until it is through and
+    /// the peer confirmed its SHA-256. The peer decides with `accept_file`
+    /// or `reject_file`; if it kept part of an earlier attempt, only the
+    /// rest is sent.
+    pub fn send_file(&mut self, peer_id: &str, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let name = transfer::offered_name(path)?;
+        let (size, sha256) = transfer::hash_file(path)?;
+        self.require_session(peer_id)?;
+        // the stream only opens once the peer accepts; a declined or
+        // unanswered offer leaves nothing behind
+        let stream_id = self.streams.reserve(peer_id);
+        let offer = PeerMessage::FileOffer {
+            stream_id,
+            name: name.clone(),
+            size,
+            sha256,
+        };
+
+        say!("📁 Offering '{}' ({} bytes) to {}...", name, size, peer_id);
+        let deadline = Instant::now() + self.config.offer_timeout;
+        let (offset, prefix) = loop {
+            self.send_sealed(peer_id, &offer)?;
+            let answer = self.inbox.wait_for(OFFER_RESEND, |item| match item {
+                Inbound::FileAnswer {
+                    from,
+                    stream_id: id,
+                    answer,
+                } if from == peer_id && *id == stream_id => Some(answer.clone()),
+                _ => None,
+            });
+            match answer {
+                Some(Ok((offset, prefix))) if offset <= size => break (offset, prefix),
+                Some(Ok((offset, _))) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("peer asked to resume at {} of {} bytes", offset, size),
+                    ))
+                }
+                Some(Err(reason)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("{} rejected '{}': {}", peer_id, name, reason),
+                    ))
+                }
+                None if Instant::now() >= deadline => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("{} did not answer the offer", peer_id),
+                    ))
+                }
+                None => {}
+            }
+        };
+        // the peer's part file may be left from another version of the file
+        let offset = if offset > 0 && transfer::hash_prefix(path, offset)? != prefix {
+            say!("🔁 {}'s partial copy differs, sending it all", peer_id);
+            0
+        } else {
+            offset
+        };
+        if offset > 0 {
+            say!("⏩ {} already has {} bytes, resuming", peer_id, offset);
+        }
+        let mut stream = self.streams.claim(peer_id, stream_id);
+
+        let started = Instant::now();
+        let logger = self.console_logger.clone();
+        let result = transfer::send_body(&mut stream, path, offset, |done| {
+            logger.lock().unwrap().log_transfer_progress(peer_id, &name, done, size)
+        })
+        .and_then(|sent| {
+            // the receiver answers with one byte once it checked the hash
+            stream.set_read_timeout(Some(self.config.offer_timeout));
+            let mut status = [0];
+            stream.read_exact(&mut status)?;
+            if status[0] != transfer::STATUS_VERIFIED {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} got a corrupt copy", peer_id),
+                ));
+            }
+            Ok(sent)
+        });
+
+        let mut logger = self.console_logger.lock().unwrap();
+        match result {
+            Ok(sent) => {
+                logger.log_transfer_complete(peer_id, &name, sent, true, started.elapsed());
+                Ok(())
+            }
+            Err(e) => {
+                logger.log_transfer_failed(peer_id, &name, &e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Accept the oldest pending file offer, from `peer_id` if given, and
+    /// receive it into `dir`. Blocks until the file is complete and its
+    /// hash checked; returns where it was saved, which is `<name> (1)` and
+    /// so on if `<name>` already exists. An interrupted transfer leaves
+    /// `<name>.part` behind, and the next offer of the file resumes from
+    /// there.
+    pub fn accept_file(&self, peer_id: Option<&str>, dir: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let offer = self.take_offer(peer_id)?;
+        let dir = dir.as_ref();
+        let part = transfer::part_path(dir, &offer.name);
+        let dest = dir.join(&offer.name);
+
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&part)?;
+        let mut offset = file.metadata()?.len();
+        if offset > offer.size {
+            // left over from some other file of that name
+            file.set_len(0)?;
+            offset = 0;
+        }
+        drop(file);
+        let prefix_sha256 = transfer::hash_prefix(&part, offset)?;
+
+        // claim the stream before the first byte can arrive on it
+        let mut stream = self.streams.claim(&offer.peer_id, offer.stream_id);
+        stream.set_read_timeout(Some(TRANSFER_IDLE_TIMEOUT));
+        let accept = PeerMessage::FileAccept {
+            stream_id: offer.stream_id,
+            offset,
+            prefix_sha256,
+        };
+        self.offers.lock().unwrap().answer(&offer, accept.clone());
+        self.send_sealed(&offer.peer_id, &accept)?;
+        say!(
+            "📥 Receiving '{}' from {} into {}{}",
+            offer.name,
+            offer.peer_id,
+            dir.display(),
+            if offset > 0 { format!(", resuming at {} bytes", offset) } else { String::new() }
+        );
+
+        let started = Instant::now();
+        let logger = self.console_logger.clone();
+        let result = transfer::receive_body(&mut stream, &part, offer.size, |done| {
+            logger
+                .lock()
+                .unwrap()
+                .log_transfer_progress(&offer.peer_id, &offer.name, done, offer.size)
+        })
+        .and_then(|()| {
+            let verified = transfer::verify_and_finish(&part, &dest, &offer.sha256);
+            let status = match verified {
+                Ok(_) => transfer::STATUS_VERIFIED,
+                Err(_) => transfer::STATUS_CORRUPT,
+            };
+            // make sure the verdict arrives before we let go of the stream
+            stream.write_all(&[status])?;
+            stream.flush()?;
+            verified
+        });
+        self.offers.lock().unwrap().finish(&offer);
+
+        let mut logger = self.console_logger.lock().unwrap();
+        match result {
+            Ok(saved) => {
+                logger.log_transfer_complete(
+                    &offer.peer_id,
+                    &offer.name,
+                    offer.size - offset,
+                    false,
+                    started.elapsed(),
+                );
+                Ok(saved)
+            }
+            Err(e) => {
+                logger.log_transfer_failed(&offer.peer_id, &offer.name, &e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Decline the oldest pending file offer, from `peer_id` if given.
+    pub fn reject_file(&self, peer_id: Option<&str>, reason: &str) -> io::Result<FileOffer> {
+        let offer = self.take_offer(peer_id)?;
+        let reject = PeerMessage::FileReject {
+            stream_id: offer.stream_id,
+            reason: reason.to_string(),
+        };
+        self.offers.lock().unwrap().answer(&offer, reject.clone());
+        self.send_sealed(&offer.peer_id, &reject)?;
+        Ok(offer)
+    }
+
+    /// Offers waiting for `accept_file` or `reject_file`, oldest first.
+    pub fn pending_offers(&self) -> Vec<FileOffer> {
+        self.offers.lock().unwrap().pending().to_vec()
+    }
+
+    fn take_offer(&self, peer_id: Option<&str>) -> io::Result<FileOffer> {
+        self.offers.lock().unwrap().take(peer_id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no pending file offer")
+        })
+    }
+
+    fn sender(&self) -> PeerSender {
+        PeerSender {
+            id: self.id.clone(),
+            socket: self.socket.clone(),
+            secure: self.secure.clone(),
+            peers: self.connected_peers.clone(),
+            wire_format: self.config.wire_format,
+        }
+    }
+
+    fn send_sealed(&self, peer_id: &str, msg: &PeerMessage) -> io::Result<SocketAddr> {
+        self.sender().send(peer_id, msg)
+    }
+
+    /// Address of `peer_id` if we have a connection to it.
+    pub fn peer_addr(&self, peer_id: &str) -> Option<SocketAddr> {
+        self.connected_peers
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .map(|p| p.addr)
+    }
 
-        self.log_message_sent("peer", message);
-        self.console_logger.lock().unwrap().print_live_update("peer");
+    fn require_peer(&self, peer_id: &str) -> io::Result<SocketAddr> {
+        self.peer_addr(peer_id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("not connected to peer '{}'", peer_id),
+            )
+        })
+    }
 
-        println!("📤 Sent message to {}: {}", peer_addr, message);
+    fn require_session(&self, peer_id: &str) -> io::Result<()> {
+        self.require_peer(peer_id)?;
+        if !self.secure.lock().unwrap().has_session(peer_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("no secure session with '{}'", peer_id),
+            ));
+        }
         Ok(())
     }
 
     pub fn listen_for_messages(&self) -> io::Result<()> {
         // method is now optional since I add background listening
         // keep it for compatibility tho
-        println!("Already listening in background. Messages will appear automatically.");
+        say!("Already listening in background. Messages will appear automatically.");
         Ok(())
     }
 
     fn send_to_server(&self, msg: &Message) -> io::Result<()> {
-        let data = msg.encode();
-        self.socket.send_to(data.as_bytes(), self.server_addr)?;
+        self.socket
+            .send_to(&msg.encode_as(self.config.wire_format), self.server_addr)?;
         Ok(())
     }
 
-    pub fn get_connected_peers(&self) -> Vec<SocketAddr> {
-        if let Ok(peers) = self.connected_peers.lock() {
-            peers.values().cloned().collect()
-        } else {
-            Vec::new()
-        }
+    /// Snapshot of the connection table, sorted by peer id.
+    pub fn get_connected_peers(&self) -> Vec<(String, PeerConnection)> {
+        let peers = self.connected_peers.lock().unwrap();
+        let mut peers: Vec<_> = peers.iter().map(|(id, p)| (id.clone(), p.clone())).collect();
+        peers.sort_by(|a, b| a.0.cmp(&b.0));
+        peers
     }
 
     pub fn has_connections(&self) -> bool {
@@ -458,3 +2665,229 @@ impl Client {
         self.console_logger.lock().unwrap().print_full_report();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::server::Server;
+    use crate::transport::MemoryNetwork;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // A server on `net` and a client for each of `ids`, not registered yet.
+    fn clients(net: &MemoryNetwork, ids: &[&str]) -> Vec<Client> {
+        let server_addr = addr("192.0.2.1:9090");
+        let mut server = Server::with_transport(net.bind(server_addr).unwrap()).unwrap();
+        thread::spawn(move || server.run());
+        let config = ClientConfig {
+            discover_timeout: Duration::from_millis(500),
+            ..ClientConfig::default()
+        };
+        ids.iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let socket = net.bind(SocketAddr::new([10, 0, 0, i as u8 + 1].into(), 0)).unwrap();
+                Client::with_transport(id.to_string(), server_addr, config.clone(), socket).unwrap()
+            })
+            .collect()
+    }
+
+    fn stop(clients: &[Client]) {
+        for client in clients {
+            client.should_listen.store(false, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_connect_over_udp() {
+        let mut server = Server::new("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut alice = Client::new("alice".to_string(), server_addr).unwrap();
+        let mut bob = Client::new("bob".to_string(), server_addr).unwrap();
+        alice.register().unwrap();
+        bob.register().unwrap();
+        let messages = bob.events();
+
+        let addr = alice.connect_to_peer("bob").unwrap();
+        assert_eq!(alice.peer_addr("bob"), Some(addr));
+        alice.send_message("bob", "hello").unwrap();
+        let delivered = std::iter::from_fn(|| messages.recv_timeout(Duration::from_secs(2)).ok())
+            .any(|event| matches!(event, ClientEvent::MessageReceived { message, .. } if message == "hello"));
+        assert!(delivered);
+
+        let mut stream = alice.open_stream("bob").unwrap();
+        stream.write_all(b"over a stream").unwrap();
+        stream.shutdown();
+        let mut accepted = bob.accept_stream(Duration::from_secs(2)).unwrap();
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"over a stream");
+        stop(&[alice, bob]);
+    }
+
+    #[test]
+    fn test_connect_errors() {
+        let net = MemoryNetwork::new();
+        let mut clients = clients(&net, &["alice"]);
+        let alice = &mut clients[0];
+        assert!(matches!(alice.connect_to_peer("bob"), Err(ConnectError::NotRegistered)));
+        alice.register().unwrap();
+        assert!(matches!(alice.connect_to_peer("bob"), Err(ConnectError::PeerNotFound(id)) if id == "bob"));
+        // ids that can't be framed are refused before anything is encoded
+        let long = "x".repeat(70_000);
+        assert!(matches!(alice.connect_to_peer(&long), Err(ConnectError::PeerNotFound(_))));
+        let socket = net.bind(addr("10.0.0.9:0")).unwrap();
+        let refused = Client::with_transport(long, addr("192.0.2.1:9090"), ClientConfig::default(), socket);
+        assert_eq!(refused.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        stop(&clients);
+    }
+
+    #[test]
+    fn test_stale_lookup_is_discarded() {
+        let net = MemoryNetwork::new();
+        let mut clients = clients(&net, &["alice", "bob"]);
+        for client in &mut clients {
+            client.register().unwrap();
+        }
+        // left over from a lookup made before bob registered
+        clients[0].inbox.push(Inbound::Signal(Message::PeerNotFound { id: "bob".to_string() }));
+        let bob_addr = clients[0].connect_to_peer("bob").unwrap();
+        assert_eq!(bob_addr, clients[1].external_addr.unwrap());
+        stop(&clients);
+    }
+
+    #[test]
+    fn test_punch_rounds_follow_the_clock() {
+        let net = MemoryNetwork::new();
+        let mut clients = clients(&net, &["alice"]);
+        let clock = Arc::new(ManualClock::new(1_000_000));
+        clients[0].set_clock(clock.clone());
+        let mut listener = clients[0].listener();
+        let bob = net.bind(addr("10.0.0.9:6000")).unwrap();
+        bob.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let punches = || {
+            let mut buf = [0; 1024];
+            let mut seqs = Vec::new();
+            while let Ok((len, _)) = bob.recv_from(&mut buf) {
+                if let Ok(PeerMessage::Punch { seq, .. }) = PeerMessage::decode(&buf[..len]) {
+                    seqs.push(seq);
+                }
+            }
+            seqs
+        };
+
+        let remote = [Candidate::new(CandidateKind::Host, bob.local_addr().unwrap(), 0)];
+        let start = clock.now() + Duration::from_secs(1);
+        listener.checks.insert("bob".to_string(), CheckList::new(&[], &remote, true, clock.now()));
+        listener.punches.push(ScheduledPunch {
+            peer_id: "bob".to_string(),
+            start,
+            rounds: 0,
+        });
+
+        // real time passing moves nothing
+        thread::sleep(STREAM_POLL_INTERVAL * 2);
+        listener.tick();
+        assert!(punches().is_empty());
+
+        clock.advance(Duration::from_secs(1));
+        listener.tick();
+        listener.tick();
+        assert_eq!(punches(), [0]);
+        clock.advance(PUNCH_SPACING * 2);
+        listener.tick();
+        assert_eq!(punches(), [1]);
+        clock.advance(PUNCH_SPACING * PUNCH_ROUNDS);
+        listener.tick();
+        assert_eq!(punches(), [2]);
+
+        // and the checks give up on the clock's time too
+        assert!(!listener.checks.get_mut("bob").unwrap().targets().is_empty());
+        clock.advance(Duration::from_secs(3));
+        listener.tick();
+        assert!(listener.checks.get_mut("bob").unwrap().targets().is_empty());
+        stop(&clients);
+    }
+
+    #[test]
+    fn test_plaintext_punches_do_not_move_peers() {
+        let net = MemoryNetwork::new();
+        let mut clients = clients(&net, &["alice", "bob"]);
+        for client in &mut clients {
+            client.register().unwrap();
+        }
+        clients[0].connect_to_peer("bob").unwrap();
+        let alice_addr = clients[1].peer_addr("alice").unwrap();
+        let bob_addr = clients[0].peer_addr("bob").unwrap();
+        let bob_events = clients[1].events();
+
+        // anyone can claim to be alice in plaintext, and bob is the side
+        // that takes nominations
+        let mallory = net.bind(addr("10.0.0.66:0")).unwrap();
+        for msg in [
+            PeerMessage::Punch { from: "alice".to_string(), seq: 0 },
+            PeerMessage::PunchAck { from: "alice".to_string(), seq: 0 },
+            PeerMessage::Nominate { from: "alice".to_string(), seq: NOMINATION_SEQ },
+        ] {
+            mallory.send_to(&msg.encode(), bob_addr).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(clients[1].peer_addr("alice"), Some(alice_addr));
+
+        clients[0].send_message("bob", "still here").unwrap();
+        let delivered = std::iter::from_fn(|| bob_events.recv_timeout(Duration::from_secs(2)).ok())
+            .any(|event| matches!(event, ClientEvent::MessageReceived { message, .. } if message == "still here"));
+        assert!(delivered);
+        stop(&clients);
+    }
+
+    #[test]
+    fn test_file_offers() {
+        let net = MemoryNetwork::new();
+        let mut clients = clients(&net, &["alice", "bob"]);
+        for client in &mut clients {
+            client.register().unwrap();
+        }
+        clients[0].connect_to_peer("bob").unwrap();
+        let bob = clients.pop().unwrap();
+        let mut alice = clients.pop().unwrap();
+        let offered = bob.events();
+        let dir = std::env::temp_dir().join(format!("nt-offers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.txt");
+        std::fs::write(&path, b"hello world").unwrap();
+        let downloads = dir.join("downloads");
+        std::fs::create_dir_all(&downloads).unwrap();
+        let wait_for_offer = || {
+            std::iter::from_fn(|| offered.recv_timeout(Duration::from_secs(2)).ok())
+                .any(|event| matches!(event, ClientEvent::FileOffered(_)))
+        };
+
+        // a declined offer never opens a stream
+        let sending = thread::spawn(move || (alice.send_file("bob", &path).map_err(|e| e.kind()), alice));
+        assert!(wait_for_offer());
+        bob.reject_file(None, "not now").unwrap();
+        let (sent, alice) = sending.join().unwrap();
+        assert_eq!(sent, Err(io::ErrorKind::PermissionDenied));
+        assert_eq!(alice.streams.len(), 0);
+
+        // a part file of the same length from some other file is resent
+        std::fs::write(transfer::part_path(&downloads, "a.txt"), b"jello").unwrap();
+        let mut alice = alice;
+        let path = dir.join("a.txt");
+        let sending = thread::spawn(move || (alice.send_file("bob", &path).map_err(|e| e.kind()), alice));
+        assert!(wait_for_offer());
+        let saved = bob.accept_file(None, &downloads).unwrap();
+        let (sent, alice) = sending.join().unwrap();
+        assert_eq!(sent, Ok(()));
+        assert_eq!(std::fs::read(saved).unwrap(), b"hello world");
+
+        stop(&[alice, bob]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
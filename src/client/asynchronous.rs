@@ -0,0 +1,276 @@
+//! `Client` on tokio: the listener and keepalives run as tasks instead of
+//! threads, and the calls that wait on the network are `async`.
+
+use super::{
+    discovered, nominated, relay_outcome, secured, Client, ClientConfig,
+    ConnectError, PeerConnection, KEEPALIVE_TICK, REGISTER_TIMEOUT, STREAM_POLL_INTERVAL,
+};
+use crate::event::ClientEvent;
+use crate::protocol::MAX_ID_LEN;
+use crate::transport::SharedWithTokio;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+
+/// A `Client` driven by the tokio runtime it was created in. Same protocol
+/// and behavior, without a dedicated thread for listening or keepalives.
+pub struct AsyncClient {
+    client: Client,
+    // the client's socket, for receiving; sends go through the std handle
+    socket: Arc<UdpSocket>,
+}
+
+impl AsyncClient {
+    /// Must be called from within a tokio runtime.
+    pub fn new(id: String, server_addr: SocketAddr) -> io::Result<Self> {
+        Self::with_config(id, server_addr, ClientConfig::default())
+    }
+
+    /// Must be called from within a tokio runtime.
+    pub fn with_config(
+        id: String,
+        server_addr: SocketAddr,
+        config: ClientConfig,
+    ) -> io::Result<Self> {
+        if config.spray.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "spraying needs the sockets of a Client",
+            ));
+        }
+        let (socket, sender) = SharedWithTokio::split(&std::net::UdpSocket::bind("0.0.0.0:0")?)?;
+        let client = Client::with_transport(id, server_addr, config, sender)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            client,
+        })
+    }
+
+    /// The wrapped client, for everything that doesn't wait on the network.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// See `Client::set_psk`. Call before `register`.
+    pub fn set_psk(&mut self, key: Vec<u8>) {
+        self.client.set_psk(key);
+    }
+
+    /// See `Client::register`.
+    pub async fn register(&mut self) -> io::Result<()> {
+        self.client.send_to_server(&self.client.registration()?)?;
+        say!("✅ Registration packet sent successfully");
+
+        let mut buf = [0; 1024];
+        let receive = async {
+            loop {
+                let (len, sender) = self.socket.recv_from(&mut buf).await?;
+                if let Some(response) = self.client.server_reply(&buf[..len], sender)? {
+                    return io::Result::Ok(response);
+                }
+            }
+        };
+        let response = time::timeout(REGISTER_TIMEOUT, receive)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        self.client.registered(response)?;
+
+        self.start_listening();
+        self.start_keepalive();
+        Ok(())
+    }
+
+    /// See `Client::connect_to_peer`.
+    pub async fn connect_to_peer(&mut self, peer_id: &str) -> Result<SocketAddr, ConnectError> {
+        let client = &self.client;
+        if !client.listening {
+            return Err(ConnectError::NotRegistered);
+        }
+        // nobody can register with it
+        if peer_id.len() > MAX_ID_LEN {
+            return Err(ConnectError::PeerNotFound(peer_id.to_string()));
+        }
+
+        let discover_msg = client.start_discovery(peer_id);
+
+        // resend once a second in case the request or the answer got lost
+        let deadline = Instant::now() + client.config.discover_timeout;
+        let mut found = None;
+        while found.is_none() && Instant::now() < deadline {
+            client.send_to_server(&discover_msg)?;
+            let wait = (deadline - Instant::now()).min(Duration::from_secs(1));
+            found = client
+                .inbox
+                .wait_for_async(wait, |item| discovered(item, peer_id))
+                .await;
+        }
+        let peer_addr = client.found(peer_id, found)?;
+        say!("✅ Step 1: '{}' is registered at {}", peer_id, peer_addr);
+
+        client.request_punch(peer_id)?;
+        say!("🔍 Step 3: Waiting for checks with '{}' to nominate a path...", peer_id);
+        let verified = client
+            .inbox
+            .wait_for_async(client.config.punch_timeout, |item| nominated(item, peer_id))
+            .await;
+
+        match verified {
+            Some(addr) => {
+                say!("✅ Hole punch successful! Connection established to {}", addr);
+                self.secure_handshake(peer_id, addr).await?;
+                Ok(addr)
+            }
+            None if client.config.relay_fallback => match self.request_relay(peer_id).await? {
+                Ok(relay_addr) => {
+                    self.secure_handshake(peer_id, relay_addr).await?;
+                    Ok(relay_addr)
+                }
+                Err(reason) => Err(client.punch_failed(peer_id, &format!("no punch acknowledgement, {}", reason))),
+            },
+            None => Err(client.punch_failed(peer_id, "no punch acknowledgement")),
+        }
+    }
+
+    async fn request_relay(&self, peer_id: &str) -> io::Result<Result<SocketAddr, String>> {
+        self.client.send_relay_request(peer_id)?;
+        let server_ip = self.client.server_addr.ip();
+        let outcome = self
+            .client
+            .inbox
+            .wait_for_async(self.client.config.discover_timeout, |item| {
+                relay_outcome(item, peer_id, server_ip)
+            })
+            .await;
+        Ok(outcome.unwrap_or_else(|| Err("no answer to the relay request".to_string())))
+    }
+
+    async fn secure_handshake(&self, peer_id: &str, peer_addr: SocketAddr) -> Result<(), ConnectError> {
+        let client = &self.client;
+        let packet = client.handshake_init(peer_id)?;
+        let deadline = Instant::now() + client.config.handshake_timeout;
+        while Instant::now() < deadline {
+            self.socket.send_to(&packet, peer_addr).await?;
+            let wait = (deadline - Instant::now()).min(Duration::from_millis(500));
+            let reply = client.inbox.wait_for_async(wait, |item| secured(item, peer_id));
+            if reply.await.is_some() {
+                say!("🔒 Step 4: Channel to '{}' is end-to-end encrypted", peer_id);
+                client.announce_path(peer_id);
+                return Ok(());
+            }
+        }
+        client.secure.lock().unwrap().forget(peer_id);
+        Err(client.handshake_failed(peer_id, "no handshake reply"))
+    }
+
+    /// See `Client::send_message`.
+    pub fn send_message(&mut self, peer_id: &str, message: &str) -> io::Result<()> {
+        self.client.send_message(peer_id, message)
+    }
+
+    /// See `Client::close`.
+    pub fn close(&mut self, peer_id: &str) -> io::Result<()> {
+        self.client.close(peer_id)
+    }
+
+    pub fn peer_addr(&self, peer_id: &str) -> Option<SocketAddr> {
+        self.client.peer_addr(peer_id)
+    }
+
+    pub fn get_connected_peers(&self) -> Vec<(String, PeerConnection)> {
+        self.client.get_connected_peers()
+    }
+
+    /// Stop the listener and keepalive tasks.
+    pub fn stop(&self) {
+        self.client.should_listen.store(false, Ordering::Relaxed);
+    }
+
+    fn start_listening(&mut self) {
+        if self.client.listening {
+            return;
+        }
+        self.client.listening = true;
+        let socket = self.socket.clone();
+        let client_id = self.client.id.clone();
+        let should_listen = self.client.should_listen.clone();
+        let events = self.client.events.clone();
+        let mut listener = self.client.listener();
+
+        tokio::spawn(async move {
+            say!("🔊 Background listener started for {}", client_id);
+            // sealed stream segments run well past 1KiB
+            let mut buf = vec![0; 65536];
+            let mut ticks = time::interval(STREAM_POLL_INTERVAL);
+
+            while should_listen.load(Ordering::Relaxed) {
+                tokio::select! {
+                    received = socket.recv_from(&mut buf) => match received {
+                        Ok((len, sender)) => listener.handle(&buf[..len], sender),
+                        Err(e) => {
+                            say!("❌ [{}] Background listener error: {}", client_id, e);
+                            events.emit(ClientEvent::Error {
+                                peer_id: None,
+                                message: format!("listener stopped: {}", e),
+                            });
+                            break;
+                        }
+                    },
+                    _ = ticks.tick() => {}
+                }
+                listener.tick();
+            }
+
+            say!("🔇 Background listener stopped for {}", client_id);
+        });
+    }
+
+    fn start_keepalive(&mut self) {
+        if self.client.keeping_alive {
+            return;
+        }
+        self.client.keeping_alive = true;
+        let mut keepalive = self.client.keepalive();
+        let should_listen = self.client.should_listen.clone();
+        tokio::spawn(async move {
+            let mut ticks = time::interval(KEEPALIVE_TICK);
+            while should_listen.load(Ordering::Relaxed) {
+                ticks.tick().await;
+                keepalive.tick();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::AsyncServer;
+
+    #[tokio::test]
+    async fn test_connect_on_one_thread() {
+        let mut server = AsyncServer::new("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut alice = AsyncClient::new("alice".to_string(), server_addr).unwrap();
+        let mut bob = AsyncClient::new("bob".to_string(), server_addr).unwrap();
+        alice.register().await.unwrap();
+        bob.register().await.unwrap();
+
+        let addr = alice.connect_to_peer("bob").await.unwrap();
+        assert_eq!(alice.peer_addr("bob"), Some(addr));
+        alice.send_message("bob", "hello").unwrap();
+        assert!(bob.peer_addr("alice").is_some());
+
+        assert!(matches!(
+            alice.connect_to_peer("nobody").await,
+            Err(ConnectError::PeerNotFound(_))
+        ));
+        alice.stop();
+        bob.stop();
+    }
+}
@@ -0,0 +1,174 @@
+//! Where `Server` and `Client` get the time for scheduling punches, so
+//! tests can skew or stop it.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for timers.
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time in milliseconds since the Unix epoch, as it goes in
+    /// `StartPunchWithPeer`.
+    fn unix_millis(&self) -> u64;
+}
+
+/// The operating system's clocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
+/// The system clock on a host whose wall clock is `offset_ms` off.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SkewedClock {
+    pub offset_ms: i64,
+}
+
+impl Clock for SkewedClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_millis(&self) -> u64 {
+        SystemClock.unix_millis().saturating_add_signed(self.offset_ms)
+    }
+}
+
+/// Time that stands still until `advance` moves it.
+pub struct ManualClock {
+    start: Instant,
+    unix_start: u64,
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// Starting at `unix_millis` on the wall clock.
+    pub fn new(unix_millis: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            unix_start: unix_millis,
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn unix_millis(&self) -> u64 {
+        self.unix_start + self.elapsed.lock().unwrap().as_millis() as u64
+    }
+}
+
+// how many exchanges `OffsetEstimator` picks its estimate from
+const OFFSET_SAMPLES: usize = 8;
+
+/// What one `TimeRequest` exchange with the server measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockOffset {
+    /// How far the server's wall clock is ahead of ours, in milliseconds.
+    pub offset_ms: i64,
+    /// Round trip without the time the server took to answer.
+    pub rtt: Duration,
+}
+
+impl ClockOffset {
+    /// NTP's arithmetic: we sent at `t0`, the server received at `t1` and
+    /// answered at `t2`, and we received at `t3`, each on its own clock.
+    pub fn from_exchange(t0: u64, t1: u64, t2: u64, t3: u64) -> Self {
+        let (t0, t1, t2, t3) = (t0 as i64, t1 as i64, t2 as i64, t3 as i64);
+        let rtt = (t3 - t0) - (t2 - t1);
+        Self {
+            offset_ms: ((t1 - t0) + (t2 - t3)) / 2,
+            rtt: Duration::from_millis(rtt.max(0) as u64),
+        }
+    }
+
+    /// `server_millis` on the server's wall clock, as a time on ours.
+    pub fn to_local(&self, server_millis: u64) -> u64 {
+        server_millis.saturating_add_signed(-self.offset_ms)
+    }
+}
+
+/// The last few exchanges. The one with the shortest round trip wins: its
+/// offset can be off by at most half of it, however lopsided the path.
+#[derive(Debug, Default)]
+pub struct OffsetEstimator {
+    samples: VecDeque<ClockOffset>,
+}
+
+impl OffsetEstimator {
+    pub fn add(&mut self, sample: ClockOffset) {
+        if self.samples.len() == OFFSET_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn estimate(&self) -> Option<ClockOffset> {
+        self.samples.iter().min_by_key(|s| s.rtt).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clocks() {
+        let clock = ManualClock::new(1_000_000);
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now() - start, Duration::from_millis(1500));
+        assert_eq!(clock.unix_millis(), 1_001_500);
+
+        let system = SystemClock.unix_millis();
+        let ahead = SkewedClock { offset_ms: 60_000 }.unix_millis();
+        let behind = SkewedClock { offset_ms: -60_000 }.unix_millis();
+        assert!((59_000..61_000).contains(&(ahead - system)));
+        assert!((59_000..61_000).contains(&(system - behind)));
+    }
+
+    #[test]
+    fn test_offset_estimate() {
+        // server 5s ahead, 40ms each way, 2ms to answer
+        let sample = ClockOffset::from_exchange(1_000, 6_040, 6_042, 1_082);
+        assert_eq!(sample.offset_ms, 5_000);
+        assert_eq!(sample.rtt, Duration::from_millis(80));
+        assert_eq!(sample.to_local(8_000), 3_000);
+
+        // 10ms out, 200ms back: off by half the asymmetry
+        let lopsided = ClockOffset::from_exchange(1_000, 6_010, 6_010, 1_210);
+        assert_eq!(lopsided.offset_ms, 4_905);
+
+        let mut estimator = OffsetEstimator::default();
+        assert_eq!(estimator.estimate(), None);
+        estimator.add(lopsided);
+        estimator.add(sample);
+        assert_eq!(estimator.estimate(), Some(sample));
+        for _ in 0..OFFSET_SAMPLES {
+            estimator.add(lopsided);
+        }
+        assert_eq!(estimator.estimate(), Some(lopsided));
+    }
+}
@@ -0,0 +1,128 @@
+//! What a client's background side reports to the application.
+
+use crate::transfer::FileOffer;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+
+/// Something that happened in the background of a `Client`, see
+/// `Client::on_event`.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The server told us where `peer_id` is registered.
+    PeerDiscovered { peer_id: String, addr: SocketAddr },
+    /// The first round of punches to `peer_id` went out.
+    PunchStarted { peer_id: String, candidates: usize },
+    /// Traffic to `peer_id` now goes to `addr`: a punch got through, a relay
+    /// was set up, or a path came back or moved.
+    Connected { peer_id: String, addr: SocketAddr },
+    MessageReceived { peer_id: String, message: String },
+    /// Waiting for `Client::accept_file` or `Client::reject_file`.
+    FileOffered(FileOffer),
+    Disconnected { peer_id: String, reason: String },
+    /// Something failed that no call is waiting to hear about, such as a
+    /// packet that didn't authenticate.
+    Error {
+        peer_id: Option<String>,
+        message: String,
+    },
+}
+
+// false once the handler wants no more events
+type Handler = Arc<dyn Fn(&ClientEvent) -> bool + Send + Sync>;
+
+/// The handlers a client calls with its events.
+#[derive(Default)]
+pub(crate) struct Events {
+    handlers: Mutex<Vec<Handler>>,
+}
+
+impl Events {
+    pub(crate) fn add(&self, handler: impl Fn(&ClientEvent) + Send + Sync + 'static) {
+        self.handlers.lock().unwrap().push(Arc::new(move |event: &ClientEvent| {
+            handler(event);
+            true
+        }));
+    }
+
+    /// A channel that receives every event from now on, until the
+    /// receiver is dropped.
+    pub(crate) fn channel(&self) -> mpsc::Receiver<ClientEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.handlers
+            .lock()
+            .unwrap()
+            .push(Arc::new(move |event: &ClientEvent| tx.send(event.clone()).is_ok()));
+        rx
+    }
+
+    pub(crate) fn emit(&self, event: ClientEvent) {
+        // not under the lock, so a handler may add another
+        let handlers = self.handlers.lock().unwrap().clone();
+        let done: Vec<Handler> = handlers.into_iter().filter(|handler| !handler(&event)).collect();
+        if !done.is_empty() {
+            self.handlers
+                .lock()
+                .unwrap()
+                .retain(|handler| !done.iter().any(|d| Arc::ptr_eq(d, handler)));
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.handlers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_every_handler_sees_every_event() {
+        let events = Arc::new(Events::default());
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        events.add(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let rx = events.channel();
+
+        let nested = events.clone();
+        events.add(move |event| {
+            if let ClientEvent::Disconnected { .. } = event {
+                nested.add(|_| {});
+            }
+        });
+
+        events.emit(ClientEvent::MessageReceived {
+            peer_id: "bob".to_string(),
+            message: "hi".to_string(),
+        });
+        events.emit(ClientEvent::Disconnected {
+            peer_id: "bob".to_string(),
+            reason: "closed".to_string(),
+        });
+
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+        let received: Vec<_> = rx.try_iter().collect();
+        assert!(matches!(
+            &received[..],
+            [ClientEvent::MessageReceived { message, .. }, ClientEvent::Disconnected { .. }] if message == "hi"
+        ));
+    }
+
+    #[test]
+    fn test_dropped_channel_is_removed() {
+        let events = Events::default();
+        let rx = events.channel();
+        events.add(|_| {});
+        drop(rx);
+        assert_eq!(events.len(), 2);
+        events.emit(ClientEvent::Disconnected {
+            peer_id: "bob".to_string(),
+            reason: "closed".to_string(),
+        });
+        assert_eq!(events.len(), 1);
+    }
+}
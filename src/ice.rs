@@ -0,0 +1,447 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// a pair nobody answered within this long is given up
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+// after the first success, how long higher-priority pairs get to catch up
+const NOMINATION_DELAY: Duration = Duration::from_millis(150);
+// the controlling side repeats a nomination until it is acknowledged
+const NOMINATION_RESEND: Duration = Duration::from_millis(200);
+const MAX_NOMINATIONS: u32 = 10;
+
+/// The `seq` of every `NOMINATE`, so its `P_PUNCH_ACK` can't be mistaken for
+/// the answer to an ordinary check (those count up from 0).
+pub(crate) const NOMINATION_SEQ: u32 = u32::MAX;
+
+/// Where a candidate address comes from, in RFC 8445 terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandidateKind {
+    /// An address of one of our own interfaces.
+    Host,
+    /// Learned from a connectivity check: the peer's packets came from an
+    /// address it never advertised, e.g. a fresh symmetric NAT mapping.
+    PeerReflexive,
+    /// Our NAT mapping as the signaling server sees it.
+    ServerReflexive,
+    /// A port on the signaling server's relay.
+    Relayed,
+}
+
+impl CandidateKind {
+    // RFC 8445 §5.1.2.2 recommended type preferences
+    fn type_preference(self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::PeerReflexive => 110,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Relayed => 0,
+        }
+    }
+}
+
+impl fmt::Display for CandidateKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            CandidateKind::Host => "host",
+            CandidateKind::PeerReflexive => "prflx",
+            CandidateKind::ServerReflexive => "srflx",
+            CandidateKind::Relayed => "relay",
+        };
+        f.write_str(s)
+    }
+}
+
+/// An address a peer might be reachable at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub addr: SocketAddr,
+    pub priority: u32,
+}
+
+impl Candidate {
+    /// `local_preference` orders candidates of the same kind, highest first.
+    pub fn new(kind: CandidateKind, addr: SocketAddr, local_preference: u16) -> Self {
+        // a single component, so the last term is always 256 - 1
+        let priority = (kind.type_preference() << 24) + ((local_preference as u32) << 8) + 255;
+        Self {
+            kind,
+            addr,
+            priority,
+        }
+    }
+}
+
+/// `host 192.168.1.10:40000 2130706431`, as in the candidate lists sent
+/// through the signaling server.
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.kind, self.addr, self.priority)
+    }
+}
+
+impl FromStr for Candidate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let mut parts = s.split(' ');
+        let kind = match parts.next() {
+            Some("host") => CandidateKind::Host,
+            Some("prflx") => CandidateKind::PeerReflexive,
+            Some("srflx") => CandidateKind::ServerReflexive,
+            Some("relay") => CandidateKind::Relayed,
+            _ => return Err(()),
+        };
+        let addr = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let priority = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(Self {
+            kind,
+            addr,
+            priority,
+        })
+    }
+}
+
+pub(crate) fn kind_from_code(code: u8) -> Option<CandidateKind> {
+    match code {
+        0 => Some(CandidateKind::Host),
+        1 => Some(CandidateKind::PeerReflexive),
+        2 => Some(CandidateKind::ServerReflexive),
+        3 => Some(CandidateKind::Relayed),
+        _ => None,
+    }
+}
+
+pub(crate) fn kind_code(kind: CandidateKind) -> u8 {
+    match kind {
+        CandidateKind::Host => 0,
+        CandidateKind::PeerReflexive => 1,
+        CandidateKind::ServerReflexive => 2,
+        CandidateKind::Relayed => 3,
+    }
+}
+
+/// Host candidates for a socket bound to every interface on `port`: one
+/// per IPv4 interface that is up, skipping loopback and link-local, which
+/// no peer could reach.
+pub fn gather_host(port: u16) -> Vec<Candidate> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            say!("❌ Could not list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    interfaces
+        .iter()
+        .filter_map(|iface| match iface.ip() {
+            IpAddr::V4(ip) if !ip.is_loopback() && !ip.is_link_local() => Some(ip),
+            _ => None,
+        })
+        .enumerate()
+        .map(|(i, ip)| {
+            let preference = u16::MAX - i.min(u16::MAX as usize) as u16;
+            Candidate::new(CandidateKind::Host, SocketAddr::new(IpAddr::V4(ip), port), preference)
+        })
+        .collect()
+}
+
+/// RFC 8445 §6.1.2.3: the same for both sides of a pair, given which one
+/// is controlling.
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (controlling as u64, controlled as u64);
+    (g.min(d) << 32) + 2 * g.max(d) + (g > d) as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CheckState {
+    Waiting,
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug)]
+struct Pair {
+    remote: Candidate,
+    priority: u64,
+    state: CheckState,
+}
+
+/// Connectivity checks towards one peer, ICE-style but with a single local
+/// socket: every local candidate shares its base, so the pairs reduce to
+/// our best local candidate against each remote one (RFC 8445 §6.1.2.4
+/// pruning). Checks are the existing `P_PUNCH`/`P_PUNCH_ACK` exchange.
+///
+/// The side with the smaller id controls: once checks settle it nominates
+/// the best working pair with `NOMINATE`, and both sides use that path.
+/// Until then, traffic goes to the best pair that worked so far.
+#[derive(Debug)]
+pub(crate) struct CheckList {
+    controlling: bool,
+    local_priority: u32,
+    // highest priority first
+    pairs: Vec<Pair>,
+    started: Instant,
+    first_success: Option<Instant>,
+    nominated: Option<SocketAddr>,
+    confirmed: bool,
+    last_nomination: Option<Instant>,
+    nominations: u32,
+}
+
+impl CheckList {
+    /// Checks against `remote` starting `now`.
+    pub(crate) fn new(local: &[Candidate], remote: &[Candidate], controlling: bool, now: Instant) -> Self {
+        let local_priority = local.iter().map(|c| c.priority).max().unwrap_or(0);
+        let mut list = Self {
+            controlling,
+            local_priority,
+            pairs: Vec::new(),
+            started: now,
+            first_success: None,
+            nominated: None,
+            confirmed: false,
+            last_nomination: None,
+            nominations: 0,
+        };
+        for candidate in remote {
+            list.add(*candidate, CheckState::Waiting);
+        }
+        list
+    }
+
+    // adds a pair unless one for that address exists; keeps the order
+    fn add(&mut self, remote: Candidate, state: CheckState) -> usize {
+        if let Some(i) = self.pairs.iter().position(|p| p.remote.addr == remote.addr) {
+            return i;
+        }
+        let priority = if self.controlling {
+            pair_priority(self.local_priority, remote.priority)
+        } else {
+            pair_priority(remote.priority, self.local_priority)
+        };
+        let i = self.pairs.partition_point(|p| p.priority >= priority);
+        self.pairs.insert(
+            i,
+            Pair {
+                remote,
+                priority,
+                state,
+            },
+        );
+        i
+    }
+
+    /// Addresses to send the next round of checks to, best first.
+    pub(crate) fn targets(&mut self) -> Vec<SocketAddr> {
+        self.pairs
+            .iter_mut()
+            .filter(|p| matches!(p.state, CheckState::Waiting | CheckState::InProgress))
+            .map(|p| {
+                p.state = CheckState::InProgress;
+                p.remote.addr
+            })
+            .collect()
+    }
+
+    /// A check from the peer reached us from `from`. An address it never
+    /// advertised becomes peer-reflexive. Returns whether to send a
+    /// triggered check back there (RFC 8445 §7.3.1.4): an unauthenticated
+    /// packet proves nothing, so the pair only works once our own check to
+    /// it is answered.
+    pub(crate) fn on_request(&mut self, from: SocketAddr) -> bool {
+        let candidate = Candidate::new(CandidateKind::PeerReflexive, from, 0);
+        let i = self.add(candidate, CheckState::Waiting);
+        let pair = &mut self.pairs[i];
+        if pair.state == CheckState::Succeeded {
+            return false;
+        }
+        pair.state = CheckState::InProgress;
+        true
+    }
+
+    /// The peer answered our check to `from`.
+    pub(crate) fn on_response(&mut self, from: SocketAddr, now: Instant) {
+        if let Some(i) = self.pairs.iter().position(|p| p.remote.addr == from) {
+            self.pairs[i].state = CheckState::Succeeded;
+            self.first_success.get_or_insert(now);
+        }
+    }
+
+    /// Whether we sent checks to `addr`, i.e. whether a `P_PUNCH_ACK` from
+    /// there can be an answer.
+    pub(crate) fn is_checking(&self, addr: SocketAddr) -> bool {
+        self.pairs
+            .iter()
+            .any(|p| p.remote.addr == addr && p.state != CheckState::Waiting)
+    }
+
+    /// The peer acknowledged our nomination of `from`.
+    pub(crate) fn on_nomination_ack(&mut self, from: SocketAddr) {
+        if self.nominated == Some(from) {
+            self.confirmed = true;
+        }
+    }
+
+    /// Controlled side: accept a nomination that came from `from` if that
+    /// pair is known to work.
+    pub(crate) fn on_nominate(&mut self, from: SocketAddr) -> bool {
+        let works = self
+            .pairs
+            .iter()
+            .any(|p| p.remote.addr == from && p.state == CheckState::Succeeded);
+        if works && !self.controlling {
+            self.nominated = Some(from);
+            self.confirmed = true;
+        }
+        works && !self.controlling
+    }
+
+    /// Controlling side: where to send a `NOMINATE` now, if anywhere. The
+    /// first call that returns `Some` picks the pair; later ones repeat it
+    /// until the peer acknowledges.
+    pub(crate) fn due_nomination(&mut self, now: Instant) -> Option<SocketAddr> {
+        if !self.controlling || self.confirmed || self.nominations >= MAX_NOMINATIONS {
+            return None;
+        }
+        if self.nominated.is_none() {
+            let settled = self
+                .pairs
+                .iter()
+                .all(|p| matches!(p.state, CheckState::Succeeded | CheckState::Failed));
+            let waited = self
+                .first_success
+                .is_some_and(|t| now.duration_since(t) >= NOMINATION_DELAY);
+            if !(settled || waited) {
+                return None;
+            }
+            self.nominated = Some(self.best()?);
+        } else if self
+            .last_nomination
+            .is_some_and(|t| now.duration_since(t) < NOMINATION_RESEND)
+        {
+            return None;
+        }
+        self.last_nomination = Some(now);
+        self.nominations += 1;
+        self.nominated
+    }
+
+    /// Give up on pairs that never answered.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        if now.duration_since(self.started) < CHECK_TIMEOUT {
+            return;
+        }
+        for pair in &mut self.pairs {
+            if pair.state != CheckState::Succeeded {
+                pair.state = CheckState::Failed;
+            }
+        }
+    }
+
+    /// The highest-priority pair that works.
+    pub(crate) fn best(&self) -> Option<SocketAddr> {
+        self.pairs
+            .iter()
+            .find(|p| p.state == CheckState::Succeeded)
+            .map(|p| p.remote.addr)
+    }
+
+    /// The nominated path, else the best one so far.
+    pub(crate) fn selected(&self) -> Option<SocketAddr> {
+        self.nominated.or_else(|| self.best())
+    }
+
+    pub(crate) fn kind_of(&self, addr: SocketAddr) -> Option<CandidateKind> {
+        self.pairs
+            .iter()
+            .find(|p| p.remote.addr == addr)
+            .map(|p| p.remote.kind)
+    }
+
+    pub(crate) fn is_nominated(&self) -> bool {
+        self.nominated.is_some() && self.confirmed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_priorities() {
+        let host = Candidate::new(CandidateKind::Host, addr("192.168.1.10:5000"), 65535);
+        let srflx = Candidate::new(CandidateKind::ServerReflexive, addr("203.0.113.7:6000"), 65535);
+        let relay = Candidate::new(CandidateKind::Relayed, addr("198.51.100.1:7000"), 65535);
+        assert_eq!(host.priority, 2130706431);
+        assert!(host.priority > srflx.priority && srflx.priority > relay.priority);
+
+        // symmetric in the candidates, tie broken by who controls
+        assert_eq!(pair_priority(10, 20) + 1, pair_priority(20, 10));
+        assert!(pair_priority(host.priority, host.priority) > pair_priority(host.priority, srflx.priority));
+
+        for c in [host, srflx, relay] {
+            assert_eq!(c.to_string().parse::<Candidate>(), Ok(c));
+        }
+        assert!("host 1.2.3.4:5".parse::<Candidate>().is_err());
+        assert!("lan 1.2.3.4:5 1".parse::<Candidate>().is_err());
+    }
+
+    #[test]
+    fn test_check_list() {
+        let local = [Candidate::new(CandidateKind::Host, addr("10.0.0.2:5000"), 65535)];
+        let remote = [
+            Candidate::new(CandidateKind::ServerReflexive, addr("203.0.113.7:6000"), 65535),
+            Candidate::new(CandidateKind::Host, addr("10.0.0.3:6000"), 65535),
+        ];
+        let start = Instant::now();
+        let mut list = CheckList::new(&local, &remote, true, start);
+        assert_eq!(list.targets(), [addr("10.0.0.3:6000"), addr("203.0.113.7:6000")]);
+
+        // the public path answers first; the LAN one is still worth a wait
+        list.on_response(addr("203.0.113.7:6000"), start);
+        assert_eq!(list.selected(), Some(addr("203.0.113.7:6000")));
+        list.on_response(addr("10.0.0.3:6000"), start);
+        assert_eq!(list.due_nomination(start), Some(addr("10.0.0.3:6000")));
+        assert_eq!(list.due_nomination(start), None);
+        assert_eq!(list.due_nomination(start + NOMINATION_RESEND), Some(addr("10.0.0.3:6000")));
+        // a late answer to an ordinary check is not the nomination's ack
+        list.on_response(addr("10.0.0.3:6000"), start);
+        assert!(!list.is_nominated());
+        list.on_nomination_ack(addr("10.0.0.3:6000"));
+        assert!(list.is_nominated());
+        assert_eq!(list.due_nomination(start + NOMINATION_RESEND * 2), None);
+
+        // controlled: only working pairs can be nominated; a check from an
+        // unknown address adds a peer-reflexive pair, which works once our
+        // triggered check to it is answered
+        let mut list = CheckList::new(&local, &remote, false, start);
+        assert!(!list.on_nominate(addr("10.0.0.3:6000")));
+        assert!(list.on_request(addr("198.51.100.9:7000")));
+        assert_eq!(list.kind_of(addr("198.51.100.9:7000")), Some(CandidateKind::PeerReflexive));
+        assert!(list.is_checking(addr("198.51.100.9:7000")));
+        assert_eq!(list.best(), None);
+        assert!(!list.on_nominate(addr("198.51.100.9:7000")));
+        list.on_response(addr("198.51.100.9:7000"), start);
+        assert!(!list.on_request(addr("198.51.100.9:7000")));
+        assert_eq!(list.due_nomination(start), None);
+        assert!(list.on_nominate(addr("198.51.100.9:7000")));
+        assert_eq!(list.selected(), Some(addr("198.51.100.9:7000")));
+
+        let mut list = CheckList::new(&local, &remote, true, start);
+        list.targets();
+        list.expire(start + CHECK_TIMEOUT);
+        assert_eq!(list.due_nomination(start + CHECK_TIMEOUT), None);
+        assert_eq!(list.best(), None);
+    }
+}
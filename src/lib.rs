@@ -1,4 +1,26 @@
+// The library's running commentary: printed only once `logger::set_verbose`
+// turned it on. Reports an application asks for print regardless.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::logger::verbose() {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub mod auth;
 pub mod client;
+pub mod clock;
+pub mod event;
+pub mod ice;
 pub mod logger;
+pub mod nat;
 pub mod protocol;
+pub mod secure;
 pub mod server;
+pub mod sim;
+pub mod spray;
+pub mod stream;
+pub mod transfer;
+pub mod transport;
+pub mod stun;
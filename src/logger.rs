@@ -1,7 +1,25 @@
+use crate::clock::ClockOffset;
+use crate::nat::NatType;
+use crate::spray::Strategy;
 use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Print what clients and servers do as they go: punches, handshakes,
+/// relays, transfers. Off by default, so an application only shows what it
+/// prints itself, e.g. from `ClientEvent`s. The `print_*` reports print
+/// either way.
+pub fn set_verbose(on: bool) {
+    VERBOSE.store(on, Ordering::Relaxed);
+}
+
+pub(crate) fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone)]
 pub struct NatTraversalStats {
@@ -18,15 +36,19 @@ pub struct NatTraversalStats {
     pub total_latency_ms: u64,
     pub connection_state: ConnectionState,
     pub error_count: u32,
+    pub file_bytes_sent: u64,
+    pub file_bytes_received: u64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConnectionState {
     Discovering,
     HolePunching,
     Connected,
     Failed,
     Disconnected,
+    /// Punching failed; traffic goes through the server's relay.
+    Relayed,
 }
 
 impl fmt::Display for ConnectionState {
@@ -36,7 +58,8 @@ impl fmt::Display for ConnectionState {
             ConnectionState::HolePunching => write!(f, "PUNCH"),
             ConnectionState::Connected => write!(f, "CONN"),
             ConnectionState::Failed => write!(f, "FAIL"),
-            ConnectionState::Disconnected => write!(f, "DISC"),
+            ConnectionState::Disconnected => write!(f, "LOST"),
+            ConnectionState::Relayed => write!(f, "RELAY"),
         }
     }
 }
@@ -46,6 +69,14 @@ pub struct NatConsoleLogger {
     start_time: Instant,
     local_addr: SocketAddr,
     external_addr: Option<SocketAddr>,
+    nat_type: Option<NatType>,
+    clock_offset: Option<ClockOffset>,
+    // peer packets dropped because they failed authentication
+    auth_failures: u32,
+    // last 10% step reported per (peer, file) transfer
+    transfer_progress: HashMap<(String, String), u64>,
+    // (successes, failures) per punch strategy
+    strategies: HashMap<Strategy, (u32, u32)>,
 }
 
 impl NatConsoleLogger {
@@ -55,6 +86,11 @@ impl NatConsoleLogger {
             start_time: Instant::now(),
             local_addr,
             external_addr: None,
+            nat_type: None,
+            clock_offset: None,
+            auth_failures: 0,
+            transfer_progress: HashMap::new(),
+            strategies: HashMap::new(),
         }
     }
 
@@ -62,6 +98,15 @@ impl NatConsoleLogger {
         self.external_addr = Some(external_addr);
     }
 
+    pub fn set_nat_type(&mut self, nat_type: NatType) {
+        self.nat_type = Some(nat_type);
+    }
+
+    /// How far the signaling server's clock is ahead of ours.
+    pub fn set_clock_offset(&mut self, offset: Option<ClockOffset>) {
+        self.clock_offset = offset;
+    }
+
     pub fn log_peer_discovery(&mut self, peer_id: String, peer_addr: Option<SocketAddr>) {
         let entry = self
             .stats
@@ -80,12 +125,14 @@ impl NatConsoleLogger {
                 total_latency_ms: 0,
                 connection_state: ConnectionState::Discovering,
                 error_count: 0,
+                file_bytes_sent: 0,
+                file_bytes_received: 0,
             });
 
         entry.peer_addr = peer_addr;
         entry.connection_state = ConnectionState::Discovering;
 
-        println!("🔍 Discovered peer: {} at {:?}", peer_id, peer_addr);
+        say!("🔍 Discovered peer: {} at {:?}", peer_id, peer_addr);
     }
 
     pub fn log_hole_punch_attempt(&mut self, peer_id: &str) {
@@ -95,7 +142,7 @@ impl NatConsoleLogger {
             stats.connection_state = ConnectionState::HolePunching;
         }
 
-        println!(
+        say!(
             "🕳️  Hole punch attempt #{} to peer: {}",
             self.stats
                 .get(peer_id)
@@ -105,7 +152,7 @@ impl NatConsoleLogger {
         );
     }
 
-    /// Called when we receive any punch-related traffic (PUNCH: or PUNCH_ACK:)
+    /// Called when we receive any punch-related traffic (P_PUNCH or P_PUNCH_ACK)
     /// Only marks connection as successful on the FIRST packet, subsequent calls are just traffic
     pub fn log_punch_traffic(&mut self, peer_id: &str, latency_ms: u64, packet_type: &str) {
         if let Some(stats) = self.stats.get_mut(peer_id) {
@@ -117,13 +164,13 @@ impl NatConsoleLogger {
                 stats.traversal_success = true;
                 stats.connection_state = ConnectionState::Connected;
                 
-                println!(
+                say!(
                     "✅ HOLE PUNCH SUCCESS for peer: {} ({}ms) via {} - CONNECTION ESTABLISHED!",
                     peer_id, latency_ms, packet_type
                 );
             } else {
                 // Connection already established, this is just ongoing traffic
-                println!(
+                say!(
                     "🔄 Punch traffic from peer: {} ({}ms) via {} - already connected",
                     peer_id, latency_ms, packet_type
                 );
@@ -140,7 +187,7 @@ impl NatConsoleLogger {
             stats.error_count += 1;
         }
 
-        println!("❌ Hole punch FAILED for peer: {}", peer_id);
+        say!("❌ Hole punch FAILED for peer: {}", peer_id);
     }
 
     pub fn log_direct_message_sent(&mut self, peer_id: &str, message: &str) {
@@ -148,7 +195,7 @@ impl NatConsoleLogger {
             stats.direct_messages_sent += 1;
         }
 
-        println!("📤 Direct message sent to {}: {}", peer_id, message);
+        say!("📤 Direct message sent to {}: {}", peer_id, message);
     }
 
     pub fn log_direct_message_received(
@@ -161,7 +208,7 @@ impl NatConsoleLogger {
             stats.direct_messages_received += 1;
         }
 
-        println!(
+        say!(
             "📥 Direct message from {} ({}): {}",
             peer_id, sender_addr, message
         );
@@ -173,7 +220,138 @@ impl NatConsoleLogger {
             stats.error_count += 1;
         }
 
-        println!("🔥 Connection failed to {}: {}", peer_id, error);
+        say!("🔥 Connection failed to {}: {}", peer_id, error);
+    }
+
+    /// The peer stopped answering keepalives; its NAT mapping is likely gone.
+    pub fn log_peer_disconnected(&mut self, peer_id: &str, silent_for: Duration) {
+        if let Some(stats) = self.stats.get_mut(peer_id) {
+            stats.connection_state = ConnectionState::Disconnected;
+        }
+
+        say!(
+            "🔌 {} went silent for {:.0}s, marking disconnected",
+            peer_id,
+            silent_for.as_secs_f64()
+        );
+    }
+
+    /// Direct punching gave up; the peer is reachable through the relay.
+    pub fn log_relayed(&mut self, peer_id: &str, relay_addr: SocketAddr) {
+        if let Some(stats) = self.stats.get_mut(peer_id) {
+            stats.connection_state = ConnectionState::Relayed;
+            stats.peer_addr = Some(relay_addr);
+        }
+
+        say!("🔁 Relaying traffic for {} through {}", peer_id, relay_addr);
+    }
+
+    pub fn log_peer_reconnected(&mut self, peer_id: &str) {
+        if let Some(stats) = self.stats.get_mut(peer_id) {
+            stats.connection_state = ConnectionState::Connected;
+        }
+
+        say!("🔗 {} is answering again", peer_id);
+    }
+
+    /// A peer packet was dropped because it failed authentication: a bad
+    /// handshake, a forged or replayed sealed packet, or plaintext where
+    /// only sealed traffic is allowed.
+    pub fn log_auth_failure(&mut self, peer_id: &str, sender_addr: SocketAddr, reason: &str) {
+        self.auth_failures += 1;
+        if let Some(stats) = self.stats.get_mut(peer_id) {
+            stats.error_count += 1;
+        }
+
+        say!(
+            "🛡️  Dropped packet from {} ({}): {}",
+            peer_id, sender_addr, reason
+        );
+    }
+
+    pub fn auth_failures(&self) -> u32 {
+        self.auth_failures
+    }
+
+    /// Report file transfer progress; prints at every 10% step.
+    pub fn log_transfer_progress(&mut self, peer_id: &str, name: &str, done: u64, total: u64) {
+        let step = (done * 10).checked_div(total).unwrap_or(10);
+        let last = self
+            .transfer_progress
+            .entry((peer_id.to_string(), name.to_string()))
+            .or_insert(u64::MAX);
+        if *last == step {
+            return;
+        }
+        *last = step;
+
+        say!(
+            "📦 {} ⇄ {}: {:>3}% ({}/{} bytes)",
+            name,
+            peer_id,
+            step * 10,
+            done,
+            total
+        );
+    }
+
+    /// `bytes` is what actually crossed the wire, less than the file size
+    /// when the transfer resumed.
+    pub fn log_transfer_complete(
+        &mut self,
+        peer_id: &str,
+        name: &str,
+        bytes: u64,
+        sent: bool,
+        elapsed: Duration,
+    ) {
+        self.transfer_progress
+            .remove(&(peer_id.to_string(), name.to_string()));
+        if let Some(stats) = self.stats.get_mut(peer_id) {
+            if sent {
+                stats.file_bytes_sent += bytes;
+            } else {
+                stats.file_bytes_received += bytes;
+            }
+        }
+
+        let secs = elapsed.as_secs_f64().max(0.001);
+        say!(
+            "✅ {} {} {} verified: {} bytes in {:.1}s ({:.1} KiB/s)",
+            name,
+            if sent { "to" } else { "from" },
+            peer_id,
+            bytes,
+            secs,
+            bytes as f64 / 1024.0 / secs
+        );
+    }
+
+    pub fn log_transfer_failed(&mut self, peer_id: &str, name: &str, error: &str) {
+        self.transfer_progress
+            .remove(&(peer_id.to_string(), name.to_string()));
+        if let Some(stats) = self.stats.get_mut(peer_id) {
+            stats.error_count += 1;
+        }
+
+        say!("❌ Transfer of {} with {} failed: {}", name, peer_id, error);
+    }
+
+    /// Whether `strategy` got a punch to `peer_id` through.
+    pub fn log_punch_strategy(&mut self, peer_id: &str, strategy: Strategy, success: bool) {
+        let (successes, failures) = self.strategies.entry(strategy).or_default();
+        if success {
+            *successes += 1;
+            say!("🎯 Punch to {} by {}: got through", peer_id, strategy);
+        } else {
+            *failures += 1;
+            say!("🧱 Punch to {} by {}: blocked", peer_id, strategy);
+        }
+    }
+
+    /// `(successes, failures)` of `strategy` so far.
+    pub fn strategy_results(&self, strategy: Strategy) -> (u32, u32) {
+        self.strategies.get(&strategy).copied().unwrap_or_default()
     }
 
     // 🔧 DEPRECATED: backward compatibility
@@ -197,6 +375,19 @@ impl NatConsoleLogger {
             None => println!("│ External Address│ {:<27} │", "Not yet discovered"),
         }
 
+        match self.nat_type {
+            Some(nat_type) => println!("│ NAT Type        │ {:<27} │", nat_type.to_string()),
+            None => println!("│ NAT Type        │ {:<27} │", "Not yet detected"),
+        }
+
+        match self.clock_offset {
+            Some(offset) => {
+                let value = format!("{:+} ms (rtt {} ms)", offset.offset_ms, offset.rtt.as_millis());
+                println!("│ Server Clock    │ {:<27} │", value)
+            }
+            None => println!("│ Server Clock    │ {:<27} │", "Not yet measured"),
+        }
+
         println!("└─────────────────┴─────────────────────────────┘");
     }
 
@@ -284,7 +475,12 @@ impl NatConsoleLogger {
         let connected_peers: Vec<_> = self
             .stats
             .iter()
-            .filter(|(_, stats)| matches!(stats.connection_state, ConnectionState::Connected))
+            .filter(|(_, stats)| {
+                matches!(
+                    stats.connection_state,
+                    ConnectionState::Connected | ConnectionState::Relayed
+                )
+            })
             .collect();
 
         if connected_peers.is_empty() {
@@ -295,13 +491,13 @@ impl NatConsoleLogger {
         self.print_section_header("Direct P2P Message Statistics");
 
         println!(
-            "┌────────────────┬──────────────────────┬─────────────┬─────────────┬─────────────┐"
+            "┌────────────────┬──────────────────────┬─────────────┬─────────────┬─────────────┬───────────────────────┐"
         );
         println!(
-            "│ Peer ID        │ Peer Address         │ Msgs Sent  │ Msgs Recv  │ Total Msgs  │"
+            "│ Peer ID        │ Peer Address         │ Msgs Sent  │ Msgs Recv  │ Total Msgs  │ File Bytes ↑/↓        │"
         );
         println!(
-            "├────────────────┼──────────────────────┼─────────────┼─────────────┼─────────────┤"
+            "├────────────────┼──────────────────────┼─────────────┼─────────────┼─────────────┼───────────────────────┤"
         );
 
         for (peer_id, stats) in connected_peers {
@@ -312,28 +508,26 @@ impl NatConsoleLogger {
 
             let total_messages = stats.direct_messages_sent + stats.direct_messages_received;
 
+            let file_bytes = format!("{}/{}", stats.file_bytes_sent, stats.file_bytes_received);
+
             println!(
-                "│ {:<14} │ {:<20} │ {:<11} │ {:<11} │ {:<11} │",
+                "│ {:<14} │ {:<20} │ {:<11} │ {:<11} │ {:<11} │ {:<21} │",
                 truncate_string(peer_id, 14),
                 truncate_string(&peer_addr_str, 20),
                 stats.direct_messages_sent,
                 stats.direct_messages_received,
-                total_messages
+                total_messages,
+                truncate_string(&file_bytes, 21)
             );
         }
 
         println!(
-            "└────────────────┴──────────────────────┴─────────────┴─────────────┴─────────────┘"
+            "└────────────────┴──────────────────────┴─────────────┴─────────────┴─────────────┴───────────────────────┘"
         );
     }
 
     fn print_summary_stats(&self) {
         let total_peers = self.stats.len();
-        let successful_connections = self
-            .stats
-            .values()
-            .filter(|stats| stats.traversal_success)
-            .count();
         let total_attempts = self
             .stats
             .values()
@@ -359,13 +553,20 @@ impl NatConsoleLogger {
         let elapsed = self.start_time.elapsed();
 
         println!("┌─────────────────────────────────────────────────────────────────────────────────────────────────────────────────┐");
-        println!("│ SUMMARY: {} total peers | {:.1}% hole punch success | {} total messages | {}ms elapsed │",
+        println!("│ SUMMARY: {} total peers | {:.1}% hole punch success | {} total messages | {} dropped | {}ms elapsed │",
             total_peers,
             success_rate,
             total_messages,
+            self.auth_failures,
             elapsed.as_millis()
         );
         println!("└─────────────────────────────────────────────────────────────────────────────────────────────────────────────────┘");
+
+        let mut strategies: Vec<_> = self.strategies.iter().collect();
+        strategies.sort();
+        for (strategy, (successes, failures)) in strategies {
+            println!("   {}: {} through, {} blocked", strategy, successes, failures);
+        }
     }
 
     fn print_section_header(&self, title: &str) {
@@ -391,7 +592,8 @@ impl NatConsoleLogger {
         }
     }
 
-    // Method to print live updates 
+    // One line on where `peer_id` stands, only when verbose: it follows
+    // every punch and message.
     pub fn print_live_update(&self, peer_id: &str) {
         if let Some(stats) = self.stats.get(peer_id) {
             let status_char = match stats.connection_state {
@@ -400,9 +602,10 @@ impl NatConsoleLogger {
                 ConnectionState::Connected => "✅",
                 ConnectionState::Failed => "❌",
                 ConnectionState::Disconnected => "🔌",
+                ConnectionState::Relayed => "🔁",
             };
 
-            println!(
+            say!(
                 "{} {} │ attempts: {} │ success: {} │ msgs: {}↑/{}↓ │",
                 status_char,
                 truncate_string(peer_id, 12),
@@ -448,7 +651,7 @@ pub trait NatLoggable {
         if success {
             if let Some(latency) = latency_ms {
                 self.get_console_logger()
-                    .log_hole_punch_success(peer_id, latency);
+                    .log_punch_traffic(peer_id, latency, "legacy");
             }
         } else {
             self.get_console_logger().log_hole_punch_failure(peer_id);
@@ -471,6 +674,7 @@ pub trait NatLoggable {
 }
 
 #[cfg(test)]
+#[allow(deprecated)]
 mod tests {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr};
@@ -510,6 +714,68 @@ mod tests {
         assert!(stats.traversal_success);
     }
 
+    #[test]
+    fn test_auth_failure_logging() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), 5000);
+        let mut logger = NatConsoleLogger::new(local_addr);
+
+        logger.log_peer_discovery("bob".to_string(), None);
+        logger.log_auth_failure("bob", local_addr, "replayed nonce 3");
+        logger.log_auth_failure("mallory", local_addr, "unencrypted MSG");
+
+        assert_eq!(logger.auth_failures(), 2);
+        assert_eq!(logger.stats["bob"].error_count, 1);
+        assert!(!logger.stats.contains_key("mallory"));
+    }
+
+    #[test]
+    fn test_disconnect_logging() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), 5000);
+        let mut logger = NatConsoleLogger::new(local_addr);
+
+        logger.log_peer_discovery("bob".to_string(), None);
+        logger.log_hole_punch_success("bob", 20);
+        logger.log_peer_disconnected("bob", Duration::from_secs(45));
+        assert_eq!(logger.stats["bob"].connection_state, ConnectionState::Disconnected);
+        logger.log_peer_reconnected("bob");
+        assert_eq!(logger.stats["bob"].connection_state, ConnectionState::Connected);
+
+        logger.log_relayed("bob", local_addr);
+        assert_eq!(logger.stats["bob"].connection_state, ConnectionState::Relayed);
+        assert_eq!(logger.stats["bob"].peer_addr, Some(local_addr));
+    }
+
+    #[test]
+    fn test_strategy_logging() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), 5000);
+        let mut logger = NatConsoleLogger::new(local_addr);
+
+        logger.log_punch_strategy("bob", Strategy::Candidates, false);
+        logger.log_punch_strategy("bob", Strategy::PortPrediction, true);
+        logger.log_punch_strategy("carol", Strategy::Candidates, true);
+        assert_eq!(logger.strategy_results(Strategy::Candidates), (1, 1));
+        assert_eq!(logger.strategy_results(Strategy::PortPrediction), (1, 0));
+        assert_eq!(logger.strategy_results(Strategy::Birthday), (0, 0));
+    }
+
+    #[test]
+    fn test_transfer_logging() {
+        let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), 5000);
+        let mut logger = NatConsoleLogger::new(local_addr);
+
+        logger.log_peer_discovery("bob".to_string(), None);
+        logger.log_transfer_progress("bob", "a.bin", 5, 100);
+        logger.log_transfer_progress("bob", "a.bin", 15, 100);
+        let key = ("bob".to_string(), "a.bin".to_string());
+        assert_eq!(logger.transfer_progress[&key], 1);
+        logger.log_transfer_complete("bob", "a.bin", 60, true, Duration::from_secs(1));
+        logger.log_transfer_complete("bob", "b.bin", 7, false, Duration::from_secs(1));
+
+        assert!(logger.transfer_progress.is_empty());
+        assert_eq!(logger.stats["bob"].file_bytes_sent, 60);
+        assert_eq!(logger.stats["bob"].file_bytes_received, 7);
+    }
+
     pub fn demo_traversal_tables() {
         println!("🚀 NAT Traversal Console Logger Demo");
         println!("=====================================\n");
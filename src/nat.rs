@@ -0,0 +1,113 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+/// NAT behavior in the classic RFC 3489 vocabulary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatType {
+    /// Mapped address equals the local address.
+    OpenInternet,
+    /// Endpoint-independent mapping and filtering.
+    FullCone,
+    /// Endpoint-independent mapping, address-dependent filtering.
+    Restricted,
+    /// Endpoint-independent mapping, address- and port-dependent filtering.
+    PortRestricted,
+    /// A new mapping per destination.
+    Symmetric,
+    /// The server could not run every test (e.g. no alternate address).
+    Unknown,
+}
+
+impl NatType {
+    /// Whether simultaneous hole punching between the two can be expected to
+    /// work. A symmetric NAT only pairs with peers that accept packets from
+    /// any source port, since its own mapped port is unpredictable.
+    pub fn can_punch_with(&self, other: &NatType) -> bool {
+        use NatType::*;
+        match (self, other) {
+            (Unknown, _) | (_, Unknown) => true,
+            (Symmetric, Symmetric) => false,
+            (Symmetric, PortRestricted) | (PortRestricted, Symmetric) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            NatType::OpenInternet => "Open Internet",
+            NatType::FullCone => "Full Cone",
+            NatType::Restricted => "Restricted Cone",
+            NatType::PortRestricted => "Port Restricted Cone",
+            NatType::Symmetric => "Symmetric",
+            NatType::Unknown => "Unknown",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Raw results of the behavior tests run by `Client::detect_nat_type`.
+/// `None` in a filtering field means the server could not run that test.
+#[derive(Debug, Clone, Copy)]
+pub struct BehaviorTests {
+    /// Our address on the path to the server.
+    pub local_addr: SocketAddr,
+    /// Mapped address reported by the primary server address.
+    pub mapped_primary: SocketAddr,
+    /// Mapped address reported by the alternate server address.
+    pub mapped_alternate: SocketAddr,
+    /// Did an answer from a different IP and port reach us?
+    pub reached_from_other_ip: Option<bool>,
+    /// Did an answer from the same IP but a different port reach us?
+    pub reached_from_other_port: Option<bool>,
+}
+
+pub fn classify(t: &BehaviorTests) -> NatType {
+    if t.mapped_primary == t.local_addr {
+        return NatType::OpenInternet;
+    }
+    if t.mapped_primary != t.mapped_alternate {
+        return NatType::Symmetric;
+    }
+    match (t.reached_from_other_ip, t.reached_from_other_port) {
+        (Some(true), _) => NatType::FullCone,
+        (_, Some(true)) => NatType::Restricted,
+        (Some(false), Some(false)) => NatType::PortRestricted,
+        // same IP alternates can still prove port-restricted filtering
+        (None, Some(false)) => NatType::PortRestricted,
+        _ => NatType::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tests(mapped_alternate: &str, other_ip: Option<bool>, other_port: Option<bool>) -> BehaviorTests {
+        BehaviorTests {
+            local_addr: "192.168.1.3:5000".parse().unwrap(),
+            mapped_primary: "10.0.0.3:5000".parse().unwrap(),
+            mapped_alternate: mapped_alternate.parse().unwrap(),
+            reached_from_other_ip: other_ip,
+            reached_from_other_port: other_port,
+        }
+    }
+
+    #[test]
+    fn test_classification() {
+        let same = "10.0.0.3:5000";
+        assert_eq!(classify(&tests(same, Some(true), Some(true))), NatType::FullCone);
+        assert_eq!(classify(&tests(same, Some(false), Some(true))), NatType::Restricted);
+        assert_eq!(classify(&tests(same, Some(false), Some(false))), NatType::PortRestricted);
+        assert_eq!(classify(&tests(same, None, None)), NatType::Unknown);
+        assert_eq!(classify(&tests("10.0.0.3:5001", Some(false), Some(false))), NatType::Symmetric);
+
+        let mut open = tests(same, None, None);
+        open.local_addr = open.mapped_primary;
+        assert_eq!(classify(&open), NatType::OpenInternet);
+
+        assert!(!NatType::Symmetric.can_punch_with(&NatType::PortRestricted));
+        assert!(NatType::Symmetric.can_punch_with(&NatType::FullCone));
+    }
+}
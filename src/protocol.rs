@@ -1,14 +1,62 @@
-use std::net::SocketAddr;
+use crate::ice::{self, Candidate};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
+/// First two bytes of every binary packet.
+pub const MAGIC: [u8; 2] = *b"NT";
+/// Version byte written after the magic number.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Longest client id, in bytes. `Client` refuses longer ones for itself
+/// and its peers, and the server refuses to register them, so ids and the
+/// reasons that quote them always fit a field.
+pub const MAX_ID_LEN: usize = 255;
+/// Most candidates a client advertises in `Candidates`; more would not fit
+/// a datagram once passed on in `StartPunchWithPeer`. The server drops the
+/// rest.
+pub const MAX_CANDIDATES: usize = 16;
+
+// magic(2) | version(1) | flags(1) | tag(1)
+const HEADER_LEN: usize = 5;
+
+/// How a message is laid out on the wire.
+///
+/// `Binary` is the default; `Text` is the pipe-delimited form kept around for
+/// debugging with `nc -u` and friends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    #[default]
+    Binary,
+    Text,
+}
+
+impl WireFormat {
+    /// Guess the format of a received packet from its first bytes.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(&MAGIC) {
+            WireFormat::Binary
+        } else {
+            WireFormat::Text
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum Message {
+    /// `public_key` is the client's static Noise key (see `secure`), which
+    /// the server hands to peers so they can authenticate the client.
+    /// `server_key` is what the server signs its packets to the client
+    /// with, see `Signed`.
     Register {
         id: String,
         port: u16,
+        public_key: Vec<u8>,
+        server_key: Vec<u8>,
     },
+    /// `external_addr` is the source address the server saw (the NAT
+    /// mapping); `claimed_addr` is that IP with the port from `Register`.
     RegisterOk {
         external_addr: SocketAddr,
+        claimed_addr: SocketAddr,
     },
     Discover {
         target: String,
@@ -16,6 +64,7 @@ pub enum Message {
     PeerFound {
         id: String,
         addr: SocketAddr,
+        public_key: Vec<u8>,
     },
     PeerNotFound {
         id: String,
@@ -27,93 +76,1599 @@ pub enum Message {
     StartPunch {
         timestamp: u64,
     },
+    /// Tells each side of a coordinated punch who it is punching and when.
+    /// `peer_candidates` is whatever the peer advertised with `Candidates`;
+    /// `peer_addr` is its registered address on top of that. `timestamp` is
+    /// the start on the server's wall clock; `delay_ms` is the same moment
+    /// counted from when the server sent this, which doesn't depend on the
+    /// clocks agreeing. `peer_next_port` and `peer_port_step` are from the
+    /// peer's `PortAllocation`, both 0 if it sent none.
     StartPunchWithPeer {
         timestamp: u64,
+        delay_ms: u32,
+        peer_id: String,
         peer_addr: SocketAddr,
+        peer_key: Vec<u8>,
+        peer_candidates: Vec<Candidate>,
+        peer_next_port: u16,
+        peer_port_step: u16,
+    },
+    /// Keeps a registration alive; the server answers with `HeartbeatAck`,
+    /// or with `NotRegistered` if the registration is gone.
+    Heartbeat {
+        id: String,
+    },
+    HeartbeatAck {
+        ttl_secs: u32,
+    },
+    /// `Register` with an HMAC over id, port, key and nonce under the
+    /// client's pre-shared key (see `auth`). Nonces must increase per id.
+    AuthRegister {
+        id: String,
+        port: u16,
+        public_key: Vec<u8>,
+        nonce: u64,
+        mac: String,
+    },
+    /// The server refused a registration, e.g. because the id is protected.
+    RegisterDenied {
+        id: String,
+        reason: String,
+    },
+    /// Ask the server to relay traffic to `to` because punching failed.
+    RelayRequest {
+        from: String,
+        to: String,
+    },
+    /// Sent to both sides: traffic for `peer_id` now goes through the
+    /// relay at `relay_port` on the server's IP. Each side first sends a
+    /// `RelayBind` there with its own `token`.
+    RelayAllocated {
+        peer_id: String,
+        relay_port: u16,
+        token: u64,
+        lifetime_secs: u32,
+        quota_bytes: u64,
+    },
+    RelayDenied {
+        peer_id: String,
+        reason: String,
+    },
+    /// Sent to the relay port, not the server's: the source address of this
+    /// packet is where the relay forwards the other side's traffic.
+    RelayBind {
+        id: String,
+        token: u64,
+    },
+    /// The relay to `peer_id` expired or ran out of quota.
+    RelayClosed {
+        peer_id: String,
+        reason: String,
+    },
+    /// The addresses a registered client may be reached at, passed on to
+    /// peers in `StartPunchWithPeer`. Replaces the previous list.
+    Candidates {
+        id: String,
+        candidates: Vec<Candidate>,
+    },
+    /// Another `Message`, encoded in the same format, from the server to a
+    /// registered client. `mac` is an HMAC over `seq` and `payload` under
+    /// the client's server key (see `auth::ServerAuth`); `seq` only grows.
+    Signed {
+        seq: u64,
+        payload: Vec<u8>,
+        mac: Vec<u8>,
+    },
+    /// Asks the server for its wall clock; `sent` is ours when sending, in
+    /// milliseconds since the Unix epoch.
+    TimeRequest {
+        sent: u64,
+    },
+    /// Echoes `TimeRequest::sent`, with the server's wall clock when the
+    /// request came in and when this went out.
+    TimeResponse {
+        sent: u64,
+        received: u64,
+        replied: u64,
+    },
+    /// How the client's NAT hands out ports, passed on to peers in
+    /// `StartPunchWithPeer` (see `spray`): the next mapping is expected at
+    /// `next_port` and later ones `step` apart. A `step` of 0 means no
+    /// pattern, `next_port` is then the last port seen.
+    PortAllocation {
+        id: String,
+        next_port: u16,
+        step: u16,
+    },
+    /// Answer to a `Heartbeat` or `HolePunch` from an address that has no
+    /// registration for `id`, so the client should register again. Sent
+    /// unsigned.
+    NotRegistered {
+        id: String,
     },
 }
 
-impl Message {
-    pub fn encode(&self) -> String {
+// (binary tag, text name) for every message type
+const MESSAGE_TAGS: &[(u8, &str)] = &[
+    (0x01, "REG"),
+    (0x02, "OK"),
+    (0x03, "FIND"),
+    (0x04, "PEER"),
+    (0x05, "NOPE"),
+    (0x06, "PUNCH"),
+    (0x07, "START"),
+    (0x08, "START_PEER"),
+    (0x09, "HEARTBEAT"),
+    (0x0a, "HEARTBEAT_ACK"),
+    (0x0b, "AUTH_REG"),
+    (0x0c, "REG_DENIED"),
+    (0x0d, "RELAY"),
+    (0x0e, "RELAY_OK"),
+    (0x0f, "RELAY_DENIED"),
+    (0x10, "RELAY_BIND"),
+    (0x11, "RELAY_CLOSED"),
+    (0x12, "CANDIDATES"),
+    (0x13, "SIGNED"),
+    (0x14, "TIME"),
+    (0x15, "TIME_ACK"),
+    (0x16, "PORTS"),
+    (0x17, "NOT_REGISTERED"),
+];
+
+/// Why a packet could not be decoded. Every variant carries the field that
+/// was being read and the byte offset into the packet where it starts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    UnknownTag { tag: String, offset: usize },
+    WrongFieldCount { field: &'static str, offset: usize },
+    BadAddress { field: &'static str, offset: usize },
+    BadPort { field: &'static str, offset: usize },
+    BadTimestamp { field: &'static str, offset: usize },
+    BadNumber { field: &'static str, offset: usize },
+    BadString { field: &'static str, offset: usize },
+    UnsupportedVersion { version: u8, offset: usize },
+}
+
+/// Field-less mirror of [`ProtocolError`], handy as a counter key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ProtocolErrorKind {
+    UnknownTag,
+    WrongFieldCount,
+    BadAddress,
+    BadPort,
+    BadTimestamp,
+    BadNumber,
+    BadString,
+    UnsupportedVersion,
+}
+
+impl ProtocolError {
+    pub fn kind(&self) -> ProtocolErrorKind {
         match self {
-            Message::Register { id, port } => format!("REG:{}:{}", id, port),
-            Message::RegisterOk { external_addr } => format!("OK:{}", external_addr),
-            Message::Discover { target } => format!("FIND:{}", target),
-            Message::PeerFound { id, addr } => format!("PEER:{}:{}", id, addr),
-            Message::PeerNotFound { id } => format!("NOPE:{}", id),
-            Message::HolePunch { from, to } => format!("PUNCH:{}:{}", from, to),
-            Message::StartPunch { timestamp } => format!("START:{}", timestamp),
+            ProtocolError::UnknownTag { .. } => ProtocolErrorKind::UnknownTag,
+            ProtocolError::WrongFieldCount { .. } => ProtocolErrorKind::WrongFieldCount,
+            ProtocolError::BadAddress { .. } => ProtocolErrorKind::BadAddress,
+            ProtocolError::BadPort { .. } => ProtocolErrorKind::BadPort,
+            ProtocolError::BadTimestamp { .. } => ProtocolErrorKind::BadTimestamp,
+            ProtocolError::BadNumber { .. } => ProtocolErrorKind::BadNumber,
+            ProtocolError::BadString { .. } => ProtocolErrorKind::BadString,
+            ProtocolError::UnsupportedVersion { .. } => ProtocolErrorKind::UnsupportedVersion,
+        }
+    }
+
+    pub fn field(&self) -> &str {
+        match self {
+            ProtocolError::UnknownTag { .. } => "tag",
+            ProtocolError::UnsupportedVersion { .. } => "version",
+            ProtocolError::WrongFieldCount { field, .. }
+            | ProtocolError::BadAddress { field, .. }
+            | ProtocolError::BadPort { field, .. }
+            | ProtocolError::BadTimestamp { field, .. }
+            | ProtocolError::BadNumber { field, .. }
+            | ProtocolError::BadString { field, .. } => field,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        match self {
+            ProtocolError::UnknownTag { offset, .. }
+            | ProtocolError::WrongFieldCount { offset, .. }
+            | ProtocolError::BadAddress { offset, .. }
+            | ProtocolError::BadPort { offset, .. }
+            | ProtocolError::BadTimestamp { offset, .. }
+            | ProtocolError::BadNumber { offset, .. }
+            | ProtocolError::BadString { offset, .. }
+            | ProtocolError::UnsupportedVersion { offset, .. } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ProtocolErrorKind::UnknownTag => "unknown tag",
+            ProtocolErrorKind::WrongFieldCount => "wrong field count",
+            ProtocolErrorKind::BadAddress => "bad address",
+            ProtocolErrorKind::BadPort => "bad port",
+            ProtocolErrorKind::BadTimestamp => "bad timestamp",
+            ProtocolErrorKind::BadNumber => "bad number",
+            ProtocolErrorKind::BadString => "bad string",
+            ProtocolErrorKind::UnsupportedVersion => "unsupported version",
+        };
+        f.write_str(s)
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownTag { tag, offset } => {
+                write!(f, "unknown tag '{}' at byte {}", tag, offset)
+            }
+            ProtocolError::UnsupportedVersion { version, offset } => {
+                write!(f, "unsupported version {} at byte {}", version, offset)
+            }
+            _ => write!(
+                f,
+                "{} in field '{}' at byte {}",
+                self.kind(),
+                self.field(),
+                self.offset()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for std::io::Error {
+    fn from(e: ProtocolError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl Schema for Message {
+    const TAGS: &'static [(u8, &'static str)] = MESSAGE_TAGS;
+
+    fn tag(&self) -> u8 {
+        match self {
+            Message::Register { .. } => 0x01,
+            Message::RegisterOk { .. } => 0x02,
+            Message::Discover { .. } => 0x03,
+            Message::PeerFound { .. } => 0x04,
+            Message::PeerNotFound { .. } => 0x05,
+            Message::HolePunch { .. } => 0x06,
+            Message::StartPunch { .. } => 0x07,
+            Message::StartPunchWithPeer { .. } => 0x08,
+            Message::Heartbeat { .. } => 0x09,
+            Message::HeartbeatAck { .. } => 0x0a,
+            Message::AuthRegister { .. } => 0x0b,
+            Message::RegisterDenied { .. } => 0x0c,
+            Message::RelayRequest { .. } => 0x0d,
+            Message::RelayAllocated { .. } => 0x0e,
+            Message::RelayDenied { .. } => 0x0f,
+            Message::RelayBind { .. } => 0x10,
+            Message::RelayClosed { .. } => 0x11,
+            Message::Candidates { .. } => 0x12,
+            Message::Signed { .. } => 0x13,
+            Message::TimeRequest { .. } => 0x14,
+            Message::TimeResponse { .. } => 0x15,
+            Message::PortAllocation { .. } => 0x16,
+            Message::NotRegistered { .. } => 0x17,
+        }
+    }
+
+    fn write_fields<W: FieldWriter>(&self, w: &mut W) {
+        match self {
+            Message::Register {
+                id,
+                port,
+                public_key,
+                server_key,
+            } => {
+                w.str(id);
+                w.u16(*port);
+                w.bytes(public_key);
+                w.bytes(server_key);
+            }
+            Message::RegisterOk {
+                external_addr,
+                claimed_addr,
+            } => {
+                w.addr(*external_addr);
+                w.addr(*claimed_addr);
+            }
+            Message::Discover { target } => w.str(target),
+            Message::PeerFound {
+                id,
+                addr,
+                public_key,
+            } => {
+                w.str(id);
+                w.addr(*addr);
+                w.bytes(public_key);
+            }
+            Message::PeerNotFound { id } | Message::NotRegistered { id } => w.str(id),
+            Message::HolePunch { from, to } | Message::RelayRequest { from, to } => {
+                w.str(from);
+                w.str(to);
+            }
+            Message::StartPunch { timestamp } => w.u64(*timestamp),
             Message::StartPunchWithPeer {
                 timestamp,
+                delay_ms,
+                peer_id,
                 peer_addr,
+                peer_key,
+                peer_candidates,
+                peer_next_port,
+                peer_port_step,
+            } => {
+                w.str(peer_id);
+                w.addr(*peer_addr);
+                w.bytes(peer_key);
+                w.u64(*timestamp);
+                w.candidates(peer_candidates);
+                w.u32(*delay_ms);
+                w.u16(*peer_next_port);
+                w.u16(*peer_port_step);
+            }
+            Message::Heartbeat { id } => w.str(id),
+            Message::HeartbeatAck { ttl_secs } => w.u32(*ttl_secs),
+            Message::AuthRegister {
+                id,
+                port,
+                public_key,
+                nonce,
+                mac,
             } => {
-                format!("START_PEER|{}|{}", peer_addr, timestamp) // use | delimiter
+                w.str(id);
+                w.u16(*port);
+                w.bytes(public_key);
+                w.u64(*nonce);
+                w.str(mac);
             }
+            Message::RegisterDenied { id, reason } => {
+                w.str(id);
+                w.str(reason);
+            }
+            Message::RelayAllocated {
+                peer_id,
+                relay_port,
+                token,
+                lifetime_secs,
+                quota_bytes,
+            } => {
+                w.str(peer_id);
+                w.u16(*relay_port);
+                w.u64(*token);
+                w.u32(*lifetime_secs);
+                w.u64(*quota_bytes);
+            }
+            Message::RelayDenied { peer_id, reason } | Message::RelayClosed { peer_id, reason } => {
+                w.str(peer_id);
+                w.str(reason);
+            }
+            Message::RelayBind { id, token } => {
+                w.str(id);
+                w.u64(*token);
+            }
+            Message::Candidates { id, candidates } => {
+                w.str(id);
+                w.candidates(candidates);
+            }
+            Message::Signed { seq, payload, mac } => {
+                w.u64(*seq);
+                w.bytes(payload);
+                w.bytes(mac);
+            }
+            Message::TimeRequest { sent } => w.u64(*sent),
+            Message::TimeResponse {
+                sent,
+                received,
+                replied,
+            } => {
+                w.u64(*sent);
+                w.u64(*received);
+                w.u64(*replied);
+            }
+            Message::PortAllocation { id, next_port, step } => {
+                w.str(id);
+                w.u16(*next_port);
+                w.u16(*step);
+            }
+        }
+    }
+
+    fn read_fields<R: FieldReader>(tag: u8, r: &mut R) -> Result<Self, ProtocolError> {
+        Ok(match tag {
+            0x01 => Message::Register {
+                id: r.str("id")?,
+                port: r.port("port")?,
+                public_key: r.bytes("public_key")?,
+                server_key: r.bytes("server_key")?,
+            },
+            0x02 => Message::RegisterOk {
+                external_addr: r.addr("external_addr")?,
+                claimed_addr: r.addr("claimed_addr")?,
+            },
+            0x03 => Message::Discover {
+                target: r.str("target")?,
+            },
+            0x04 => Message::PeerFound {
+                id: r.str("id")?,
+                addr: r.addr("addr")?,
+                public_key: r.bytes("public_key")?,
+            },
+            0x05 => Message::PeerNotFound { id: r.str("id")? },
+            0x06 => Message::HolePunch {
+                from: r.str("from")?,
+                to: r.str("to")?,
+            },
+            0x07 => Message::StartPunch {
+                timestamp: r.timestamp("timestamp")?,
+            },
+            0x08 => Message::StartPunchWithPeer {
+                peer_id: r.str("peer_id")?,
+                peer_addr: r.addr("peer_addr")?,
+                peer_key: r.bytes("peer_key")?,
+                timestamp: r.timestamp("timestamp")?,
+                peer_candidates: r.candidates("peer_candidates")?,
+                delay_ms: r.u32("delay_ms")?,
+                peer_next_port: r.port("peer_next_port")?,
+                peer_port_step: r.port("peer_port_step")?,
+            },
+            0x09 => Message::Heartbeat { id: r.str("id")? },
+            0x0a => Message::HeartbeatAck {
+                ttl_secs: r.u32("ttl_secs")?,
+            },
+            0x0b => Message::AuthRegister {
+                id: r.str("id")?,
+                port: r.port("port")?,
+                public_key: r.bytes("public_key")?,
+                nonce: r.u64("nonce")?,
+                mac: r.str("mac")?,
+            },
+            0x0c => Message::RegisterDenied {
+                id: r.str("id")?,
+                reason: r.str("reason")?,
+            },
+            0x0d => Message::RelayRequest {
+                from: r.str("from")?,
+                to: r.str("to")?,
+            },
+            0x0e => Message::RelayAllocated {
+                peer_id: r.str("peer_id")?,
+                relay_port: r.port("relay_port")?,
+                token: r.u64("token")?,
+                lifetime_secs: r.u32("lifetime_secs")?,
+                quota_bytes: r.u64("quota_bytes")?,
+            },
+            0x0f => Message::RelayDenied {
+                peer_id: r.str("peer_id")?,
+                reason: r.str("reason")?,
+            },
+            0x10 => Message::RelayBind {
+                id: r.str("id")?,
+                token: r.u64("token")?,
+            },
+            0x11 => Message::RelayClosed {
+                peer_id: r.str("peer_id")?,
+                reason: r.str("reason")?,
+            },
+            0x12 => Message::Candidates {
+                id: r.str("id")?,
+                candidates: r.candidates("candidates")?,
+            },
+            0x13 => Message::Signed {
+                seq: r.u64("seq")?,
+                payload: r.bytes("payload")?,
+                mac: r.bytes("mac")?,
+            },
+            0x14 => Message::TimeRequest {
+                sent: r.timestamp("sent")?,
+            },
+            0x15 => Message::TimeResponse {
+                sent: r.timestamp("sent")?,
+                received: r.timestamp("received")?,
+                replied: r.timestamp("replied")?,
+            },
+            0x16 => Message::PortAllocation {
+                id: r.str("id")?,
+                next_port: r.port("next_port")?,
+                step: r.port("step")?,
+            },
+            0x17 => Message::NotRegistered { id: r.str("id")? },
+            _ => unreachable!("tag validated by caller"),
+        })
+    }
+}
+
+impl Message {
+    /// Binary encoding: header followed by length-prefixed fields.
+    ///
+    /// Panics if a field is longer than 65535 bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_binary(self)
+    }
+
+    /// Pipe-delimited text encoding, e.g. `FIND|bob`.
+    pub fn encode_text(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn encode_as(&self, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Binary => self.encode(),
+            WireFormat::Text => self.encode_text().into_bytes(),
+        }
+    }
+
+    /// Decode a packet in either wire format.
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        decode_any(data)
+    }
+
+    pub fn decode_text(s: &str) -> Result<Self, ProtocolError> {
+        decode_text(s)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_text(self))
+    }
+}
+
+/// Traffic sent directly between peers over the punched path.
+///
+/// Shares the header and field codec with [`Message`] but uses its own tag
+/// range and text names, so a signaling packet never decodes as a peer
+/// packet or vice versa.
+#[derive(Debug, Clone)]
+pub enum PeerMessage {
+    Punch { from: String, seq: u32 },
+    PunchAck { from: String, seq: u32 },
+    /// Sent by the controlling side to the pair it picked, see
+    /// `ice::CheckList`; answered with a `PunchAck` echoing `seq`.
+    Nominate { from: String, seq: u32 },
+    Data { payload: String },
+    Keepalive { seq: u32 },
+    Close { from: String },
+    /// First Noise handshake message, sent by the side that connects.
+    HandshakeInit { from: String, payload: Vec<u8> },
+    /// Second and last Noise handshake message.
+    HandshakeReply { from: String, payload: Vec<u8> },
+    /// Another `PeerMessage`, binary encoded and AEAD-sealed under the
+    /// session with `from`. `nonce` doubles as the replay-window counter.
+    Sealed {
+        from: String,
+        nonce: u64,
+        payload: Vec<u8>,
+    },
+    /// One segment of a reliable stream; an empty payload ends the stream.
+    /// Only ever sent sealed.
+    StreamData {
+        stream_id: u32,
+        seq: u32,
+        payload: Vec<u8>,
+    },
+    /// `ack` is the next segment expected, bit n of `sack` means segment
+    /// `ack + 1 + n` arrived early, `window` is free receive buffer in bytes.
+    StreamAck {
+        stream_id: u32,
+        ack: u32,
+        sack: u32,
+        window: u32,
+    },
+    /// Offer a file; its bytes follow on stream `stream_id` once accepted.
+    FileOffer {
+        stream_id: u32,
+        name: String,
+        size: u64,
+        sha256: Vec<u8>,
+    },
+    /// Send the offered file starting at `offset`, which is how much of it
+    /// we already have from an interrupted transfer. `prefix_sha256` hashes
+    /// those bytes, so the sender can tell they are from the same file.
+    FileAccept {
+        stream_id: u32,
+        offset: u64,
+        prefix_sha256: Vec<u8>,
+    },
+    FileReject { stream_id: u32, reason: String },
+    /// Answer to a `Keepalive`, echoing its `seq`.
+    KeepaliveAck { seq: u32 },
+}
+
+const PEER_MESSAGE_TAGS: &[(u8, &str)] = &[
+    // "P_" keeps them apart from the signaling `PUNCH` in text
+    (0x40, "P_PUNCH"),
+    (0x41, "P_PUNCH_ACK"),
+    (0x42, "MSG"),
+    (0x43, "KEEPALIVE"),
+    (0x44, "CLOSE"),
+    (0x45, "HS_INIT"),
+    (0x46, "HS_REPLY"),
+    (0x47, "SEALED"),
+    (0x48, "STREAM"),
+    (0x49, "STREAM_ACK"),
+    (0x4a, "FILE_OFFER"),
+    (0x4b, "FILE_ACCEPT"),
+    (0x4c, "FILE_REJECT"),
+    (0x4d, "KEEPALIVE_ACK"),
+    (0x4e, "NOMINATE"),
+];
+
+impl Schema for PeerMessage {
+    const TAGS: &'static [(u8, &'static str)] = PEER_MESSAGE_TAGS;
+
+    fn tag(&self) -> u8 {
+        match self {
+            PeerMessage::Punch { .. } => 0x40,
+            PeerMessage::PunchAck { .. } => 0x41,
+            PeerMessage::Data { .. } => 0x42,
+            PeerMessage::Keepalive { .. } => 0x43,
+            PeerMessage::Close { .. } => 0x44,
+            PeerMessage::HandshakeInit { .. } => 0x45,
+            PeerMessage::HandshakeReply { .. } => 0x46,
+            PeerMessage::Sealed { .. } => 0x47,
+            PeerMessage::StreamData { .. } => 0x48,
+            PeerMessage::StreamAck { .. } => 0x49,
+            PeerMessage::FileOffer { .. } => 0x4a,
+            PeerMessage::FileAccept { .. } => 0x4b,
+            PeerMessage::FileReject { .. } => 0x4c,
+            PeerMessage::KeepaliveAck { .. } => 0x4d,
+            PeerMessage::Nominate { .. } => 0x4e,
+        }
+    }
+
+    fn write_fields<W: FieldWriter>(&self, w: &mut W) {
+        match self {
+            PeerMessage::Punch { from, seq }
+            | PeerMessage::PunchAck { from, seq }
+            | PeerMessage::Nominate { from, seq } => {
+                w.str(from);
+                w.u32(*seq);
+            }
+            PeerMessage::Data { payload } => w.str(payload),
+            PeerMessage::Keepalive { seq } | PeerMessage::KeepaliveAck { seq } => w.u32(*seq),
+            PeerMessage::Close { from } => w.str(from),
+            PeerMessage::HandshakeInit { from, payload }
+            | PeerMessage::HandshakeReply { from, payload } => {
+                w.str(from);
+                w.bytes(payload);
+            }
+            PeerMessage::Sealed {
+                from,
+                nonce,
+                payload,
+            } => {
+                w.str(from);
+                w.u64(*nonce);
+                w.bytes(payload);
+            }
+            PeerMessage::StreamData {
+                stream_id,
+                seq,
+                payload,
+            } => {
+                w.u32(*stream_id);
+                w.u32(*seq);
+                w.bytes(payload);
+            }
+            PeerMessage::StreamAck {
+                stream_id,
+                ack,
+                sack,
+                window,
+            } => {
+                w.u32(*stream_id);
+                w.u32(*ack);
+                w.u32(*sack);
+                w.u32(*window);
+            }
+            PeerMessage::FileOffer {
+                stream_id,
+                name,
+                size,
+                sha256,
+            } => {
+                w.u32(*stream_id);
+                w.str(name);
+                w.u64(*size);
+                w.bytes(sha256);
+            }
+            PeerMessage::FileAccept {
+                stream_id,
+                offset,
+                prefix_sha256,
+            } => {
+                w.u32(*stream_id);
+                w.u64(*offset);
+                w.bytes(prefix_sha256);
+            }
+            PeerMessage::FileReject { stream_id, reason } => {
+                w.u32(*stream_id);
+                w.str(reason);
+            }
+        }
+    }
+
+    fn read_fields<R: FieldReader>(tag: u8, r: &mut R) -> Result<Self, ProtocolError> {
+        Ok(match tag {
+            0x40 => PeerMessage::Punch {
+                from: r.str("from")?,
+                seq: r.u32("seq")?,
+            },
+            0x41 => PeerMessage::PunchAck {
+                from: r.str("from")?,
+                seq: r.u32("seq")?,
+            },
+            0x42 => PeerMessage::Data {
+                payload: r.str("payload")?,
+            },
+            0x43 => PeerMessage::Keepalive { seq: r.u32("seq")? },
+            0x44 => PeerMessage::Close {
+                from: r.str("from")?,
+            },
+            0x45 => PeerMessage::HandshakeInit {
+                from: r.str("from")?,
+                payload: r.bytes("payload")?,
+            },
+            0x46 => PeerMessage::HandshakeReply {
+                from: r.str("from")?,
+                payload: r.bytes("payload")?,
+            },
+            0x47 => PeerMessage::Sealed {
+                from: r.str("from")?,
+                nonce: r.u64("nonce")?,
+                payload: r.bytes("payload")?,
+            },
+            0x48 => PeerMessage::StreamData {
+                stream_id: r.u32("stream_id")?,
+                seq: r.u32("seq")?,
+                payload: r.bytes("payload")?,
+            },
+            0x49 => PeerMessage::StreamAck {
+                stream_id: r.u32("stream_id")?,
+                ack: r.u32("ack")?,
+                sack: r.u32("sack")?,
+                window: r.u32("window")?,
+            },
+            0x4a => PeerMessage::FileOffer {
+                stream_id: r.u32("stream_id")?,
+                name: r.str("name")?,
+                size: r.u64("size")?,
+                sha256: r.bytes("sha256")?,
+            },
+            0x4b => PeerMessage::FileAccept {
+                stream_id: r.u32("stream_id")?,
+                offset: r.u64("offset")?,
+                prefix_sha256: r.bytes("prefix_sha256")?,
+            },
+            0x4c => PeerMessage::FileReject {
+                stream_id: r.u32("stream_id")?,
+                reason: r.str("reason")?,
+            },
+            0x4d => PeerMessage::KeepaliveAck { seq: r.u32("seq")? },
+            0x4e => PeerMessage::Nominate {
+                from: r.str("from")?,
+                seq: r.u32("seq")?,
+            },
+            _ => unreachable!("tag validated by caller"),
+        })
+    }
+}
+
+impl PeerMessage {
+    /// Panics if a field is longer than 65535 bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_binary(self)
+    }
+
+    pub fn encode_text(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn encode_as(&self, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Binary => self.encode(),
+            WireFormat::Text => self.encode_text().into_bytes(),
         }
     }
 
-    pub fn decode(s: &str) -> Result<Self, &'static str> {
-        // handle START_PEER with | delimiter first
-        if s.starts_with("START_PEER|") {
-            let parts: Vec<&str> = s.split('|').collect();
-            if parts.len() == 3 {
-                let peer_addr = parts[1].parse().map_err(|_| "Invalid peer address")?;
-                let timestamp = parts[2].parse().map_err(|_| "Invalid timestamp")?;
-                return Ok(Message::StartPunchWithPeer {
-                    timestamp,
-                    peer_addr,
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        decode_any(data)
+    }
+
+    pub fn decode_text(s: &str) -> Result<Self, ProtocolError> {
+        decode_text(s)
+    }
+}
+
+impl fmt::Display for PeerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_text(self))
+    }
+}
+
+// One schema per message family: a tag table plus ordered field lists.
+// The generic functions below turn a schema into either wire format.
+trait Schema: Sized {
+    const TAGS: &'static [(u8, &'static str)];
+    fn tag(&self) -> u8;
+    fn write_fields<W: FieldWriter>(&self, w: &mut W);
+    fn read_fields<R: FieldReader>(tag: u8, r: &mut R) -> Result<Self, ProtocolError>;
+}
+
+fn encode_binary<T: Schema>(msg: &T) -> Vec<u8> {
+    let mut w = BinaryWriter::new(msg.tag());
+    msg.write_fields(&mut w);
+    w.buf
+}
+
+fn encode_text<T: Schema>(msg: &T) -> String {
+    let tag = msg.tag();
+    let name = T::TAGS
+        .iter()
+        .find(|(t, _)| *t == tag)
+        .map(|(_, n)| *n)
+        .unwrap_or("?");
+    let mut w = TextWriter {
+        out: name.to_string(),
+    };
+    msg.write_fields(&mut w);
+    w.out
+}
+
+fn decode_any<T: Schema>(data: &[u8]) -> Result<T, ProtocolError> {
+    match WireFormat::detect(data) {
+        WireFormat::Binary => {
+            let (tag, mut r) = BinaryReader::open(data)?;
+            if !T::TAGS.iter().any(|(t, _)| *t == tag) {
+                return Err(ProtocolError::UnknownTag {
+                    tag: format!("{:#04x}", tag),
+                    offset: HEADER_LEN - 1,
                 });
-            } else {
-                return Err("Invalid START_PEER format");
-            }
-        }
-
-        // handle all other messages with : delimiter -> should remove this everything should use pipe
-        let parts: Vec<&str> = s.split(':').collect();
-        if parts.len() < 2 {
-            return Err("Invalid message format");
-        }
-
-        match parts[0] {
-            "REG" if parts.len() == 3 => Ok(Message::Register {
-                id: parts[1].to_string(),
-                port: parts[2].parse().map_err(|_| "Invalid port")?,
-            }),
-            "OK" if parts.len() >= 2 => {
-                let addr_str = if parts.len() == 3 {
-                    format!("{}:{}", parts[1], parts[2])
-                } else {
-                    parts[1..].join(":")
-                };
-                Ok(Message::RegisterOk {
-                    external_addr: addr_str.parse().map_err(|_| "Invalid address")?,
-                })
             }
-            "FIND" => Ok(Message::Discover {
-                target: parts[1].to_string(),
-            }),
-            "PEER" if parts.len() >= 3 => {
-                let addr_str = if parts.len() == 4 && !parts[2].starts_with('[') {
-                    format!("{}:{}", parts[2], parts[3])
-                } else {
-                    parts[2..].join(":")
-                };
-                Ok(Message::PeerFound {
-                    id: parts[1].to_string(),
-                    addr: addr_str.parse().map_err(|_| "Invalid peer address")?,
+            let msg = T::read_fields(tag, &mut r)?;
+            r.finish()?;
+            Ok(msg)
+        }
+        WireFormat::Text => {
+            let s = std::str::from_utf8(data).map_err(|e| ProtocolError::BadString {
+                field: "message",
+                offset: e.valid_up_to(),
+            })?;
+            decode_text(s)
+        }
+    }
+}
+
+fn decode_text<T: Schema>(s: &str) -> Result<T, ProtocolError> {
+    let (name, mut r) = TextReader::open(s);
+    let tag = T::TAGS
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(t, _)| *t)
+        .ok_or_else(|| ProtocolError::UnknownTag {
+            tag: name.to_string(),
+            offset: 0,
+        })?;
+    let msg = T::read_fields(tag, &mut r)?;
+    r.finish()?;
+    Ok(msg)
+}
+
+// Both wire formats share one schema: each message writes and reads its
+// fields in order through these traits, and only the framing differs.
+trait FieldWriter {
+    fn str(&mut self, v: &str);
+    fn u16(&mut self, v: u16);
+    fn u32(&mut self, v: u32);
+    fn u64(&mut self, v: u64);
+    fn addr(&mut self, v: SocketAddr);
+    /// Raw in binary, hex in text.
+    fn bytes(&mut self, v: &[u8]);
+    /// Counted records in binary, comma-separated `Candidate`s in text.
+    fn candidates(&mut self, v: &[Candidate]);
+}
+
+trait FieldReader {
+    fn str(&mut self, field: &'static str) -> Result<String, ProtocolError>;
+    fn port(&mut self, field: &'static str) -> Result<u16, ProtocolError>;
+    fn u32(&mut self, field: &'static str) -> Result<u32, ProtocolError>;
+    fn u64(&mut self, field: &'static str) -> Result<u64, ProtocolError>;
+    fn timestamp(&mut self, field: &'static str) -> Result<u64, ProtocolError>;
+    fn addr(&mut self, field: &'static str) -> Result<SocketAddr, ProtocolError>;
+    fn bytes(&mut self, field: &'static str) -> Result<Vec<u8>, ProtocolError>;
+    fn candidates(&mut self, field: &'static str) -> Result<Vec<Candidate>, ProtocolError>;
+    /// Fails if there are fields left over.
+    fn finish(&mut self) -> Result<(), ProtocolError>;
+}
+
+struct BinaryWriter {
+    buf: Vec<u8>,
+}
+
+impl BinaryWriter {
+    fn new(tag: u8) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&MAGIC);
+        buf.push(PROTOCOL_VERSION);
+        buf.push(0); // flags, reserved
+        buf.push(tag);
+        Self { buf }
+    }
+
+    // A longer field can't be framed, and cutting the prefix short would
+    // corrupt the rest of the message. What callers pass in is bounded
+    // where it enters (see `MAX_ID_LEN`), so this is a bug, not input.
+    fn len(&mut self, len: usize) {
+        let len = u16::try_from(len).expect("field longer than 65535 bytes");
+        self.buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+impl FieldWriter for BinaryWriter {
+    fn str(&mut self, v: &str) {
+        self.len(v.len());
+        self.buf.extend_from_slice(v.as_bytes());
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn addr(&mut self, v: SocketAddr) {
+        match v.ip() {
+            IpAddr::V4(ip) => {
+                self.buf.push(4);
+                self.buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                self.buf.push(6);
+                self.buf.extend_from_slice(&ip.octets());
+            }
+        }
+        self.buf.extend_from_slice(&v.port().to_be_bytes());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.len(v.len());
+        self.buf.extend_from_slice(v);
+    }
+
+    fn candidates(&mut self, v: &[Candidate]) {
+        self.len(v.len());
+        for candidate in v {
+            self.buf.push(ice::kind_code(candidate.kind));
+            self.addr(candidate.addr);
+            self.u32(candidate.priority);
+        }
+    }
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    /// Validate the header and return the message tag.
+    fn open(data: &'a [u8]) -> Result<(u8, Self), ProtocolError> {
+        if data.len() < HEADER_LEN {
+            return Err(ProtocolError::WrongFieldCount {
+                field: "header",
+                offset: data.len(),
+            });
+        }
+        if data[2] != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion {
+                version: data[2],
+                offset: 2,
+            });
+        }
+        Ok((
+            data[4],
+            Self {
+                data,
+                pos: HEADER_LEN,
+            },
+        ))
+    }
+
+    fn take(&mut self, field: &'static str, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.data.len() - self.pos < n {
+            return Err(ProtocolError::WrongFieldCount {
+                field,
+                offset: self.pos,
+            });
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self, field: &'static str) -> Result<u16, ProtocolError> {
+        let b = self.take(field, 2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+impl FieldReader for BinaryReader<'_> {
+    fn str(&mut self, field: &'static str) -> Result<String, ProtocolError> {
+        let offset = self.pos;
+        let len = self.u16(field)? as usize;
+        let bytes = self.take(field, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::BadString { field, offset })
+    }
+
+    fn port(&mut self, field: &'static str) -> Result<u16, ProtocolError> {
+        self.u16(field)
+    }
+
+    fn u32(&mut self, field: &'static str) -> Result<u32, ProtocolError> {
+        let b = self.take(field, 4)?;
+        Ok(u32::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self, field: &'static str) -> Result<u64, ProtocolError> {
+        let b = self.take(field, 8)?;
+        Ok(u64::from_be_bytes(b.try_into().unwrap()))
+    }
+
+    fn timestamp(&mut self, field: &'static str) -> Result<u64, ProtocolError> {
+        self.u64(field)
+    }
+
+    fn addr(&mut self, field: &'static str) -> Result<SocketAddr, ProtocolError> {
+        let offset = self.pos;
+        let ip = match self.take(field, 1)?[0] {
+            4 => {
+                let b: [u8; 4] = self.take(field, 4)?.try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(b))
+            }
+            6 => {
+                let b: [u8; 16] = self.take(field, 16)?.try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(b))
+            }
+            _ => return Err(ProtocolError::BadAddress { field, offset }),
+        };
+        Ok(SocketAddr::new(ip, self.u16(field)?))
+    }
+
+    fn bytes(&mut self, field: &'static str) -> Result<Vec<u8>, ProtocolError> {
+        let len = self.u16(field)? as usize;
+        Ok(self.take(field, len)?.to_vec())
+    }
+
+    fn candidates(&mut self, field: &'static str) -> Result<Vec<Candidate>, ProtocolError> {
+        let count = self.u16(field)?;
+        (0..count)
+            .map(|_| {
+                let offset = self.pos;
+                let kind = ice::kind_from_code(self.take(field, 1)?[0])
+                    .ok_or(ProtocolError::BadString { field, offset })?;
+                Ok(Candidate {
+                    kind,
+                    addr: self.addr(field)?,
+                    priority: self.u32(field)?,
                 })
+            })
+            .collect()
+    }
+
+    fn finish(&mut self) -> Result<(), ProtocolError> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(ProtocolError::WrongFieldCount {
+                field: "trailing",
+                offset: self.pos,
+            })
+        }
+    }
+}
+
+struct TextWriter {
+    out: String,
+}
+
+impl FieldWriter for TextWriter {
+    fn str(&mut self, v: &str) {
+        self.out.push('|');
+        // escape the delimiter so ids may contain it
+        for c in v.chars() {
+            match c {
+                '%' => self.out.push_str("%25"),
+                '|' => self.out.push_str("%7C"),
+                c => self.out.push(c),
+            }
+        }
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.out.push('|');
+        self.out.push_str(&v.to_string());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.out.push('|');
+        self.out.push_str(&v.to_string());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.out.push('|');
+        self.out.push_str(&v.to_string());
+    }
+
+    fn addr(&mut self, v: SocketAddr) {
+        self.out.push('|');
+        self.out.push_str(&v.to_string());
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.out.push('|');
+        self.out.push_str(&to_hex(v));
+    }
+
+    fn candidates(&mut self, v: &[Candidate]) {
+        self.out.push('|');
+        let list: Vec<String> = v.iter().map(|c| c.to_string()).collect();
+        self.out.push_str(&list.join(","));
+    }
+}
+
+struct TextReader<'a> {
+    rest: &'a str,
+    // byte offset of `rest` within the original packet
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> TextReader<'a> {
+    /// Split off the message name.
+    fn open(s: &'a str) -> (&'a str, Self) {
+        let s = s.trim_end_matches(['\r', '\n']);
+        let (name, rest, done) = match s.find('|') {
+            Some(i) => (&s[..i], &s[i + 1..], false),
+            None => (s, "", true),
+        };
+        let pos = name.len() + 1;
+        (name, Self { rest, pos, done })
+    }
+
+    /// Next raw field and the offset it starts at.
+    fn next(&mut self, field: &'static str) -> Result<(&'a str, usize), ProtocolError> {
+        if self.done {
+            return Err(ProtocolError::WrongFieldCount {
+                field,
+                offset: self.pos,
+            });
+        }
+        let offset = self.pos;
+        let raw = match self.rest.find('|') {
+            Some(i) => {
+                let raw = &self.rest[..i];
+                self.rest = &self.rest[i + 1..];
+                raw
+            }
+            None => {
+                self.done = true;
+                self.rest
+            }
+        };
+        self.pos += raw.len() + 1;
+        Ok((raw, offset))
+    }
+}
+
+impl FieldReader for TextReader<'_> {
+    fn str(&mut self, field: &'static str) -> Result<String, ProtocolError> {
+        let (raw, _) = self.next(field)?;
+        Ok(raw.replace("%7C", "|").replace("%25", "%"))
+    }
+
+    fn port(&mut self, field: &'static str) -> Result<u16, ProtocolError> {
+        let (raw, offset) = self.next(field)?;
+        raw.parse()
+            .map_err(|_| ProtocolError::BadPort { field, offset })
+    }
+
+    fn u32(&mut self, field: &'static str) -> Result<u32, ProtocolError> {
+        let (raw, offset) = self.next(field)?;
+        raw.parse()
+            .map_err(|_| ProtocolError::BadNumber { field, offset })
+    }
+
+    fn u64(&mut self, field: &'static str) -> Result<u64, ProtocolError> {
+        let (raw, offset) = self.next(field)?;
+        raw.parse()
+            .map_err(|_| ProtocolError::BadNumber { field, offset })
+    }
+
+    fn timestamp(&mut self, field: &'static str) -> Result<u64, ProtocolError> {
+        let (raw, offset) = self.next(field)?;
+        raw.parse()
+            .map_err(|_| ProtocolError::BadTimestamp { field, offset })
+    }
+
+    fn addr(&mut self, field: &'static str) -> Result<SocketAddr, ProtocolError> {
+        let (raw, offset) = self.next(field)?;
+        raw.parse()
+            .map_err(|_| ProtocolError::BadAddress { field, offset })
+    }
+
+    fn bytes(&mut self, field: &'static str) -> Result<Vec<u8>, ProtocolError> {
+        let (raw, offset) = self.next(field)?;
+        from_hex(raw).ok_or(ProtocolError::BadString { field, offset })
+    }
+
+    fn candidates(&mut self, field: &'static str) -> Result<Vec<Candidate>, ProtocolError> {
+        let (raw, offset) = self.next(field)?;
+        if raw.is_empty() {
+            return Ok(Vec::new());
+        }
+        raw.split(',')
+            .map(|c| c.parse().map_err(|_| ProtocolError::BadString { field, offset }))
+            .collect()
+    }
+
+    fn finish(&mut self) -> Result<(), ProtocolError> {
+        if self.done {
+            Ok(())
+        } else {
+            Err(ProtocolError::WrongFieldCount {
+                field: "trailing",
+                offset: self.pos,
+            })
+        }
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ice::CandidateKind;
+
+    fn all_messages() -> Vec<Message> {
+        let v4: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        vec![
+            Message::Register {
+                id: "alice:home|laptop".to_string(),
+                port: 5000,
+                public_key: vec![0xab; 32],
+                server_key: vec![0x5e; 32],
+            },
+            Message::RegisterOk {
+                external_addr: v6,
+                claimed_addr: v6,
+            },
+            Message::Discover {
+                target: "bob".to_string(),
+            },
+            Message::PeerFound {
+                id: "bob".to_string(),
+                addr: v6,
+                public_key: Vec::new(),
+            },
+            Message::PeerNotFound {
+                id: "charlie".to_string(),
+            },
+            Message::HolePunch {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+            },
+            Message::StartPunch { timestamp: 42 },
+            Message::StartPunchWithPeer {
+                timestamp: 1_700_000_000_000,
+                delay_ms: 2000,
+                peer_id: "bob".to_string(),
+                peer_addr: v4,
+                peer_key: vec![7; 32],
+                peer_candidates: vec![
+                    Candidate::new(CandidateKind::Host, v4, 65535),
+                    Candidate::new(CandidateKind::ServerReflexive, v6, 100),
+                ],
+                peer_next_port: 20016,
+                peer_port_step: 4,
+            },
+            Message::Heartbeat {
+                id: "alice".to_string(),
+            },
+            Message::HeartbeatAck { ttl_secs: 60 },
+            Message::AuthRegister {
+                id: "alice".to_string(),
+                port: 5000,
+                public_key: vec![1, 2, 3],
+                nonce: u64::MAX,
+                mac: "00ff".repeat(16),
+            },
+            Message::RegisterDenied {
+                id: "alice".to_string(),
+                reason: "id in use | try later".to_string(),
+            },
+            Message::RelayRequest {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+            },
+            Message::RelayAllocated {
+                peer_id: "bob".to_string(),
+                relay_port: 50000,
+                token: 0xdead_beef_0000_0001,
+                lifetime_secs: 600,
+                quota_bytes: 1 << 24,
+            },
+            Message::RelayDenied {
+                peer_id: "bob".to_string(),
+                reason: "relay disabled".to_string(),
+            },
+            Message::RelayBind {
+                id: "alice".to_string(),
+                token: 1,
+            },
+            Message::RelayClosed {
+                peer_id: "bob".to_string(),
+                reason: "quota exhausted".to_string(),
+            },
+            Message::Candidates {
+                id: "alice".to_string(),
+                candidates: vec![],
+            },
+            Message::Candidates {
+                id: "alice".to_string(),
+                candidates: vec![Candidate::new(CandidateKind::Relayed, v4, 1)],
+            },
+            Message::Signed {
+                seq: u64::MAX,
+                payload: b"OK|1.2.3.4:5|1.2.3.4:5".to_vec(),
+                mac: vec![0xcd; 32],
+            },
+            Message::TimeRequest {
+                sent: 1_700_000_000_000,
+            },
+            Message::TimeResponse {
+                sent: 1_700_000_000_000,
+                received: 1_700_000_005_040,
+                replied: 1_700_000_005_041,
+            },
+            Message::PortAllocation {
+                id: "alice".to_string(),
+                next_port: 40123,
+                step: 0,
+            },
+            Message::NotRegistered {
+                id: "alice".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        for msg in all_messages() {
+            let data = msg.encode();
+            assert_eq!(&data[..2], &MAGIC);
+            let decoded = Message::decode(&data).unwrap();
+            assert_eq!(decoded.encode(), data, "{}", msg);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "longer than 65535")]
+    fn test_oversized_field_panics() {
+        Message::Discover {
+            target: "b".repeat(70_000),
+        }
+        .encode();
+    }
+
+    #[test]
+    fn test_text_roundtrip() {
+        for msg in all_messages() {
+            let text = msg.encode_text();
+            let decoded = Message::decode(text.as_bytes()).unwrap();
+            assert_eq!(decoded.encode_text(), text);
+            assert_eq!(
+                PeerMessage::decode_text(&text).unwrap_err().kind(),
+                ProtocolErrorKind::UnknownTag,
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_text_is_human_readable() {
+        let msg = Message::PeerFound {
+            id: "bob".to_string(),
+            addr: "[::1]:9000".parse().unwrap(),
+            public_key: vec![0xbe, 0xef],
+        };
+        assert_eq!(msg.encode_text(), "PEER|bob|[::1]:9000|beef");
+        assert!(matches!(
+            Message::decode(b"FIND|bob\n"),
+            Ok(Message::Discover { target }) if target == "bob"
+        ));
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let mut data = Message::Discover {
+            target: "bob".to_string(),
+        }
+        .encode();
+        data[2] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Message::decode(&data).unwrap_err(),
+            ProtocolError::UnsupportedVersion {
+                version: PROTOCOL_VERSION + 1,
+                offset: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_peer_messages() {
+        let msgs = vec![
+            PeerMessage::Punch {
+                from: "alice".to_string(),
+                seq: 3,
+            },
+            PeerMessage::PunchAck {
+                from: "bob".to_string(),
+                seq: 3,
+            },
+            PeerMessage::Data {
+                payload: "PUNCH:0 is just text".to_string(),
+            },
+            PeerMessage::Keepalive { seq: 9 },
+            PeerMessage::KeepaliveAck { seq: 9 },
+            PeerMessage::Nominate {
+                from: "alice".to_string(),
+                seq: 1,
+            },
+            PeerMessage::Close {
+                from: "alice".to_string(),
+            },
+            PeerMessage::HandshakeInit {
+                from: "alice".to_string(),
+                payload: vec![0; 64],
+            },
+            PeerMessage::HandshakeReply {
+                from: "bob".to_string(),
+                payload: vec![0xff; 48],
+            },
+            PeerMessage::Sealed {
+                from: "alice".to_string(),
+                nonce: 1 << 40,
+                payload: b"ciphertext".to_vec(),
+            },
+            PeerMessage::StreamData {
+                stream_id: 2,
+                seq: 7,
+                payload: vec![],
+            },
+            PeerMessage::StreamAck {
+                stream_id: 2,
+                ack: 7,
+                sack: 0b101,
+                window: 65536,
+            },
+            PeerMessage::FileOffer {
+                stream_id: 4,
+                name: "notes.txt".to_string(),
+                size: 1 << 33,
+                sha256: vec![0xab; 32],
+            },
+            PeerMessage::FileAccept {
+                stream_id: 4,
+                offset: 4096,
+                prefix_sha256: vec![0xcd; 32],
+            },
+            PeerMessage::FileReject {
+                stream_id: 4,
+                reason: "no thanks".to_string(),
+            },
+        ];
+        for msg in msgs {
+            let data = msg.encode();
+            assert_eq!(PeerMessage::decode(&data).unwrap().encode(), data);
+            // the tag ranges never overlap
+            assert_eq!(
+                Message::decode(&data).unwrap_err().kind(),
+                ProtocolErrorKind::UnknownTag
+            );
+            let text = msg.encode_text();
+            assert_eq!(PeerMessage::decode_text(&text).unwrap().encode_text(), text);
+            assert_eq!(
+                Message::decode_text(&text).unwrap_err().kind(),
+                ProtocolErrorKind::UnknownTag,
+                "{}",
+                text
+            );
+        }
+
+        let data = Message::Discover {
+            target: "bob".to_string(),
+        }
+        .encode();
+        assert!(PeerMessage::decode(&data).is_err());
+        assert!(matches!(
+            PeerMessage::decode(b"MSG|PUNCH:1"),
+            Ok(PeerMessage::Data { payload }) if payload == "PUNCH:1"
+        ));
+    }
+
+    #[test]
+    fn test_error_categories() {
+        let err = Message::decode(b"REG|alice|notaport").unwrap_err();
+        assert_eq!(
+            err,
+            ProtocolError::BadPort {
+                field: "port",
+                offset: 10
             }
-            "NOPE" => Ok(Message::PeerNotFound {
-                id: parts[1].to_string(),
-            }),
-            "PUNCH" if parts.len() == 3 => Ok(Message::HolePunch {
-                from: parts[1].to_string(),
-                to: parts[2].to_string(),
-            }),
-            "START" => Ok(Message::StartPunch {
-                timestamp: parts[1].parse().map_err(|_| "Invalid timestamp")?,
-            }),
-            _ => Err("Unknown message type"),
+        );
+
+        let err = Message::decode(b"START_PEER|bob|1.2.3.4:5||soon").unwrap_err();
+        assert_eq!(err.kind(), ProtocolErrorKind::BadTimestamp);
+        assert_eq!(err.offset(), 26);
+
+        let err = Message::decode(b"REG|alice|5000|xyz").unwrap_err();
+        assert_eq!(err.kind(), ProtocolErrorKind::BadString);
+        assert_eq!(err.field(), "public_key");
+
+        let err = Message::decode(b"PEER|bob|not-an-addr").unwrap_err();
+        assert_eq!(err.kind(), ProtocolErrorKind::BadAddress);
+        assert_eq!(err.field(), "addr");
+
+        assert_eq!(
+            Message::decode(b"FIND|bob|extra").unwrap_err().kind(),
+            ProtocolErrorKind::WrongFieldCount
+        );
+        assert_eq!(
+            Message::decode(b"HELLO|bob").unwrap_err().kind(),
+            ProtocolErrorKind::UnknownTag
+        );
+
+        // binary: truncated second field of a HolePunch
+        let data = Message::HolePunch {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
         }
+        .encode();
+        let err = Message::decode(&data[..data.len() - 1]).unwrap_err();
+        assert_eq!(
+            err,
+            ProtocolError::WrongFieldCount {
+                field: "to",
+                offset: 14
+            }
+        );
     }
 }
@@ -0,0 +1,419 @@
+use snow::{Builder, HandshakeState, Keypair, StatelessTransportState};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Both peers learn each other's static key from the signaling server
+/// before they talk, which is exactly what the KK pattern expects.
+pub const NOISE_PARAMS: &str = "Noise_KK_25519_ChaChaPoly_SHA256";
+// Noise caps every message at 64KiB
+const MAX_NOISE_MESSAGE: usize = 65535;
+// how far behind the newest nonce a packet may arrive and still be accepted
+const REPLAY_WINDOW: u64 = 64;
+// sessions answered per peer that have yet to open a packet, oldest dropped
+const MAX_UNTESTED: usize = 4;
+// inits remembered per peer, so answering one again costs no new session
+const MAX_ANSWERED: usize = 16;
+
+/// Why a handshake or sealed packet was rejected.
+#[derive(Debug)]
+pub enum SecureError {
+    /// The server never told us this peer's static key.
+    NoPeerKey(String),
+    /// No finished handshake with this peer.
+    NoSession(String),
+    /// A handshake reply arrived but we had not started a handshake.
+    NotInitiating(String),
+    /// The nonce was already used or is too old for the replay window.
+    Replay(u64),
+    /// Decryption or a handshake step failed.
+    Noise(snow::Error),
+}
+
+impl fmt::Display for SecureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecureError::NoPeerKey(id) => write!(f, "no public key for '{}'", id),
+            SecureError::NoSession(id) => write!(f, "no secure session with '{}'", id),
+            SecureError::NotInitiating(id) => {
+                write!(f, "unexpected handshake reply from '{}'", id)
+            }
+            SecureError::Replay(nonce) => write!(f, "replayed nonce {}", nonce),
+            SecureError::Noise(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SecureError {}
+
+impl From<snow::Error> for SecureError {
+    fn from(e: snow::Error) -> Self {
+        SecureError::Noise(e)
+    }
+}
+
+fn builder() -> Builder<'static> {
+    Builder::new(NOISE_PARAMS.parse().expect("valid Noise parameters"))
+}
+
+// Sliding bitmap over the last REPLAY_WINDOW nonces; bit n is `next - 1 - n`.
+#[derive(Default)]
+pub(crate) struct ReplayWindow {
+    next: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    pub(crate) fn is_fresh(&self, nonce: u64) -> bool {
+        if nonce == u64::MAX {
+            return false;
+        }
+        if nonce >= self.next {
+            return true;
+        }
+        let age = self.next - 1 - nonce;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+    }
+
+    // only after the packet authenticated, so forgeries can't move the window
+    pub(crate) fn mark(&mut self, nonce: u64) {
+        if nonce >= self.next {
+            let shift = nonce - self.next + 1;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = nonce + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - nonce);
+        }
+    }
+}
+
+struct Session {
+    transport: StatelessTransportState,
+    send_nonce: u64,
+    replay: ReplayWindow,
+}
+
+impl Session {
+    fn new(handshake: HandshakeState) -> Result<Self, SecureError> {
+        Ok(Self {
+            transport: handshake.into_stateless_transport_mode()?,
+            send_nonce: 0,
+            replay: ReplayWindow::default(),
+        })
+    }
+
+    fn open(&mut self, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, SecureError> {
+        if !self.replay.is_fresh(nonce) {
+            return Err(SecureError::Replay(nonce));
+        }
+        let mut buf = vec![0; ciphertext.len()];
+        let len = self.transport.read_message(nonce, ciphertext, &mut buf)?;
+        buf.truncate(len);
+        self.replay.mark(nonce);
+        Ok(buf)
+    }
+}
+
+// an init and our reply to it
+type Answered = (Vec<u8>, Vec<u8>);
+
+/// Our static key plus the Noise state for every peer.
+///
+/// Whoever calls `Client::connect_to_peer` initiates; the other side
+/// responds from its background listener. Packets carry their nonce so they
+/// can be decrypted out of order, with a replay window on top.
+pub struct Channels {
+    keypair: Keypair,
+    peer_keys: HashMap<String, Vec<u8>>,
+    pending: HashMap<String, HandshakeState>,
+    // the inits we answered and our replies, oldest first, so a
+    // retransmitted init gets the same reply and both ends keep agreeing on
+    // the session keys, and a replayed one gets nothing new
+    answered: HashMap<String, Vec<Answered>>,
+    sessions: HashMap<String, Session>,
+    // from inits we answered while a session was live, oldest first; one
+    // only replaces that session once it decrypts a packet, as a replayed
+    // init never will
+    untested: HashMap<String, Vec<Session>>,
+}
+
+impl Channels {
+    pub fn new() -> Result<Self, SecureError> {
+        Ok(Self {
+            keypair: builder().generate_keypair()?,
+            peer_keys: HashMap::new(),
+            pending: HashMap::new(),
+            answered: HashMap::new(),
+            sessions: HashMap::new(),
+            untested: HashMap::new(),
+        })
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.keypair.public
+    }
+
+    /// Remember the static key the server reported for `peer_id`. A changed
+    /// key invalidates everything negotiated under the old one.
+    pub fn set_peer_key(&mut self, peer_id: &str, key: Vec<u8>) {
+        if self.peer_keys.get(peer_id).is_some_and(|k| *k != key) {
+            self.forget(peer_id);
+        }
+        self.peer_keys.insert(peer_id.to_string(), key);
+    }
+
+    pub fn has_session(&self, peer_id: &str) -> bool {
+        self.sessions.contains_key(peer_id)
+    }
+
+    pub fn is_initiating(&self, peer_id: &str) -> bool {
+        self.pending.contains_key(peer_id)
+    }
+
+    /// Start a handshake with `peer_id`; returns the first message.
+    pub fn initiate(&mut self, peer_id: &str) -> Result<Vec<u8>, SecureError> {
+        let mut handshake = builder()
+            .local_private_key(&self.keypair.private)
+            .remote_public_key(self.peer_key(peer_id)?)
+            .build_initiator()?;
+        let mut buf = vec![0; MAX_NOISE_MESSAGE];
+        let len = handshake.write_message(&[], &mut buf)?;
+        buf.truncate(len);
+        self.pending.insert(peer_id.to_string(), handshake);
+        Ok(buf)
+    }
+
+    /// Answer a handshake from `peer_id`, which completes the session on
+    /// our side. Returns the reply to send back. A live session stays until
+    /// the new one has opened a packet: anyone who recorded an init could
+    /// replay it, but only the initiator can seal under its session. For the
+    /// same reason our own handshake with `peer_id`, if any, carries on.
+    pub fn respond(&mut self, peer_id: &str, init: &[u8]) -> Result<Vec<u8>, SecureError> {
+        let answered = self.answered.get(peer_id);
+        if let Some((_, reply)) = answered.and_then(|a| a.iter().find(|(seen, _)| seen == init)) {
+            return Ok(reply.clone());
+        }
+
+        let mut handshake = builder()
+            .local_private_key(&self.keypair.private)
+            .remote_public_key(self.peer_key(peer_id)?)
+            .build_responder()?;
+        let mut scratch = vec![0; MAX_NOISE_MESSAGE];
+        handshake.read_message(init, &mut scratch)?;
+        let mut reply = vec![0; MAX_NOISE_MESSAGE];
+        let len = handshake.write_message(&[], &mut reply)?;
+        reply.truncate(len);
+
+        let session = Session::new(handshake)?;
+        if self.sessions.contains_key(peer_id) {
+            push_bounded(self.untested.entry(peer_id.to_string()).or_default(), session, MAX_UNTESTED);
+        } else {
+            self.sessions.insert(peer_id.to_string(), session);
+        }
+        let answered = self.answered.entry(peer_id.to_string()).or_default();
+        push_bounded(answered, (init.to_vec(), reply.clone()), MAX_ANSWERED);
+        Ok(reply)
+    }
+
+    /// Finish a handshake we started with the reply from `peer_id`.
+    pub fn complete(&mut self, peer_id: &str, reply: &[u8]) -> Result<(), SecureError> {
+        let mut handshake = self
+            .pending
+            .remove(peer_id)
+            .ok_or_else(|| SecureError::NotInitiating(peer_id.to_string()))?;
+        let mut scratch = vec![0; MAX_NOISE_MESSAGE];
+        if let Err(e) = handshake.read_message(reply, &mut scratch) {
+            // a forged reply must not cost us the real one
+            self.pending.insert(peer_id.to_string(), handshake);
+            return Err(e.into());
+        }
+        self.answered.remove(peer_id);
+        self.untested.remove(peer_id);
+        self.sessions
+            .insert(peer_id.to_string(), Session::new(handshake)?);
+        Ok(())
+    }
+
+    /// Encrypt for `peer_id`; returns the nonce to send along.
+    pub fn seal(&mut self, peer_id: &str, plaintext: &[u8]) -> Result<(u64, Vec<u8>), SecureError> {
+        let session = self
+            .sessions
+            .get_mut(peer_id)
+            .ok_or_else(|| SecureError::NoSession(peer_id.to_string()))?;
+        let nonce = session.send_nonce;
+        let mut buf = vec![0; plaintext.len() + 16];
+        let len = session.transport.write_message(nonce, plaintext, &mut buf)?;
+        buf.truncate(len);
+        session.send_nonce += 1;
+        Ok((nonce, buf))
+    }
+
+    /// Decrypt a packet from `peer_id`, rejecting forgeries and replays.
+    pub fn open(&mut self, peer_id: &str, nonce: u64, ciphertext: &[u8]) -> Result<Vec<u8>, SecureError> {
+        let opened = match self.sessions.get_mut(peer_id) {
+            Some(session) => session.open(nonce, ciphertext),
+            None => Err(SecureError::NoSession(peer_id.to_string())),
+        };
+        if opened.is_ok() {
+            return opened;
+        }
+        // the peer may have moved on to a session we answered since
+        let Some(untested) = self.untested.get_mut(peer_id) else {
+            return opened;
+        };
+        for i in (0..untested.len()).rev() {
+            if let Ok(plaintext) = untested[i].open(nonce, ciphertext) {
+                // anything answered before it is stale or replayed
+                let session = untested.remove(i);
+                untested.drain(..i);
+                self.sessions.insert(peer_id.to_string(), session);
+                return Ok(plaintext);
+            }
+        }
+        opened
+    }
+
+    /// Drop all handshake and session state for `peer_id`.
+    pub fn forget(&mut self, peer_id: &str) {
+        self.pending.remove(peer_id);
+        self.answered.remove(peer_id);
+        self.sessions.remove(peer_id);
+        self.untested.remove(peer_id);
+    }
+
+    fn peer_key(&self, peer_id: &str) -> Result<&[u8], SecureError> {
+        self.peer_keys
+            .get(peer_id)
+            .map(|k| k.as_slice())
+            .filter(|k| !k.is_empty())
+            .ok_or_else(|| SecureError::NoPeerKey(peer_id.to_string()))
+    }
+}
+
+fn push_bounded<T>(list: &mut Vec<T>, item: T, max: usize) {
+    if list.len() == max {
+        list.remove(0);
+    }
+    list.push(item);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Channels, Channels) {
+        let mut alice = Channels::new().unwrap();
+        let mut bob = Channels::new().unwrap();
+        alice.set_peer_key("bob", bob.public_key().to_vec());
+        bob.set_peer_key("alice", alice.public_key().to_vec());
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_handshake_and_replay() {
+        let (mut alice, mut bob) = pair();
+        let init = alice.initiate("bob").unwrap();
+        let reply = bob.respond("alice", &init).unwrap();
+        // a retransmitted init gets the very same reply
+        assert_eq!(bob.respond("alice", &init).unwrap(), reply);
+        alice.complete("bob", &reply).unwrap();
+        assert!(alice.has_session("bob") && bob.has_session("alice"));
+
+        let (n0, first) = alice.seal("bob", b"hello").unwrap();
+        let (n1, second) = alice.seal("bob", b"again").unwrap();
+        // out of order is fine, twice is not
+        assert_eq!(bob.open("alice", n1, &second).unwrap(), b"again");
+        assert_eq!(bob.open("alice", n0, &first).unwrap(), b"hello");
+        assert!(matches!(bob.open("alice", n0, &first), Err(SecureError::Replay(0))));
+
+        let (n2, mut forged) = bob.seal("alice", b"pay bob").unwrap();
+        forged[0] ^= 1;
+        assert!(matches!(alice.open("bob", n2, &forged), Err(SecureError::Noise(_))));
+    }
+
+    #[test]
+    fn test_replayed_init_keeps_session() {
+        let (mut alice, mut bob) = pair();
+        let old_init = alice.initiate("bob").unwrap();
+        let reply = bob.respond("alice", &old_init).unwrap();
+        alice.complete("bob", &reply).unwrap();
+
+        // alice reconnects; bob moves over once her first packet opens
+        let init = alice.initiate("bob").unwrap();
+        let reply = bob.respond("alice", &init).unwrap();
+        let (n, stale) = bob.seal("alice", b"old session").unwrap();
+        alice.complete("bob", &reply).unwrap();
+        assert!(alice.open("bob", n, &stale).is_err());
+        let (n, sealed) = alice.seal("bob", b"new session").unwrap();
+        assert_eq!(bob.open("alice", n, &sealed).unwrap(), b"new session");
+
+        // a recording of the first init gets an answer, but changes nothing
+        bob.respond("alice", &old_init).unwrap();
+        let (n, sealed) = alice.seal("bob", b"still new").unwrap();
+        assert_eq!(bob.open("alice", n, &sealed).unwrap(), b"still new");
+        let (n, sealed) = bob.seal("alice", b"and back").unwrap();
+        assert_eq!(alice.open("bob", n, &sealed).unwrap(), b"and back");
+    }
+
+    #[test]
+    fn test_replayed_init_during_reconnect() {
+        let (mut alice, mut bob) = pair();
+        let mut recorded = Vec::new();
+        for _ in 0..3 {
+            let init = alice.initiate("bob").unwrap();
+            let reply = bob.respond("alice", &init).unwrap();
+            alice.complete("bob", &reply).unwrap();
+            recorded.push(init);
+        }
+
+        // every old init replayed while alice reconnects and bob starts a
+        // handshake of his own
+        let bob_init = bob.initiate("alice").unwrap();
+        let init = alice.initiate("bob").unwrap();
+        let reply = bob.respond("alice", &init).unwrap();
+        for old in &recorded {
+            bob.respond("alice", old).unwrap();
+        }
+        assert!(bob.is_initiating("alice"));
+
+        // alice's reconnect still goes through
+        alice.complete("bob", &reply).unwrap();
+        let (n, sealed) = alice.seal("bob", b"reconnected").unwrap();
+        assert_eq!(bob.open("alice", n, &sealed).unwrap(), b"reconnected");
+        let (n, sealed) = bob.seal("alice", b"and back").unwrap();
+        assert_eq!(alice.open("bob", n, &sealed).unwrap(), b"and back");
+
+        // and so does bob's
+        let bob_reply = alice.respond("bob", &bob_init).unwrap();
+        bob.complete("alice", &bob_reply).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_static_key_fails() {
+        let (mut alice, _) = pair();
+        let mut mallory = Channels::new().unwrap();
+        mallory.set_peer_key("alice", alice.public_key().to_vec());
+
+        // an init meant for bob is useless to anyone without bob's key
+        let init = alice.initiate("bob").unwrap();
+        assert!(mallory.respond("alice", &init).is_err());
+
+        // and mallory can't pass for bob towards alice
+        let init = mallory.initiate("alice").unwrap();
+        assert!(alice.respond("bob", &init).is_err());
+        assert!(!alice.has_session("bob"));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut w = ReplayWindow::default();
+        for n in [0, 5, 3, 100] {
+            assert!(w.is_fresh(n));
+            w.mark(n);
+            assert!(!w.is_fresh(n));
+        }
+        assert!(w.is_fresh(99));
+        assert!(!w.is_fresh(100 - REPLAY_WINDOW));
+        assert!(!w.is_fresh(u64::MAX));
+    }
+}
@@ -1,52 +1,330 @@
-use crate::protocol::Message;
+use crate::auth::{self, Credentials};
+use crate::clock::{Clock, SystemClock};
+use crate::ice::Candidate;
+use crate::protocol::{Message, ProtocolError, ProtocolErrorKind, WireFormat, MAX_CANDIDATES, MAX_ID_LEN};
+use crate::stun::{self, BindingError, BindingRequest, BindingResponse};
+use crate::transport::DatagramTransport;
 use std::collections::HashMap;
 use std::io;
 use std::net::{SocketAddr, UdpSocket};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "async")]
+mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncServer;
+
+/// How long a registration lives without a heartbeat.
+pub const DEFAULT_REGISTRATION_TTL: Duration = Duration::from_secs(60);
+// also the socket read timeout, so the sweep runs even when nobody talks to us
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// how far ahead a coordinated punch starts, so both sides have the message
+const PUNCH_LEAD: Duration = Duration::from_millis(2000);
+
+/// Limits for each relayed pair, see `Server::enable_relay`.
+#[derive(Debug, Clone, Copy)]
+pub struct RelayConfig {
+    /// Bytes forwarded in both directions before the relay closes.
+    pub quota_bytes: u64,
+    /// How long a relay lives, however much traffic it carries.
+    pub lifetime: Duration,
+    /// Relays open at once, over all clients.
+    pub max_relays: usize,
+    /// Relays open at once that one client asked for. Being the other
+    /// side of a relay doesn't count, so nobody can use up someone else's.
+    pub max_per_client: usize,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            quota_bytes: 16 * 1024 * 1024,
+            lifetime: Duration::from_secs(600),
+            max_relays: 64,
+            max_per_client: 4,
+        }
+    }
+}
+
+// what the main loop keeps of a relay thread
+struct RelayHandle {
+    port: u16,
+    // tokens in the same order as the pair's ids
+    tokens: [u64; 2],
+    lifetime_secs: u32,
+    closed: Arc<AtomicBool>,
+    // whose `max_per_client` it counts against
+    requester: String,
+}
+
+// one client of a relay, and where to tell it that the relay closed
+struct RelaySide {
+    id: String,
+    token: u64,
+    control: SocketAddr,
+    format: WireFormat,
+    server_key: Vec<u8>,
+}
+
+struct Registration {
+    // source address of the packet that registered it, i.e. the NAT mapping
+    addr: SocketAddr,
+    expires_at: Instant,
+    // static Noise key, passed on to peers that look this client up
+    public_key: Vec<u8>,
+    // what the client advertised with `Candidates` since registering
+    candidates: Vec<Candidate>,
+    // (next port, step) from `PortAllocation`, zeros if none
+    allocation: (u16, u16),
+    // what we sign our packets to the client with; empty: we don't
+    server_key: Vec<u8>,
+}
 
 pub struct Server {
-    socket: UdpSocket,
-    clients: HashMap<String, SocketAddr>,
+    socket: Arc<dyn DatagramTransport>,
+    clients: HashMap<String, Registration>,
+    registration_ttl: Duration,
+    last_sweep: Instant,
+    // last format seen from each address, so replies match what the client speaks
+    wire_formats: HashMap<SocketAddr, WireFormat>,
+    malformed: HashMap<ProtocolErrorKind, u64>,
+    // primary socket first, then alternates; only STUN is served on alternates
+    stun_sockets: Vec<Arc<dyn DatagramTransport>>,
+    // ids listed here may only register with a valid MAC
+    credentials: Credentials,
+    // highest accepted `AuthRegister` nonce per id, against replays
+    nonces: HashMap<String, u64>,
+    // for `Signed`; starts at the wall clock so a restart doesn't go back
+    seq: Arc<AtomicU64>,
+    // None unless relaying was enabled
+    relay: Option<RelayConfig>,
+    // keyed by the pair's ids, sorted
+    relays: HashMap<(String, String), RelayHandle>,
+    // what punch start times are taken from
+    clock: Arc<dyn Clock>,
+    // relays run as tasks on this runtime instead of threads, see `AsyncServer`
+    #[cfg(feature = "async")]
+    runtime: Option<tokio::runtime::Handle>,
 }
 
 impl Server {
     pub fn new(addr: &str) -> io::Result<Self> {
-        let socket = UdpSocket::bind(addr)?;
-        println!("📡 Server listening on {}", socket.local_addr()?);
+        Self::with_transport(Arc::new(UdpSocket::bind(addr)?))
+    }
+
+    /// Serve on `socket` instead of a UDP socket of our own, e.g. one on a
+    /// simulated network (see `sim`).
+    pub fn with_transport(socket: Arc<dyn DatagramTransport>) -> io::Result<Self> {
+        socket.set_read_timeout(Some(SWEEP_INTERVAL))?;
+        say!("📡 Server listening on {}", socket.local_addr()?);
+        let stun_sockets = vec![socket.clone()];
         Ok(Self {
             socket,
+            stun_sockets,
             clients: HashMap::new(),
+            registration_ttl: DEFAULT_REGISTRATION_TTL,
+            last_sweep: Instant::now(),
+            wire_formats: HashMap::new(),
+            malformed: HashMap::new(),
+            credentials: Credentials::default(),
+            nonces: HashMap::new(),
+            seq: Arc::new(AtomicU64::new(now_micros())),
+            relay: None,
+            relays: HashMap::new(),
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "async")]
+            runtime: None,
         })
     }
 
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Change how long registrations survive without a heartbeat.
+    pub fn set_registration_ttl(&mut self, ttl: Duration) {
+        self.registration_ttl = ttl;
+    }
+
+    /// Take punch start times from `clock` instead of the system clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Require authentication for the ids in `credentials`. Ids without an
+    /// entry may still register unauthenticated, but can't take over an id
+    /// that is live at another address.
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        say!("🔐 {} protected client id(s)", credentials.len());
+        self.credentials = credentials;
+    }
+
+    /// `set_credentials` from a file, see `Credentials` for the format.
+    pub fn load_credentials(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.set_credentials(Credentials::load(path)?);
+        Ok(())
+    }
+
+    /// Forward traffic between clients whose hole punch failed. Each pair
+    /// gets its own relay port on this server's IP, closed once `config`'s
+    /// quota or lifetime runs out. Requests past its relay limits are
+    /// denied until some relay closes.
+    pub fn enable_relay(&mut self, config: RelayConfig) {
+        say!(
+            "🔁 Relay enabled: {} bytes per pair for {}s",
+            config.quota_bytes,
+            config.lifetime.as_secs()
+        );
+        self.relay = Some(config);
+    }
+
+    /// Also answer STUN on `addr`. With an alternate that differs in IP and
+    /// port (and ideally one that differs in port only) clients can run the
+    /// RFC 5780 behavior tests behind `Client::detect_nat_type`.
+    pub fn add_alternate(&mut self, addr: &str) -> io::Result<()> {
+        self.add_alternate_transport(Arc::new(UdpSocket::bind(addr)?))
+    }
+
+    /// `add_alternate` with a socket of the caller's.
+    pub fn add_alternate_transport(&mut self, socket: Arc<dyn DatagramTransport>) -> io::Result<()> {
+        socket.set_read_timeout(None)?;
+        say!("📡 Alternate STUN address {}", socket.local_addr()?);
+        self.stun_sockets.push(socket);
+        Ok(())
+    }
+
     pub fn run(&mut self) -> io::Result<()> {
-        let mut buf = [0; 1024];
+        // a full candidate list in text, under a long id, runs past 1KiB
+        let mut buf = vec![0; 65536];
+
+        for index in 1..self.stun_sockets.len() {
+            let sockets = self.stun_sockets.clone();
+            thread::spawn(move || serve_alternate(sockets, index));
+        }
 
         loop {
-            let (len, client_addr) = self.socket.recv_from(&mut buf)?;
-            let msg_str = String::from_utf8_lossy(&buf[..len]);
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, client_addr)) => self.handle_datagram(&buf[..len], client_addr)?,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            self.sweep_if_due();
+        }
+    }
 
-            match Message::decode(&msg_str) {
-                Ok(msg) => self.handle_message(msg, client_addr)?,
-                Err(e) => println!("❌ Parse error: {}", e),
+    // One datagram on the primary socket: STUN or signaling.
+    fn handle_datagram(&mut self, packet: &[u8], client_addr: SocketAddr) -> io::Result<()> {
+        if stun::is_stun(packet) {
+            match BindingRequest::decode(packet) {
+                Ok(request) => answer_stun_binding(&self.stun_sockets, 0, &request, client_addr)?,
+                Err(e) => self.record_malformed(&e, client_addr),
             }
+            return Ok(());
         }
+
+        self.wire_formats
+            .insert(client_addr, WireFormat::detect(packet));
+        match Message::decode(packet) {
+            Ok(msg) => self.handle_message(msg, client_addr)?,
+            Err(e) => self.record_malformed(&e, client_addr),
+        }
+        Ok(())
+    }
+
+    fn sweep_if_due(&mut self) {
+        if self.last_sweep.elapsed() >= SWEEP_INTERVAL {
+            self.sweep_expired();
+        }
+    }
+
+    /// Drop registrations whose TTL ran out.
+    fn sweep_expired(&mut self) {
+        let now = Instant::now();
+        self.last_sweep = now;
+        self.clients.retain(|id, reg| {
+            let alive = reg.expires_at > now;
+            if !alive {
+                say!("⌛ Registration for {} at {} expired", id, reg.addr);
+            }
+            alive
+        });
+
+        let clients = &self.clients;
+        self.wire_formats
+            .retain(|addr, _| clients.values().any(|reg| reg.addr == *addr));
+        self.relays
+            .retain(|_, relay| !relay.closed.load(Ordering::Relaxed));
+    }
+
+    fn lookup(&self, id: &str) -> Option<(SocketAddr, Vec<u8>)> {
+        self.clients
+            .get(id)
+            .map(|reg| (reg.addr, reg.public_key.clone()))
     }
 
     fn handle_message(&mut self, msg: Message, addr: SocketAddr) -> io::Result<()> {
         match msg {
-            Message::Register { id, port } => {
-                let external_addr = SocketAddr::new(addr.ip(), port);
-                self.clients.insert(id.clone(), external_addr);
-                let response = Message::RegisterOk { external_addr };
-                self.send_to(&response, addr)?;
-                println!("✅ Registered {} at {}", id, external_addr);
+            Message::Register {
+                id,
+                port,
+                public_key,
+                server_key,
+            } => {
+                let now = Instant::now();
+                if id.len() > MAX_ID_LEN {
+                    self.deny_registration(id, "id too long", addr)?;
+                } else if self.credentials.key(&id).is_some() {
+                    self.deny_registration(id, "authentication required", addr)?;
+                } else if self.clients.get(&id).is_some_and(|reg| {
+                    // the static key is public, so it proves nothing; a
+                    // client whose mapping moved waits out the old entry
+                    // or uses `AuthRegister`
+                    reg.addr != addr && reg.expires_at > now
+                }) {
+                    self.deny_registration(id, "id is registered from another address", addr)?;
+                } else {
+                    self.accept_registration(id, port, public_key, server_key, addr)?;
+                }
+            }
+            Message::AuthRegister {
+                id,
+                port,
+                public_key,
+                nonce,
+                mac,
+            } => {
+                let fields = auth::RegisterFields {
+                    id: &id,
+                    port,
+                    public_key: &public_key,
+                    nonce,
+                };
+                let verified = self
+                    .credentials
+                    .key(&id)
+                    .is_some_and(|key| auth::verify_register(key, fields, &mac));
+                if !verified {
+                    self.deny_registration(id, "bad credentials", addr)?;
+                } else if self.nonces.get(&id).is_some_and(|last| nonce <= *last) {
+                    self.deny_registration(id, "stale nonce", addr)?;
+                } else {
+                    self.nonces.insert(id.clone(), nonce);
+                    let server_key = self.credentials.key(&id).map(auth::server_key).unwrap_or_default();
+                    self.accept_registration(id, port, public_key, server_key, addr)?;
+                }
             }
             Message::Discover { target } => {
-                if let Some(&peer_addr) = self.clients.get(&target) {
+                if let Some((peer_addr, public_key)) = self.lookup(&target) {
                     let response = Message::PeerFound {
                         id: target,
                         addr: peer_addr,
+                        public_key,
                     };
                     self.send_to(&response, addr)?;
                 } else {
@@ -55,44 +333,850 @@ impl Server {
                 }
             }
             Message::HolePunch { from, to } => {
-                if let (Some(&from_addr), Some(&to_addr)) =
-                    (self.clients.get(&from), self.clients.get(&to))
+                // only the registered client may ask to punch on its behalf,
+                // as with relays
+                if self.lookup(&from).is_none_or(|(from_addr, _)| from_addr != addr) {
+                    say!("🚫 Ignoring hole punch for {} from {}", from, addr);
+                    self.send_to(&Message::NotRegistered { id: from }, addr)?;
+                    return Ok(());
+                }
+                if let (Some((from_addr, from_key)), Some((to_addr, to_key))) =
+                    (self.lookup(&from), self.lookup(&to))
                 {
-                    let timestamp = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u64
-                        + 2000;
+                    let delay_ms = PUNCH_LEAD.as_millis() as u32;
+                    let timestamp = self.clock.unix_millis() + delay_ms as u64;
 
+                    let (next_port, step) = self.clients[&to].allocation;
                     let start_msg_to_requester = Message::StartPunchWithPeer {
                         timestamp,
+                        delay_ms,
+                        peer_id: to.clone(),
                         peer_addr: to_addr,
+                        peer_key: to_key,
+                        peer_candidates: self.clients[&to].candidates.clone(),
+                        peer_next_port: next_port,
+                        peer_port_step: step,
                     };
                     self.send_to(&start_msg_to_requester, from_addr)?;
 
+                    let (next_port, step) = self.clients[&from].allocation;
                     let start_msg_to_target = Message::StartPunchWithPeer {
                         timestamp,
+                        delay_ms,
+                        peer_id: from.clone(),
                         peer_addr: from_addr,
+                        peer_key: from_key,
+                        peer_candidates: self.clients[&from].candidates.clone(),
+                        peer_next_port: next_port,
+                        peer_port_step: step,
                     };
                     self.send_to(&start_msg_to_target, to_addr)?;
 
-                    println!(
+                    say!(
                         "🕳️  Coordinating hole punch: {} ({}) ↔ {} ({})",
                         from, from_addr, to, to_addr
                     );
                 } else {
-                    println!("❌ Cannot coordinate hole punch: missing client addresses");
+                    say!("❌ Cannot coordinate hole punch: {} is not registered", to);
+                    self.send_to(&Message::PeerNotFound { id: to }, addr)?;
+                }
+            }
+            Message::Heartbeat { id } => {
+                let ttl = self.registration_ttl;
+                match self.clients.get_mut(&id) {
+                    Some(reg) if reg.addr == addr => {
+                        reg.expires_at = Instant::now() + ttl;
+                        let response = Message::HeartbeatAck {
+                            ttl_secs: ttl.as_secs() as u32,
+                        };
+                        self.send_to(&response, addr)?;
+                    }
+                    _ => {
+                        // expired, never registered, or the mapping moved:
+                        // tell the client to register again
+                        say!("💔 Heartbeat from unregistered {} at {}", id, addr);
+                        self.send_to(&Message::NotRegistered { id }, addr)?;
+                    }
+                }
+            }
+            Message::Candidates { id, mut candidates } => match self.clients.get_mut(&id) {
+                // only the registered client may say where it can be reached
+                Some(reg) if reg.addr == addr => {
+                    candidates.truncate(MAX_CANDIDATES);
+                    say!("🧭 {} advertises {} candidate(s)", id, candidates.len());
+                    reg.candidates = candidates;
+                }
+                _ => say!("🚫 Ignoring candidates for {} from {}", id, addr),
+            },
+            Message::PortAllocation { id, next_port, step } => match self.clients.get_mut(&id) {
+                Some(reg) if reg.addr == addr => {
+                    say!("🎲 {} expects its next port at {} (step {})", id, next_port, step);
+                    reg.allocation = (next_port, step);
+                }
+                _ => say!("🚫 Ignoring port allocation for {} from {}", id, addr),
+            },
+            Message::RelayRequest { from, to } => {
+                match self.allocate_relay(&from, &to, addr) {
+                    Ok(()) => {}
+                    Err(reason) => {
+                        say!("🚫 No relay {} ↔ {}: {}", from, to, reason);
+                        let response = Message::RelayDenied {
+                            peer_id: to,
+                            reason,
+                        };
+                        self.send_to(&response, addr)?;
+                    }
+                }
+            }
+            Message::TimeRequest { sent } => {
+                // the answer is bigger than the request, so a spoofed
+                // source would make it bounce off a stranger
+                if !self.clients.values().any(|reg| reg.addr == addr) {
+                    say!("🚫 Ignoring time request from unregistered {}", addr);
+                    return Ok(());
                 }
+                // answered on the spot, so receiving and replying are
+                // as good as the same moment
+                let now = self.clock.unix_millis();
+                let response = Message::TimeResponse {
+                    sent,
+                    received: now,
+                    replied: now,
+                };
+                self.send_to(&response, addr)?;
             }
             _ => {}
         }
         Ok(())
     }
 
+    /// Set up (or repeat the offer of) a relay between `from` and `to` and
+    /// tell both about it. The error is the reason sent back to `from`.
+    fn allocate_relay(&mut self, from: &str, to: &str, addr: SocketAddr) -> Result<(), String> {
+        let Some(config) = self.relay else {
+            return Err("relay disabled".to_string());
+        };
+        let from_addr = match self.lookup(from) {
+            Some((from_addr, _)) if from_addr == addr => from_addr,
+            _ => return Err(format!("{} is not registered from {}", from, addr)),
+        };
+        let Some((to_addr, _)) = self.lookup(to) else {
+            return Err(format!("{} is not registered", to));
+        };
+
+        let key = if from < to {
+            (from.to_string(), to.to_string())
+        } else {
+            (to.to_string(), from.to_string())
+        };
+        let live = self
+            .relays
+            .get(&key)
+            .is_some_and(|relay| !relay.closed.load(Ordering::Relaxed));
+        if !live {
+            self.check_relay_limits(from, config)?;
+            let relay = self
+                .spawn_relay(&key, from, config)
+                .map_err(|e| format!("relay allocation failed: {}", e))?;
+            say!(
+                "🔁 Relaying {} ↔ {} on port {}",
+                key.0, key.1, relay.port
+            );
+            self.relays.insert(key.clone(), relay);
+        }
+
+        let relay = &self.relays[&key];
+        let ids = [&key.0, &key.1];
+        for (i, id) in ids.into_iter().enumerate() {
+            let control = if id == from { from_addr } else { to_addr };
+            let msg = Message::RelayAllocated {
+                peer_id: ids[1 - i].clone(),
+                relay_port: relay.port,
+                token: relay.tokens[i],
+                lifetime_secs: relay.lifetime_secs,
+                quota_bytes: config.quota_bytes,
+            };
+            if let Err(e) = self.send_to(&msg, control) {
+                say!("❌ Could not tell {} about the relay: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_relay_limits(&self, requester: &str, config: RelayConfig) -> Result<(), String> {
+        let open: Vec<_> = self
+            .relays
+            .values()
+            .filter(|relay| !relay.closed.load(Ordering::Relaxed))
+            .collect();
+        if open.len() >= config.max_relays {
+            return Err("too many relays open".to_string());
+        }
+        let theirs = open.iter().filter(|relay| relay.requester == requester).count();
+        if theirs >= config.max_per_client {
+            return Err(format!("{} has too many relays open", requester));
+        }
+        Ok(())
+    }
+
+    fn spawn_relay(&self, ids: &(String, String), requester: &str, config: RelayConfig) -> io::Result<RelayHandle> {
+        let socket = self.socket.bind_sibling(0)?;
+        socket.set_read_timeout(Some(SWEEP_INTERVAL))?;
+        let port = socket.local_addr()?.port();
+        let tokens = [random_token(), random_token()];
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let mut sides = Vec::with_capacity(2);
+        for (id, token) in [&ids.0, &ids.1].into_iter().zip(tokens) {
+            let reg = self
+                .clients
+                .get(id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, id.clone()))?;
+            let format = self.wire_formats.get(&reg.addr).copied().unwrap_or_default();
+            sides.push(RelaySide {
+                id: id.clone(),
+                token,
+                control: reg.addr,
+                format,
+                server_key: reg.server_key.clone(),
+            });
+        }
+        let mut relay = Relay {
+            sides: sides.try_into().ok().unwrap(),
+            bound: [None, None],
+            expires_at: Instant::now() + config.lifetime,
+            forwarded: 0,
+            quota_bytes: config.quota_bytes,
+            port,
+            control_socket: self.socket.clone(),
+            seq: self.seq.clone(),
+            closed: closed.clone(),
+        };
+        let handle = RelayHandle {
+            port,
+            tokens,
+            lifetime_secs: config.lifetime.as_secs() as u32,
+            closed,
+            requester: requester.to_string(),
+        };
+
+        #[cfg(feature = "async")]
+        if let Some(runtime) = &self.runtime {
+            runtime.spawn(asynchronous::run_relay(socket, relay));
+            return Ok(handle);
+        }
+        thread::spawn(move || {
+            let reason = run_relay(&*socket, &mut relay);
+            relay.close(&reason);
+        });
+        Ok(handle)
+    }
+
+    fn accept_registration(
+        &mut self,
+        id: String,
+        port: u16,
+        public_key: Vec<u8>,
+        server_key: Vec<u8>,
+        addr: SocketAddr,
+    ) -> io::Result<()> {
+        // Peers must be sent to the mapping the NAT actually created,
+        // which is only the claimed port if the NAT preserves ports.
+        let claimed_addr = SocketAddr::new(addr.ip(), port);
+        self.clients.insert(
+            id.clone(),
+            Registration {
+                addr,
+                expires_at: Instant::now() + self.registration_ttl,
+                public_key,
+                candidates: Vec::new(),
+                allocation: (0, 0),
+                server_key,
+            },
+        );
+        let response = Message::RegisterOk {
+            external_addr: addr,
+            claimed_addr,
+        };
+        self.send_to(&response, addr)?;
+        if claimed_addr == addr {
+            say!("✅ Registered {} at {}", id, addr);
+        } else {
+            say!(
+                "✅ Registered {} at {} (claimed {}, NAT rewrote the port)",
+                id, addr, claimed_addr
+            );
+        }
+        Ok(())
+    }
+
+    fn deny_registration(&self, id: String, reason: &str, addr: SocketAddr) -> io::Result<()> {
+        say!("🚫 Refused registration of {} from {}: {}", id, addr, reason);
+        let response = Message::RegisterDenied {
+            id,
+            reason: reason.to_string(),
+        };
+        self.send_to(&response, addr)
+    }
+
+    /// Malformed packets received so far, by error category.
+    pub fn malformed_counts(&self) -> &HashMap<ProtocolErrorKind, u64> {
+        &self.malformed
+    }
+
+    fn record_malformed(&mut self, err: &ProtocolError, addr: SocketAddr) {
+        *self.malformed.entry(err.kind()).or_insert(0) += 1;
+
+        let mut counts: Vec<_> = self.malformed.iter().collect();
+        counts.sort();
+        let summary = counts
+            .iter()
+            .map(|(kind, n)| format!("{}: {}", kind, n))
+            .collect::<Vec<_>>()
+            .join(", ");
+        say!("❌ Malformed packet from {}: {}", addr, err);
+        say!("   Malformed so far → {}", summary);
+    }
+
+    // Signed if `addr` is a client that gave us a key.
     fn send_to(&self, msg: &Message, addr: SocketAddr) -> io::Result<()> {
-        let data = msg.encode();
-        self.socket.send_to(data.as_bytes(), addr)?;
-        println!("📤 Sent to {}: {}", addr, data);
+        let format = self.wire_formats.get(&addr).copied().unwrap_or_default();
+        let key = self
+            .clients
+            .values()
+            .find(|reg| reg.addr == addr)
+            .map_or(&[][..], |reg| &reg.server_key);
+        self.socket.send_to(&seal(msg, format, key, &self.seq), addr)?;
+        say!("📤 Sent to {}: {}", addr, msg);
         Ok(())
     }
 }
+
+/// Answer a STUN Binding Request with the reflexive address we saw.
+/// `sockets[receiving]` got the request; a CHANGE-REQUEST is honoured by
+/// answering from a socket whose address differs in exactly the requested
+/// way, or with a 420 error if we have no such socket.
+fn answer_stun_binding(
+    sockets: &[Arc<dyn DatagramTransport>],
+    receiving: usize,
+    request: &BindingRequest,
+    from: SocketAddr,
+) -> io::Result<()> {
+    let addrs = sockets
+        .iter()
+        .map(|s| s.local_addr())
+        .collect::<io::Result<Vec<_>>>()?;
+    let local = addrs[receiving];
+
+    let responder = addrs.iter().position(|a| {
+        (a.ip() != local.ip()) == request.change_ip && (a.port() != local.port()) == request.change_port
+    });
+    let Some(responder) = responder else {
+        let error = BindingError {
+            transaction_id: request.transaction_id,
+            code: 420,
+            reason: "No alternate address for CHANGE-REQUEST".to_string(),
+        };
+        sockets[receiving].send_to(&error.encode(), from)?;
+        return Ok(());
+    };
+
+    // prefer an alternate that differs in both IP and port, as RFC 5780 asks
+    let other_addr = addrs
+        .iter()
+        .find(|a| a.ip() != local.ip() && a.port() != local.port())
+        .or_else(|| addrs.iter().find(|a| **a != local))
+        .copied();
+
+    let response = BindingResponse {
+        transaction_id: request.transaction_id,
+        mapped_addr: from,
+        other_addr,
+    };
+    sockets[responder].send_to(&response.encode(), from)?;
+    say!(
+        "📤 STUN binding response to {} from {}",
+        from, addrs[responder]
+    );
+    Ok(())
+}
+
+// `msg` as sent to a client with server key `key`.
+fn seal(msg: &Message, format: WireFormat, key: &[u8], seq: &AtomicU64) -> Vec<u8> {
+    if key.is_empty() {
+        return msg.encode_as(format);
+    }
+    let seq = seq.fetch_add(1, Ordering::Relaxed);
+    auth::sign(key, seq, msg, format).encode_as(format)
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_micros() as u64
+}
+
+fn random_token() -> u64 {
+    let mut bytes = [0; 8];
+    getrandom::fill(&mut bytes).expect("no system randomness");
+    u64::from_be_bytes(bytes)
+}
+
+/// What one relay knows: who may use it and how much is left. A side is
+/// known by the source of its last valid `RelayBind`; anything else is
+/// forwarded untouched, it is sealed end to end anyway.
+struct Relay {
+    sides: [RelaySide; 2],
+    bound: [Option<SocketAddr>; 2],
+    expires_at: Instant,
+    forwarded: u64,
+    quota_bytes: u64,
+    port: u16,
+    // the main socket, to tell both sides when the relay closes
+    control_socket: Arc<dyn DatagramTransport>,
+    seq: Arc<AtomicU64>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Relay {
+    /// Where to forward `packet` from `from`, if anywhere, or why the relay
+    /// is done.
+    fn route(&mut self, packet: &[u8], from: SocketAddr) -> Result<Option<SocketAddr>, String> {
+        if let Ok(Message::RelayBind { id, token }) = Message::decode(packet) {
+            if let Some(i) = self.sides.iter().position(|s| s.id == id && s.token == token) {
+                if self.bound[i] != Some(from) {
+                    say!("🔁 {} bound relay port {} from {}", id, self.port, from);
+                }
+                self.bound[i] = Some(from);
+            }
+            return Ok(None);
+        }
+
+        let Some(i) = self.bound.iter().position(|b| *b == Some(from)) else {
+            return Ok(None);
+        };
+        if self.forwarded + packet.len() as u64 > self.quota_bytes {
+            return Err("quota exhausted".to_string());
+        }
+        Ok(self.bound[1 - i])
+    }
+
+    fn forwarded(&mut self, len: usize) {
+        self.forwarded += len as u64;
+    }
+
+    /// Mark the relay closed and tell both sides why.
+    fn close(&self, reason: &str) {
+        self.closed.store(true, Ordering::Relaxed);
+        let sides = &self.sides;
+        say!(
+            "🔁 Relay {} ↔ {} closed: {}",
+            sides[0].id, sides[1].id, reason
+        );
+        for (side, other) in [(&sides[0], &sides[1]), (&sides[1], &sides[0])] {
+            let msg = Message::RelayClosed {
+                peer_id: other.id.clone(),
+                reason: reason.to_string(),
+            };
+            let packet = seal(&msg, side.format, &side.server_key, &self.seq);
+            let _ = self.control_socket.send_to(&packet, side.control);
+        }
+    }
+}
+
+/// Forward datagrams between the two sides of a relay until its quota or
+/// lifetime runs out, returning why it stopped.
+fn run_relay(socket: &dyn DatagramTransport, relay: &mut Relay) -> String {
+    let mut buf = [0; 65536];
+    loop {
+        if Instant::now() >= relay.expires_at {
+            return "lifetime expired".to_string();
+        }
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => return format!("socket error: {}", e),
+        };
+
+        match relay.route(&buf[..len], from) {
+            Ok(Some(to)) => {
+                if socket.send_to(&buf[..len], to).is_ok() {
+                    relay.forwarded(len);
+                }
+            }
+            Ok(None) => {}
+            Err(reason) => return reason,
+        }
+    }
+}
+
+fn serve_alternate(sockets: Vec<Arc<dyn DatagramTransport>>, index: usize) {
+    let mut buf = [0; 1024];
+    loop {
+        match sockets[index].recv_from(&mut buf) {
+            Ok((len, from)) => answer_alternate(&sockets, index, &buf[..len], from),
+            Err(e) => {
+                say!("❌ Alternate STUN socket stopped: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// A packet on alternate `index`, where only STUN is served.
+fn answer_alternate(sockets: &[Arc<dyn DatagramTransport>], index: usize, packet: &[u8], from: SocketAddr) {
+    match BindingRequest::decode(packet) {
+        Ok(request) => {
+            if let Err(e) = answer_stun_binding(sockets, index, &request, from) {
+                say!("❌ STUN answer to {} failed: {}", from, e);
+            }
+        }
+        Err(e) => say!("❌ Ignoring non-STUN packet on alternate: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ice::{Candidate, CandidateKind};
+    use crate::transport::{MemoryNetwork, MemorySocket};
+    use std::thread;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn setup() -> (Server, MemoryNetwork) {
+        let net = MemoryNetwork::new();
+        let server = Server::with_transport(net.bind(addr("203.0.113.1:9090")).unwrap()).unwrap();
+        (server, net)
+    }
+
+    // Hand `msg` from `client` to the server, as its loop would.
+    fn send(server: &mut Server, client: &MemorySocket, msg: Message) {
+        let from = client.local_addr().unwrap();
+        server.handle_datagram(&msg.encode(), from).unwrap();
+    }
+
+    fn reply(client: &MemorySocket) -> Option<Message> {
+        reply_within(client, Duration::from_millis(20))
+    }
+
+    fn reply_within(client: &MemorySocket, timeout: Duration) -> Option<Message> {
+        client.set_read_timeout(Some(timeout)).unwrap();
+        let mut buf = [0; 1024];
+        let (len, _) = client.recv_from(&mut buf).ok()?;
+        Some(Message::decode(&buf[..len]).unwrap())
+    }
+
+    fn register(server: &mut Server, client: &MemorySocket, id: &str) {
+        let msg = Message::Register {
+            id: id.to_string(),
+            port: client.local_addr().unwrap().port(),
+            public_key: vec![1; 32],
+            server_key: Vec::new(),
+        };
+        send(server, client, msg);
+        assert!(matches!(reply(client), Some(Message::RegisterOk { .. })));
+    }
+
+    #[test]
+    fn test_register_reports_both_endpoints() {
+        let (mut server, net) = setup();
+        let alice = net.bind(addr("198.51.100.1:40001")).unwrap();
+        let msg = Message::Register {
+            id: "alice".to_string(),
+            port: 5000,
+            public_key: Vec::new(),
+            server_key: Vec::new(),
+        };
+        send(&mut server, &alice, msg);
+        let Some(Message::RegisterOk {
+            external_addr,
+            claimed_addr,
+        }) = reply(&alice)
+        else {
+            panic!("no RegisterOk");
+        };
+        // peers are sent to the mapping the NAT made, not the claimed port
+        assert_eq!(external_addr, addr("198.51.100.1:40001"));
+        assert_eq!(claimed_addr, addr("198.51.100.1:5000"));
+        assert_eq!(server.lookup("alice").unwrap().0, external_addr);
+    }
+
+    #[test]
+    fn test_registration_expires() {
+        let (mut server, net) = setup();
+        server.set_registration_ttl(Duration::from_millis(50));
+        let alice = net.bind(addr("198.51.100.1:5000")).unwrap();
+        register(&mut server, &alice, "alice");
+        assert!(server.lookup("alice").is_some());
+
+        thread::sleep(Duration::from_millis(80));
+        server.sweep_expired();
+        assert!(server.lookup("alice").is_none());
+
+        // a heartbeat after expiry is told to register again
+        send(&mut server, &alice, Message::Heartbeat { id: "alice".to_string() });
+        assert!(matches!(reply(&alice), Some(Message::NotRegistered { id }) if id == "alice"));
+    }
+
+    #[test]
+    fn test_heartbeat_refreshes_registration() {
+        let (mut server, net) = setup();
+        server.set_registration_ttl(Duration::from_millis(100));
+        let alice = net.bind(addr("198.51.100.1:5000")).unwrap();
+        let mallory = net.bind(addr("198.51.100.9:5000")).unwrap();
+        register(&mut server, &alice, "alice");
+
+        for _ in 0..3 {
+            thread::sleep(Duration::from_millis(50));
+            send(&mut server, &alice, Message::Heartbeat { id: "alice".to_string() });
+            assert!(matches!(reply(&alice), Some(Message::HeartbeatAck { .. })));
+            server.sweep_expired();
+            assert!(server.lookup("alice").is_some());
+        }
+
+        // only from the registered address
+        send(&mut server, &mallory, Message::Heartbeat { id: "alice".to_string() });
+        assert!(matches!(reply(&mallory), Some(Message::NotRegistered { .. })));
+        thread::sleep(Duration::from_millis(120));
+        server.sweep_expired();
+        assert!(server.lookup("alice").is_none());
+    }
+
+    #[test]
+    fn test_hole_punch_needs_the_registered_address() {
+        let (mut server, net) = setup();
+        let alice = net.bind(addr("198.51.100.1:5000")).unwrap();
+        let bob = net.bind(addr("198.51.100.2:5000")).unwrap();
+        let mallory = net.bind(addr("198.51.100.9:5000")).unwrap();
+        register(&mut server, &alice, "alice");
+        register(&mut server, &bob, "bob");
+
+        let punch = || Message::HolePunch {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+        };
+        send(&mut server, &mallory, punch());
+        assert!(matches!(reply(&mallory), Some(Message::NotRegistered { id }) if id == "alice"));
+        assert!(reply(&alice).is_none());
+        assert!(reply(&bob).is_none());
+
+        send(&mut server, &alice, punch());
+        assert!(matches!(reply(&alice), Some(Message::StartPunchWithPeer { peer_id, .. }) if peer_id == "bob"));
+        assert!(matches!(reply(&bob), Some(Message::StartPunchWithPeer { peer_id, .. }) if peer_id == "alice"));
+    }
+
+    #[test]
+    fn test_time_requests_only_for_registered() {
+        let (mut server, net) = setup();
+        let alice = net.bind(addr("198.51.100.1:5000")).unwrap();
+        let victim = net.bind(addr("198.51.100.9:5000")).unwrap();
+        send(&mut server, &alice, Message::TimeRequest { sent: 1 });
+        assert!(reply(&alice).is_none());
+
+        register(&mut server, &alice, "alice");
+        send(&mut server, &alice, Message::TimeRequest { sent: 1 });
+        assert!(matches!(reply(&alice), Some(Message::TimeResponse { sent: 1, .. })));
+        send(&mut server, &victim, Message::TimeRequest { sent: 1 });
+        assert!(reply(&victim).is_none());
+    }
+
+    // Ask for a relay from `from` to `to`; on success both bind to it and
+    // the relay's address is returned, else the reason it was denied.
+    fn relay(
+        server: &mut Server,
+        from: (&MemorySocket, &str),
+        to: (&MemorySocket, &str),
+    ) -> Result<SocketAddr, String> {
+        let request = Message::RelayRequest {
+            from: from.1.to_string(),
+            to: to.1.to_string(),
+        };
+        send(server, from.0, request);
+        let mut relay_addr = None;
+        for (client, id) in [from, to] {
+            match reply(client) {
+                Some(Message::RelayAllocated { relay_port, token, .. }) => {
+                    let addr = SocketAddr::new(server.socket.local_addr().unwrap().ip(), relay_port);
+                    let bind = Message::RelayBind { id: id.to_string(), token };
+                    client.send_to(&bind.encode(), addr).unwrap();
+                    relay_addr = Some(addr);
+                }
+                Some(Message::RelayDenied { reason, .. }) => return Err(reason),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        // the binds have to land before anything is forwarded
+        thread::sleep(Duration::from_millis(20));
+        Ok(relay_addr.unwrap())
+    }
+
+    #[test]
+    fn test_relay_forwards_within_quota() {
+        let (mut server, net) = setup();
+        server.enable_relay(RelayConfig {
+            quota_bytes: 100,
+            lifetime: Duration::from_secs(5),
+            ..RelayConfig::default()
+        });
+        let alice = net.bind(addr("198.51.100.1:5000")).unwrap();
+        let bob = net.bind(addr("198.51.100.2:5000")).unwrap();
+        register(&mut server, &alice, "alice");
+        register(&mut server, &bob, "bob");
+        let relay_addr = relay(&mut server, (&alice, "alice"), (&bob, "bob")).unwrap();
+
+        alice.send_to(&[7; 60], relay_addr).unwrap();
+        bob.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut buf = [0; 1024];
+        let (len, from) = bob.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&[7; 60][..], relay_addr));
+
+        // strangers get nothing through
+        let mallory = net.bind(addr("198.51.100.9:5000")).unwrap();
+        mallory.send_to(&[6; 10], relay_addr).unwrap();
+        assert!(bob.recv_from(&mut buf).is_err());
+
+        // the next 60 bytes would pass the quota
+        bob.send_to(&[8; 60], relay_addr).unwrap();
+        for client in [&alice, &bob] {
+            let closed = reply_within(client, Duration::from_secs(1));
+            assert!(matches!(closed, Some(Message::RelayClosed { reason, .. }) if reason == "quota exhausted"));
+        }
+    }
+
+    #[test]
+    fn test_relay_limits_and_expiry() {
+        let (mut server, net) = setup();
+        server.enable_relay(RelayConfig {
+            lifetime: Duration::from_millis(50),
+            max_relays: 2,
+            max_per_client: 1,
+            ..RelayConfig::default()
+        });
+        let ids = ["alice", "bob", "carol", "dave", "erin", "frank"];
+        let clients: Vec<_> = (0..ids.len())
+            .map(|i| net.bind(SocketAddr::new([198, 51, 100, i as u8 + 1].into(), 5000)).unwrap())
+            .collect();
+        for (client, id) in clients.iter().zip(ids) {
+            register(&mut server, client, id);
+        }
+        let side = |i: usize| (&*clients[i], ids[i]);
+
+        relay(&mut server, side(0), side(1)).unwrap();
+        let denied = relay(&mut server, side(0), side(2)).unwrap_err();
+        assert_eq!(denied, "alice has too many relays open");
+        relay(&mut server, side(2), side(3)).unwrap();
+        assert_eq!(relay(&mut server, side(4), side(5)).unwrap_err(), "too many relays open");
+
+        // a closed relay makes room again
+        for client in &clients[..2] {
+            let closed = reply_within(client, Duration::from_secs(2));
+            assert!(matches!(closed, Some(Message::RelayClosed { reason, .. }) if reason == "lifetime expired"));
+        }
+        relay(&mut server, side(4), side(5)).unwrap();
+    }
+
+    #[test]
+    fn test_relay_quota_counts_requests() {
+        let (mut server, net) = setup();
+        server.enable_relay(RelayConfig {
+            max_per_client: 1,
+            ..RelayConfig::default()
+        });
+        let ids = ["alice", "bob", "carol", "dave", "erin"];
+        let clients: Vec<_> = (0..ids.len())
+            .map(|i| net.bind(SocketAddr::new([198, 51, 100, i as u8 + 1].into(), 5000)).unwrap())
+            .collect();
+        for (client, id) in clients.iter().zip(ids) {
+            register(&mut server, client, id);
+        }
+        let side = |i: usize| (&*clients[i], ids[i]);
+
+        // others asking for relays to bob leave bob's own quota alone
+        relay(&mut server, side(0), side(1)).unwrap();
+        relay(&mut server, side(2), side(1)).unwrap();
+        relay(&mut server, side(1), side(3)).unwrap();
+        assert_eq!(relay(&mut server, side(1), side(4)).unwrap_err(), "bob has too many relays open");
+    }
+
+    #[test]
+    fn test_full_candidate_list_in_text() {
+        let (mut server, net) = setup();
+        let server_addr = server.socket.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        let alice = net.bind(addr("198.51.100.1:5000")).unwrap();
+        let bob = net.bind(addr("198.51.100.2:5000")).unwrap();
+        let long = "a".repeat(MAX_ID_LEN);
+        for (client, id) in [(&alice, long.as_str()), (&bob, "bob")] {
+            let msg = Message::Register {
+                id: id.to_string(),
+                port: 5000,
+                public_key: vec![1; 32],
+                server_key: Vec::new(),
+            };
+            client.send_to(&msg.encode(), server_addr).unwrap();
+            assert!(matches!(reply_within(client, Duration::from_secs(1)), Some(Message::RegisterOk { .. })));
+        }
+
+        let ip = "fe80::1234:5678:9abc:def0".parse().unwrap();
+        let candidates: Vec<_> = (0..MAX_CANDIDATES as u16)
+            .map(|i| Candidate::new(CandidateKind::Host, SocketAddr::new(ip, 40000 + i), u16::MAX))
+            .collect();
+        let msg = Message::Candidates {
+            id: long.clone(),
+            candidates: candidates.clone(),
+        };
+        let packet = msg.encode_as(WireFormat::Text);
+        assert!(packet.len() > 1024);
+        alice.send_to(&packet, server_addr).unwrap();
+
+        let punch = Message::HolePunch {
+            from: "bob".to_string(),
+            to: long,
+        };
+        bob.send_to(&punch.encode(), server_addr).unwrap();
+        match reply_within(&bob, Duration::from_secs(1)) {
+            Some(Message::StartPunchWithPeer { peer_candidates, .. }) => assert_eq!(peer_candidates, candidates),
+            other => panic!("expected StartPunchWithPeer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_live_registration_stays_put() {
+        let (mut server, net) = setup();
+        let alice = net.bind(addr("198.51.100.1:5000")).unwrap();
+        let mallory = net.bind(addr("198.51.100.9:5000")).unwrap();
+        register(&mut server, &alice, "alice");
+
+        // alice's static key is no secret
+        let msg = Message::Register {
+            id: "alice".to_string(),
+            port: 5000,
+            public_key: vec![1; 32],
+            server_key: Vec::new(),
+        };
+        send(&mut server, &mallory, msg);
+        assert!(matches!(reply(&mallory), Some(Message::RegisterDenied { .. })));
+        assert_eq!(server.lookup("alice").unwrap().0, alice.local_addr().unwrap());
+
+        // an id no client would send, whose reasons might not fit a field
+        let long = "x".repeat(MAX_ID_LEN + 1);
+        let msg = Message::Register {
+            id: long.clone(),
+            port: 5000,
+            public_key: vec![1; 32],
+            server_key: Vec::new(),
+        };
+        send(&mut server, &mallory, msg);
+        assert!(matches!(reply(&mallory), Some(Message::RegisterDenied { reason, .. }) if reason == "id too long"));
+        assert!(server.lookup(&long).is_none());
+    }
+}
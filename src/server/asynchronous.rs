@@ -0,0 +1,225 @@
+//! `Server` on tokio: the main loop, alternates and relays run as tasks
+//! instead of blocking threads.
+
+use super::{answer_alternate, Relay, RelayConfig, Server, SWEEP_INTERVAL};
+use crate::auth::Credentials;
+use crate::protocol::ProtocolErrorKind;
+use crate::transport::{DatagramTransport, SharedWithTokio};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
+use tokio::time;
+
+/// The signaling server on tokio's `UdpSocket`. Same protocol and behavior
+/// as `Server`, see there for the configuration.
+pub struct AsyncServer {
+    server: Server,
+}
+
+impl AsyncServer {
+    pub fn new(addr: &str) -> io::Result<Self> {
+        Ok(Self {
+            server: Server::new(addr)?,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.server.local_addr()
+    }
+
+    pub fn set_registration_ttl(&mut self, ttl: Duration) {
+        self.server.set_registration_ttl(ttl);
+    }
+
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.server.set_credentials(credentials);
+    }
+
+    pub fn load_credentials(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.server.load_credentials(path)
+    }
+
+    pub fn enable_relay(&mut self, config: RelayConfig) {
+        self.server.enable_relay(config);
+    }
+
+    pub fn add_alternate(&mut self, addr: &str) -> io::Result<()> {
+        self.server.add_alternate(addr)
+    }
+
+    pub fn malformed_counts(&self) -> &HashMap<ProtocolErrorKind, u64> {
+        self.server.malformed_counts()
+    }
+
+    /// Serve until the socket fails. Must be awaited within a tokio runtime,
+    /// which also runs the alternates and any relays.
+    pub async fn run(&mut self) -> io::Result<()> {
+        let server = &mut self.server;
+        server.runtime = Some(Handle::current());
+
+        // tokio receives; the server's own handles keep sending
+        let mut receivers = Vec::new();
+        for socket in &mut server.stun_sockets {
+            let (receiver, sender) = SharedWithTokio::split(udp(&**socket)?)?;
+            *socket = sender;
+            receivers.push(receiver);
+        }
+        server.socket = server.stun_sockets[0].clone();
+        let mut receivers = receivers.into_iter();
+        let socket = receivers.next().unwrap();
+        for (index, receiver) in receivers.enumerate() {
+            tokio::spawn(serve_alternate(receiver, server.stun_sockets.clone(), index + 1));
+        }
+
+        let mut sweeps = time::interval(SWEEP_INTERVAL);
+        // a full candidate list in text, under a long id, runs past 1KiB
+        let mut buf = vec![0; 65536];
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut buf) => {
+                    let (len, client_addr) = received?;
+                    server.handle_datagram(&buf[..len], client_addr)?;
+                }
+                _ = sweeps.tick() => {}
+            }
+            server.sweep_if_due();
+        }
+    }
+}
+
+fn udp(socket: &dyn DatagramTransport) -> io::Result<&std::net::UdpSocket> {
+    socket
+        .as_udp()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "AsyncServer needs UDP sockets"))
+}
+
+// A tokio handle on `socket`, which nothing else uses.
+fn nonblocking(socket: &dyn DatagramTransport) -> io::Result<UdpSocket> {
+    let socket = udp(socket)?.try_clone()?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+async fn serve_alternate(socket: UdpSocket, sockets: Vec<Arc<dyn DatagramTransport>>, index: usize) {
+    let mut buf = [0; 1024];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, from)) => answer_alternate(&sockets, index, &buf[..len], from),
+            Err(e) => {
+                say!("❌ Alternate STUN socket stopped: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// `super::run_relay` as a task.
+pub(super) async fn run_relay(socket: Arc<dyn DatagramTransport>, mut relay: Relay) {
+    let reason = forward(socket, &mut relay).await;
+    relay.close(&reason);
+}
+
+async fn forward(socket: Arc<dyn DatagramTransport>, relay: &mut Relay) -> String {
+    let socket = match nonblocking(&*socket) {
+        Ok(socket) => socket,
+        Err(e) => return format!("socket error: {}", e),
+    };
+    let expires_at = time::Instant::from_std(relay.expires_at);
+    let mut buf = [0; 65536];
+    loop {
+        let (len, from) = match time::timeout_at(expires_at, socket.recv_from(&mut buf)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => return format!("socket error: {}", e),
+            Err(_) => return "lifetime expired".to_string(),
+        };
+
+        match relay.route(&buf[..len], from) {
+            Ok(Some(to)) => {
+                if socket.send_to(&buf[..len], to).await.is_ok() {
+                    relay.forwarded(len);
+                }
+            }
+            Ok(None) => {}
+            Err(reason) => return reason,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Message;
+    use crate::stun::{BindingRequest, BindingResponse};
+
+    // The next packet `socket` receives, and from where.
+    async fn receive(socket: &UdpSocket) -> (Vec<u8>, SocketAddr) {
+        let mut buf = [0; 2048];
+        let received = time::timeout(Duration::from_secs(2), socket.recv_from(&mut buf)).await;
+        let (len, from) = received.expect("nothing received").unwrap();
+        (buf[..len].to_vec(), from)
+    }
+
+    async fn reply(socket: &UdpSocket) -> Message {
+        Message::decode(&receive(socket).await.0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run_serves_lookup_stun_and_relay() {
+        let mut server = AsyncServer::new("127.0.0.1:0").unwrap();
+        server.enable_relay(RelayConfig::default());
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let alice = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let bob = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let bob_addr = bob.local_addr().unwrap();
+        for (socket, id) in [(&alice, "alice"), (&bob, "bob")] {
+            let msg = Message::Register {
+                id: id.to_string(),
+                port: socket.local_addr().unwrap().port(),
+                public_key: vec![1; 32],
+                server_key: Vec::new(),
+            };
+            socket.send_to(&msg.encode(), server_addr).await.unwrap();
+            assert!(matches!(reply(socket).await, Message::RegisterOk { .. }));
+        }
+
+        let lookup = Message::Discover {
+            target: "bob".to_string(),
+        };
+        alice.send_to(&lookup.encode(), server_addr).await.unwrap();
+        assert!(matches!(reply(&alice).await, Message::PeerFound { id, addr, .. } if id == "bob" && addr == bob_addr));
+
+        let request = BindingRequest::new();
+        alice.send_to(&request.encode(), server_addr).await.unwrap();
+        let response = BindingResponse::decode(&receive(&alice).await.0).unwrap();
+        assert_eq!(response.transaction_id, request.transaction_id);
+        assert_eq!(response.mapped_addr, alice.local_addr().unwrap());
+
+        let request = Message::RelayRequest {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+        };
+        alice.send_to(&request.encode(), server_addr).await.unwrap();
+        let mut relay_addr = None;
+        for (socket, id) in [(&alice, "alice"), (&bob, "bob")] {
+            let Message::RelayAllocated { relay_port, token, .. } = reply(socket).await else {
+                panic!("no relay for {}", id);
+            };
+            let addr = SocketAddr::new(server_addr.ip(), relay_port);
+            let bind = Message::RelayBind { id: id.to_string(), token };
+            socket.send_to(&bind.encode(), addr).await.unwrap();
+            relay_addr = Some(addr);
+        }
+        let relay_addr = relay_addr.unwrap();
+        // the binds have to land before anything is forwarded
+        time::sleep(Duration::from_millis(20)).await;
+        alice.send_to(b"through the relay", relay_addr).await.unwrap();
+        assert_eq!(receive(&bob).await, (b"through the relay".to_vec(), relay_addr));
+    }
+}
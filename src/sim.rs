@@ -0,0 +1,615 @@
+//! An in-process network with simulated NAT boxes, so hole punching can be
+//! tested without containers. Hosts either sit on the public side or
+//! behind a `NatBox`, and every socket is a `DatagramTransport` that
+//! `Server::with_transport` and `Client::with_transport` accept.
+//!
+//! Delivery is immediate and lossless; what the NATs let through is the
+//! only thing that decides whether a packet arrives.
+
+use crate::transport::{DatagramTransport, Mailbox};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// where ephemeral ports (and Sequential NAT ports) start
+const FIRST_EPHEMERAL: u16 = 40000;
+const FIRST_NAT_PORT: u16 = 20000;
+
+/// How a NAT maps and filters, in RFC 4787 terms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatKind {
+    /// Endpoint-independent mapping and filtering.
+    FullCone,
+    /// Endpoint-independent mapping, address-dependent filtering.
+    AddressRestricted,
+    /// Endpoint-independent mapping, address- and port-dependent filtering.
+    PortRestricted,
+    /// A mapping per destination, address- and port-dependent filtering.
+    Symmetric,
+}
+
+/// Which public port a new mapping gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortAllocation {
+    /// The private port if it is free, else the next free one above it.
+    Preserve,
+    /// Ports in steps of the given size from 20000 on, skipping used ones.
+    Sequential(u16),
+    /// Any free port.
+    Random,
+    /// Any free port from the first to the last.
+    RandomWithin(u16, u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NatConfig {
+    pub kind: NatKind,
+    /// How long a mapping survives without outbound traffic.
+    pub mapping_timeout: Duration,
+    pub ports: PortAllocation,
+}
+
+impl NatConfig {
+    /// `kind` with port preservation and a 30s mapping timeout.
+    pub fn new(kind: NatKind) -> Self {
+        Self {
+            kind,
+            mapping_timeout: Duration::from_secs(30),
+            ports: PortAllocation::Preserve,
+        }
+    }
+}
+
+// where an address is meaningful: the public side, or behind one NAT
+type Realm = Option<usize>;
+
+/// The simulated network. Clones share it.
+#[derive(Clone, Default)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    sockets: HashMap<(Realm, SocketAddr), Arc<Mailbox>>,
+    hosts: HashSet<(Realm, IpAddr)>,
+    nats: Vec<Nat>,
+    // last ephemeral port handed out per host
+    ephemeral: HashMap<(Realm, IpAddr), u16>,
+}
+
+impl Network {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A host at `ip` on the public side.
+    pub fn public_host(&self, ip: IpAddr) -> Host {
+        self.state.lock().unwrap().hosts.insert((None, ip));
+        Host {
+            net: self.clone(),
+            realm: None,
+            ip,
+        }
+    }
+
+    /// A NAT box whose public side is `public_ip`. Hosts behind it get
+    /// private addresses of their own, see `NatBox::host`.
+    pub fn nat(&self, public_ip: IpAddr, config: NatConfig) -> NatBox {
+        let mut state = self.state.lock().unwrap();
+        state.hosts.insert((None, public_ip));
+        state.nats.push(Nat {
+            ip: public_ip,
+            config,
+            mappings: Vec::new(),
+            next_port: FIRST_NAT_PORT,
+        });
+        NatBox {
+            net: self.clone(),
+            index: state.nats.len() - 1,
+        }
+    }
+
+    // Carry a datagram from `from` in `realm` to `to`, through whatever
+    // NATs are in between.
+    fn route(&self, mut realm: Realm, mut from: SocketAddr, to: SocketAddr, payload: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mut to = to;
+
+        // leaving a private network, unless the destination is on it
+        if let Some(index) = realm {
+            if !state.hosts.contains(&(realm, to.ip())) {
+                from = state.nats[index].outbound(from, to, now);
+                realm = None;
+            }
+        }
+
+        // arriving at a NAT's public side
+        if realm.is_none() {
+            if let Some(index) = state.nats.iter().position(|n| n.ip == to.ip()) {
+                match state.nats[index].inbound(from, to.port(), now) {
+                    Some(private) => {
+                        realm = Some(index);
+                        to = private;
+                    }
+                    None => return,
+                }
+            }
+        }
+
+        if let Some(mailbox) = state.sockets.get(&(realm, to)) {
+            mailbox.push(payload.to_vec(), from);
+        }
+    }
+
+    fn bind(&self, realm: Realm, ip: IpAddr, port: u16) -> io::Result<Arc<SimSocket>> {
+        let mut state = self.state.lock().unwrap();
+        let port = match port {
+            0 => state.free_port(realm, ip)?,
+            port => port,
+        };
+        let addr = SocketAddr::new(ip, port);
+        if state.sockets.contains_key(&(realm, addr)) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, addr.to_string()));
+        }
+        let mailbox = Arc::new(Mailbox::default());
+        state.sockets.insert((realm, addr), mailbox.clone());
+        Ok(Arc::new(SimSocket {
+            net: self.clone(),
+            realm,
+            addr,
+            mailbox,
+        }))
+    }
+}
+
+impl State {
+    fn free_port(&mut self, realm: Realm, ip: IpAddr) -> io::Result<u16> {
+        let last = self.ephemeral.entry((realm, ip)).or_insert(FIRST_EPHEMERAL - 1);
+        for _ in FIRST_EPHEMERAL..=u16::MAX {
+            *last = if *last == u16::MAX { FIRST_EPHEMERAL } else { *last + 1 };
+            if !self.sockets.contains_key(&(realm, SocketAddr::new(ip, *last))) {
+                return Ok(*last);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "no free ephemeral port"))
+    }
+}
+
+/// A NAT box on a `Network`.
+pub struct NatBox {
+    net: Network,
+    index: usize,
+}
+
+impl NatBox {
+    /// A host at private address `ip` behind this NAT.
+    pub fn host(&self, ip: IpAddr) -> Host {
+        let realm = Some(self.index);
+        self.net.state.lock().unwrap().hosts.insert((realm, ip));
+        Host {
+            net: self.net.clone(),
+            realm,
+            ip,
+        }
+    }
+
+    pub fn public_ip(&self) -> IpAddr {
+        self.net.state.lock().unwrap().nats[self.index].ip
+    }
+
+    /// Mappings that have not timed out.
+    pub fn mappings(&self) -> usize {
+        let mut state = self.net.state.lock().unwrap();
+        let nat = &mut state.nats[self.index];
+        nat.expire(Instant::now());
+        nat.mappings.len()
+    }
+}
+
+/// A machine on a `Network` that sockets can be bound on.
+#[derive(Clone)]
+pub struct Host {
+    net: Network,
+    realm: Realm,
+    ip: IpAddr,
+}
+
+impl Host {
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// A socket at `port` on this host, or at a free port if `port` is 0.
+    pub fn bind(&self, port: u16) -> io::Result<Arc<SimSocket>> {
+        self.net.bind(self.realm, self.ip, port)
+    }
+}
+
+struct Nat {
+    ip: IpAddr,
+    config: NatConfig,
+    mappings: Vec<Mapping>,
+    // for `PortAllocation::Sequential`
+    next_port: u16,
+}
+
+struct Mapping {
+    private: SocketAddr,
+    // the one destination of a symmetric NAT's mapping
+    remote: Option<SocketAddr>,
+    port: u16,
+    // destinations the private side sent to, which filtering lets back in
+    permitted: HashSet<SocketAddr>,
+    last_used: Instant,
+}
+
+impl Nat {
+    // The public address a packet from `private` to `to` leaves with.
+    fn outbound(&mut self, private: SocketAddr, to: SocketAddr, now: Instant) -> SocketAddr {
+        self.expire(now);
+        let remote = (self.config.kind == NatKind::Symmetric).then_some(to);
+        let index = match self
+            .mappings
+            .iter()
+            .position(|m| m.private == private && m.remote == remote)
+        {
+            Some(index) => index,
+            None => {
+                let port = self.allocate(private.port());
+                self.mappings.push(Mapping {
+                    private,
+                    remote,
+                    port,
+                    permitted: HashSet::new(),
+                    last_used: now,
+                });
+                self.mappings.len() - 1
+            }
+        };
+        let mapping = &mut self.mappings[index];
+        mapping.permitted.insert(to);
+        mapping.last_used = now;
+        SocketAddr::new(self.ip, mapping.port)
+    }
+
+    // Where a packet from `from` to our public `port` goes, if the
+    // filtering lets it in.
+    fn inbound(&mut self, from: SocketAddr, port: u16, now: Instant) -> Option<SocketAddr> {
+        self.expire(now);
+        let kind = self.config.kind;
+        self.mappings
+            .iter()
+            .filter(|m| m.port == port)
+            .find(|m| match kind {
+                NatKind::FullCone => true,
+                NatKind::AddressRestricted => m.permitted.iter().any(|p| p.ip() == from.ip()),
+                NatKind::PortRestricted | NatKind::Symmetric => m.permitted.contains(&from),
+            })
+            .map(|m| m.private)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.config.mapping_timeout;
+        self.mappings.retain(|m| now.duration_since(m.last_used) < timeout);
+    }
+
+    fn allocate(&mut self, private_port: u16) -> u16 {
+        let in_use = |nat: &Nat, port: u16| port == 0 || nat.mappings.iter().any(|m| m.port == port);
+        match self.config.ports {
+            PortAllocation::Preserve => {
+                let mut port = private_port;
+                while in_use(self, port) {
+                    port = port.wrapping_add(1);
+                }
+                port
+            }
+            PortAllocation::Sequential(step) => {
+                while in_use(self, self.next_port) {
+                    self.next_port = self.next_port.wrapping_add(step.max(1));
+                }
+                let port = self.next_port;
+                self.next_port = port.wrapping_add(step.max(1));
+                port
+            }
+            PortAllocation::Random => self.allocate_within(1024, u16::MAX),
+            PortAllocation::RandomWithin(first, last) => self.allocate_within(first, last),
+        }
+    }
+
+    // A random free port from `first` to `last`: the first free one from a
+    // random place in that range on.
+    fn allocate_within(&self, first: u16, last: u16) -> u16 {
+        let mut bytes = [0; 4];
+        getrandom::fill(&mut bytes).expect("no system randomness");
+        let len = u32::from(last - first) + 1;
+        let offset = u32::from_be_bytes(bytes) % len;
+        (0..len)
+            .map(|i| first + ((offset + i) % len) as u16)
+            .find(|&port| port != 0 && !self.mappings.iter().any(|m| m.port == port))
+            .expect("simulated NAT out of ports")
+    }
+}
+
+/// A socket on a `Network`. Unbinds when dropped.
+pub struct SimSocket {
+    net: Network,
+    realm: Realm,
+    addr: SocketAddr,
+    mailbox: Arc<Mailbox>,
+}
+
+impl DatagramTransport for SimSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.net.route(self.realm, self.addr, addr, buf);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.mailbox.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.mailbox.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.mailbox.set_nonblocking(nonblocking)
+    }
+
+    fn bind_sibling(&self, port: u16) -> io::Result<Arc<dyn DatagramTransport>> {
+        Ok(self.net.bind(self.realm, self.addr.ip(), port)?)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.net.state.lock().unwrap().sockets.remove(&(self.realm, self.addr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientConfig};
+    use crate::clock::SkewedClock;
+    use crate::nat::NatType;
+    use crate::server::Server;
+    use crate::spray::{SprayConfig, Strategy};
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn recv(socket: &SimSocket) -> Option<SocketAddr> {
+        socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        socket.recv_from(&mut [0; 64]).ok().map(|(_, from)| from)
+    }
+
+    // A host behind a `kind` NAT that sent to `server`, plus two outside
+    // hosts: one on the server's IP, one elsewhere.
+    struct Setup {
+        inside: Arc<SimSocket>,
+        server: Arc<SimSocket>,
+        same_ip: Arc<SimSocket>,
+        elsewhere: Arc<SimSocket>,
+        mapped: SocketAddr,
+    }
+
+    fn setup(config: NatConfig) -> Setup {
+        let net = Network::new();
+        let nat = net.nat(ip("198.51.100.1"), config);
+        let inside = nat.host(ip("10.0.0.2")).bind(5000).unwrap();
+        let outside = net.public_host(ip("203.0.113.1"));
+        let server = outside.bind(3478).unwrap();
+        let same_ip = outside.bind(3479).unwrap();
+        let elsewhere = net.public_host(ip("203.0.113.2")).bind(3478).unwrap();
+
+        inside.send_to(b"hi", server.local_addr().unwrap()).unwrap();
+        let mapped = recv(&server).unwrap();
+        Setup {
+            inside,
+            server,
+            same_ip,
+            elsewhere,
+            mapped,
+        }
+    }
+
+    #[test]
+    fn test_filtering() {
+        for (kind, from_same_ip, from_elsewhere) in [
+            (NatKind::FullCone, true, true),
+            (NatKind::AddressRestricted, true, false),
+            (NatKind::PortRestricted, false, false),
+            (NatKind::Symmetric, false, false),
+        ] {
+            let s = setup(NatConfig::new(kind));
+            assert_eq!(s.mapped, "198.51.100.1:5000".parse().unwrap(), "{:?}", kind);
+
+            s.server.send_to(b"back", s.mapped).unwrap();
+            assert_eq!(recv(&s.inside), Some(s.server.local_addr().unwrap()));
+            s.same_ip.send_to(b"x", s.mapped).unwrap();
+            assert_eq!(recv(&s.inside).is_some(), from_same_ip, "{:?}", kind);
+            s.elsewhere.send_to(b"x", s.mapped).unwrap();
+            assert_eq!(recv(&s.inside).is_some(), from_elsewhere, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_mapping() {
+        for kind in [NatKind::PortRestricted, NatKind::Symmetric] {
+            let s = setup(NatConfig::new(kind));
+            s.inside.send_to(b"x", s.elsewhere.local_addr().unwrap()).unwrap();
+            let mapped = recv(&s.elsewhere).unwrap();
+            assert_eq!(mapped == s.mapped, kind != NatKind::Symmetric, "{:?}", kind);
+        }
+
+        let mut config = NatConfig::new(NatKind::Symmetric);
+        config.ports = PortAllocation::Sequential(4);
+        let s = setup(config);
+        s.inside.send_to(b"x", s.same_ip.local_addr().unwrap()).unwrap();
+        s.inside.send_to(b"x", s.elsewhere.local_addr().unwrap()).unwrap();
+        assert_eq!(s.mapped.port(), 20000);
+        assert_eq!(recv(&s.same_ip).unwrap().port(), 20004);
+        assert_eq!(recv(&s.elsewhere).unwrap().port(), 20008);
+    }
+
+    #[test]
+    fn test_mapping_timeout() {
+        let mut config = NatConfig::new(NatKind::FullCone);
+        config.mapping_timeout = Duration::from_millis(50);
+        let net = Network::new();
+        let nat = net.nat(ip("198.51.100.1"), config);
+        let inside = nat.host(ip("10.0.0.2")).bind(5000).unwrap();
+        let outside = net.public_host(ip("203.0.113.1")).bind(0).unwrap();
+
+        inside.send_to(b"x", outside.local_addr().unwrap()).unwrap();
+        let mapped = recv(&outside).unwrap();
+        assert_eq!(nat.mappings(), 1);
+        thread::sleep(Duration::from_millis(80));
+        outside.send_to(b"late", mapped).unwrap();
+        assert_eq!(recv(&inside), None);
+        assert_eq!(nat.mappings(), 0);
+    }
+
+    fn nat_type(kind: NatKind) -> NatType {
+        match kind {
+            NatKind::FullCone => NatType::FullCone,
+            NatKind::AddressRestricted => NatType::Restricted,
+            NatKind::PortRestricted => NatType::PortRestricted,
+            NatKind::Symmetric => NatType::Symmetric,
+        }
+    }
+
+    fn punch(a: NatKind, b: NatKind, skew_ms: [i64; 2]) -> bool {
+        punch_with([NatConfig::new(a), NatConfig::new(b)], skew_ms, [None; 2])
+    }
+
+    // Register a client behind each NAT, with its wall clock off by the
+    // matching entry of `skew_ms`, and have the first connect to the second.
+    fn punch_with(nats: [NatConfig; 2], skew_ms: [i64; 2], sprays: [Option<SprayConfig>; 2]) -> bool {
+        let mut clients = behind(nats, skew_ms, sprays);
+        let connected = clients[0].connect_to_peer("bob").is_ok();
+        for client in &clients {
+            client.should_listen.store(false, Ordering::Relaxed);
+        }
+        connected
+    }
+
+    // alice and bob, registered from behind `nats`
+    fn behind(nats: [NatConfig; 2], skew_ms: [i64; 2], sprays: [Option<SprayConfig>; 2]) -> Vec<Client> {
+        let net = Network::new();
+        let server_socket = net.public_host(ip("203.0.113.1")).bind(9090).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let mut server = Server::with_transport(server_socket).unwrap();
+        thread::spawn(move || server.run());
+
+        let mut clients = Vec::new();
+        for (i, nat_config) in nats.into_iter().enumerate() {
+            let config = ClientConfig {
+                punch_timeout: Duration::from_secs(4),
+                relay_fallback: false,
+                spray: sprays[i],
+                ..ClientConfig::default()
+            };
+            let nat = net.nat(ip(&format!("198.51.100.{}", i + 1)), nat_config);
+            let socket = nat.host(ip(&format!("10.0.{}.2", i + 1))).bind(0).unwrap();
+            let id = ["alice", "bob"][i].to_string();
+            let mut client = Client::with_transport(id, server_addr, config, socket).unwrap();
+            client.set_clock(Arc::new(SkewedClock { offset_ms: skew_ms[i] }));
+            client.register().unwrap();
+            clients.push(client);
+        }
+        clients
+    }
+
+    #[test]
+    fn test_punching_between_nat_kinds() {
+        use NatKind::*;
+        let kinds = [FullCone, AddressRestricted, PortRestricted, Symmetric];
+        let pairs: Vec<_> = kinds
+            .iter()
+            .enumerate()
+            .flat_map(|(i, a)| kinds[i..].iter().map(move |b| (*a, *b)))
+            .collect();
+
+        thread::scope(|scope| {
+            let runs: Vec<_> = pairs
+                .iter()
+                .map(|&(a, b)| (a, b, scope.spawn(move || punch(a, b, [0, 0]))))
+                .collect();
+            for (a, b, run) in runs {
+                let expected = nat_type(a).can_punch_with(&nat_type(b));
+                assert_eq!(run.join().unwrap(), expected, "{:?} to {:?}", a, b);
+            }
+        });
+    }
+
+    #[test]
+    fn test_punching_with_skewed_clocks() {
+        // a minute either way of the server: waiting for the wall-clock
+        // start time would leave one side punching long after the other
+        assert!(punch(NatKind::PortRestricted, NatKind::PortRestricted, [-60_000, 60_000]));
+    }
+
+    #[test]
+    fn test_spraying_between_symmetric_nats() {
+        let spray = SprayConfig {
+            sockets: 32,
+            max_packets: 512,
+            ..SprayConfig::default()
+        };
+        let mut sequential = NatConfig::new(NatKind::Symmetric);
+        sequential.ports = PortAllocation::Sequential(2);
+
+        assert!(punch_with([sequential, sequential], [0, 0], [Some(spray); 2]));
+        assert!(!punch_with([sequential, sequential], [0, 0], [None; 2]));
+    }
+
+    #[test]
+    fn test_birthday_spray_into_a_small_port_range() {
+        // alice's NAT maps at random, but only within the 32 ports bob's
+        // own socket sprays at, so one of them is where her punches leave
+        let mut random = NatConfig::new(NatKind::Symmetric);
+        random.ports = PortAllocation::RandomWithin(30000, 30031);
+        let cone = NatConfig::new(NatKind::PortRestricted);
+        let spray = SprayConfig {
+            sockets: 32,
+            max_packets: 512,
+            ports: (30000, 30031),
+            ..SprayConfig::default()
+        };
+        assert!(!punch_with([random, cone], [0, 0], [None; 2]));
+
+        let mut clients = behind([random, cone], [0, 0], [None, Some(spray)]);
+        let bob_addr = clients[0].connect_to_peer("bob").unwrap();
+        let alice_addr = clients[1].peer_addr("alice").unwrap();
+        assert_eq!(bob_addr.port(), clients[1].external_addr.unwrap().port());
+        assert!((30000..=30031).contains(&alice_addr.port()));
+
+        // bob logs the spray once it is over; the path it found is one of
+        // the ports it aimed at
+        let logged = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(100));
+            let logger = clients[1].console_logger.lock().unwrap();
+            logger.strategy_results(Strategy::Birthday) != (0, 0)
+        });
+        assert!(logged);
+        let logger = clients[1].console_logger.lock().unwrap();
+        assert_eq!(logger.strategy_results(Strategy::Birthday), (1, 0));
+        drop(logger);
+        for client in &clients {
+            client.should_listen.store(false, Ordering::Relaxed);
+        }
+    }
+}
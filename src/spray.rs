@@ -0,0 +1,367 @@
+//! Punching through NATs that give every destination a mapping of its own
+//! (symmetric NATs), where a burst to the peer's registered address can't
+//! work. Two ways, both opt-in with `ClientConfig::spray`:
+//!
+//! - Port prediction. Many NATs hand out ports in sequence. A client maps
+//!   a few probe sockets through its NAT, works out the step and tells the
+//!   server with `PortAllocation`, which passes it on in
+//!   `StartPunchWithPeer`. Each side then sends from a row of fresh
+//!   sockets, each to one predicted port of the peer, so both NATs open
+//!   mappings in the order the other side aims at.
+//! - Birthday paradox. Without a pattern, the client sends from many fresh
+//!   sockets to the peer's registered address, and from its own socket to
+//!   as many random ports of the peer's IP. With 256 of each, the chance
+//!   that some pair meets in 64k ports is close to two in three.
+//!
+//! The k-th fresh socket opens the k-th new mapping, give or take the
+//! mappings opened in between ("drift"). The controlling side aims socket
+//! k at the peer's predicted port 2k, the controlled side at port k, so
+//! some pair lines up as long as the drift on both sides together stays
+//! under half the sockets.
+
+use crate::stun::{BindingRequest, StunReply};
+use crate::transport::{DatagramTransport, SocketGroup};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// steps above this look more like chance than a pattern
+const MAX_STEP: u16 = 64;
+// per STUN attempt of a probe socket, and attempts before giving up
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+const PROBE_ATTEMPTS: u32 = 3;
+// how long after its last packet a spray waits for answers before its
+// sockets are closed and its outcome is logged
+const SPRAY_LINGER: Duration = Duration::from_secs(2);
+
+/// Limits for spraying, see the module docs.
+#[derive(Debug, Clone, Copy)]
+pub struct SprayConfig {
+    /// Probe sockets that measure how our NAT allocates ports.
+    pub probes: usize,
+    /// Fresh sockets per spray, and random ports for the birthday spray.
+    pub sockets: usize,
+    /// Packets one spray sends at most, over all sockets and rounds.
+    pub max_packets: usize,
+    pub packets_per_sec: u32,
+    /// The lowest and highest port the birthday spray aims at on the
+    /// peer's IP. With no more of them than `sockets`, it aims at all.
+    pub ports: (u16, u16),
+}
+
+impl Default for SprayConfig {
+    fn default() -> Self {
+        Self {
+            probes: 4,
+            sockets: 256,
+            max_packets: 2048,
+            packets_per_sec: 1000,
+            ports: (1024, u16::MAX),
+        }
+    }
+}
+
+/// How a NAT hands out public ports to new mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// The next mapping gets `next`, each one after that `step` more.
+    Sequential { next: u16, step: u16 },
+    /// No pattern; `last` is the last port seen.
+    Random { last: u16 },
+}
+
+impl Allocation {
+    /// From the ports that probe sockets were mapped to, in the order they
+    /// were opened. None with fewer than two.
+    pub fn from_ports(ports: &[u16]) -> Option<Self> {
+        let [first, second, ..] = *ports else {
+            return None;
+        };
+        let last = ports[ports.len() - 1];
+        let step = second.wrapping_sub(first);
+        let sequential =
+            (1..=MAX_STEP).contains(&step) && ports.windows(2).all(|w| w[1].wrapping_sub(w[0]) == step);
+        Some(if sequential {
+            Allocation::Sequential {
+                next: last.wrapping_add(step),
+                step,
+            }
+        } else {
+            Allocation::Random { last }
+        })
+    }
+
+    /// From the fields of `PortAllocation` or `StartPunchWithPeer`.
+    pub fn from_wire(next_port: u16, step: u16) -> Option<Self> {
+        match (next_port, step) {
+            (0, _) => None,
+            (last, 0) => Some(Allocation::Random { last }),
+            (next, step) => Some(Allocation::Sequential { next, step }),
+        }
+    }
+
+    /// `(next_port, step)` as in `PortAllocation`.
+    pub fn to_wire(self) -> (u16, u16) {
+        match self {
+            Allocation::Sequential { next, step } => (next, step),
+            Allocation::Random { last } => (last, 0),
+        }
+    }
+
+    /// The port the `index`-th mapping from now is expected at.
+    pub fn port(self, index: usize) -> Option<u16> {
+        match self {
+            Allocation::Sequential { next, step } => {
+                Some(next.wrapping_add(step.wrapping_mul(index as u16)))
+            }
+            Allocation::Random { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Allocation::Sequential { next, step } => write!(f, "sequential, next {} step {}", next, step),
+            Allocation::Random { last } => write!(f, "random, last {}", last),
+        }
+    }
+}
+
+/// What got a punch through, for `NatConsoleLogger::log_punch_strategy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Strategy {
+    /// The usual rounds to every candidate of the peer.
+    Candidates,
+    PortPrediction,
+    Birthday,
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Strategy::Candidates => "candidates",
+            Strategy::PortPrediction => "port prediction",
+            Strategy::Birthday => "birthday",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Map `probes` fresh sockets next to `socket` through the NAT and return
+/// the public ports `stun_server` saw, in order.
+pub(crate) fn measure(
+    socket: &dyn DatagramTransport,
+    stun_server: SocketAddr,
+    probes: usize,
+) -> io::Result<Vec<u16>> {
+    // all bound up front, so none reuses the port of one before it
+    let sockets = (0..probes)
+        .map(|_| socket.bind_sibling(0))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut ports = Vec::with_capacity(probes);
+    let mut buf = [0; 1024];
+    for probe in &sockets {
+        probe.set_read_timeout(Some(PROBE_TIMEOUT))?;
+        let request = BindingRequest::new();
+        let mut mapped = None;
+        for _ in 0..PROBE_ATTEMPTS {
+            probe.send_to(&request.encode(), stun_server)?;
+            let deadline = Instant::now() + PROBE_TIMEOUT;
+            while mapped.is_none() && Instant::now() < deadline {
+                let len = match probe.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                        break
+                    }
+                    Err(e) => return Err(e),
+                };
+                if let Ok(StunReply::Success(response)) = StunReply::decode(&buf[..len]) {
+                    if response.transaction_id == request.transaction_id {
+                        mapped = Some(response.mapped_addr);
+                    }
+                }
+            }
+            if mapped.is_some() {
+                break;
+            }
+        }
+        let mapped = mapped.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no STUN response from {} to a probe socket", stun_server),
+            )
+        })?;
+        ports.push(mapped.port());
+    }
+    Ok(ports)
+}
+
+/// One spray at a peer: fresh sockets each sending to one target, plus
+/// random targets for our own socket, repeated in rounds until the packet
+/// budget is spent. `send_due` keeps to the rate.
+pub(crate) struct Spray {
+    pub(crate) peer_id: String,
+    pub(crate) strategy: Strategy,
+    sockets: Vec<(Arc<dyn DatagramTransport>, SocketAddr)>,
+    // random ports of the peer's IP, sent to from the client's socket
+    own_targets: Vec<SocketAddr>,
+    start: Instant,
+    total: usize,
+    sent: usize,
+    packets_per_sec: u32,
+    finished: Option<Instant>,
+}
+
+impl Spray {
+    /// Bind the sockets for a spray at `peer_addr` into `group`, to start
+    /// at `start`. With the peer's allocation known to be sequential this
+    /// is port prediction, otherwise the birthday spray.
+    pub(crate) fn new(
+        config: &SprayConfig,
+        group: &SocketGroup,
+        peer_id: String,
+        peer_addr: SocketAddr,
+        peer_allocation: Option<Allocation>,
+        controlling: bool,
+        start: Instant,
+    ) -> io::Result<Self> {
+        let ip = peer_addr.ip();
+        let (strategy, targets, own_targets) = match peer_allocation {
+            Some(allocation @ Allocation::Sequential { .. }) => {
+                let width = config.sockets.min(config.max_packets).max(1);
+                let stride = if controlling { 2 } else { 1 };
+                let targets = (0..width)
+                    .filter_map(|k| allocation.port(k * stride))
+                    .map(|port| SocketAddr::new(ip, port))
+                    .collect();
+                (Strategy::PortPrediction, targets, Vec::new())
+            }
+            _ => {
+                let width = config.sockets.min(config.max_packets / 2).max(1);
+                let ports = birthday_ports(config.ports, width);
+                let random = ports.into_iter().map(|port| SocketAddr::new(ip, port)).collect();
+                (Strategy::Birthday, vec![peer_addr; width], random)
+            }
+        };
+
+        let mut sockets = Vec::with_capacity(targets.len());
+        for target in targets {
+            let socket = match group.bind_sibling(0).and_then(|s| group.add(s.clone()).map(|_| s)) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    let bound: Vec<_> = sockets.into_iter().map(|(s, _)| s).collect();
+                    group.release_unrouted(&bound);
+                    return Err(e);
+                }
+            };
+            sockets.push((socket, target));
+        }
+
+        // whole rounds only, and at least one
+        let per_round = sockets.len() + own_targets.len();
+        let total = (config.max_packets / per_round).max(1) * per_round;
+        Ok(Self {
+            peer_id,
+            strategy,
+            sockets,
+            own_targets,
+            start,
+            total,
+            sent: 0,
+            packets_per_sec: config.packets_per_sec.max(1),
+            finished: None,
+        })
+    }
+
+    /// Send what the rate allows by `now`, `own` for the packets from our
+    /// own socket. `packet` makes the punch for a round.
+    pub(crate) fn send_due(&mut self, now: Instant, own: &dyn DatagramTransport, packet: impl Fn(u32) -> Vec<u8>) {
+        if now < self.start || self.sent == self.total {
+            return;
+        }
+        let elapsed = now.duration_since(self.start).as_secs_f64();
+        let due = ((elapsed * self.packets_per_sec as f64) as usize + 1).min(self.total);
+        let per_round = self.sockets.len() + self.own_targets.len();
+        while self.sent < due {
+            let (round, i) = (self.sent / per_round, self.sent % per_round);
+            let data = packet(round as u32);
+            // a failed send loses one packet of many, the next round repeats it
+            let _ = match self.sockets.get(i) {
+                Some((socket, target)) => socket.send_to(&data, *target),
+                None => own.send_to(&data, self.own_targets[i - self.sockets.len()]),
+            };
+            self.sent += 1;
+        }
+        if self.sent == self.total {
+            self.finished = Some(now);
+        }
+    }
+
+    /// Send nothing more, e.g. because a path was nominated.
+    pub(crate) fn stop(&mut self, now: Instant) {
+        if self.finished.is_none() {
+            self.sent = self.total;
+            self.finished = Some(now);
+        }
+    }
+
+    /// Everything sent, and answers had time to come back.
+    pub(crate) fn is_done(&self, now: Instant) -> bool {
+        self.finished.is_some_and(|at| now >= at + SPRAY_LINGER)
+    }
+
+    pub(crate) fn packets(&self) -> usize {
+        self.total
+    }
+
+    pub(crate) fn sockets(&self) -> Vec<Arc<dyn DatagramTransport>> {
+        self.sockets.iter().map(|(socket, _)| socket.clone()).collect()
+    }
+
+    /// Whether our own socket sprayed at `addr`.
+    pub(crate) fn aimed_at(&self, addr: SocketAddr) -> bool {
+        self.own_targets.contains(&addr)
+    }
+}
+
+// `count` random ports from `first` to `last`, or all of them if there
+// are no more than that
+fn birthday_ports((first, last): (u16, u16), count: usize) -> Vec<u16> {
+    let (first, last) = (first.min(last), first.max(last));
+    let len = usize::from(last - first) + 1;
+    if len <= count {
+        return (first..=last).collect();
+    }
+    (0..count)
+        .map(|_| {
+            let mut bytes = [0; 4];
+            getrandom::fill(&mut bytes).expect("no system randomness");
+            first + (u32::from_be_bytes(bytes) as usize % len) as u16
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocation() {
+        assert_eq!(Allocation::from_ports(&[20000]), None);
+        let sequential = Allocation::from_ports(&[20004, 20008, 20012]).unwrap();
+        assert_eq!(sequential, Allocation::Sequential { next: 20016, step: 4 });
+        assert_eq!(sequential.port(2), Some(20024));
+        assert_eq!(Allocation::from_ports(&[65534, 65535, 0]), Some(Allocation::Sequential { next: 1, step: 1 }));
+        assert_eq!(Allocation::from_ports(&[20004, 20008, 20013]), Some(Allocation::Random { last: 20013 }));
+        assert_eq!(Allocation::from_ports(&[31000, 2000]), Some(Allocation::Random { last: 2000 }));
+
+        for allocation in [sequential, Allocation::Random { last: 31337 }] {
+            let (next_port, step) = allocation.to_wire();
+            assert_eq!(Allocation::from_wire(next_port, step), Some(allocation));
+        }
+        assert_eq!(Allocation::from_wire(0, 0), None);
+    }
+}
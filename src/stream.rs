@@ -0,0 +1,973 @@
+use crate::protocol::PeerMessage;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Largest payload per segment; keeps a sealed segment under a typical MTU.
+pub const MAX_SEGMENT: usize = 1024;
+// bytes `write` may queue ahead of the window
+const SEND_BUFFER: usize = 256 * 1024;
+// bytes buffered for the reader, in order or not; advertised as the window
+const RECV_BUFFER: usize = 256 * 1024;
+// width of the selective-ack bitmap in `StreamAck`
+const SACK_BITS: u32 = 32;
+// the receiver keeps nothing further ahead than the bitmap reaches, so more
+// in flight would only be thrown away after a loss
+const MAX_IN_FLIGHT: usize = SACK_BITS as usize;
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(10);
+// a segment unacknowledged this long means the peer is gone; long enough to
+// ride out keepalive detection and a re-punch
+const GIVE_UP_AFTER: Duration = Duration::from_secs(120);
+// incoming streams nobody has accepted yet
+const ACCEPT_BACKLOG: usize = 64;
+// how long a closed stream still acks retransmits of the peer's FIN, whose
+// ack may have been lost; after this the peer has given up anyway. Also how
+// long a dropped stream waits for the peer to shut down its side.
+const LINGER: Duration = GIVE_UP_AFTER;
+
+/// Round-trip estimate and retransmission timeout as in RFC 6298.
+#[derive(Debug)]
+struct Rtt {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Rtt {
+    fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap();
+        self.rto = (srtt + (self.rttvar * 4).max(Duration::from_millis(10))).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+
+    // the path changed; what we learned about it says nothing any more
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[derive(Debug)]
+struct Sent {
+    payload: Vec<u8>,
+    first_sent: Instant,
+    sent_at: Instant,
+    transmissions: u32,
+    // the receiver holds it out of order; only a cumulative ack frees it
+    sacked: bool,
+    // a later segment was sacked, so this one was probably lost
+    fast_retransmit: bool,
+}
+
+/// One direction pair of a stream: the sending and the receiving half, as a
+/// state machine without I/O. `poll` hands out what should go on the wire.
+///
+/// Segments are numbered from zero; an empty payload is the FIN. Acks carry
+/// the next expected segment, a bitmap of the 32 segments after it that
+/// arrived early, and the free receive buffer in bytes.
+#[derive(Debug)]
+pub(crate) struct Reliable {
+    stream_id: u32,
+    // send half
+    queue: VecDeque<u8>,
+    next_seq: u32,
+    in_flight: BTreeMap<u32, Sent>,
+    peer_window: u32,
+    fin_queued: bool,
+    fin_sent: bool,
+    rtt: Rtt,
+    failed: bool,
+    // receive half
+    recv_next: u32,
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    readable: VecDeque<u8>,
+    eof: bool,
+    ack_pending: bool,
+    // since when nobody holds the stream and only the peer's FIN is missing
+    half_open_since: Option<Instant>,
+}
+
+impl Reliable {
+    pub(crate) fn new(stream_id: u32) -> Self {
+        Self {
+            stream_id,
+            queue: VecDeque::new(),
+            next_seq: 0,
+            in_flight: BTreeMap::new(),
+            peer_window: RECV_BUFFER as u32,
+            fin_queued: false,
+            fin_sent: false,
+            rtt: Rtt::new(),
+            failed: false,
+            recv_next: 0,
+            out_of_order: BTreeMap::new(),
+            readable: VecDeque::new(),
+            eof: false,
+            ack_pending: false,
+            half_open_since: None,
+        }
+    }
+
+    /// Queue as much of `data` as the send buffer takes.
+    pub(crate) fn write(&mut self, data: &[u8]) -> usize {
+        if self.fin_queued {
+            return 0;
+        }
+        let n = data.len().min(SEND_BUFFER - self.queue.len());
+        self.queue.extend(&data[..n]);
+        n
+    }
+
+    /// Send a FIN once everything queued so far is out.
+    pub(crate) fn shutdown(&mut self) {
+        self.fin_queued = true;
+    }
+
+    pub(crate) fn read(&mut self, buf: &mut [u8]) -> usize {
+        let stalled = self.window() < (MAX_SEGMENT * 4) as u32;
+        let n = buf.len().min(self.readable.len());
+        for (dst, src) in buf.iter_mut().zip(self.readable.drain(..n)) {
+            *dst = src;
+        }
+        // the sender may be waiting on a zero window; tell it there's room
+        if stalled && n > 0 {
+            self.ack_pending = true;
+        }
+        n
+    }
+
+    pub(crate) fn on_data(&mut self, seq: u32, payload: &[u8]) {
+        self.ack_pending = true;
+        if seq < self.recv_next || self.eof || self.out_of_order.contains_key(&seq) {
+            return;
+        }
+        if payload.len() > self.window() as usize {
+            return;
+        }
+        if seq != self.recv_next {
+            if seq - self.recv_next <= SACK_BITS {
+                self.out_of_order.insert(seq, payload.to_vec());
+            }
+            return;
+        }
+        self.deliver(payload);
+        while let Some(payload) = self.out_of_order.remove(&self.recv_next) {
+            self.deliver(&payload);
+        }
+    }
+
+    fn deliver(&mut self, payload: &[u8]) {
+        self.recv_next += 1;
+        if payload.is_empty() {
+            self.eof = true;
+            self.out_of_order.clear();
+        } else {
+            self.readable.extend(payload);
+        }
+    }
+
+    pub(crate) fn on_ack(&mut self, ack: u32, sack: u32, window: u32, now: Instant) {
+        self.peer_window = window;
+
+        let acked: Vec<u32> = self.in_flight.range(..ack).map(|(seq, _)| *seq).collect();
+        for seq in acked {
+            let sent = self.in_flight.remove(&seq).unwrap();
+            // Karn: a retransmitted segment's ack says nothing about the RTT
+            if sent.transmissions == 1 && !sent.sacked {
+                self.rtt.sample(now - sent.sent_at);
+            }
+        }
+
+        let mut highest_sacked = None;
+        for bit in 0..SACK_BITS {
+            if sack & (1 << bit) == 0 {
+                continue;
+            }
+            let seq = ack + 1 + bit;
+            if let Some(sent) = self.in_flight.get_mut(&seq) {
+                if !sent.sacked && sent.transmissions == 1 {
+                    self.rtt.sample(now - sent.sent_at);
+                }
+                sent.sacked = true;
+                highest_sacked = Some(seq);
+            }
+        }
+
+        // holes below a sacked segment were most likely lost; resend them
+        // without waiting for the timer, at most once per round trip
+        if let Some(highest) = highest_sacked {
+            let srtt = self.rtt.srtt.unwrap_or(self.rtt.rto);
+            for sent in self.in_flight.range_mut(..highest).map(|(_, s)| s) {
+                if !sent.sacked && now - sent.sent_at >= srtt {
+                    sent.fast_retransmit = true;
+                }
+            }
+        }
+    }
+
+    /// Segments and acks due at `now`: expired retransmits first, then new
+    /// data the window allows, then an ack if the peer is owed one.
+    pub(crate) fn poll(&mut self, now: Instant) -> Vec<PeerMessage> {
+        let mut out = Vec::new();
+        if self.failed {
+            return out;
+        }
+
+        let rto = self.rtt.rto;
+        let mut timed_out = false;
+        for (seq, sent) in self.in_flight.iter_mut() {
+            if sent.sacked {
+                continue;
+            }
+            let expired = now - sent.sent_at >= rto;
+            if !expired && !sent.fast_retransmit {
+                continue;
+            }
+            if now - sent.first_sent >= GIVE_UP_AFTER {
+                self.failed = true;
+                return Vec::new();
+            }
+            timed_out |= expired && !sent.fast_retransmit;
+            sent.fast_retransmit = false;
+            sent.sent_at = now;
+            sent.transmissions += 1;
+            out.push(PeerMessage::StreamData {
+                stream_id: self.stream_id,
+                seq: *seq,
+                payload: sent.payload.clone(),
+            });
+        }
+        if timed_out {
+            self.rtt.backoff();
+        }
+
+        while self.in_flight.len() < MAX_IN_FLIGHT && !self.fin_sent {
+            let len = self.queue.len().min(MAX_SEGMENT);
+            if len == 0 && !self.fin_queued {
+                break;
+            }
+            // a lone segment may probe a zero window
+            let in_flight_bytes: usize = self
+                .in_flight
+                .values()
+                .filter(|s| !s.sacked)
+                .map(|s| s.payload.len())
+                .sum();
+            if !self.in_flight.is_empty() && in_flight_bytes + len > self.peer_window as usize {
+                break;
+            }
+            let payload: Vec<u8> = self.queue.drain(..len).collect();
+            self.fin_sent = payload.is_empty();
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            out.push(PeerMessage::StreamData {
+                stream_id: self.stream_id,
+                seq,
+                payload: payload.clone(),
+            });
+            self.in_flight.insert(
+                seq,
+                Sent {
+                    payload,
+                    first_sent: now,
+                    sent_at: now,
+                    transmissions: 1,
+                    sacked: false,
+                    fast_retransmit: false,
+                },
+            );
+        }
+
+        if self.ack_pending {
+            self.ack_pending = false;
+            out.push(self.ack());
+        }
+        out
+    }
+
+    fn ack(&self) -> PeerMessage {
+        let sack = self
+            .out_of_order
+            .keys()
+            .map(|seq| seq - self.recv_next - 1)
+            .filter(|bit| *bit < SACK_BITS)
+            .fold(0, |bits, bit| bits | 1 << bit);
+        PeerMessage::StreamAck {
+            stream_id: self.stream_id,
+            ack: self.recv_next,
+            sack,
+            window: self.window(),
+        }
+    }
+
+    fn window(&self) -> u32 {
+        let buffered = self.readable.len() + self.out_of_order.values().map(Vec::len).sum::<usize>();
+        RECV_BUFFER.saturating_sub(buffered) as u32
+    }
+
+    /// The path to the peer was re-established, maybe at a new address:
+    /// forget the RTT estimate and its backoff and resend what is missing.
+    pub(crate) fn resume(&mut self) {
+        self.rtt.reset();
+        for sent in self.in_flight.values_mut().filter(|s| !s.sacked) {
+            sent.fast_retransmit = true;
+        }
+        self.ack_pending = true;
+    }
+
+    /// Everything written, including a queued FIN, has been acknowledged.
+    pub(crate) fn all_acked(&self) -> bool {
+        self.queue.is_empty() && self.in_flight.is_empty() && (self.fin_sent || !self.fin_queued)
+    }
+
+    /// Both sides shut down and everything of ours was acknowledged.
+    pub(crate) fn finished(&self) -> bool {
+        self.eof && self.fin_queued && self.all_acked()
+    }
+
+    pub(crate) fn has_failed(&self) -> bool {
+        self.failed
+    }
+
+    fn fail(&mut self) {
+        self.failed = true;
+    }
+}
+
+/// How the stream layer gets a message to a peer. The client seals it under
+/// the peer's Noise session and sends it over the punched path.
+pub(crate) type SendFn = Arc<dyn Fn(&str, &PeerMessage) -> io::Result<()> + Send + Sync>;
+
+struct Shared {
+    peer_id: String,
+    stream_id: u32,
+    state: Mutex<Reliable>,
+    changed: Condvar,
+    send: SendFn,
+}
+
+impl Shared {
+    // Put whatever the state machine has ready on the wire. The lock is not
+    // held while sending.
+    fn transmit(&self, now: Instant) {
+        let (msgs, failed) = {
+            let mut state = self.state.lock().unwrap();
+            let msgs = state.poll(now);
+            (msgs, state.has_failed())
+        };
+        for msg in &msgs {
+            if let Err(e) = (self.send)(&self.peer_id, msg) {
+                say!(
+                    "❌ Stream {} to {}: send failed: {}",
+                    self.stream_id, self.peer_id, e
+                );
+            }
+        }
+        if failed {
+            self.changed.notify_all();
+        }
+    }
+}
+
+fn reset() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "peer stopped acknowledging")
+}
+
+/// A reliable, ordered byte stream to one peer, multiplexed over the
+/// punched UDP path. Obtained from `Client::open_stream` or
+/// `Client::accept_stream`.
+///
+/// `write` queues and returns; `flush` blocks until the peer has
+/// acknowledged everything. `read` returns 0 once the peer shut down its
+/// side. Dropping the stream shuts down our side.
+pub struct Stream {
+    shared: Arc<Shared>,
+    read_timeout: Option<Duration>,
+}
+
+impl Stream {
+    fn new(shared: Arc<Shared>) -> Self {
+        Self {
+            shared,
+            read_timeout: None,
+        }
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.shared.peer_id
+    }
+
+    pub fn id(&self) -> u32 {
+        self.shared.stream_id
+    }
+
+    /// `None` blocks forever; otherwise `read` fails with `TimedOut`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Send a FIN after the data written so far; the peer's reads then end.
+    pub fn shutdown(&self) {
+        self.shared.state.lock().unwrap().shutdown();
+        self.shared.transmit(Instant::now());
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.map(|t| Instant::now() + t);
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            let n = state.read(buf);
+            if n > 0 {
+                drop(state);
+                // may carry a window update
+                self.shared.transmit(Instant::now());
+                return Ok(n);
+            }
+            if state.eof || buf.is_empty() {
+                return Ok(0);
+            }
+            if state.has_failed() {
+                return Err(reset());
+            }
+            state = match deadline {
+                None => self.shared.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "stream read timed out"));
+                    }
+                    self.shared.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.has_failed() {
+                return Err(reset());
+            }
+            if state.fin_queued {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "stream is shut down"));
+            }
+            let n = state.write(buf);
+            if n > 0 || buf.is_empty() {
+                drop(state);
+                self.shared.transmit(Instant::now());
+                return Ok(n);
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if state.has_failed() {
+                return Err(reset());
+            }
+            if state.all_acked() {
+                return Ok(());
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.fin_queued && !state.has_failed() {
+            state.shutdown();
+            drop(state);
+            self.shared.transmit(Instant::now());
+        }
+    }
+}
+
+/// Every stream of one client, keyed by peer and stream id.
+pub(crate) struct Streams {
+    local_id: String,
+    send: SendFn,
+    table: Mutex<HashMap<(String, u32), Arc<Shared>>>,
+    // finished streams that were dropped: the last segment acked, and when
+    closed: Mutex<HashMap<(String, u32), (u32, Instant)>>,
+    incoming: Mutex<VecDeque<Stream>>,
+    incoming_ready: Condvar,
+    next: AtomicU32,
+}
+
+impl Streams {
+    pub(crate) fn new(local_id: String, send: SendFn) -> Self {
+        Self {
+            local_id,
+            send,
+            table: Mutex::new(HashMap::new()),
+            closed: Mutex::new(HashMap::new()),
+            incoming: Mutex::new(VecDeque::new()),
+            incoming_ready: Condvar::new(),
+            next: AtomicU32::new(0),
+        }
+    }
+
+    // Both ends may open streams; the smaller id uses even stream ids.
+    fn parity(&self, peer_id: &str) -> u32 {
+        if self.local_id.as_str() < peer_id {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn insert(&self, peer_id: &str, stream_id: u32) -> Arc<Shared> {
+        let shared = Arc::new(Shared {
+            peer_id: peer_id.to_string(),
+            stream_id,
+            state: Mutex::new(Reliable::new(stream_id)),
+            changed: Condvar::new(),
+            send: self.send.clone(),
+        });
+        self.table
+            .lock()
+            .unwrap()
+            .insert((peer_id.to_string(), stream_id), shared.clone());
+        shared
+    }
+
+    /// The id our next stream to `peer_id` gets, without opening it yet.
+    pub(crate) fn reserve(&self, peer_id: &str) -> u32 {
+        self.next.fetch_add(1, Ordering::Relaxed) * 2 + self.parity(peer_id)
+    }
+
+    pub(crate) fn open(&self, peer_id: &str) -> Stream {
+        Stream::new(self.insert(peer_id, self.reserve(peer_id)))
+    }
+
+    /// Take a stream the peer is about to open, announced out of band, so
+    /// it never shows up in `accept`; or open one we reserved.
+    pub(crate) fn claim(&self, peer_id: &str, stream_id: u32) -> Stream {
+        let known = self
+            .table
+            .lock()
+            .unwrap()
+            .get(&(peer_id.to_string(), stream_id))
+            .cloned();
+        Stream::new(known.unwrap_or_else(|| self.insert(peer_id, stream_id)))
+    }
+
+    pub(crate) fn accept(&self, timeout: Duration) -> Option<Stream> {
+        let incoming = self.incoming.lock().unwrap();
+        let (mut incoming, _) = self
+            .incoming_ready
+            .wait_timeout_while(incoming, timeout, |q| q.is_empty())
+            .unwrap();
+        incoming.pop_front()
+    }
+
+    /// Route a stream packet from an authenticated peer. Returns false if
+    /// `msg` is not stream traffic.
+    pub(crate) fn handle(&self, peer_id: &str, msg: &PeerMessage) -> bool {
+        let now = Instant::now();
+        let stream_id = match msg {
+            PeerMessage::StreamData { stream_id, .. } | PeerMessage::StreamAck { stream_id, .. } => *stream_id,
+            _ => return false,
+        };
+
+        let key = (peer_id.to_string(), stream_id);
+        let known = self.table.lock().unwrap().get(&key).cloned();
+        if known.is_none() && matches!(msg, PeerMessage::StreamData { .. }) {
+            // a retransmit on a stream we closed; our ack of its FIN got lost
+            let lingering = self.closed.lock().unwrap().get(&key).map(|(ack, _)| *ack);
+            if let Some(ack) = lingering {
+                let ack = PeerMessage::StreamAck {
+                    stream_id,
+                    ack,
+                    sack: 0,
+                    window: RECV_BUFFER as u32,
+                };
+                // best effort; the peer retransmits until one gets through
+                let _ = (self.send)(peer_id, &ack);
+                return true;
+            }
+        }
+        let shared = match (known, msg) {
+            (Some(shared), _) => shared,
+            // data on a new stream of theirs opens it
+            (None, PeerMessage::StreamData { .. }) if stream_id % 2 != self.parity(peer_id) => {
+                let mut incoming = self.incoming.lock().unwrap();
+                if incoming.len() >= ACCEPT_BACKLOG {
+                    return true;
+                }
+                let shared = self.insert(peer_id, stream_id);
+                incoming.push_back(Stream::new(shared.clone()));
+                self.incoming_ready.notify_all();
+                say!("🧵 {} opened stream {}", peer_id, stream_id);
+                shared
+            }
+            _ => return true,
+        };
+
+        {
+            let mut state = shared.state.lock().unwrap();
+            match msg {
+                PeerMessage::StreamData { seq, payload, .. } => state.on_data(*seq, payload),
+                PeerMessage::StreamAck {
+                    ack, sack, window, ..
+                } => state.on_ack(*ack, *sack, *window, now),
+                _ => unreachable!(),
+            }
+        }
+        shared.changed.notify_all();
+        shared.transmit(now);
+        true
+    }
+
+    /// Run retransmission timers and forget streams that are done; called
+    /// regularly by the listener.
+    pub(crate) fn poll(&self) {
+        self.poll_at(Instant::now());
+    }
+
+    fn poll_at(&self, now: Instant) {
+        let streams: Vec<_> = {
+            let mut table = self.table.lock().unwrap();
+            let mut closed = self.closed.lock().unwrap();
+            closed.retain(|_, (_, at)| now - *at < LINGER);
+            // handles are only cloned out of the table under its lock, so a
+            // count of one means no `Stream` is left, accepted or not
+            table.retain(|key, shared| {
+                if Arc::strong_count(shared) > 1 {
+                    return true;
+                }
+                let mut state = shared.state.lock().unwrap();
+                if state.finished() {
+                    closed.insert(key.clone(), (state.recv_next, now));
+                    return false;
+                }
+                if state.has_failed() {
+                    return false;
+                }
+                if !state.fin_queued || !state.all_acked() {
+                    return true;
+                }
+                // ours is done but the peer never shut down its side
+                let since = *state.half_open_since.get_or_insert(now);
+                if now - since < LINGER {
+                    return true;
+                }
+                say!("🧵 Giving up on half-open stream {} to {}", key.1, key.0);
+                closed.insert(key.clone(), (state.recv_next, now));
+                false
+            });
+            table.values().cloned().collect()
+        };
+        for shared in streams {
+            shared.transmit(now);
+        }
+    }
+
+    /// The path to `peer_id` came back after an outage: retransmit right
+    /// away instead of waiting out a backed-off timer.
+    pub(crate) fn resume(&self, peer_id: &str) {
+        let now = Instant::now();
+        let streams: Vec<_> = self
+            .table
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((peer, _), _)| peer == peer_id)
+            .map(|(_, shared)| shared.clone())
+            .collect();
+        for shared in streams {
+            shared.state.lock().unwrap().resume();
+            shared.transmit(now);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// The peer closed the connection: fail its streams and forget them.
+    pub(crate) fn drop_peer(&self, peer_id: &str) {
+        self.closed.lock().unwrap().retain(|(peer, _), _| peer != peer_id);
+        let mut table = self.table.lock().unwrap();
+        table.retain(|(peer, _), shared| {
+            if peer != peer_id {
+                return true;
+            }
+            shared.state.lock().unwrap().fail();
+            shared.changed.notify_all();
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deliver every message from `from` to `to`, dropping some by index.
+    fn exchange(from: &mut Reliable, to: &mut Reliable, now: Instant, drop: &mut dyn FnMut() -> bool) {
+        let mut msgs = from.poll(now);
+        // reorder a little, as UDP may
+        msgs.reverse();
+        for msg in msgs {
+            if drop() {
+                continue;
+            }
+            match msg {
+                PeerMessage::StreamData { seq, payload, .. } => to.on_data(seq, &payload),
+                PeerMessage::StreamAck { ack, sack, window, .. } => to.on_ack(ack, sack, window, now),
+                other => panic!("unexpected {}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_lossy_transfer() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut a = Reliable::new(0);
+        let mut b = Reliable::new(0);
+        let mut written = 0;
+        let mut received = Vec::new();
+        // drop a third of the packets in either direction; a fixed stride
+        // would fall into step with the rounds and always hit the same ones
+        let mut rng = 0x2545_f491u32;
+        let mut lossy = || {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            rng.is_multiple_of(3)
+        };
+
+        let mut now = Instant::now();
+        for _ in 0..10_000 {
+            if written < data.len() {
+                written += a.write(&data[written..]);
+            } else {
+                a.shutdown();
+            }
+            exchange(&mut a, &mut b, now, &mut lossy);
+            exchange(&mut b, &mut a, now, &mut lossy);
+            let mut buf = [0; 4096];
+            loop {
+                let got = b.read(&mut buf);
+                if got == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..got]);
+            }
+            if b.eof && a.all_acked() {
+                break;
+            }
+            now += Duration::from_millis(50);
+        }
+
+        assert!(!a.has_failed());
+        assert!(b.eof);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_selective_ack() {
+        let now = Instant::now();
+        let mut a = Reliable::new(7);
+        let mut b = Reliable::new(7);
+        a.write(&[1; MAX_SEGMENT * 3]);
+        let msgs = a.poll(now);
+        assert_eq!(msgs.len(), 3);
+        // segment 0 is lost, 1 and 2 arrive
+        for msg in &msgs[1..] {
+            if let PeerMessage::StreamData { seq, payload, .. } = msg {
+                b.on_data(*seq, payload);
+            }
+        }
+        let ack = b.poll(now).pop().unwrap();
+        assert!(matches!(ack, PeerMessage::StreamAck { ack: 0, sack: 0b11, .. }));
+
+        // after an RTT the hole is resent right away, the sacked ones never
+        let later = now + INITIAL_RTO / 2;
+        if let PeerMessage::StreamAck { ack, sack, window, .. } = ack {
+            a.on_ack(ack, sack, window, later);
+        }
+        let resent = a.poll(later);
+        assert_eq!(resent.len(), 1);
+        assert!(matches!(resent[0], PeerMessage::StreamData { seq: 0, .. }));
+    }
+
+    #[test]
+    fn test_zero_window_and_failure() {
+        let mut now = Instant::now();
+        let mut a = Reliable::new(1);
+        a.write(&[0; MAX_SEGMENT * 4]);
+        a.on_ack(0, 0, 0, now);
+        // a closed window still lets one probe out
+        assert_eq!(a.poll(now).len(), 1);
+        assert!(a.poll(now).is_empty());
+
+        // nobody ever answers
+        let started = now;
+        while now - started < GIVE_UP_AFTER - MAX_RTO {
+            now += MAX_RTO;
+            a.poll(now);
+        }
+        assert!(!a.has_failed());
+        now += MAX_RTO;
+        a.poll(now);
+        assert!(a.has_failed());
+    }
+
+    #[test]
+    fn test_resume_after_outage() {
+        let mut now = Instant::now();
+        let mut a = Reliable::new(3);
+        a.write(&[5; MAX_SEGMENT * 2]);
+        assert_eq!(a.poll(now).len(), 2);
+        // the path is down for a while; the timer backs off
+        for _ in 0..6 {
+            now += MAX_RTO;
+            a.poll(now);
+        }
+        assert_eq!(a.rtt.rto, MAX_RTO);
+
+        // re-punched: everything goes out again without waiting
+        a.resume();
+        now += Duration::from_millis(1);
+        let resent = a.poll(now);
+        assert_eq!(resent.len(), 3);
+        assert!(matches!(resent[2], PeerMessage::StreamAck { .. }));
+        assert_eq!(a.rtt.rto, INITIAL_RTO);
+        assert!(!a.has_failed());
+    }
+
+    #[test]
+    fn test_finished_stream_is_forgotten() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let log = sent.clone();
+        let send: SendFn = Arc::new(move |_: &str, msg: &PeerMessage| {
+            log.lock().unwrap().push(msg.clone());
+            Ok(())
+        });
+        let streams = Streams::new("alice".to_string(), send);
+        let data = |seq, payload: &[u8]| PeerMessage::StreamData {
+            stream_id: 1,
+            seq,
+            payload: payload.to_vec(),
+        };
+
+        // bob opens stream 1, sends a line and shuts down
+        streams.handle("bob", &data(0, b"hi"));
+        streams.handle("bob", &data(1, b""));
+        let mut stream = streams.accept(Duration::ZERO).unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hi");
+        streams.poll();
+        assert_eq!(streams.table.lock().unwrap().len(), 1);
+
+        // our FIN goes out with the drop; once bob acks it nothing is left
+        drop(stream);
+        streams.poll();
+        assert_eq!(streams.table.lock().unwrap().len(), 1);
+        streams.handle(
+            "bob",
+            &PeerMessage::StreamAck {
+                stream_id: 1,
+                ack: 1,
+                sack: 0,
+                window: RECV_BUFFER as u32,
+            },
+        );
+        streams.poll();
+        assert!(streams.table.lock().unwrap().is_empty());
+
+        // bob never saw our ack of his FIN: ack it again, don't reopen
+        sent.lock().unwrap().clear();
+        streams.handle("bob", &data(1, b""));
+        assert!(streams.accept(Duration::ZERO).is_none());
+        assert!(streams.table.lock().unwrap().is_empty());
+        assert!(matches!(
+            sent.lock().unwrap()[..],
+            [PeerMessage::StreamAck { ack: 2, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_half_open_stream_is_forgotten() {
+        let send: SendFn = Arc::new(|_: &str, _: &PeerMessage| Ok(()));
+        let streams = Streams::new("alice".to_string(), send);
+        let stream = streams.open("bob");
+        let stream_id = stream.shared.stream_id;
+
+        // bob acks our FIN but never sends his
+        drop(stream);
+        streams.handle(
+            "bob",
+            &PeerMessage::StreamAck {
+                stream_id,
+                ack: 1,
+                sack: 0,
+                window: RECV_BUFFER as u32,
+            },
+        );
+        let now = Instant::now();
+        streams.poll_at(now);
+        streams.poll_at(now + LINGER / 2);
+        assert_eq!(streams.len(), 1);
+        streams.poll_at(now + LINGER);
+        assert_eq!(streams.len(), 0);
+    }
+
+    #[test]
+    fn test_in_flight_fits_sack_window() {
+        let mut a = Reliable::new(0);
+        a.write(&[0; MAX_SEGMENT * 100]);
+        assert_eq!(a.poll(Instant::now()).len(), SACK_BITS as usize);
+    }
+
+    #[test]
+    fn test_rto_estimate() {
+        let mut rtt = Rtt::new();
+        assert_eq!(rtt.rto, INITIAL_RTO);
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(rtt.rto, Duration::from_millis(300));
+        for _ in 0..50 {
+            rtt.sample(Duration::from_millis(100));
+        }
+        assert_eq!(rtt.rto, MIN_RTO);
+        rtt.backoff();
+        assert_eq!(rtt.rto, MIN_RTO * 2);
+    }
+}
@@ -0,0 +1,434 @@
+// Minimal RFC 5389 STUN codec: Binding requests and Binding success
+// responses carrying XOR-MAPPED-ADDRESS. Enough for standard tools and ICE
+// stacks to learn their reflexive address from our signaling server, plus
+// the RFC 5780 CHANGE-REQUEST / OTHER-ADDRESS pair used for NAT behavior
+// discovery.
+
+use crate::protocol::ProtocolError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_OTHER_ADDRESS: u16 = 0x802c;
+
+const CHANGE_IP: u32 = 0x04;
+const CHANGE_PORT: u32 = 0x02;
+
+pub type TransactionId = [u8; 12];
+
+/// Cheap check used to route a packet to this codec before trying
+/// `protocol::Message`: the two top bits are zero and the cookie matches.
+pub fn is_stun(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN
+        && data[0] & 0xc0 == 0
+        && data[4..8] == MAGIC_COOKIE.to_be_bytes()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindingRequest {
+    pub transaction_id: TransactionId,
+    /// Ask the server to answer from its other IP address.
+    pub change_ip: bool,
+    /// Ask the server to answer from its other port.
+    pub change_port: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BindingResponse {
+    pub transaction_id: TransactionId,
+    pub mapped_addr: SocketAddr,
+    /// The server's alternate address, if it has one (RFC 5780).
+    pub other_addr: Option<SocketAddr>,
+}
+
+/// Binding Error Response, e.g. 420 when the server cannot honour a
+/// CHANGE-REQUEST because it lacks the required alternate socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindingError {
+    pub transaction_id: TransactionId,
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Anything a client may get back for a Binding Request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StunReply {
+    Success(BindingResponse),
+    Error(BindingError),
+}
+
+impl BindingRequest {
+    /// A request with a fresh random transaction id.
+    pub fn new() -> Self {
+        Self::with_change(false, false)
+    }
+
+    pub fn with_change(change_ip: bool, change_port: bool) -> Self {
+        let mut transaction_id = [0u8; 12];
+        getrandom::fill(&mut transaction_id).expect("no system randomness");
+        Self {
+            transaction_id,
+            change_ip,
+            change_port,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        if !self.change_ip && !self.change_port {
+            return encode_header(BINDING_REQUEST, &self.transaction_id, 0);
+        }
+        let mut flags = 0;
+        if self.change_ip {
+            flags |= CHANGE_IP;
+        }
+        if self.change_port {
+            flags |= CHANGE_PORT;
+        }
+        let mut data = encode_header(BINDING_REQUEST, &self.transaction_id, 8);
+        data.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+        data.extend_from_slice(&4u16.to_be_bytes());
+        data.extend_from_slice(&flags.to_be_bytes());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        let (msg_type, transaction_id, body) = decode_header(data)?;
+        if msg_type != BINDING_REQUEST {
+            return Err(unknown_type(msg_type));
+        }
+        let mut flags = 0;
+        for (attr_type, offset, value) in attributes(body)? {
+            if attr_type == ATTR_CHANGE_REQUEST {
+                let value: [u8; 4] = value.try_into().map_err(|_| ProtocolError::BadNumber {
+                    field: "CHANGE-REQUEST",
+                    offset,
+                })?;
+                flags = u32::from_be_bytes(value);
+            }
+        }
+        Ok(Self {
+            transaction_id,
+            change_ip: flags & CHANGE_IP != 0,
+            change_port: flags & CHANGE_PORT != 0,
+        })
+    }
+}
+
+impl Default for BindingRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BindingResponse {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(48);
+        put_xor_address(&mut body, self.mapped_addr, &self.transaction_id);
+        // plain MAPPED-ADDRESS for RFC 3489 clients
+        put_address(&mut body, ATTR_MAPPED_ADDRESS, self.mapped_addr);
+        if let Some(other) = self.other_addr {
+            put_address(&mut body, ATTR_OTHER_ADDRESS, other);
+        }
+
+        let mut data = encode_header(BINDING_SUCCESS, &self.transaction_id, body.len());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        let (msg_type, transaction_id, body) = decode_header(data)?;
+        if msg_type != BINDING_SUCCESS {
+            return Err(unknown_type(msg_type));
+        }
+
+        let mut xor_mapped = None;
+        let mut mapped = None;
+        let mut other_addr = None;
+        for (attr_type, offset, value) in attributes(body)? {
+            match attr_type {
+                ATTR_XOR_MAPPED_ADDRESS => {
+                    let addr = read_address(value, offset)?;
+                    xor_mapped = Some(xor_address(addr, &transaction_id));
+                }
+                ATTR_MAPPED_ADDRESS => mapped = Some(read_address(value, offset)?),
+                ATTR_OTHER_ADDRESS => other_addr = Some(read_address(value, offset)?),
+                _ => {}
+            }
+        }
+
+        let mapped_addr = xor_mapped
+            .or(mapped)
+            .ok_or(ProtocolError::WrongFieldCount {
+                field: "XOR-MAPPED-ADDRESS",
+                offset: data.len(),
+            })?;
+        Ok(Self {
+            transaction_id,
+            mapped_addr,
+            other_addr,
+        })
+    }
+}
+
+impl BindingError {
+    pub fn encode(&self) -> Vec<u8> {
+        let reason = self.reason.as_bytes();
+        let len = 4 + reason.len();
+        let padded = (len + 3) & !3;
+
+        let mut data = encode_header(BINDING_ERROR, &self.transaction_id, 4 + padded);
+        data.extend_from_slice(&ATTR_ERROR_CODE.to_be_bytes());
+        data.extend_from_slice(&(len as u16).to_be_bytes());
+        data.extend_from_slice(&[0, 0, (self.code / 100) as u8, (self.code % 100) as u8]);
+        data.extend_from_slice(reason);
+        data.resize(HEADER_LEN + 4 + padded, 0);
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        let (msg_type, transaction_id, body) = decode_header(data)?;
+        if msg_type != BINDING_ERROR {
+            return Err(unknown_type(msg_type));
+        }
+        for (attr_type, offset, value) in attributes(body)? {
+            if attr_type == ATTR_ERROR_CODE && value.len() >= 4 {
+                let code = (value[2] & 0x07) as u16 * 100 + value[3] as u16;
+                let reason = std::str::from_utf8(&value[4..])
+                    .map_err(|_| ProtocolError::BadString {
+                        field: "ERROR-CODE reason",
+                        offset: offset + 4,
+                    })?
+                    .to_string();
+                return Ok(Self {
+                    transaction_id,
+                    code,
+                    reason,
+                });
+            }
+        }
+        Err(ProtocolError::WrongFieldCount {
+            field: "ERROR-CODE",
+            offset: data.len(),
+        })
+    }
+}
+
+impl StunReply {
+    pub fn decode(data: &[u8]) -> Result<Self, ProtocolError> {
+        let (msg_type, _, _) = decode_header(data)?;
+        match msg_type {
+            BINDING_ERROR => BindingError::decode(data).map(StunReply::Error),
+            _ => BindingResponse::decode(data).map(StunReply::Success),
+        }
+    }
+
+    pub fn transaction_id(&self) -> TransactionId {
+        match self {
+            StunReply::Success(r) => r.transaction_id,
+            StunReply::Error(e) => e.transaction_id,
+        }
+    }
+}
+
+fn unknown_type(msg_type: u16) -> ProtocolError {
+    ProtocolError::UnknownTag {
+        tag: format!("stun {:#06x}", msg_type),
+        offset: 0,
+    }
+}
+
+fn encode_header(msg_type: u16, transaction_id: &TransactionId, body_len: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LEN + body_len);
+    data.extend_from_slice(&msg_type.to_be_bytes());
+    data.extend_from_slice(&(body_len as u16).to_be_bytes());
+    data.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    data.extend_from_slice(transaction_id);
+    data
+}
+
+fn decode_header(data: &[u8]) -> Result<(u16, TransactionId, &[u8]), ProtocolError> {
+    if !is_stun(data) {
+        return Err(ProtocolError::WrongFieldCount {
+            field: "stun header",
+            offset: 0,
+        });
+    }
+    let msg_type = u16::from_be_bytes([data[0], data[1]]);
+    let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if data.len() != HEADER_LEN + len {
+        return Err(ProtocolError::WrongFieldCount {
+            field: "stun length",
+            offset: 2,
+        });
+    }
+    let transaction_id = data[8..HEADER_LEN].try_into().unwrap();
+    Ok((msg_type, transaction_id, &data[HEADER_LEN..]))
+}
+
+// (type, absolute offset of the value, value)
+type Attribute<'a> = (u16, usize, &'a [u8]);
+
+fn attributes(body: &[u8]) -> Result<Vec<Attribute<'_>>, ProtocolError> {
+    let mut attrs = Vec::new();
+    let mut pos = 0;
+    while pos < body.len() {
+        let offset = HEADER_LEN + pos;
+        if body.len() - pos < 4 {
+            return Err(ProtocolError::WrongFieldCount {
+                field: "attribute header",
+                offset,
+            });
+        }
+        let attr_type = u16::from_be_bytes([body[pos], body[pos + 1]]);
+        let len = u16::from_be_bytes([body[pos + 2], body[pos + 3]]) as usize;
+        let start = pos + 4;
+        if body.len() - start < len {
+            return Err(ProtocolError::WrongFieldCount {
+                field: "attribute value",
+                offset: offset + 4,
+            });
+        }
+        attrs.push((attr_type, offset + 4, &body[start..start + len]));
+        // values are padded to a multiple of four bytes
+        pos = (start + len + 3) & !3;
+    }
+    Ok(attrs)
+}
+
+fn read_address(value: &[u8], offset: usize) -> Result<SocketAddr, ProtocolError> {
+    let bad = ProtocolError::BadAddress {
+        field: "mapped address",
+        offset,
+    };
+    if value.len() < 4 {
+        return Err(bad);
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]);
+    let ip = match (value[1], value.len()) {
+        (0x01, 8) => IpAddr::V4(Ipv4Addr::new(value[4], value[5], value[6], value[7])),
+        (0x02, 20) => {
+            let octets: [u8; 16] = value[4..20].try_into().unwrap();
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(bad),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn put_address(out: &mut Vec<u8>, attr_type: u16, addr: SocketAddr) {
+    let (family, ip): (u8, Vec<u8>) = match addr.ip() {
+        IpAddr::V4(ip) => (0x01, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (0x02, ip.octets().to_vec()),
+    };
+    out.extend_from_slice(&attr_type.to_be_bytes());
+    out.extend_from_slice(&(4 + ip.len() as u16).to_be_bytes());
+    out.push(0);
+    out.push(family);
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out.extend_from_slice(&ip);
+}
+
+fn put_xor_address(out: &mut Vec<u8>, addr: SocketAddr, transaction_id: &TransactionId) {
+    put_address(
+        out,
+        ATTR_XOR_MAPPED_ADDRESS,
+        xor_address(addr, transaction_id),
+    );
+}
+
+/// XOR-MAPPED-ADDRESS obfuscation; applying it twice is the identity.
+fn xor_address(addr: SocketAddr, transaction_id: &TransactionId) -> SocketAddr {
+    let cookie = MAGIC_COOKIE.to_be_bytes();
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut o = ip.octets();
+            for (b, k) in o.iter_mut().zip(cookie.iter()) {
+                *b ^= k;
+            }
+            IpAddr::V4(Ipv4Addr::from(o))
+        }
+        IpAddr::V6(ip) => {
+            let mut o = ip.octets();
+            let key = cookie.iter().chain(transaction_id.iter());
+            for (b, k) in o.iter_mut().zip(key) {
+                *b ^= k;
+            }
+            IpAddr::V6(Ipv6Addr::from(o))
+        }
+    };
+    SocketAddr::new(ip, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc5769_ipv4_response() {
+        // RFC 5769 section 2.2 "Sample IPv4 Response"
+        let data: [u8; 80] = [
+            0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34,
+            0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74,
+            0x20, 0x76, 0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01,
+            0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99,
+            0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b,
+            0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+        ];
+        assert!(is_stun(&data));
+        let response = BindingResponse::decode(&data).unwrap();
+        assert_eq!(response.mapped_addr, "192.0.2.1:32853".parse().unwrap());
+    }
+
+    #[test]
+    fn test_binding_roundtrip() {
+        let request = BindingRequest::new();
+        let data = request.encode();
+        assert!(is_stun(&data));
+        assert_eq!(BindingRequest::decode(&data).unwrap(), request);
+
+        for addr in ["203.0.113.5:40000", "[2001:db8::7]:5000"] {
+            let response = BindingResponse {
+                transaction_id: request.transaction_id,
+                mapped_addr: addr.parse().unwrap(),
+                other_addr: Some("198.51.100.2:3479".parse().unwrap()),
+            };
+            assert_eq!(BindingResponse::decode(&response.encode()).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_change_request_and_error() {
+        let request = BindingRequest::with_change(false, true);
+        let decoded = BindingRequest::decode(&request.encode()).unwrap();
+        assert!(!decoded.change_ip && decoded.change_port);
+
+        let error = BindingError {
+            transaction_id: request.transaction_id,
+            code: 420,
+            reason: "No alternate".to_string(),
+        };
+        assert_eq!(
+            StunReply::decode(&error.encode()).unwrap(),
+            StunReply::Error(error)
+        );
+    }
+
+    #[test]
+    fn test_signaling_packets_are_not_stun() {
+        let msg = crate::protocol::Message::Discover {
+            target: "a-rather-long-peer-id".to_string(),
+        };
+        assert!(!is_stun(&msg.encode()));
+        assert!(!is_stun(msg.encode_text().as_bytes()));
+    }
+}
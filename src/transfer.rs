@@ -0,0 +1,326 @@
+use crate::protocol::PeerMessage;
+use crate::stream::Stream;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// read and write granularity for file bodies
+const CHUNK: usize = 64 * 1024;
+// offers nobody answered yet; further ones are dropped until some are
+const MAX_PENDING_OFFERS: usize = 32;
+
+/// Sent by the receiver once it checked the whole-file hash.
+pub(crate) const STATUS_VERIFIED: u8 = 1;
+pub(crate) const STATUS_CORRUPT: u8 = 0;
+
+/// A file a peer wants to send us, waiting for `accept` or `reject`.
+#[derive(Debug, Clone)]
+pub struct FileOffer {
+    pub peer_id: String,
+    pub stream_id: u32,
+    pub name: String,
+    pub size: u64,
+    pub sha256: Vec<u8>,
+}
+
+/// Offers received but not answered, and answers already given. Offers
+/// travel as plain datagrams and are repeated until answered, so a repeat
+/// of an answered offer gets the same answer again.
+#[derive(Default)]
+pub(crate) struct Offers {
+    pending: Vec<FileOffer>,
+    answered: HashMap<(String, u32), PeerMessage>,
+}
+
+impl Offers {
+    /// Queue an offer; false if it is a repeat or the queue is full.
+    pub(crate) fn offer(&mut self, offer: FileOffer) -> bool {
+        let repeat = self
+            .pending
+            .iter()
+            .any(|o| o.peer_id == offer.peer_id && o.stream_id == offer.stream_id);
+        if repeat || self.pending.len() >= MAX_PENDING_OFFERS {
+            return false;
+        }
+        self.pending.push(offer);
+        true
+    }
+
+    pub(crate) fn answer_for(&self, peer_id: &str, stream_id: u32) -> Option<&PeerMessage> {
+        self.answered.get(&(peer_id.to_string(), stream_id))
+    }
+
+    /// The oldest offer, from `peer_id` if given.
+    pub(crate) fn take(&mut self, peer_id: Option<&str>) -> Option<FileOffer> {
+        let i = self
+            .pending
+            .iter()
+            .position(|o| peer_id.is_none_or(|id| o.peer_id == id))?;
+        Some(self.pending.remove(i))
+    }
+
+    pub(crate) fn answer(&mut self, offer: &FileOffer, msg: PeerMessage) {
+        self.answered
+            .insert((offer.peer_id.clone(), offer.stream_id), msg);
+    }
+
+    /// The transfer is over, one way or the other.
+    pub(crate) fn finish(&mut self, offer: &FileOffer) {
+        self.answered
+            .remove(&(offer.peer_id.clone(), offer.stream_id));
+    }
+
+    pub(crate) fn pending(&self) -> &[FileOffer] {
+        &self.pending
+    }
+}
+
+/// The name a file is offered under: its last path component.
+pub(crate) fn offered_name(path: &Path) -> io::Result<String> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{}' does not name a file", path.display()),
+            )
+        })
+}
+
+/// Whether an offered name is safe to create in the download directory:
+/// a single path component, not hidden, no separators.
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\', '\0'])
+        && Path::new(name).file_name().is_some_and(|n| n == name)
+}
+
+/// Where an incoming file collects until its hash checks out. It survives
+/// an interrupted transfer, and its length is the offset to resume from.
+pub(crate) fn part_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.part", name))
+}
+
+/// Size and SHA-256 of a file.
+pub(crate) fn hash_file(path: &Path) -> io::Result<(u64, Vec<u8>)> {
+    hash(File::open(path)?)
+}
+
+/// SHA-256 of the first `len` bytes of a file, which the receiver of an
+/// interrupted transfer already has.
+pub(crate) fn hash_prefix(path: &Path, len: u64) -> io::Result<Vec<u8>> {
+    Ok(hash(File::open(path)?.take(len))?.1)
+}
+
+fn hash(mut file: impl Read) -> io::Result<(u64, Vec<u8>)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; CHUNK];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hasher.finalize().to_vec()))
+}
+
+/// Write `path` from `offset` on to the stream and end our side of it.
+/// The body starts with `offset` itself, as 8 bytes big-endian, so the
+/// receiver knows where to continue its part file.
+pub(crate) fn send_body(
+    stream: &mut Stream,
+    path: &Path,
+    offset: u64,
+    mut progress: impl FnMut(u64),
+) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    stream.write_all(&offset.to_be_bytes())?;
+    let mut buf = vec![0; CHUNK];
+    let mut done = offset;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        stream.write_all(&buf[..n])?;
+        done += n as u64;
+        progress(done);
+    }
+    stream.shutdown();
+    Ok(done - offset)
+}
+
+/// Append the stream to the part file, from where the sender says it
+/// starts, until the sender ends it. Fails, keeping what arrived, if it
+/// ends early or overshoots `size`.
+pub(crate) fn receive_body(
+    stream: &mut Stream,
+    part: &Path,
+    size: u64,
+    mut progress: impl FnMut(u64),
+) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).open(part)?;
+    let mut start = [0; 8];
+    stream.read_exact(&mut start)?;
+    let start = u64::from_be_bytes(start);
+    if start > file.metadata()?.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "sender resumed past what we have",
+        ));
+    }
+    // less than we have: what we kept was from some other version
+    file.set_len(start)?;
+    let mut done = start;
+    let mut buf = vec![0; CHUNK];
+    loop {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if done + n as u64 > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "sender sent more than it offered",
+            ));
+        }
+        file.write_all(&buf[..n])?;
+        done += n as u64;
+        progress(done);
+    }
+    file.sync_all()?;
+    if done != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("transfer ended at {} of {} bytes", done, size),
+        ));
+    }
+    Ok(())
+}
+
+/// Check the finished part file against the offered hash and move it into
+/// place, returning where it went. A mismatch deletes it, so the next
+/// attempt starts over. An existing file is never replaced: the download is
+/// saved as `name (1).ext`, `name (2).ext` and so on instead.
+pub(crate) fn verify_and_finish(part: &Path, dest: &Path, sha256: &[u8]) -> io::Result<PathBuf> {
+    let (_, actual) = hash_file(part)?;
+    if actual != sha256 {
+        fs::remove_file(part)?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "SHA-256 mismatch, discarded the download",
+        ));
+    }
+    let dest = reserve_free_path(dest)?;
+    fs::rename(part, &dest)?;
+    Ok(dest)
+}
+
+// Create an empty file at `dest` or the first free numbered variant of it,
+// so nothing else takes the name before we rename onto it.
+fn reserve_free_path(dest: &Path) -> io::Result<PathBuf> {
+    let stem = dest.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = dest.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    for n in 0u32.. {
+        let candidate = match n {
+            0 => dest.to_path_buf(),
+            n => dest.with_file_name(format!("{} ({}){}", stem, n, ext)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("some name is free")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer(peer_id: &str, stream_id: u32) -> FileOffer {
+        FileOffer {
+            peer_id: peer_id.to_string(),
+            stream_id,
+            name: "a.txt".to_string(),
+            size: 3,
+            sha256: vec![],
+        }
+    }
+
+    #[test]
+    fn test_offers() {
+        let mut offers = Offers::default();
+        assert!(offers.offer(offer("alice", 0)));
+        assert!(!offers.offer(offer("alice", 0)));
+        assert!(offers.offer(offer("bob", 1)));
+        assert!(offers.offer(offer("alice", 2)));
+
+        let first = offers.take(Some("bob")).unwrap();
+        assert_eq!(first.stream_id, 1);
+        let first = offers.take(None).unwrap();
+        assert_eq!((first.peer_id.as_str(), first.stream_id), ("alice", 0));
+        offers.answer(
+            &first,
+            PeerMessage::FileAccept {
+                stream_id: 0,
+                offset: 0,
+                prefix_sha256: vec![],
+            },
+        );
+        assert!(offers.answer_for("alice", 0).is_some());
+        offers.finish(&first);
+        assert!(offers.answer_for("alice", 0).is_none());
+        assert_eq!(offers.pending().len(), 1);
+    }
+
+    #[test]
+    fn test_safe_names() {
+        assert!(is_safe_name("report.pdf"));
+        for name in ["", ".", "..", ".bashrc", "../x", "a/b", "a\\b", "/etc/passwd"] {
+            assert!(!is_safe_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_verify_and_finish() {
+        let dir = std::env::temp_dir().join(format!("nt-transfer-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let part = part_path(&dir, "a.txt");
+        let dest = dir.join("a.txt");
+
+        fs::write(&part, b"abc").unwrap();
+        let (size, sha256) = hash_file(&part).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(hash_prefix(&part, 3).unwrap(), sha256);
+        assert_ne!(hash_prefix(&part, 2).unwrap(), sha256);
+        assert!(verify_and_finish(&part, &dest, &[0; 32]).is_err());
+        assert!(!part.exists());
+
+        fs::write(&part, b"abc").unwrap();
+        assert_eq!(verify_and_finish(&part, &dest, &sha256).unwrap(), dest);
+        assert_eq!(fs::read(&dest).unwrap(), b"abc");
+
+        // a file of that name is kept, the download goes next to it
+        for n in 1..=2 {
+            fs::write(&part, b"abc").unwrap();
+            let saved = verify_and_finish(&part, &dest, &sha256).unwrap();
+            assert_eq!(saved, dir.join(format!("a ({}).txt", n)));
+            assert_eq!(fs::read(&saved).unwrap(), b"abc");
+        }
+        fs::write(&dest, b"mine").unwrap();
+        fs::write(&part, b"abc").unwrap();
+        assert_eq!(verify_and_finish(&part, &dest, &sha256).unwrap(), dir.join("a (3).txt"));
+        assert_eq!(fs::read(&dest).unwrap(), b"mine");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
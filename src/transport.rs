@@ -0,0 +1,739 @@
+//! What `Server` and `Client` send and receive datagrams through: a real
+//! `UdpSocket`, a `MemorySocket` passing datagrams over channels, or a
+//! socket on a simulated network (see `sim`). `Impaired` adds loss and
+//! latency to any of them, and `SocketGroup` receives on several at once.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// where `MemoryNetwork` hands out ports for binds to port 0
+const FIRST_EPHEMERAL: u16 = 40000;
+// how long a `SocketGroup`'s primary reader blocks before checking whether
+// it should stop
+const GROUP_READ_SLICE: Duration = Duration::from_millis(50);
+// how long the `SocketGroup` poller rests after finding its sockets empty
+const GROUP_POLL_PAUSE: Duration = Duration::from_millis(2);
+// datagrams a `Mailbox` holds before it drops new ones, like a full OS buffer
+const QUEUE_LIMIT: usize = 4096;
+// how long a send on a non-blocking socket waits for the buffer to drain
+const SEND_RETRY: Duration = Duration::from_millis(1);
+
+/// A bound datagram socket. Methods take `&self` so one socket can be
+/// shared between a foreground call and a background listener.
+pub trait DatagramTransport: Send + Sync {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Fails with `WouldBlock` or `TimedOut` once the read timeout passes.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// `None` blocks until a datagram arrives.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Non-blocking, `recv_from` fails with `WouldBlock` at once when
+    /// nothing is there, and so may `send_to` when the buffer is full.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Another socket on the same host and IP, at `port` (0 for any free
+    /// one). The server binds its relay ports this way.
+    fn bind_sibling(&self, port: u16) -> io::Result<Arc<dyn DatagramTransport>>;
+
+    /// The OS socket underneath, if there is one. The async server and
+    /// client need it to register with tokio.
+    fn as_udp(&self) -> Option<&UdpSocket> {
+        None
+    }
+}
+
+impl DatagramTransport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn bind_sibling(&self, port: u16) -> io::Result<Arc<dyn DatagramTransport>> {
+        let ip = UdpSocket::local_addr(self)?.ip();
+        Ok(Arc::new(UdpSocket::bind(SocketAddr::new(ip, port))?))
+    }
+
+    fn as_udp(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+}
+
+/// The std handle of a socket tokio also receives on. Non-blocking mode is
+/// a property of the socket, not the handle, so a send that finds the
+/// buffer full would fail with `WouldBlock`; this one waits instead, as it
+/// would on a blocking socket.
+#[cfg(feature = "async")]
+pub(crate) struct SharedWithTokio(UdpSocket);
+
+#[cfg(feature = "async")]
+impl SharedWithTokio {
+    /// Switch `socket` to non-blocking and return a tokio handle for
+    /// receiving and a std one for sending.
+    pub(crate) fn split(socket: &UdpSocket) -> io::Result<(tokio::net::UdpSocket, Arc<Self>)> {
+        let sender = Arc::new(Self(socket.try_clone()?));
+        let receiver = socket.try_clone()?;
+        receiver.set_nonblocking(true)?;
+        Ok((tokio::net::UdpSocket::from_std(receiver)?, sender))
+    }
+}
+
+#[cfg(feature = "async")]
+impl DatagramTransport for SharedWithTokio {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        send_waiting(&self.0, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(timeout)
+    }
+
+    // tokio needs it non-blocking
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match nonblocking {
+            true => Ok(()),
+            false => Err(io::Error::new(io::ErrorKind::Unsupported, "socket is shared with tokio")),
+        }
+    }
+
+    fn bind_sibling(&self, port: u16) -> io::Result<Arc<dyn DatagramTransport>> {
+        self.0.bind_sibling(port)
+    }
+
+    fn as_udp(&self) -> Option<&UdpSocket> {
+        Some(&self.0)
+    }
+}
+
+// Send on a socket that may be non-blocking, waiting out a full buffer as
+// a blocking send would.
+fn send_waiting(socket: &dyn DatagramTransport, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    loop {
+        match socket.send_to(buf, addr) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(SEND_RETRY),
+            sent => return sent,
+        }
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// The receiving end of an in-process socket: datagrams queue up here
+/// until `recv_from` takes them, within the read timeout if one is set.
+#[derive(Default)]
+pub(crate) struct Mailbox {
+    queue: Mutex<VecDeque<Datagram>>,
+    arrived: Condvar,
+    read_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+}
+
+impl Mailbox {
+    /// Queue a datagram, or drop it if the queue is full.
+    pub(crate) fn push(&self, payload: Vec<u8>, from: SocketAddr) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() < QUEUE_LIMIT {
+            queue.push_back((payload, from));
+            self.arrived.notify_one();
+        }
+    }
+
+    pub(crate) fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = self.read_timeout.lock().unwrap().map(|t| Instant::now() + t);
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some((payload, from)) = queue.pop_front() {
+                // like UDP, whatever doesn't fit is cut off
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+                return Ok((len, from));
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            queue = match deadline {
+                None => self.arrived.wait(queue).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    self.arrived.wait_timeout(queue, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero read timeout"));
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Sockets that pass datagrams straight into each other's mailboxes, with
+/// no NATs or loss in between. Clones share the network.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    sockets: Arc<Mutex<HashMap<SocketAddr, Arc<Mailbox>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A socket at `addr`, or at a free port on its IP if the port is 0.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<Arc<MemorySocket>> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let addr = match addr.port() {
+            0 => free_port(&sockets, addr.ip())?,
+            _ if sockets.contains_key(&addr) => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, addr.to_string()))
+            }
+            _ => addr,
+        };
+        let mailbox = Arc::new(Mailbox::default());
+        sockets.insert(addr, mailbox.clone());
+        Ok(Arc::new(MemorySocket {
+            net: self.clone(),
+            addr,
+            mailbox,
+        }))
+    }
+}
+
+fn free_port(sockets: &HashMap<SocketAddr, Arc<Mailbox>>, ip: IpAddr) -> io::Result<SocketAddr> {
+    (FIRST_EPHEMERAL..=u16::MAX)
+        .map(|port| SocketAddr::new(ip, port))
+        .find(|addr| !sockets.contains_key(addr))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "no free port"))
+}
+
+/// A socket on a `MemoryNetwork`. Unbinds when dropped.
+pub struct MemorySocket {
+    net: MemoryNetwork,
+    addr: SocketAddr,
+    mailbox: Arc<Mailbox>,
+}
+
+impl DatagramTransport for MemorySocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        // nobody bound there: gone, as with UDP
+        if let Some(mailbox) = self.net.sockets.lock().unwrap().get(&addr) {
+            mailbox.push(buf.to_vec(), self.addr);
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.mailbox.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.mailbox.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.mailbox.set_nonblocking(nonblocking)
+    }
+
+    fn bind_sibling(&self, port: u16) -> io::Result<Arc<dyn DatagramTransport>> {
+        Ok(self.net.bind(SocketAddr::new(self.addr.ip(), port))?)
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.net.sockets.lock().unwrap().remove(&self.addr);
+    }
+}
+
+/// What `Impaired` does to outgoing datagrams.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Impairment {
+    /// Share of datagrams dropped, from 0 to 1.
+    pub loss: f64,
+    /// Added to every datagram's delivery.
+    pub latency: Duration,
+    /// Up to this much more, at random, which can reorder datagrams.
+    pub jitter: Duration,
+}
+
+/// Another transport whose sends are dropped and delayed per `Impairment`.
+/// Delayed datagrams go out from a thread of their own.
+pub struct Impaired {
+    inner: Arc<dyn DatagramTransport>,
+    impairment: Impairment,
+    delayed: Mutex<mpsc::Sender<(Instant, Datagram)>>,
+}
+
+impl Impaired {
+    pub fn new(inner: Arc<dyn DatagramTransport>, impairment: Impairment) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let sender = inner.clone();
+        thread::spawn(move || deliver_delayed(&*sender, rx));
+        Self {
+            inner,
+            impairment,
+            delayed: Mutex::new(tx),
+        }
+    }
+}
+
+// Send each datagram once its time comes, until the `Impaired` is gone and
+// nothing is left to send.
+fn deliver_delayed(socket: &dyn DatagramTransport, rx: mpsc::Receiver<(Instant, Datagram)>) {
+    let mut queue: BinaryHeap<Reverse<(Instant, u64, Datagram)>> = BinaryHeap::new();
+    let mut seq = 0u64;
+    let mut open = true;
+    while open || !queue.is_empty() {
+        let now = Instant::now();
+        while queue.peek().is_some_and(|Reverse((due, _, _))| *due <= now) {
+            let Reverse((_, _, (payload, to))) = queue.pop().unwrap();
+            let _ = socket.send_to(&payload, to);
+        }
+        let received = match queue.peek() {
+            Some(Reverse((due, _, _))) => rx.recv_timeout(due.saturating_duration_since(now)),
+            None if open => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            None => break,
+        };
+        match received {
+            // `seq` keeps datagrams due at the same instant in order
+            Ok((due, datagram)) => {
+                queue.push(Reverse((due, seq, datagram)));
+                seq += 1;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => open = false,
+        }
+    }
+}
+
+// uniform in [0, 1)
+fn random_fraction() -> f64 {
+    let mut bytes = [0; 8];
+    getrandom::fill(&mut bytes).expect("no system randomness");
+    (u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl DatagramTransport for Impaired {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let Impairment { loss, latency, jitter } = self.impairment;
+        if loss > 0.0 && random_fraction() < loss {
+            return Ok(buf.len());
+        }
+        let delay = latency + jitter.mul_f64(random_fraction());
+        if delay.is_zero() {
+            return self.inner.send_to(buf, addr);
+        }
+        let due = Instant::now() + delay;
+        let _ = self.delayed.lock().unwrap().send((due, (buf.to_vec(), addr)));
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.inner.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn bind_sibling(&self, port: u16) -> io::Result<Arc<dyn DatagramTransport>> {
+        Ok(Arc::new(Impaired::new(self.inner.bind_sibling(port)?, self.impairment)))
+    }
+}
+
+/// A primary socket plus extra ones, received on as one. Each address is
+/// answered from the socket it was last heard on, anything else goes out
+/// the primary. Spraying (see `spray`) adds its sockets here, so whichever
+/// one a punch gets through on carries the connection from then on.
+///
+/// A thread reads the primary socket and one more polls all the extra
+/// ones, which are switched to non-blocking.
+pub struct SocketGroup {
+    primary: Arc<dyn DatagramTransport>,
+    shared: Arc<GroupShared>,
+}
+
+struct Extra {
+    socket: Arc<dyn DatagramTransport>,
+    // released, and kept only while an address is routed through it
+    route_only: bool,
+}
+
+struct GroupShared {
+    mailbox: Mailbox,
+    // addresses last heard on an extra socket, and that socket
+    routes: Mutex<HashMap<SocketAddr, Arc<dyn DatagramTransport>>>,
+    extras: Mutex<Vec<Extra>>,
+    closed: AtomicBool,
+}
+
+impl GroupShared {
+    // Answer `from` on `socket` from now on, or on the primary without one.
+    fn route(&self, from: SocketAddr, socket: Option<&Arc<dyn DatagramTransport>>) {
+        let mut routes = self.routes.lock().unwrap();
+        let moved = match socket {
+            Some(socket) => routes
+                .insert(from, socket.clone())
+                .is_some_and(|old| !Arc::ptr_eq(&old, socket)),
+            None => routes.remove(&from).is_some(),
+        };
+        if moved {
+            self.prune(&routes);
+        }
+    }
+
+    // Close the released sockets no address is routed through any more.
+    fn prune(&self, routes: &HashMap<SocketAddr, Arc<dyn DatagramTransport>>) {
+        self.extras
+            .lock()
+            .unwrap()
+            .retain(|e| !e.route_only || routes.values().any(|r| Arc::ptr_eq(r, &e.socket)));
+    }
+}
+
+impl SocketGroup {
+    pub fn new(primary: Arc<dyn DatagramTransport>) -> io::Result<Self> {
+        let shared = Arc::new(GroupShared {
+            mailbox: Mailbox::default(),
+            routes: Mutex::new(HashMap::new()),
+            extras: Mutex::new(Vec::new()),
+            closed: AtomicBool::new(false),
+        });
+        spawn_reader(&shared, primary.clone())?;
+        spawn_poller(&shared);
+        Ok(Self { primary, shared })
+    }
+
+    /// Receive on `socket` too. It is switched to non-blocking.
+    pub fn add(&self, socket: Arc<dyn DatagramTransport>) -> io::Result<()> {
+        socket.set_nonblocking(true)?;
+        self.shared.extras.lock().unwrap().push(Extra {
+            socket,
+            route_only: false,
+        });
+        Ok(())
+    }
+
+    /// Stop receiving on those of `sockets` that no address is routed
+    /// through; they close once the poller lets go. The others stay until
+    /// their last route moves away or is forgotten. Returns how many are
+    /// kept.
+    pub fn release_unrouted(&self, sockets: &[Arc<dyn DatagramTransport>]) -> usize {
+        let routes = self.shared.routes.lock().unwrap();
+        let mut kept = 0;
+        self.shared.extras.lock().unwrap().retain_mut(|extra| {
+            if !sockets.iter().any(|s| Arc::ptr_eq(s, &extra.socket)) {
+                return true;
+            }
+            if routes.values().any(|r| Arc::ptr_eq(r, &extra.socket)) {
+                extra.route_only = true;
+                kept += 1;
+                return true;
+            }
+            false
+        });
+        kept
+    }
+
+    /// Answer `addr` from the primary again, e.g. once its peer is gone,
+    /// closing the extra socket it was routed through if that was released.
+    pub fn forget(&self, addr: SocketAddr) {
+        self.shared.route(addr, None);
+    }
+
+    /// Whether `addr` was last heard on one of the extra sockets.
+    pub fn is_routed(&self, addr: SocketAddr) -> bool {
+        self.shared.routes.lock().unwrap().contains_key(&addr)
+    }
+
+    #[cfg(test)]
+    fn extras(&self) -> usize {
+        self.shared.extras.lock().unwrap().len()
+    }
+}
+
+// Read the primary socket into the group's mailbox until the group is done.
+fn spawn_reader(shared: &Arc<GroupShared>, socket: Arc<dyn DatagramTransport>) -> io::Result<()> {
+    socket.set_read_timeout(Some(GROUP_READ_SLICE))?;
+    let shared = shared.clone();
+    thread::spawn(move || {
+        let mut buf = vec![0; 65536];
+        while !shared.closed.load(Ordering::Relaxed) {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(_) => break,
+            };
+            // routed before anyone can see the datagram, so the answer
+            // already goes out the right socket
+            shared.route(from, None);
+            shared.mailbox.push(buf[..len].to_vec(), from);
+        }
+    });
+    Ok(())
+}
+
+// Drain the extra sockets in turn until the group is done, resting
+// whenever a round found them all empty.
+fn spawn_poller(shared: &Arc<GroupShared>) {
+    let shared = shared.clone();
+    thread::spawn(move || {
+        let mut buf = vec![0; 65536];
+        while !shared.closed.load(Ordering::Relaxed) {
+            let sockets: Vec<_> = shared.extras.lock().unwrap().iter().map(|e| e.socket.clone()).collect();
+            let mut idle = true;
+            for socket in &sockets {
+                // anything but a datagram leaves the socket for next round
+                while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                    idle = false;
+                    shared.route(from, Some(socket));
+                    shared.mailbox.push(buf[..len].to_vec(), from);
+                }
+            }
+            drop(sockets);
+            if idle {
+                thread::sleep(GROUP_POLL_PAUSE);
+            }
+        }
+    });
+}
+
+impl DatagramTransport for SocketGroup {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let route = self.shared.routes.lock().unwrap().get(&addr).cloned();
+        match route {
+            Some(socket) => send_waiting(&*socket, buf, addr),
+            None => self.primary.send_to(buf, addr),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.shared.mailbox.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.primary.local_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.shared.mailbox.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.shared.mailbox.set_nonblocking(nonblocking)
+    }
+
+    fn bind_sibling(&self, port: u16) -> io::Result<Arc<dyn DatagramTransport>> {
+        self.primary.bind_sibling(port)
+    }
+}
+
+impl Drop for SocketGroup {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, ClientConfig};
+    use crate::clock::SkewedClock;
+    use crate::event::ClientEvent;
+    use crate::server::Server;
+    use std::sync::atomic::Ordering;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn received(socket: &dyn DatagramTransport, wait: Duration) -> usize {
+        socket.set_read_timeout(Some(wait)).unwrap();
+        std::iter::from_fn(|| socket.recv_from(&mut [0; 16]).ok()).count()
+    }
+
+    #[test]
+    fn test_memory_sockets() {
+        let net = MemoryNetwork::new();
+        let a = net.bind(addr("10.0.0.1:0")).unwrap();
+        let b = net.bind(addr("10.0.0.2:7000")).unwrap();
+        assert_eq!(a.local_addr().unwrap(), addr("10.0.0.1:40000"));
+        assert!(net.bind(addr("10.0.0.2:7000")).is_err());
+
+        a.send_to(b"hello", b.local_addr().unwrap()).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(b.recv_from(&mut buf).unwrap(), (3, a.local_addr().unwrap()));
+        assert_eq!(&buf, b"hel");
+
+        b.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(b.recv_from(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let sibling = b.bind_sibling(0).unwrap();
+        assert_eq!(sibling.local_addr().unwrap().ip(), b.local_addr().unwrap().ip());
+        drop(b);
+        a.send_to(b"gone", addr("10.0.0.2:7000")).unwrap();
+    }
+
+    #[test]
+    fn test_socket_group() {
+        let net = MemoryNetwork::new();
+        let group = SocketGroup::new(net.bind(addr("10.0.0.1:5000")).unwrap()).unwrap();
+        group.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let extras: Vec<Arc<dyn DatagramTransport>> = (0..3).map(|_| group.bind_sibling(0).unwrap()).collect();
+        for extra in &extras {
+            group.add(extra.clone()).unwrap();
+        }
+        let peer = net.bind(addr("10.0.0.2:6000")).unwrap();
+        let mut buf = [0; 16];
+
+        // one poller reads them all; replies go out where the peer was heard
+        for extra in &extras {
+            peer.send_to(b"hi", extra.local_addr().unwrap()).unwrap();
+            assert_eq!(group.recv_from(&mut buf).unwrap(), (2, peer.local_addr().unwrap()));
+        }
+        assert!(group.is_routed(peer.local_addr().unwrap()));
+        group.send_to(b"back", peer.local_addr().unwrap()).unwrap();
+        assert_eq!(peer.recv_from(&mut buf).unwrap().1, extras[2].local_addr().unwrap());
+
+        assert_eq!(group.release_unrouted(&extras), 1);
+        assert_eq!(group.extras(), 1);
+        group.forget(peer.local_addr().unwrap());
+        assert_eq!(group.extras(), 0);
+        group.send_to(b"back", peer.local_addr().unwrap()).unwrap();
+        assert_eq!(peer.recv_from(&mut buf).unwrap().1, addr("10.0.0.1:5000"));
+    }
+
+    #[test]
+    fn test_impairment() {
+        let net = MemoryNetwork::new();
+        let receiver = net.bind(addr("10.0.0.2:0")).unwrap();
+        let to = receiver.local_addr().unwrap();
+
+        let lossy = Impaired::new(
+            net.bind(addr("10.0.0.1:0")).unwrap(),
+            Impairment { loss: 0.5, ..Impairment::default() },
+        );
+        for _ in 0..400 {
+            lossy.send_to(b"x", to).unwrap();
+        }
+        let arrived = received(&*receiver, Duration::from_millis(10));
+        assert!((100..300).contains(&arrived), "{} of 400 arrived", arrived);
+
+        let slow = Impaired::new(
+            net.bind(addr("10.0.0.1:0")).unwrap(),
+            Impairment {
+                latency: Duration::from_millis(60),
+                jitter: Duration::from_millis(20),
+                ..Impairment::default()
+            },
+        );
+        for _ in 0..10 {
+            slow.send_to(b"x", to).unwrap();
+        }
+        assert_eq!(received(&*receiver, Duration::from_millis(40)), 0);
+        drop(slow);
+        assert_eq!(received(&*receiver, Duration::from_millis(100)), 10);
+    }
+
+    #[test]
+    fn test_signaling_in_process() {
+        let net = MemoryNetwork::new();
+        let impairment = Impairment {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            ..Impairment::default()
+        };
+        let impaired = |addr: SocketAddr| -> Arc<dyn DatagramTransport> {
+            Arc::new(Impaired::new(net.bind(addr).unwrap(), impairment))
+        };
+
+        let mut server = Server::with_transport(impaired(addr("192.0.2.1:9090"))).unwrap();
+        thread::spawn(move || server.run());
+        let server_addr = addr("192.0.2.1:9090");
+
+        let mut clients: Vec<_> = ["alice", "bob"]
+            .iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let socket = impaired(SocketAddr::new([10, 0, 0, i as u8 + 1].into(), 0));
+                Client::with_transport(id.to_string(), server_addr, ClientConfig::default(), socket).unwrap()
+            })
+            .collect();
+        // bob's wall clock runs 3s behind everyone else's
+        clients[1].set_clock(Arc::new(SkewedClock { offset_ms: -3_000 }));
+        for client in &mut clients {
+            client.register().unwrap();
+        }
+        let bob_events = clients[1].events();
+
+        let bob_addr = clients[0].connect_to_peer("bob").unwrap();
+        assert_eq!(bob_addr, clients[1].external_addr.unwrap());
+        // within half the worst round trip of 20ms
+        let alice_offset = clients[0].clock_offset().unwrap();
+        let bob_offset = clients[1].clock_offset().unwrap();
+        assert!(alice_offset.offset_ms.abs() <= 10, "{:?}", alice_offset);
+        assert!((bob_offset.offset_ms - 3_000).abs() <= 10, "{:?}", bob_offset);
+        clients[0].send_message("bob", "over channels").unwrap();
+        let delivered = std::iter::from_fn(|| bob_events.recv_timeout(Duration::from_secs(2)).ok()).any(|event| {
+            matches!(event, ClientEvent::MessageReceived { message, .. } if message == "over channels")
+        });
+        assert!(delivered);
+        for client in &clients {
+            client.should_listen.store(false, Ordering::Relaxed);
+        }
+    }
+}